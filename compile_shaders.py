@@ -6,6 +6,18 @@ import os
 import argparse
 
 
+# Shaders also built with a macro defined, into `<name>.<macro in lowercase>.spv`
+VARIANTS = {
+    'resources/shaders/deferred_lighting.frag': ['SINGLE_SAMPLE'],
+    'resources/shaders/point_lighting.frag': ['SINGLE_SAMPLE'],
+    'resources/shaders/ssr/ssr.frag': ['SINGLE_SAMPLE'],
+    'resources/shaders/blocks_terrain/selection_peel.frag': ['SINGLE_SAMPLE', 'FIRST_SAMPLE'],
+    'resources/shaders/decal/decal.frag': ['SINGLE_SAMPLE', 'FIRST_SAMPLE'],
+    'resources/shaders/imgui/render_depth_multisampled.frag': ['SINGLE_SAMPLE'],
+    'resources/shaders/blocks_terrain/mrt_bindless.frag': ['UNIFORM_INDEX'],
+}
+
+
 def get_mtime(fpath) -> float:
     try:
         stat = os.stat(fpath)
@@ -28,19 +40,25 @@ def main():
                 os.unlink(src_path)
         return
 
+    # Shared snippets may be included by any shader, so a change there rebuilds everything
+    includes_mtime = max([get_mtime(f) for f in glob.glob('resources/shaders/common/*.glsl')] + [0.0])
+
     for file in glob.glob('resources/shaders/**', recursive=True):
         src_path = os.path.abspath(file)
-        if not (src_path.endswith('.vert') or src_path.endswith('.frag')):
+        if not (src_path.endswith('.vert') or src_path.endswith('.frag') or src_path.endswith('.comp')):
             continue
 
-        dst_path = '{}.spv'.format(src_path)
+        builds = [('{}.spv'.format(src_path), [])]
+        for macro in VARIANTS.get(file.replace(os.sep, '/'), []):
+            builds.append(('{}.{}.spv'.format(src_path, macro.lower()), ['-D{}'.format(macro)]))
 
-        if get_mtime(dst_path) > get_mtime(src_path):
-            continue
+        for dst_path, defines in builds:
+            if get_mtime(dst_path) > max(get_mtime(src_path), includes_mtime):
+                continue
 
-        print('Process {}'.format(file))
-        ret = subprocess.run(['glslc', '-o', dst_path, src_path])
-        ret.check_returncode()
+            print('Process {} {}'.format(file, ' '.join(defines)).rstrip())
+            ret = subprocess.run(['glslc', '-I', 'resources/shaders'] + defines + ['-o', dst_path, src_path])
+            ret.check_returncode()
 
 
 if __name__ == '__main__':
@@ -0,0 +1,5 @@
+#version 450
+
+// Bounding boxes only feed occlusion queries, color writes are masked out.
+void main() {
+}
@@ -1,17 +1,27 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "common/material.glsl"
 
 layout(location = 0) out vec4 f_color;
 layout(location = 1) out vec4 f_normal;
 layout(location = 2) out vec4 f_position;
+layout(location = 3) out uint f_object_id;
 
 
 layout(location = 1) in vec3 in_normal;
 layout(location = 2) in vec3 in_world;
 layout(location = 3) in vec3 in_color;
 layout(location=5) in vec4 in_hightlight;
+layout(location=7) flat in uint in_object_id;
+
+// Material of the draw batch
+layout(set = 1, binding = 0) uniform sampler2D albedo;
 
 void main() {
-    f_color = vec4(in_color, 1.0) * in_hightlight.x + vec4(0.0, 0.0, 1.0, 1.0) * (1 - in_hightlight.x);
-    f_normal = vec4(in_normal, 1.0);
+    vec4 base = vec4(in_color, 1.0) * texture(albedo, box_uv(in_world, in_normal));
+    f_color = base * in_hightlight.x + vec4(0.0, 0.0, 1.0, 1.0) * (1 - in_hightlight.x);
+    f_normal = vec4(in_normal, BLOCK_REFLECTIVITY);
     f_position = vec4(in_world, 1.0);
+    f_object_id = in_object_id;
 }
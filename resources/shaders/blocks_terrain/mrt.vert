@@ -1,11 +1,19 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "common/instance.glsl"
 
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec3 normal;
 layout(location = 2) in vec3 color;
 
-layout(location = 3) in vec2 position_offset;
+// See `TerrainRenderSystem::block_offset`
+layout(location = 3) in vec3 position_offset;
+// Block id in rgb, alpha 0 for no id. See `RenderPipeline::ObjectIdMap`.
+layout(location = 4) in vec4 object_id;
 layout(location = 5) in vec4 highlight;
+layout(location = 6) in uint material_index;
+layout(location = 7) in vec4 transform;
 
 layout(set = 0, binding = 0) uniform Data {
     mat4 world;
@@ -17,18 +25,26 @@ layout(location=1) out vec3 rnormal;
 layout(location=2) out vec3 rpos;
 layout(location=3) out vec3 out_color;
 layout(location=5) out vec4 out_hightlight;
+layout(location=6) flat out uint out_material;
+// Block id plus one for the object id target of the gbuffer, 0 is nothing
+layout(location=7) flat out uint out_object_id;
 
 void main() {
     mat4 worldview = uniforms.view;// * uniforms.world;
 
-    vec3 s_pos = position;
+    vec3 s_pos = instance_position(position, transform);
     s_pos.x += position_offset.x;
-    s_pos.z -= position_offset.y;
+    s_pos.y += position_offset.y;
+    s_pos.z -= position_offset.z;
 
     gl_Position = uniforms.proj * worldview * vec4(s_pos, 1.0);
 
     rpos = s_pos;
-    rnormal = normal;
+    rnormal = instance_normal(normal, transform);
     out_color = color;
     out_hightlight = highlight;
+    out_material = material_index;
+
+    uvec4 id_bytes = uvec4(round(object_id * 255.0));
+    out_object_id = id_bytes.a == 0u ? 0u : (id_bytes.r | id_bytes.g << 8 | id_bytes.b << 16) + 1u;
 }
@@ -1,9 +1,13 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "common/instance.glsl"
 
 layout(location = 0) in vec3 position;
 
-layout(location = 3) in vec2 position_offset;
+layout(location = 3) in vec3 position_offset;
 layout(location = 4) in vec4 object_id;
+layout(location = 7) in vec4 transform;
 
 layout(set = 0, binding = 0) uniform Data {
     mat4 world;
@@ -16,10 +20,11 @@ layout(location=1) out vec4 out_color;
 void main() {
     mat4 worldview = uniforms.view;
 
+    vec3 local = instance_position(position, transform);
     vec4 s_pos = vec4(
-    position.x + position_offset.x,
-    position.y,
-    position.z - position_offset.y,
+    local.x + position_offset.x,
+    local.y + position_offset.y,
+    local.z - position_offset.z,
     1.0
     );
 
@@ -0,0 +1,16 @@
+#version 450
+
+layout(location = 1) in vec3 rnormal;
+
+layout(push_constant) uniform PushConstants {
+// Straight alpha, see `TerrainRenderSystem::render_selection`
+    vec4 color;
+} push_constants;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    // Side faces a bit darker than the top, so the overlay keeps the block shape readable
+    float shade = mix(0.7, 1.0, abs(rnormal.y));
+    f_color = vec4(push_constants.color.rgb * shade, push_constants.color.a);
+}
@@ -0,0 +1,23 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "common/gbuffer.glsl"
+#include "common/peel.glsl"
+
+layout(location = 1) in vec3 rnormal;
+
+layout(push_constant) uniform PushConstants {
+// Straight alpha, see `TerrainRenderSystem::render_selection`
+    vec4 color;
+} push_constants;
+
+layout(location = 0) out vec4 f_color;
+
+// `selection.frag` for depth peeling: one fragment per layer, so no blending and the color is
+// written premultiplied
+void main() {
+    peel();
+
+    float shade = mix(0.7, 1.0, abs(rnormal.y));
+    f_color = vec4(push_constants.color.rgb * shade * push_constants.color.a, push_constants.color.a);
+}
@@ -0,0 +1,9 @@
+// Shared depth helpers. Include with:
+//   #extension GL_GOOGLE_include_directive : require
+//   #include "common/depth.glsl"
+
+// Converts a depth buffer value written with the camera projection (cgmath::perspective,
+// OpenGL-style clip space) back to the linear view-space distance.
+float linearize_depth(float d, float near, float far) {
+    return 2.0 * near * far / (far + near - d * (far - near));
+}
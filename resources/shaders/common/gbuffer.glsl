@@ -0,0 +1,17 @@
+// G-buffer sampler type. Include with:
+//   #extension GL_GOOGLE_include_directive : require
+//   #include "common/gbuffer.glsl"
+//
+// The targets are multisampled unless the shader is built with SINGLE_SAMPLE (see `VARIANTS` in
+// compile_shaders.py). texelFetch takes the sample index in one case and the mip level in the
+// other, so sample 0 reads the only texel of a single-sampled target.
+
+#ifdef SINGLE_SAMPLE
+#define gbuffer_sampler sampler2D
+#define gbuffer_size(tex) textureSize(tex, 0)
+#define gbuffer_samples(tex) 1
+#else
+#define gbuffer_sampler sampler2DMS
+#define gbuffer_size(tex) textureSize(tex)
+#define gbuffer_samples(tex) textureSamples(tex)
+#endif
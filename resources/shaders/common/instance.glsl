@@ -0,0 +1,29 @@
+// Per-instance block transform. Include with:
+//   #extension GL_GOOGLE_include_directive : require
+//   #include "common/instance.glsl"
+//
+// `transform` is the scale along x/y/z in xyz and the rotation around the vertical axis in w
+// (radians), both applied around the center of the block footprint.
+
+// Center of the bottom face of the cube mesh
+const vec3 INSTANCE_PIVOT = vec3(0.5, 0.0, -0.5);
+
+vec2 rotate_xz(vec2 v, float angle) {
+    float c = cos(angle);
+    float s = sin(angle);
+    return vec2(c * v.x - s * v.y, s * v.x + c * v.y);
+}
+
+// Cube mesh position to block space, `position_offset` still has to be added
+vec3 instance_position(vec3 position, vec4 transform) {
+    vec3 local = (position - INSTANCE_PIVOT) * transform.xyz;
+    local.xz = rotate_xz(local.xz, transform.w);
+    return local + INSTANCE_PIVOT;
+}
+
+// Inverse transpose of the scale keeps the normals perpendicular to stretched faces
+vec3 instance_normal(vec3 normal, vec4 transform) {
+    vec3 n = normal / transform.xyz;
+    n.xz = rotate_xz(n.xz, transform.w);
+    return normalize(n);
+}
@@ -0,0 +1,18 @@
+// Block material helpers. Include with:
+//   #extension GL_GOOGLE_include_directive : require
+//   #include "common/material.glsl"
+
+// Box mapping: project the world position along the dominant axis of the normal
+vec2 box_uv(vec3 world, vec3 normal) {
+    vec3 n = abs(normal);
+    if (n.y >= n.x && n.y >= n.z) {
+        return world.xz;
+    }
+    if (n.x >= n.z) {
+        return world.zy;
+    }
+    return world.xy;
+}
+
+// Written to the alpha of the normals target, read by the screen-space reflections
+const float BLOCK_REFLECTIVITY = 0.3;
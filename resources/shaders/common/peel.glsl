@@ -0,0 +1,29 @@
+// Depth peeling test for pipelines drawing into `TransparentPass::peel_subpass`. Include with:
+//   #extension GL_GOOGLE_include_directive : require
+//   #include "common/gbuffer.glsl"
+//   #include "common/peel.glsl"
+//
+// Set 1 is `PeelLayer::descriptor_set`. Multisampled, the depths are read per sample, which
+// makes the fragment shader run per sample. That needs `sample_rate_shading`, devices without it
+// get the FIRST_SAMPLE build which tests the first sample of every pixel only.
+
+// Depth of the opaque scene
+layout(set = 1, binding = 0) uniform gbuffer_sampler u_opaque_depth;
+// Depth of the previous (nearer) layer, 0 before the first one
+layout(set = 1, binding = 1) uniform gbuffer_sampler u_peel_depth;
+
+// Discards fragments behind the opaque scene or already taken by a nearer layer. Fragments on the
+// opaque surface pass, like LessOrEqual. Coplanar fragments fall into the same layer, only one of
+// them is kept.
+void peel() {
+#if defined(SINGLE_SAMPLE) || defined(FIRST_SAMPLE)
+    int s = 0;
+#else
+    int s = gl_SampleID;
+#endif
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    float z = gl_FragCoord.z;
+    if (z > texelFetch(u_opaque_depth, pixel, s).r || z <= texelFetch(u_peel_depth, pixel, s).r) {
+        discard;
+    }
+}
@@ -0,0 +1,45 @@
+// Shadow map sampling. Include with:
+//   #extension GL_GOOGLE_include_directive : require
+//   #include "common/shadow.glsl"
+
+// Must match `shadow_map::MAX_CASCADES`
+#define MAX_CASCADES 4
+
+// Index of the cascade covering the view-space distance `view_distance`, given the far split of
+// every cascade. The last cascade also takes everything past it.
+int select_cascade(vec4 splits[MAX_CASCADES], int cascades, float view_distance) {
+    for (int i = 0; i < cascades - 1; i++) {
+        if (view_distance < splits[i].x) {
+            return i;
+        }
+    }
+    return cascades - 1;
+}
+
+// Fraction of light reaching `world_pos`, from the layer `cascade` of the shadow map rendered
+// with `light_view_proj` (depth in [0, 1]). Averages a (2 * pcf_radius + 1)^2 kernel of
+// comparisons around the projected point; `pcf_radius` 0 takes a single hard-edged tap.
+// `normal_offset` moves the point along its normal and `depth_bias` is subtracted from its
+// depth, both against shadow acne. Points outside of the map are lit.
+float sample_shadow(sampler2DArray shadow_map, int cascade, mat4 light_view_proj, vec3 world_pos,
+                    vec3 normal, int pcf_radius, float depth_bias, float normal_offset) {
+    vec4 light_pos = light_view_proj * vec4(world_pos + normal * normal_offset, 1.0);
+    vec3 coords = light_pos.xyz / light_pos.w;
+    vec2 uv = coords.xy * 0.5 + 0.5;
+
+    if (uv.x < 0.0 || uv.y < 0.0 || uv.x > 1.0 || uv.y > 1.0 || coords.z > 1.0) {
+        return 1.0;
+    }
+
+    vec2 texel = 1.0 / vec2(textureSize(shadow_map, 0).xy);
+    float lit = 0.0;
+    for (int y = -pcf_radius; y <= pcf_radius; y++) {
+        for (int x = -pcf_radius; x <= pcf_radius; x++) {
+            float closest = texture(shadow_map, vec3(uv + vec2(x, y) * texel, float(cascade))).r;
+            lit += (coords.z - depth_bias > closest) ? 0.0 : 1.0;
+        }
+    }
+
+    int size = 2 * pcf_radius + 1;
+    return lit / float(size * size);
+}
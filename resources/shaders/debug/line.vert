@@ -0,0 +1,15 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec4 color;
+
+layout(push_constant) uniform PushConstants {
+    mat4 view_proj;
+} push_constants;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    gl_Position = push_constants.view_proj * vec4(position, 1.0);
+    out_color = color;
+}
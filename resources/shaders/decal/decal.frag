@@ -0,0 +1,43 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "common/gbuffer.glsl"
+
+// Depth of the G-buffer, read per sample unless SINGLE_SAMPLE. FIRST_SAMPLE reads the first sample
+// of every pixel, for devices without `sample_rate_shading` (needed by gl_SampleID).
+layout(set = 0, binding = 0) uniform gbuffer_sampler u_depth;
+// Decal albedo with alpha, projected along the box y axis
+layout(set = 0, binding = 1) uniform sampler2D u_texture;
+
+layout(set = 0, binding = 2) uniform DecalData {
+// Inverse of `proj * view`, clip space to world
+    mat4 inv_view_proj;
+// Inverse of the decal transform, world to the unit box around the origin
+    mat4 inv_transform;
+} decal;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+#if defined(SINGLE_SAMPLE) || defined(FIRST_SAMPLE)
+    int s = 0;
+#else
+    int s = gl_SampleID;
+#endif
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    float depth = texelFetch(u_depth, pixel, s).r;
+    // Background, nothing was drawn there
+    if (depth >= 1.0) {
+        discard;
+    }
+
+    // The camera projection is used as is (see common/depth.glsl), so the depth is the clip z
+    vec2 ndc = gl_FragCoord.xy / vec2(gbuffer_size(u_depth)) * 2.0 - 1.0;
+    vec4 world = decal.inv_view_proj * vec4(ndc, depth, 1.0);
+    vec3 local = (decal.inv_transform * vec4(world.xyz / world.w, 1.0)).xyz;
+    if (any(greaterThan(abs(local), vec3(0.5)))) {
+        discard;
+    }
+
+    f_color = texture(u_texture, local.xz + 0.5);
+}
@@ -1,11 +1,52 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "common/depth.glsl"
+#include "common/gbuffer.glsl"
+#include "common/shadow.glsl"
 
 // The `color_input` parameter of the `draw` method.
-layout(set = 0, binding = 0) uniform sampler2DMS u_diffuse;
+layout(set = 0, binding = 0) uniform gbuffer_sampler u_diffuse;
+// The `depth_input` parameter of the `draw` method.
+layout(set = 0, binding = 1) uniform gbuffer_sampler u_depth;
+layout(set = 0, binding = 2) uniform gbuffer_sampler u_normals;
+layout(set = 0, binding = 3) uniform gbuffer_sampler u_positions;
+// One layer per cascade
+layout(set = 0, binding = 4) uniform sampler2DArray u_shadow_map;
+
+layout(set = 0, binding = 5) uniform ShadowData {
+    mat4 light_view_proj[MAX_CASCADES];
+// x is the view-space distance where the cascade ends
+    vec4 splits[MAX_CASCADES];
+// Zero disables shadows.
+    int cascades;
+    int pcf_radius;
+    float depth_bias;
+    float normal_offset;
+} shadow;
+
+// Must match `MAX_AMBIENT_ZONES` in lighting_pass.rs
+#define MAX_AMBIENT_ZONES 8
+
+// World-space boxes with their own ambient color, see `AmbientZone`
+layout(set = 0, binding = 6) uniform ZoneData {
+// xyz is the box corner, w of `box_min` the distance over which the zone fades out
+    vec4 box_min[MAX_AMBIENT_ZONES];
+    vec4 box_max[MAX_AMBIENT_ZONES];
+    vec4 colors[MAX_AMBIENT_ZONES];
+// Zero uses the global ambient everywhere.
+    int count;
+} zones;
 
 layout(push_constant) uniform PushConstants {
-// The `ambient_color` parameter of the `draw` method.
+// The `ambient` parameter of the `draw` method, sky and ground color.
     vec4 color;
+    vec4 ground_color;
+    vec4 fog_color;
+// Zero density disables fog.
+    float fog_density;
+    float near;
+    float far;
 } push_constants;
 
 layout(location = 0) out vec4 f_color;
@@ -13,17 +54,51 @@ layout (constant_id = 0) const int NUM_SAMPLES = 8;
 
 layout (location = 1) in vec2 inUV;
 
+// Part of the ambient light left in full shadow
+const float SHADOW_AMBIENT = 0.4;
+
+
+// Global ambient blended towards the zones containing `world`, later zones win. Visually up is
+// -y, an up-facing `normal` gets the sky color.
+vec3 ambient_at(vec3 world, vec3 normal) {
+    vec3 ambient = mix(push_constants.ground_color.rgb, push_constants.color.rgb, clamp(0.5 - 0.5 * normal.y, 0.0, 1.0));
+    for (int i = 0; i < zones.count; i++) {
+        vec3 outside = max(max(zones.box_min[i].xyz - world, world - zones.box_max[i].xyz), 0.0);
+        float fade = max(zones.box_min[i].w, 0.0001);
+        float weight = 1.0 - clamp(length(outside) / fade, 0.0, 1.0);
+        ambient = mix(ambient, zones.colors[i].rgb, weight);
+    }
+    return ambient;
+}
 
 void main() {
     vec4 result = vec4(0.0);
+    float visibility = 0.0;
     for (int i = 0; i < NUM_SAMPLES; i++)
     {
         vec4 val = texelFetch(u_diffuse, ivec2(gl_FragCoord.xy), i);
-        result += val;
+
+        float depth = texelFetch(u_depth, ivec2(gl_FragCoord.xy), i).r;
+        float view_distance = linearize_depth(depth, push_constants.near, push_constants.far);
+        visibility += exp(-push_constants.fog_density * view_distance);
+
+        // Background samples have no position (w == 0)
+        vec4 position = texelFetch(u_positions, ivec2(gl_FragCoord.xy), i);
+        vec3 normal = texelFetch(u_normals, ivec2(gl_FragCoord.xy), i).xyz;
+        float lit = 1.0;
+        if (shadow.cascades > 0 && position.w > 0.0) {
+            int cascade = select_cascade(shadow.splits, shadow.cascades, view_distance);
+            lit = sample_shadow(u_shadow_map, cascade, shadow.light_view_proj[cascade], position.xyz, normal,
+                                shadow.pcf_radius, shadow.depth_bias, shadow.normal_offset);
+        }
+        vec3 ambient = position.w > 0.0 ? ambient_at(position.xyz, normal) : push_constants.color.rgb;
+        result += vec4(ambient, 1.0) * val * mix(SHADOW_AMBIENT, 1.0, lit);
     }
     // Average resolved samples
     result = result / float(NUM_SAMPLES);
+    visibility = visibility / float(NUM_SAMPLES);
 
-    f_color.rgb = push_constants.color.rgb * result.rgb;
-    f_color.a = 1.0;
+    // The blend scales the lights already in the target by the alpha, so fog covers them too
+    f_color.rgb = result.rgb * visibility + push_constants.fog_color.rgb * (1.0 - visibility);
+    f_color.a = visibility;
 }
@@ -0,0 +1,12 @@
+#version 450
+
+layout(push_constant) uniform PushConstants {
+// The clear color of the id map, see `Terrain::draw_object_id`
+    vec4 color;
+} push_constants;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    f_color = push_constants.color;
+}
@@ -1,16 +1,151 @@
 #version 450
 
 layout(location = 0) out vec4 f_color;
+layout(location = 1) out vec4 f_normal;
+layout(location = 2) out vec4 f_position;
+// The ground can't be picked
+layout(location = 3) out uint f_object_id;
+
 layout(location = 1) in vec3 in_normal;
 layout(location = 2) in vec3 in_world;
+// Grid coordinates of the height map
 layout(location = 3) in vec2 in_tex;
+layout(location = 4) in vec4 in_tangent;
 
 layout(set = 0, binding = 1) uniform sampler2D tex;
+// One texel per grid vertex, visually up is -y like the mesh
+layout(set = 0, binding = 2) uniform sampler2D heights;
+
+layout(set = 0, binding = 3) uniform AoData {
+    vec2 grid_size;
+    float cell_size;
+// Distance of the neighbour samples, in cells
+    float radius;
+// Zero disables the occlusion term.
+    float strength;
+    float slope_strength;
+// Of the grid texcoords, see `Terrain::new`
+    vec2 texture_scale;
+} ao;
+
+// Tangent space, only read when `use_normal_map` is set
+layout(set = 0, binding = 4) uniform sampler2D normal_map;
+// Output of normals.comp, one texel per grid vertex. Only read when `gpu_normals` is set.
+layout(set = 0, binding = 5) uniform sampler2D gpu_normals;
+
+layout(push_constant) uniform PushConstants {
+// Zero shades with the vertex normals only
+    int use_normal_map;
+// Non-zero projects the textures along the world axes instead of using the grid texcoords
+    int triplanar;
+// Non-zero takes the normals from `gpu_normals` instead of the vertices
+    int gpu_normals;
+} push_constants;
+
+// Texture coordinates of the albedo and normal map
+const float TEXTURE_SCALE = 1.0 / 25.0;
+
+// Never darker than this
+const float MIN_AO = 0.3;
+
+// Height map coordinates of the fragment. The texcoords are scaled, the height map isn't.
+vec2 grid_uv() {
+    return (in_tex / ao.texture_scale + 0.5) / ao.grid_size;
+}
+
+// Terrain normal before the normal map
+vec3 vertex_normal() {
+    if (push_constants.gpu_normals != 0) {
+        return normalize(texture(gpu_normals, grid_uv()).xyz);
+    }
+    return normalize(in_normal);
+}
+
+// Crevices (below the average of the neighbours) and steep slopes see less of the sky
+float analytic_ao() {
+    if (ao.strength <= 0.0 && ao.slope_strength <= 0.0) {
+        return 1.0;
+    }
+
+    vec2 uv = grid_uv();
+    vec2 offset = ao.radius / ao.grid_size;
+
+    float center = texture(heights, uv).r;
+    float neighbours = (texture(heights, uv + vec2(offset.x, 0.0)).r
+                      + texture(heights, uv - vec2(offset.x, 0.0)).r
+                      + texture(heights, uv + vec2(0.0, offset.y)).r
+                      + texture(heights, uv - vec2(0.0, offset.y)).r) * 0.25;
+
+    // Positive when the neighbours are higher, relative to the sampling distance
+    float concavity = max(center - neighbours, 0.0) / (ao.radius * ao.cell_size);
+    float slope = 1.0 - abs(vertex_normal().y);
+
+    return clamp(1.0 - ao.strength * concavity - ao.slope_strength * slope, MIN_AO, 1.0);
+}
+
+// Weights of the YZ, XZ and XY projections, sharpened so the blend zone stays narrow
+vec3 triplanar_weights(vec3 n) {
+    vec3 w = pow(abs(n), vec3(4.0));
+    return w / (w.x + w.y + w.z);
+}
+
+// Texture coordinates of the projections, scaled like the grid texcoords
+vec2 projected_tex(vec2 world) {
+    return world / ao.cell_size * ao.texture_scale * TEXTURE_SCALE;
+}
+
+vec4 triplanar_albedo(vec3 n) {
+    vec3 w = triplanar_weights(n);
+    return texture(tex, projected_tex(in_world.zy)) * w.x
+         + texture(tex, projected_tex(in_world.xz)) * w.y
+         + texture(tex, projected_tex(in_world.xy)) * w.z;
+}
+
+// Whiteout blend: every projection is treated as a normal map of the plane it's projected on
+vec3 triplanar_normal(vec3 n) {
+    vec3 w = triplanar_weights(n);
+    vec3 tx = texture(normal_map, projected_tex(in_world.zy)).xyz * 2.0 - 1.0;
+    vec3 ty = texture(normal_map, projected_tex(in_world.xz)).xyz * 2.0 - 1.0;
+    vec3 tz = texture(normal_map, projected_tex(in_world.xy)).xyz * 2.0 - 1.0;
+
+    // The map z faces the same way as the normal
+    tx = vec3(tx.xy + n.zy, abs(tx.z) * n.x);
+    ty = vec3(ty.xy + n.xz, abs(ty.z) * n.y);
+    tz = vec3(tz.xy + n.xy, abs(tz.z) * n.z);
+
+    return normalize(tx.zyx * w.x + ty.xzy * w.y + tz.xyz * w.z);
+}
+
+// Vertex normal perturbed by the normal map
+vec3 surface_normal() {
+    vec3 n = vertex_normal();
+    if (push_constants.use_normal_map == 0) {
+        return n;
+    }
+    if (push_constants.triplanar != 0) {
+        return triplanar_normal(n);
+    }
+
+    // Interpolation breaks the orthogonality, re-orthogonalize before building the frame
+    vec3 t = normalize(in_tangent.xyz - n * dot(n, in_tangent.xyz));
+    vec3 b = cross(n, t) * in_tangent.w;
+    vec3 mapped = texture(normal_map, in_tex * TEXTURE_SCALE).xyz * 2.0 - 1.0;
+    return normalize(mat3(t, b, n) * mapped);
+}
 
 void main() {
+    vec3 normal = surface_normal();
     vec3 light_pos = normalize(vec3(0.2, 0.2, 0.2));
-    float light_percent = max(-dot(light_pos, in_normal), 0.0);
+    float light_percent = max(-dot(light_pos, normal), 0.0);
 
-    f_color = texture(tex, in_tex / 25.0) * min(0.35+light_percent, 1.0);
+    vec4 albedo = push_constants.triplanar != 0
+        ? triplanar_albedo(vertex_normal())
+        : texture(tex, in_tex * TEXTURE_SCALE);
+    f_color = albedo * min(0.35+light_percent, 1.0);
+    // Scales everything the lighting pass derives from the albedo, the ambient term included
+    f_color.rgb *= analytic_ao();
+    // Alpha is the reflectivity, the ground is rough
+    f_normal = vec4(normal, 0.0);
+    f_position = vec4(in_world, 1.0);
+    f_object_id = 0u;
 }
-
@@ -3,6 +3,8 @@
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec3 normal;
 layout(location = 2) in vec2 texcoord;
+// Direction of growing texcoord.x, w is the handedness of the tangent frame
+layout(location = 3) in vec4 tangent;
 
 layout(set = 0, binding = 0) uniform Data {
     mat4 world;
@@ -13,6 +15,7 @@ layout(set = 0, binding = 0) uniform Data {
 layout(location=1) out vec3 rnormal;
 layout(location=2) out vec3 rpos;
 layout(location=3) out vec2 rtex;
+layout(location=4) out vec4 rtangent;
 void main() {
     mat4 worldview = uniforms.view;// * uniforms.world;
     gl_Position = uniforms.proj * worldview * vec4(position, 1.0);
@@ -20,5 +23,6 @@ void main() {
     rpos = position;
     rnormal = normal;
     rtex = texcoord;
+    rtangent = tangent;
 }
 
@@ -0,0 +1,33 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "common/depth.glsl"
+#include "common/gbuffer.glsl"
+
+layout(binding = 0) uniform gbuffer_sampler tex;
+
+// Same block as render.vert, extended with the camera `[near, far]` (see `GuiPass::set_depth_range`)
+layout(push_constant) uniform DepthPC {
+    mat4 matrix;
+    float near;
+    float far;
+};
+
+layout(location = 0) in vec2 f_uv;
+layout(location = 1) in vec4 f_color;
+
+layout(location = 0) out vec4 Target0;
+
+void main() {
+    float depth = 0.0;
+    ivec2 sz = gbuffer_size(tex);
+    int samples = gbuffer_samples(tex);
+    for (int i = 0; i < samples; i++) {
+        depth += texelFetch(tex, ivec2(f_uv.xy * sz), i).r;
+    }
+    depth = depth / samples;
+
+    // Linear distance, so the whole range is readable rather than just the near plane
+    float v = (linearize_depth(depth, near, far) - near) / (far - near);
+    Target0 = vec4(vec3(v), 1.0);
+}
@@ -10,9 +10,10 @@ layout(location = 0) out vec4 Target0;
 void main() {
     vec3 ret = vec3(0.0);
     ivec2 sz = textureSize(tex);
-    for (int i = 0; i < 4; i++) {
+    int samples = textureSamples(tex);
+    for (int i = 0; i < samples; i++) {
         ret += texelFetch(tex, ivec2(f_uv.xy * sz), i).rgb;
     }
 
-    Target0 = vec4(ret / 4, 1.0);
+    Target0 = vec4(ret / samples, 1.0);
 }
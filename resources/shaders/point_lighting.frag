@@ -0,0 +1,50 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "common/gbuffer.glsl"
+
+// G-buffer inputs, see `PointLightingSystem::draw`
+layout(set = 0, binding = 0) uniform gbuffer_sampler u_diffuse;
+layout(set = 0, binding = 1) uniform gbuffer_sampler u_normals;
+layout(set = 0, binding = 2) uniform gbuffer_sampler u_depth;
+
+layout(push_constant) uniform PushConstants {
+// Inverse of `proj * view`, maps NDC back to world space.
+    mat4 screen_to_world;
+// Light color, alpha is unused.
+    vec4 color;
+// Light position in world space, alpha is unused.
+    vec4 position;
+} push_constants;
+
+layout(location = 0) out vec4 f_color;
+layout (constant_id = 0) const int NUM_SAMPLES = 8;
+
+void main() {
+    ivec2 coord = ivec2(gl_FragCoord.xy);
+    vec2 ndc_xy = gl_FragCoord.xy / vec2(gbuffer_size(u_depth)) * 2.0 - 1.0;
+
+    vec3 result = vec3(0.0);
+    for (int i = 0; i < NUM_SAMPLES; i++)
+    {
+        float depth = texelFetch(u_depth, coord, i).r;
+        if (depth >= 1.0) {
+            // Nothing was drawn here
+            continue;
+        }
+
+        vec4 world = push_constants.screen_to_world * vec4(ndc_xy, depth, 1.0);
+        world /= world.w;
+
+        vec3 normal = normalize(texelFetch(u_normals, coord, i).rgb);
+        vec3 to_light = push_constants.position.xyz - world.xyz;
+        float distance = length(to_light);
+
+        float light_percent = max(dot(normal, to_light / distance), 0.0);
+        light_percent *= 1.0 / exp(distance);
+
+        result += push_constants.color.rgb * texelFetch(u_diffuse, coord, i).rgb * light_percent;
+    }
+
+    f_color = vec4(result / float(NUM_SAMPLES), 1.0);
+}
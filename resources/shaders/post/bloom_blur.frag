@@ -0,0 +1,30 @@
+#version 450
+
+// Same size as the target
+layout(set = 0, binding = 0) uniform sampler2D u_source;
+
+layout(push_constant) uniform PushConstants {
+// (1, 0) or (0, 1)
+    vec2 direction;
+// Spacing of the taps in texels
+    float radius;
+} push_constants;
+
+layout(location = 0) out vec4 f_color;
+
+// 9-tap Gaussian, center weight first
+const float WEIGHTS[5] = float[](0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(u_source, 0));
+    vec2 uv = gl_FragCoord.xy * texel;
+    vec2 step = push_constants.direction * texel * push_constants.radius;
+
+    vec3 color = texture(u_source, uv).rgb * WEIGHTS[0];
+    for (int i = 1; i < 5; i++) {
+        color += texture(u_source, uv + step * float(i)).rgb * WEIGHTS[i];
+        color += texture(u_source, uv - step * float(i)).rgb * WEIGHTS[i];
+    }
+
+    f_color = vec4(color, 1.0);
+}
@@ -0,0 +1,18 @@
+#version 450
+
+// One blurred level, upscaled by the sampler
+layout(set = 0, binding = 0) uniform sampler2D u_source;
+
+layout(push_constant) uniform PushConstants {
+// 1 / size of the HDR image
+    vec2 target_texel;
+    float intensity;
+} push_constants;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    vec2 uv = gl_FragCoord.xy * push_constants.target_texel;
+    // Added to the target, alpha is left alone by the blending
+    f_color = vec4(texture(u_source, uv).rgb * push_constants.intensity, 0.0);
+}
@@ -0,0 +1,30 @@
+#version 450
+
+// Previous level, or the HDR lit image for the first one
+layout(set = 0, binding = 0) uniform sampler2D u_source;
+
+layout(push_constant) uniform PushConstants {
+// 1 / size of the target level
+    vec2 target_texel;
+// Brightness kept from every pixel, 0 keeps everything
+    float threshold;
+} push_constants;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    vec2 uv = gl_FragCoord.xy * push_constants.target_texel;
+    vec2 texel = 1.0 / vec2(textureSize(u_source, 0));
+
+    // Four bilinear taps cover a 4x4 block of the source
+    vec3 color = texture(u_source, uv + texel * vec2(-1.0, -1.0)).rgb
+               + texture(u_source, uv + texel * vec2(1.0, -1.0)).rgb
+               + texture(u_source, uv + texel * vec2(-1.0, 1.0)).rgb
+               + texture(u_source, uv + texel * vec2(1.0, 1.0)).rgb;
+    color *= 0.25;
+
+    float brightness = max(color.r, max(color.g, color.b));
+    color *= max(brightness - push_constants.threshold, 0.0) / max(brightness, 0.0001);
+
+    f_color = vec4(color, 1.0);
+}
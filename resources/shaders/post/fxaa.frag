@@ -0,0 +1,57 @@
+#version 450
+
+// Tone-mapped image, same size as the target
+layout(set = 0, binding = 0) uniform sampler2D u_input;
+
+layout(location = 0) out vec4 f_color;
+
+// Longest blur along an edge, in pixels
+const float SPAN_MAX = 8.0;
+const float REDUCE_MUL = 1.0 / 8.0;
+const float REDUCE_MIN = 1.0 / 128.0;
+
+// Edges are found on perceptual brightness, the input is linear
+float luma(vec3 color) {
+    return sqrt(dot(color, vec3(0.299, 0.587, 0.114)));
+}
+
+// The compact FXAA variant: blur along the local edge direction, fall back to a shorter blur if
+// the longer one picked up colors from outside the neighbourhood
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(u_input, 0));
+    vec2 uv = gl_FragCoord.xy * texel;
+
+    vec3 rgb_nw = texture(u_input, uv + vec2(-1.0, -1.0) * texel).rgb;
+    vec3 rgb_ne = texture(u_input, uv + vec2(1.0, -1.0) * texel).rgb;
+    vec3 rgb_sw = texture(u_input, uv + vec2(-1.0, 1.0) * texel).rgb;
+    vec3 rgb_se = texture(u_input, uv + vec2(1.0, 1.0) * texel).rgb;
+    vec4 center = texture(u_input, uv);
+
+    float luma_nw = luma(rgb_nw);
+    float luma_ne = luma(rgb_ne);
+    float luma_sw = luma(rgb_sw);
+    float luma_se = luma(rgb_se);
+    float luma_m = luma(center.rgb);
+
+    float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    vec2 dir = vec2(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        (luma_nw + luma_sw) - (luma_ne + luma_se)
+    );
+    float dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+    float rcp_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * rcp_dir_min, vec2(-SPAN_MAX), vec2(SPAN_MAX)) * texel;
+
+    vec3 rgb_a = 0.5 * (
+        texture(u_input, uv + dir * (1.0 / 3.0 - 0.5)).rgb +
+        texture(u_input, uv + dir * (2.0 / 3.0 - 0.5)).rgb);
+    vec3 rgb_b = rgb_a * 0.5 + 0.25 * (
+        texture(u_input, uv + dir * -0.5).rgb +
+        texture(u_input, uv + dir * 0.5).rgb);
+
+    float luma_b = luma(rgb_b);
+    vec3 rgb = (luma_b < luma_min || luma_b > luma_max) ? rgb_a : rgb_b;
+    f_color = vec4(rgb, center.a);
+}
@@ -0,0 +1,48 @@
+#version 450
+
+// HDR lit image, same size as the target
+layout(set = 0, binding = 0) uniform sampler2D u_hdr;
+// Color grading, red along x, green along y and blue along z
+layout(set = 0, binding = 1) uniform sampler3D u_lut;
+
+layout(push_constant) uniform PushConstants {
+    float exposure;
+// 0 clamp, 1 Reinhard, 2 ACES, see `ToneMapOperator`
+    int tone_operator;
+// Zero skips the LUT
+    int use_lut;
+// Brightest output relative to SDR white, 1 on an SDR target
+    float peak;
+// Output value of SDR white, 1 unless the target is scRGB
+    float paper_white;
+} push_constants;
+
+layout(location = 0) out vec4 f_color;
+
+// Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+void main() {
+    float peak = push_constants.peak;
+    // Curves work in [0, 1], scaled so that 1 is the peak of the output
+    vec3 hdr = texelFetch(u_hdr, ivec2(gl_FragCoord.xy), 0).rgb * push_constants.exposure / peak;
+
+    vec3 ldr;
+    if (push_constants.tone_operator == 1) {
+        ldr = hdr / (1.0 + hdr);
+    } else if (push_constants.tone_operator == 2) {
+        ldr = aces(hdr);
+    } else {
+        ldr = clamp(hdr, 0.0, 1.0);
+    }
+
+    if (push_constants.use_lut != 0) {
+        // Texel centers, so 0 and 1 hit the first and last entries exactly
+        float size = float(textureSize(u_lut, 0).x);
+        ldr = texture(u_lut, ldr * (size - 1.0) / size + 0.5 / size).rgb;
+    }
+
+    f_color = vec4(ldr * peak * push_constants.paper_white, 1.0);
+}
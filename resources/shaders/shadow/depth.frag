@@ -0,0 +1,5 @@
+#version 450
+
+// Shadow casters only write depth.
+void main() {
+}
@@ -0,0 +1,73 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "common/gbuffer.glsl"
+
+// Lit image, single-sampled
+layout(set = 0, binding = 0) uniform sampler2D u_lit;
+// G-buffer inputs, only the first sample is used. Alpha of the normals is the reflectivity.
+layout(set = 0, binding = 1) uniform gbuffer_sampler u_normals;
+layout(set = 0, binding = 2) uniform gbuffer_sampler u_positions;
+
+layout(set = 0, binding = 3) uniform SsrData {
+    mat4 view;
+    mat4 proj;
+// Zero disables the reflections.
+    int steps;
+    float thickness;
+    float max_distance;
+} ssr;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    ivec2 coord = ivec2(gl_FragCoord.xy);
+    vec4 lit = texelFetch(u_lit, coord, 0);
+    f_color = vec4(lit.rgb, 1.0);
+
+    vec4 normal = texelFetch(u_normals, coord, 0);
+    vec4 position = texelFetch(u_positions, coord, 0);
+    float reflectivity = normal.w;
+    // Background samples have no position (w == 0)
+    if (ssr.steps <= 0 || reflectivity <= 0.0 || position.w == 0.0) {
+        return;
+    }
+
+    vec3 view_pos = (ssr.view * vec4(position.xyz, 1.0)).xyz;
+    vec3 view_normal = normalize(mat3(ssr.view) * normal.xyz);
+    vec3 dir = normalize(reflect(normalize(view_pos), view_normal));
+
+    vec2 size = vec2(textureSize(u_lit, 0));
+    float step_length = ssr.max_distance / float(ssr.steps);
+
+    for (int i = 1; i <= ssr.steps; i++) {
+        vec3 ray = view_pos + dir * step_length * float(i);
+        vec4 clip = ssr.proj * vec4(ray, 1.0);
+        if (clip.w <= 0.0) {
+            break;
+        }
+
+        vec2 ndc = clip.xy / clip.w;
+        if (abs(ndc.x) > 1.0 || abs(ndc.y) > 1.0) {
+            break;
+        }
+
+        ivec2 hit = ivec2((ndc * 0.5 + 0.5) * size);
+        vec4 scene = texelFetch(u_positions, hit, 0);
+        if (scene.w == 0.0) {
+            continue;
+        }
+
+        // Positive when the ray went behind the visible surface
+        float behind = (ssr.view * vec4(scene.xyz, 1.0)).z - ray.z;
+        if (behind > 0.0 && behind < ssr.thickness) {
+            // Fade out towards the screen edges and the end of the ray
+            float edge_fade = 1.0 - smoothstep(0.8, 1.0, max(abs(ndc.x), abs(ndc.y)));
+            float distance_fade = 1.0 - float(i) / float(ssr.steps);
+
+            vec3 reflected = texelFetch(u_lit, hit, 0).rgb;
+            f_color.rgb = mix(lit.rgb, reflected, reflectivity * edge_fade * distance_fade);
+            return;
+        }
+    }
+}
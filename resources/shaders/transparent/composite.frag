@@ -0,0 +1,10 @@
+#version 450
+
+// Resolved transparent layer, premultiplied alpha. See `TransparentPass`
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_layer;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    f_color = subpassLoad(u_layer);
+}
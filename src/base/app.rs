@@ -1,41 +1,177 @@
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use imgui::{Context, FontConfig, FontGlyphRanges, FontSource};
 use imgui_winit_support::{HiDpiMode, WinitPlatform};
 use vulkano::{format, swapchain, sync, Version};
 use vulkano::device::{Device, Queue};
 use vulkano::device::DeviceExtensions;
-use vulkano::image::{ImageUsage, ImageViewAbstract};
+use vulkano::image::{ImageCreationError, ImageUsage, ImageViewAbstract, SampleCount};
 use vulkano::image::view::ImageView;
 use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice};
 use vulkano::instance::debug::{DebugCallback, MessageSeverity, MessageType};
-use vulkano::swapchain::{AcquireError, Swapchain, SwapchainCreationError};
-use vulkano::sync::{FlushError, GpuFuture};
+use vulkano::swapchain::{AcquireError, ColorSpace, CompositeAlpha, Surface, Swapchain, SwapchainCreationError};
+use vulkano::sync::{FlushError, GpuFuture, SharingMode};
 use vulkano_win::VkSurfaceBuild;
-use winit::event::{ElementState, Event, VirtualKeyCode, WindowEvent};
+use winit::event::{ElementState, Event, ModifiersState, WindowEvent};
+use winit::dpi::PhysicalSize;
 use winit::event_loop::{ControlFlow, EventLoop};
+use winit::platform::run_return::EventLoopExtRunReturn;
 use winit::window::WindowBuilder;
 
 use super::imgui_pass::GuiPass;
+use super::key_bindings::{Action, KeyBindings};
+use super::render_stats::RenderCounters;
 use crate::base::imgui_pass;
 
+// Swapchain format used when `AppConfig::hdr_swapchain` is on and the surface supports it:
+// scRGB, linear values where 1.0 is 80 nits and anything above goes brighter than SDR white.
+pub const HDR_SWAPCHAIN_FORMAT: (format::Format, ColorSpace) =
+    (format::Format::R16G16B16A16Sfloat, ColorSpace::ExtendedSrgbLinear);
+
+// `Resized` events closer together than this are coalesced, the swapchain is recreated once the
+// window stops changing size instead of on every step of a drag
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(100);
+
+// See `AppConfig::reactive`
+const REACTIVE_FRAMES: u32 = 3;
+
+// Simulation steps run before one frame at most. Time beyond that is dropped, so a long stall
+// (or waking up from reactive idle) doesn't turn into a burst of catch-up steps.
+const MAX_UPDATES_PER_FRAME: u32 = 8;
+
+// Frames in a row that may fail to allocate their images, at a new size or at the one we went
+// back to, before giving up
+const MAX_ALLOCATION_FAILURES: u32 = 5;
+
+pub struct AppConfig {
+    // Preferred number of swapchain images. `None` asks for one more than the surface minimum,
+    // so the CPU can record a frame while the GPU still has one queued. Always clamped to what
+    // the surface supports.
+    pub swapchain_images: Option<u32>,
+    // Prefer `HDR_SWAPCHAIN_FORMAT`, falls back to the first format of the surface if it's not
+    // supported. `create_app` gets the format that was picked.
+    pub hdr_swapchain: bool,
+    // MSAA sample count the app builds its targets for. Lowered to the highest count the
+    // device supports for both color and depth targets, `create_app` gets the result.
+    pub samples: SampleCount,
+    // How the window is blended with what's behind it. Falls back to the first mode the surface
+    // supports, which can leave the window see-through where the image alpha isn't 1.
+    pub composite_alpha: CompositeAlpha,
+    // Only `Action::Quit` is handled here, the app gets the other keys through `handle_event`
+    pub key_bindings: KeyBindings,
+    // Only draw after window events or while `App::needs_redraw`, sleep otherwise. For mostly
+    // static scenes, e.g. editing.
+    pub reactive: bool,
+    // File imgui keeps the GUI window positions, sizes and collapsed state in across runs.
+    // Read at startup and written as windows change. `None` starts from the defaults every time.
+    pub gui_ini_path: Option<PathBuf>,
+    // Print what the surface supports at startup, see `print_surface_info`
+    pub verbose: bool,
+    // Step of `App::update`. The simulation advances in these steps whatever the frame rate,
+    // so it plays out the same on every machine. Must not be zero.
+    pub fixed_timestep: Duration,
+}
+
+impl Default for AppConfig {
+    fn default() -> AppConfig {
+        AppConfig {
+            swapchain_images: None,
+            hdr_swapchain: false,
+            samples: SampleCount::Sample4,
+            composite_alpha: CompositeAlpha::Opaque,
+            key_bindings: KeyBindings::default(),
+            reactive: false,
+            gui_ini_path: None,
+            verbose: false,
+            fixed_timestep: Duration::from_secs(1) / 60,
+        }
+    }
+}
+
 pub trait App {
-    fn resize_swapchain(&mut self, dimensions: [u32; 2], textures: &mut imgui::Textures<imgui_pass::Texture>);
-    fn render<F, I>(&mut self, before_future: F, dimensions: [u32; 2], image: Arc<I>) -> Box<dyn GpuFuture>
+    // An error (e.g. out of device memory for the new targets) makes `run_app` go back to the
+    // last dimensions that worked.
+    fn resize_swapchain(&mut self, dimensions: [u32; 2], textures: &mut imgui::Textures<imgui_pass::Texture>)
+                        -> Result<(), ImageCreationError>;
+
+    // True after the app changed something its size-dependent resources are built from, e.g. the
+    // gbuffer sample count. `run_app` then calls `resize_swapchain` with the current dimensions
+    // before the next frame.
+    fn needs_resize(&self) -> bool {
+        false
+    }
+
+    // Failing to allocate an image skips the frame, `run_app` then recreates the swapchain
+    fn render<F, I>(&mut self, before_future: F, dimensions: [u32; 2], image: Arc<I>)
+                    -> Result<Box<dyn GpuFuture>, ImageCreationError>
         where F: GpuFuture + 'static,
               I: ImageViewAbstract + Send + Sync + 'static;
 
+    // Runs before `render`, e.g. for passes that have to be done before the gbuffer is drawn
+    fn before_render(&mut self, before_future: Box<dyn GpuFuture>, _dimensions: [u32; 2]) -> Box<dyn GpuFuture> {
+        before_future
+    }
+
+    // Runs after `render` and before the GUI is drawn on top of `image`
+    fn after_render<I>(&mut self, after_future: Box<dyn GpuFuture>, _image: Arc<I>) -> Box<dyn GpuFuture>
+        where I: ImageViewAbstract + Send + Sync + 'static
+    {
+        after_future
+    }
+
     fn handle_event(&mut self, event: &WindowEvent);
 
+    // Advances the simulation by `dt` seconds, always `AppConfig::fixed_timestep`. Runs zero or
+    // more times before every frame, as many steps as fit into the real time that passed.
+    fn update(&mut self, _dt: f32) {}
+
+    // Called before every frame after the `update` steps: how far in [0, 1) real time already is
+    // into the next step, for drawing moving things between their last two simulated states
+    fn interpolate(&mut self, _alpha: f32) {}
+
     fn render_gui(&mut self, ui: &mut imgui::Ui);
+
+    // Keeps a reactive `run_app` drawing without input, e.g. while something animates or a GPU
+    // result is expected. Continuous mode ignores it.
+    fn needs_redraw(&self) -> bool {
+        false
+    }
+
+    // Counters the GUI draws should be added to, see `render_stats`
+    fn render_counters(&self) -> Option<RenderCounters> {
+        None
+    }
+
+    // Color the swapchain image is cleared to before the GUI is drawn, for apps that show their
+    // frame in a GUI window instead of rendering it into the swapchain image
+    fn gui_clear_color(&self) -> Option<[f32; 4]> {
+        None
+    }
+
+    // Camera `[near, far]` used to display depth textures registered as imgui images
+    fn gui_depth_range(&self) -> [f32; 2] {
+        [0.01, 100.0]
+    }
+
+    // Called once after the window is closed and the GPU finished the last frame, before the
+    // app is dropped. The place to persist state.
+    fn on_exit(&mut self) {}
 }
 
-pub fn run_app<F, A>(create_app: F)
-    where F: Fn(Arc<Queue>, format::Format) -> A,
+pub fn run_app<F, A>(config: AppConfig, create_app: F)
+    where F: Fn(Arc<Queue>, Arc<Queue>, format::Format, SampleCount) -> A,
           A: App + 'static,
 {
+    // Needed for any color space other than sRGB
+    let colorspace_supported = InstanceExtensions::supported_by_core()
+        .map(|supported| supported.ext_swapchain_colorspace)
+        .unwrap_or(false);
+
     let required_extensions = InstanceExtensions {
         ext_debug_utils: true,
+        ext_swapchain_colorspace: config.hdr_swapchain && colorspace_supported,
         ..vulkano_win::required_extensions()
     };
 
@@ -89,30 +225,87 @@ pub fn run_app<F, A>(create_app: F)
 
     let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
 
-    let event_loop = EventLoop::new();
+    let mut event_loop = EventLoop::new();
     let surface = WindowBuilder::new().build_vk_surface(&event_loop, instance.clone()).unwrap();
 
-    let queue_family = physical.queue_families().find(|&q| {
-        q.supports_graphics() && surface.is_supported(q).unwrap_or(false)
-    }).unwrap();
+    // One family doing both is preferred. Some devices only present from families without
+    // graphics, those get a second queue just for presenting.
+    let queue_family = physical.queue_families()
+        .find(|&q| q.supports_graphics() && surface.is_supported(q).unwrap_or(false))
+        .or_else(|| physical.queue_families().find(|&q| q.supports_graphics()))
+        .unwrap();
+    let present_family = if surface.is_supported(queue_family).unwrap_or(false) {
+        None
+    } else {
+        Some(physical.queue_families()
+            .find(|&q| surface.is_supported(q).unwrap_or(false))
+            .expect("no queue family can present to the window"))
+    };
+
+    // Dedicated transfer family (no graphics bit) lets uploads run alongside rendering.
+    let transfer_family = physical.queue_families().find(|&q| {
+        q.explicitly_supports_transfers() && !q.supports_graphics() && !q.supports_compute()
+    });
 
-    let device_ext = DeviceExtensions { khr_swapchain: true, ..DeviceExtensions::none() };
+    let mut queue_families = vec![(queue_family, 0.5)];
+    if let Some(family) = present_family {
+        queue_families.push((family, 0.5));
+    }
+    // A transfer-only family that also presents is left to the present queue, uploads go
+    // through the graphics queue then
+    if let Some(family) = transfer_family.filter(|f| Some(f.id()) != present_family.map(|p| p.id())) {
+        queue_families.push((family, 0.5));
+    }
+
+    // Descriptor indexing backs the bindless material path of the terrain, where there is one
+    let supported_ext = DeviceExtensions::supported_by_device(physical);
+    let device_ext = DeviceExtensions {
+        khr_swapchain: true,
+        ext_descriptor_indexing: supported_ext.ext_descriptor_indexing,
+        khr_maintenance3: supported_ext.khr_maintenance3,
+        ..DeviceExtensions::none()
+    };
     let (device, mut queues) = Device::new(physical, physical.supported_features(), &device_ext,
-                                           [(queue_family, 0.5)].iter().cloned()).unwrap();
+                                           queue_families).unwrap();
     let queue = queues.next().unwrap();
+    let present_queue = present_family.map(|_| queues.next().unwrap());
+
+    // Immutable buffers/images are created with concurrent sharing across all active queue
+    // families of the device, so no explicit ownership transfer is needed between the
+    // transfer and graphics queues.
+    let transfer_queue = queues.next().unwrap_or(queue.clone());
+
+    if config.verbose {
+        print_surface_info(physical, &surface);
+    }
 
     let (mut swapchain, mut swapchain_images) = {
         let caps = surface.capabilities(physical).unwrap();
-        let composite_alpha = caps.supported_composite_alpha.iter().next().unwrap();
-        let format = caps.supported_formats[0].0;
+        let composite_alpha = if caps.supported_composite_alpha.supports(config.composite_alpha) {
+            config.composite_alpha
+        } else {
+            let fallback = caps.supported_composite_alpha.iter().next().unwrap();
+            println!("{:?} composite alpha is not supported, using {:?}", config.composite_alpha, fallback);
+            fallback
+        };
+        let (format, color_space) = caps.supported_formats.iter().cloned()
+            .find(|&format| config.hdr_swapchain && colorspace_supported && format == HDR_SWAPCHAIN_FORMAT)
+            .unwrap_or(caps.supported_formats[0]);
         let dimensions: [u32; 2] = surface.window().inner_size().into();
 
+        let mut num_images = config.swapchain_images.unwrap_or(caps.min_image_count + 1)
+            .max(caps.min_image_count);
+        if let Some(max_image_count) = caps.max_image_count {
+            num_images = num_images.min(max_image_count);
+        }
+
         let (swapchain, images) = Swapchain::start(device.clone(), surface.clone())
-            .num_images(caps.min_image_count)
+            .num_images(num_images)
             .format(format)
+            .color_space(color_space)
             .dimensions(dimensions)
             .usage(ImageUsage::color_attachment())
-            .sharing_mode(&queue)
+            .sharing_mode(swapchain_sharing(&queue, &present_queue))
             .composite_alpha(composite_alpha)
             .build()
             .unwrap();
@@ -127,10 +320,10 @@ pub fn run_app<F, A>(create_app: F)
     // [IMGUI]
     let (mut imgui, mut imgui_platform) = {
         let mut imgui = Context::create();
-        imgui.set_ini_filename(None);
+        imgui.set_ini_filename(config.gui_ini_path.clone());
 
         let mut platform = WinitPlatform::init(&mut imgui);
-        platform.attach_window(imgui.io_mut(), &surface.window(), HiDpiMode::Rounded);
+        platform.attach_window(imgui.io_mut(), surface.window(), HiDpiMode::Rounded);
 
         let hidpi_factor = platform.hidpi_factor();
         let font_size = (13.0 * hidpi_factor) as f32;
@@ -160,15 +353,35 @@ pub fn run_app<F, A>(create_app: F)
     let mut imgui_render = GuiPass::new(&mut imgui, queue.clone(), swapchain.format());
     // [/IMGUI]
 
-    let mut app = create_app(queue.clone(), swapchain.format());
-    app.resize_swapchain(surface.window().inner_size().into(), &mut imgui_render.textures);
+    let samples = supported_samples(physical, config.samples);
+    if samples != config.samples {
+        println!("{:?} is not supported, using {:?}", config.samples, samples);
+    }
+
+    let mut app = create_app(queue.clone(), transfer_queue.clone(), swapchain.format(), samples);
+    imgui_render.set_counters(app.render_counters());
+    let mut app_dimensions: [u32; 2] = surface.window().inner_size().into();
+    app.resize_swapchain(app_dimensions, &mut imgui_render.textures).unwrap();
 
     let mut recreate_swapchain = false;
+    // Time of the last `Resized` event not applied to the swapchain yet
+    let mut pending_resize: Option<Instant> = None;
+    let mut modifiers = ModifiersState::empty();
+    // Frames skipped in a row by `resize_swapchain` or `render` failures
+    let mut allocation_failures = 0;
+    // Frames to draw in reactive mode before waiting for events again. More than one, the GUI
+    // reacts to input a frame late.
+    let mut redraw_frames = REACTIVE_FRAMES;
     let mut previous_frame_end = Some(Box::new(sync::now(device.clone())) as Box<dyn GpuFuture>);
-    event_loop.run(move |event, _, control_flow| {
-        match &event {
-            Event::WindowEvent { event, window_id: _ } => app.handle_event(event),
-            _ => {}
+    // Real time not simulated by `App::update` yet
+    let mut update_accumulator = Duration::from_secs(0);
+    let mut last_update = Instant::now();
+    // Unlike `run`, `run_return` gives control back on exit, so the app gets `on_exit` and
+    // everything is dropped properly
+    event_loop.run_return(|event, _, control_flow| {
+        if let Event::WindowEvent { event, window_id: _ } = &event {
+            app.handle_event(event);
+            redraw_frames = REACTIVE_FRAMES;
         }
 
         imgui_platform.handle_event(imgui.io_mut(), surface.window(), &event);
@@ -178,23 +391,43 @@ pub fn run_app<F, A>(create_app: F)
                 *control_flow = ControlFlow::Exit;
             }
             Event::WindowEvent { event: WindowEvent::Resized(_), .. } => {
-                recreate_swapchain = true;
+                pending_resize = Some(Instant::now());
             }
-            Event::WindowEvent { event: WindowEvent::KeyboardInput { input, .. }, .. } => {
-                if input.state == ElementState::Released {
-                    if input.virtual_keycode == Some(VirtualKeyCode::Escape) {
-                        *control_flow = ControlFlow::Exit;
-                        return;
-                    }
-                }
+            Event::WindowEvent { event: WindowEvent::ModifiersChanged(state), .. } => {
+                modifiers = state;
+            }
+            Event::WindowEvent { event: WindowEvent::KeyboardInput { input, .. }, .. }
+                if input.state == ElementState::Released
+                    && config.key_bindings.action(input.virtual_keycode, modifiers) == Some(Action::Quit) => {
+                *control_flow = ControlFlow::Exit;
             }
             Event::MainEventsCleared => {
+                if *control_flow == ControlFlow::Exit {
+                    return;
+                }
+
+                let redraw = !config.reactive || redraw_frames > 0 || pending_resize.is_some() || app.needs_redraw();
+                if !redraw {
+                    *control_flow = ControlFlow::Wait;
+                    return;
+                }
+
+                *control_flow = ControlFlow::Poll;
+                redraw_frames = redraw_frames.saturating_sub(1);
                 imgui_platform.prepare_frame(imgui.io_mut(), surface.window()).unwrap();
                 surface.window().request_redraw();
             }
             Event::RedrawRequested(_) => {
                 previous_frame_end.as_mut().unwrap().cleanup_finished();
-                if recreate_swapchain {
+                if let Some(resized_at) = pending_resize {
+                    if resized_at.elapsed() >= RESIZE_DEBOUNCE {
+                        pending_resize = None;
+                        recreate_swapchain = true;
+                    }
+                }
+
+                let resize = if recreate_swapchain {
+                    // The latest size, whatever the events in between were
                     let dimensions: [u32; 2] = surface.window().inner_size().into();
                     let (new_swapchain, new_images) =
                         match swapchain.recreate().dimensions(dimensions).build() {
@@ -212,25 +445,76 @@ pub fn run_app<F, A>(create_app: F)
                     swapchain = new_swapchain;
                     swapchain_images = new_images;
 
-                    app.resize_swapchain(dimensions, &mut imgui_render.textures);
                     recreate_swapchain = false;
+                    Some(dimensions)
+                } else if app.needs_resize() {
+                    Some(app_dimensions)
+                } else {
+                    None
+                };
+
+                if let Some(dimensions) = resize {
+                    if let Err(e) = app.resize_swapchain(dimensions, &mut imgui_render.textures) {
+                        allocation_failures += 1;
+                        if allocation_failures > MAX_ALLOCATION_FAILURES {
+                            panic!("Failed to resize to {:?}: {:?}", dimensions, e);
+                        }
+                        // Shrink the window back, the swapchain follows on the next frame
+                        println!("Failed to resize to {:?}: {:?}, going back to {:?}", dimensions, e, app_dimensions);
+                        surface.window().set_inner_size(PhysicalSize::new(app_dimensions[0], app_dimensions[1]));
+                        recreate_swapchain = true;
+                        return;
+                    }
+                    app_dimensions = dimensions;
                 }
 
                 let (image_num, suboptimal, acquire_future) = match swapchain::acquire_next_image(swapchain.clone(), None) {
                     Ok(r) => r,
                     Err(AcquireError::OutOfDate) => {
-                        recreate_swapchain = true;
+                        // Recreated anyway once the pending resize settles
+                        recreate_swapchain = pending_resize.is_none();
                         return;
                     }
                     Err(e) => panic!("Failed to acquire next image: {:?}", e)
                 };
 
-                if suboptimal {
+                if suboptimal && pending_resize.is_none() {
                     recreate_swapchain = true;
                 }
 
-                let dims: [u32; 2] = surface.window().inner_size().into();
-                let mut after_future = app.render(acquire_future, dims, swapchain_images[image_num].clone());
+                let now = Instant::now();
+                update_accumulator += now - last_update;
+                last_update = now;
+                let mut updates = 0;
+                while update_accumulator >= config.fixed_timestep {
+                    if updates == MAX_UPDATES_PER_FRAME {
+                        update_accumulator = Duration::from_secs(0);
+                        break;
+                    }
+                    app.update(config.fixed_timestep.as_secs_f32());
+                    update_accumulator -= config.fixed_timestep;
+                    updates += 1;
+                }
+                app.interpolate(update_accumulator.as_secs_f32() / config.fixed_timestep.as_secs_f32());
+
+                // Size of the swapchain images, lags behind the window while a resize is pending
+                let dims = app_dimensions;
+                let before_future = app.before_render(Box::new(acquire_future), dims);
+                let after_future = match app.render(before_future, dims, swapchain_images[image_num].clone()) {
+                    Ok(after_future) => after_future,
+                    Err(e) => {
+                        allocation_failures += 1;
+                        if allocation_failures > MAX_ALLOCATION_FAILURES {
+                            panic!("Failed to render at {:?}: {:?}", dims, e);
+                        }
+                        // The acquired image is never presented, a new swapchain replaces it
+                        println!("Failed to render at {:?}: {:?}", dims, e);
+                        recreate_swapchain = true;
+                        return;
+                    }
+                };
+                allocation_failures = 0;
+                let mut after_future = app.after_render(after_future, swapchain_images[image_num].clone());
 
                 // [IMGUI]
                 let mut ui = imgui.frame();
@@ -239,6 +523,8 @@ pub fn run_app<F, A>(create_app: F)
 
                 let draw_data = ui.render();
 
+                imgui_render.set_clear_color(app.gui_clear_color());
+                imgui_render.set_depth_range(app.gui_depth_range());
                 after_future = imgui_render.draw(
                     after_future,
                     queue.clone(),
@@ -248,9 +534,17 @@ pub fn run_app<F, A>(create_app: F)
                 );
                 // [/IMGUI]
 
-                let frame_future = after_future
-                    .then_swapchain_present(queue.clone(), swapchain.clone(), image_num)
-                    .then_signal_fence_and_flush();
+                let frame_future = match &present_queue {
+                    None => (Box::new(after_future.then_swapchain_present(queue.clone(), swapchain.clone(), image_num))
+                        as Box<dyn GpuFuture>).then_signal_fence_and_flush(),
+                    // The rendering signals a semaphore the present on the other queue waits for,
+                    // the CPU doesn't. The images are shared concurrently, so no ownership
+                    // transfer is needed.
+                    Some(present_queue) => after_future.then_signal_semaphore_and_flush()
+                        .and_then(|rendered| (Box::new(rendered
+                            .then_swapchain_present(present_queue.clone(), swapchain.clone(), image_num))
+                            as Box<dyn GpuFuture>).then_signal_fence_and_flush()),
+                };
 
                 match frame_future {
                     Ok(future) => {
@@ -258,7 +552,7 @@ pub fn run_app<F, A>(create_app: F)
                         previous_frame_end = Some(Box::new(future) as Box<_>);
                     }
                     Err(FlushError::OutOfDate) => {
-                        recreate_swapchain = true;
+                        recreate_swapchain = pending_resize.is_none();
                         previous_frame_end = Some(Box::new(sync::now(device.clone())) as Box<_>);
                     }
                     Err(e) => {
@@ -270,4 +564,70 @@ pub fn run_app<F, A>(create_app: F)
             _ => ()
         }
     });
+
+    // Every frame waits for its fence, nothing is in flight anymore
+    app.on_exit();
+}
+
+// Formats, present modes, composite alpha modes and image counts `surface` supports on
+// `physical`, the candidates for the swapchain settings. Worth attaching to bug reports about
+// the picked format.
+pub fn print_surface_info<W>(physical: PhysicalDevice, surface: &Surface<W>) {
+    let caps = match surface.capabilities(physical) {
+        Ok(caps) => caps,
+        Err(e) => {
+            println!("Failed to query the surface capabilities: {:?}", e);
+            return;
+        }
+    };
+
+    println!("Surface of {}:", physical.properties().device_name.as_ref().unwrap());
+    println!("  formats:");
+    for (format, color_space) in caps.supported_formats.iter() {
+        println!("    {:?} {:?}", format, color_space);
+    }
+    println!("  present modes: {:?}", caps.present_modes.iter().collect::<Vec<_>>());
+    println!("  composite alpha: {:?}", caps.supported_composite_alpha.iter().collect::<Vec<_>>());
+    match caps.max_image_count {
+        Some(max_image_count) => println!("  image count: {}..={}", caps.min_image_count, max_image_count),
+        None => println!("  image count: {}.., no maximum", caps.min_image_count),
+    }
+}
+
+// Swapchain images are used by the graphics queue and, if there is a separate one, the present
+// queue
+fn swapchain_sharing(queue: &Arc<Queue>, present_queue: &Option<Arc<Queue>>) -> SharingMode {
+    match present_queue {
+        Some(present_queue) => SharingMode::Concurrent(vec![queue.family().id(), present_queue.family().id()]),
+        None => SharingMode::from(queue),
+    }
+}
+
+// `requested`, or the highest count below it usable for the color and depth attachments and for
+// sampling them back afterwards, including the integer object id target
+fn supported_samples(physical: PhysicalDevice, requested: SampleCount) -> SampleCount {
+    let properties = physical.properties();
+    let counts = [
+        properties.framebuffer_color_sample_counts,
+        properties.framebuffer_depth_sample_counts,
+        properties.sampled_image_color_sample_counts,
+        properties.sampled_image_depth_sample_counts,
+        properties.sampled_image_integer_sample_counts,
+    ];
+    let supports = |samples: SampleCount| counts.iter().all(|counts| counts.is_some_and(|counts| match samples {
+        SampleCount::Sample1 => counts.sample1,
+        SampleCount::Sample2 => counts.sample2,
+        SampleCount::Sample4 => counts.sample4,
+        SampleCount::Sample8 => counts.sample8,
+        SampleCount::Sample16 => counts.sample16,
+        SampleCount::Sample32 => counts.sample32,
+        SampleCount::Sample64 => counts.sample64,
+    }));
+
+    [SampleCount::Sample64, SampleCount::Sample32, SampleCount::Sample16, SampleCount::Sample8,
+        SampleCount::Sample4, SampleCount::Sample2]
+        .iter()
+        .cloned()
+        .find(|&samples| samples as u32 <= requested as u32 && supports(samples))
+        .unwrap_or(SampleCount::Sample1)
 }
@@ -0,0 +1,146 @@
+use std::sync::{Arc, Mutex};
+
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::{AttachmentImage, ImageCreationError, ImageUsage, SampleCount};
+
+// How many unused images are kept around for reuse, the oldest ones are freed first
+const MAX_FREE_IMAGES: usize = 16;
+
+#[derive(Clone, Copy, Default)]
+pub struct AttachmentPoolStats {
+    // Estimated size of every image owned by the pool, in use or not
+    pub allocated_bytes: u64,
+    pub peak_bytes: u64,
+    pub allocations: u64,
+    pub reuses: u64,
+}
+
+#[derive(Clone, PartialEq)]
+struct ImageKey {
+    dimensions: [u32; 2],
+    samples: SampleCount,
+    format: Format,
+    usage: ImageUsage,
+}
+
+struct PooledImage {
+    key: ImageKey,
+    image: Arc<AttachmentImage>,
+    bytes: u64,
+    // Value of `PoolState::tick` when the image was last handed out
+    last_used: u64,
+}
+
+struct PoolState {
+    images: Vec<PooledImage>,
+    tick: u64,
+    stats: AttachmentPoolStats,
+}
+
+// Attachment images that outlive their users: once every other reference to an image is dropped
+// (e.g. the old gbuffer after a resize), the next request with the same parameters gets it
+// back instead of a new allocation. Cloning gives another handle to the same pool.
+#[derive(Clone)]
+pub struct AttachmentPool {
+    device: Arc<Device>,
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl AttachmentPool {
+    pub fn new(device: Arc<Device>) -> AttachmentPool {
+        AttachmentPool {
+            device,
+            state: Arc::new(Mutex::new(PoolState {
+                images: vec![],
+                tick: 0,
+                stats: AttachmentPoolStats::default(),
+            })),
+        }
+    }
+
+    pub fn image(&self, dimensions: [u32; 2], samples: SampleCount, format: Format,
+                 usage: ImageUsage) -> Arc<AttachmentImage> {
+        self.try_image(dimensions, samples, format, usage).unwrap()
+    }
+
+    // Same as `image`, but allocation failures (e.g. out of device memory) are returned. Every
+    // unused image is freed and the allocation retried once before giving up.
+    pub fn try_image(&self, dimensions: [u32; 2], samples: SampleCount, format: Format,
+                     usage: ImageUsage) -> Result<Arc<AttachmentImage>, ImageCreationError> {
+        let key = ImageKey { dimensions, samples, format, usage };
+
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+
+        // The pool holds the only reference of an image nobody uses anymore
+        let free = state.images.iter_mut()
+            .find(|pooled| pooled.key == key && Arc::strong_count(&pooled.image) == 1);
+        if let Some(pooled) = free {
+            pooled.last_used = tick;
+            let image = pooled.image.clone();
+            state.stats.reuses += 1;
+            return Ok(image);
+        }
+
+        state.trim(MAX_FREE_IMAGES);
+
+        let allocate = || AttachmentImage::multisampled_with_usage(
+            self.device.clone(),
+            dimensions,
+            samples,
+            format,
+            usage,
+        );
+        let image = match allocate() {
+            Ok(image) => image,
+            Err(_) => {
+                state.trim(0);
+                allocate()?
+            }
+        };
+
+        let bytes = dimensions[0] as u64 * dimensions[1] as u64 * samples as u64
+            * format.size().unwrap_or(4) as u64;
+        state.images.push(PooledImage { key, image: image.clone(), bytes, last_used: tick });
+
+        state.stats.allocations += 1;
+        state.stats.allocated_bytes += bytes;
+        state.stats.peak_bytes = state.stats.peak_bytes.max(state.stats.allocated_bytes);
+
+        Ok(image)
+    }
+
+    // Frees every image nobody uses, e.g. the targets of the old size after a resize. The ones
+    // handed out every frame are allocated again on their next request.
+    pub fn purge(&self) {
+        self.state.lock().unwrap().trim(0);
+    }
+
+    pub fn stats(&self) -> AttachmentPoolStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+impl PoolState {
+    // Frees the least recently used images above `keep` unused ones
+    fn trim(&mut self, keep: usize) {
+        let mut free: Vec<(u64, usize)> = self.images.iter().enumerate()
+            .filter(|(_, pooled)| Arc::strong_count(&pooled.image) == 1)
+            .map(|(idx, pooled)| (pooled.last_used, idx))
+            .collect();
+        if free.len() <= keep {
+            return;
+        }
+
+        free.sort();
+        let mut evicted: Vec<usize> = free[..free.len() - keep].iter().map(|&(_, idx)| idx).collect();
+        evicted.sort();
+
+        for idx in evicted.into_iter().rev() {
+            let pooled = self.images.swap_remove(idx);
+            self.stats.allocated_bytes -= pooled.bytes;
+        }
+    }
+}
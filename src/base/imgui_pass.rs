@@ -6,7 +6,7 @@ use vulkano::buffer::{BufferAccess, BufferUsage, CpuBufferPool};
 use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, SubpassContents};
 use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
 use vulkano::device::{Device, Queue};
-use vulkano::format::{ClearValue, Format};
+use vulkano::format::{ClearValue, Format, FormatTy};
 use vulkano::image::{ImageDimensions, ImageViewAbstract, ImmutableImage};
 use vulkano::image::view::ImageView;
 use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
@@ -16,6 +16,8 @@ use vulkano::render_pass::Subpass;
 use vulkano::sampler::Sampler;
 use vulkano::sync::GpuFuture;
 
+use super::render_stats::RenderCounters;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum RendererError {
@@ -31,13 +33,23 @@ pub struct GuiPass {
     gfx_queue: Arc<Queue>,
     pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
     pipeline_ms: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pipeline_depth: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pipeline_depth_ms: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
 
     vrt_buffer_pool: CpuBufferPool<Vertex>,
     idx_buffer_pool: CpuBufferPool<u16>,
     font_texture: Texture,
     pub textures: Textures<Texture>,
+    // See `App::render_counters`
+    counters: Option<RenderCounters>,
+    // See `set_clear_color`
+    clear_color: Option<[f32; 4]>,
+    // See `set_depth_range`
+    depth_range: [f32; 2],
 
     render_pass: Arc<render_pass::RenderPass>,
+    // Same as `render_pass` but clears the target first, compatible with the same pipelines
+    clear_render_pass: Arc<render_pass::RenderPass>,
 }
 
 #[allow(dead_code)]
@@ -62,6 +74,24 @@ impl GuiPass {
             ).unwrap(),
         );
 
+        let clear_render_pass = Arc::new(
+            vulkano::single_pass_renderpass!(
+                gfx_queue.device().clone(),
+                attachments: {
+                    final_color: {
+                        load: Clear,
+                        store: Store,
+                        format: output_format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                        color: [final_color],
+                        depth_stencil: {}
+                    }
+            ).unwrap(),
+        );
+
         let pipeline = {
             let vs = vs::Shader::load(gfx_queue.device().clone())
                 .expect("failed to create shader module");
@@ -100,6 +130,44 @@ impl GuiPass {
             )
         };
 
+        let pipeline_depth = {
+            let vs = vs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+            let fs = fs_depth::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_input_single_buffer::<Vertex>()
+                    .vertex_shader(vs.main_entry_point(), ())
+                    .triangle_list()
+                    .viewports_scissors_dynamic(1)
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .blend_alpha_blending()
+                    .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+                    .build(gfx_queue.device().clone()).unwrap()
+            )
+        };
+
+        let pipeline_depth_ms = {
+            let vs = vs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+            let fs = fs_depth_multisampled::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_input_single_buffer::<Vertex>()
+                    .vertex_shader(vs.main_entry_point(), ())
+                    .triangle_list()
+                    .viewports_scissors_dynamic(1)
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .blend_alpha_blending()
+                    .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+                    .build(gfx_queue.device().clone()).unwrap()
+            )
+        };
+
         let device = gfx_queue.device().clone();
 
         let vrt_buffer_pool = CpuBufferPool::new(device.clone(), BufferUsage::vertex_buffer_transfer_destination());
@@ -113,14 +181,36 @@ impl GuiPass {
             gfx_queue,
             pipeline,
             pipeline_ms,
+            pipeline_depth,
+            pipeline_depth_ms,
             textures,
             font_texture,
             vrt_buffer_pool,
             idx_buffer_pool,
+            counters: None,
+            clear_color: None,
+            depth_range: [0.01, 100.0],
             render_pass,
+            clear_render_pass,
         }
     }
 
+    // GUI draws are counted in `counters` from now on
+    pub fn set_counters(&mut self, counters: Option<RenderCounters>) {
+        self.counters = counters;
+    }
+
+    // `Some` clears the target before drawing, for frames where nothing else was rendered into
+    // it. `None` (the default) draws on top of the frame.
+    pub fn set_clear_color(&mut self, clear_color: Option<[f32; 4]>) {
+        self.clear_color = clear_color;
+    }
+
+    // Camera `[near, far]` used to linearize depth textures, see `Camera::depth_range`
+    pub fn set_depth_range(&mut self, depth_range: [f32; 2]) {
+        self.depth_range = depth_range;
+    }
+
     pub fn draw<F, I>(
         &mut self,
         before_future: F,
@@ -133,8 +223,13 @@ impl GuiPass {
             F: GpuFuture + 'static,
             I: ImageViewAbstract + Send + Sync + 'static
     {
+        let (render_pass, clear_value) = match self.clear_color {
+            Some(color) => (self.clear_render_pass.clone(), ClearValue::Float(color)),
+            None => (self.render_pass.clone(), ClearValue::None),
+        };
+
         let framebuffer = Arc::new(
-            render_pass::Framebuffer::start(self.render_pass.clone())
+            render_pass::Framebuffer::start(render_pass)
                 .add(target_image.clone())
                 .unwrap()
                 .build()
@@ -153,7 +248,7 @@ impl GuiPass {
                 framebuffer,
                 SubpassContents::Inline,
                 vec![
-                    ClearValue::None,
+                    clear_value,
                 ],
             ).unwrap();
 
@@ -186,17 +281,19 @@ impl GuiPass {
                 ]
             };
 
-            let mut dynamic_state = DynamicState::default();
-            dynamic_state.viewports = Some(vec![
-                Viewport {
-                    origin: [0.0, 0.0],
-                    dimensions: [viewport_dimensions[0] as f32, viewport_dimensions[1] as f32],
-                    depth_range: 0.0..1.0,
-                }
-            ]);
-            dynamic_state.scissors = Some(vec![
-                Scissor::default()
-            ]);
+            let mut dynamic_state = DynamicState {
+                viewports: Some(vec![
+                    Viewport {
+                        origin: [0.0, 0.0],
+                        dimensions: [viewport_dimensions[0] as f32, viewport_dimensions[1] as f32],
+                        depth_range: 0.0..1.0,
+                    }
+                ]),
+                scissors: Some(vec![
+                    Scissor::default()
+                ]),
+                ..DynamicState::default()
+            };
 
             let clip_off = draw_data.display_pos;
             let clip_scale = draw_data.framebuffer_scale;
@@ -243,10 +340,14 @@ impl GuiPass {
                                 .unwrap();
 
 
-                            let pipeline = if (tex.0.image().samples() as u32) == 1 {
-                                self.pipeline.clone()
-                            } else {
-                                self.pipeline_ms.clone()
+                            let is_depth = matches!(tex.0.format().ty(), FormatTy::Depth | FormatTy::DepthStencil);
+
+                            let multisampled = (tex.0.image().samples() as u32) != 1;
+                            let pipeline = match (is_depth, multisampled) {
+                                (false, false) => self.pipeline.clone(),
+                                (false, true) => self.pipeline_ms.clone(),
+                                (true, false) => self.pipeline_depth.clone(),
+                                (true, true) => self.pipeline_depth_ms.clone(),
                             };
 
                             let layout = pipeline.layout().descriptor_set_layout(0).unwrap();
@@ -259,14 +360,35 @@ impl GuiPass {
                                     .unwrap()
                             );
 
-                            builder.draw_indexed(
-                                pipeline,
-                                &dynamic_state,
-                                vec![vertex_buffer.clone()],
-                                index_buffer.clone().into_buffer_slice().slice(idx_offset..(idx_offset + count)).unwrap(),
-                                set,
-                                pc,
-                                vec![]).unwrap();
+                            if let Some(counters) = &self.counters {
+                                counters.draw(1, count as u64 / 3);
+                            }
+                            let indices = index_buffer.clone().into_buffer_slice().slice(idx_offset..(idx_offset + count)).unwrap();
+                            if is_depth {
+                                // The depth pipelines read a larger block, see render_depth_multisampled.frag
+                                let pc = fs_depth_multisampled::ty::DepthPC {
+                                    matrix: pc.matrix,
+                                    near: self.depth_range[0],
+                                    far: self.depth_range[1],
+                                };
+                                builder.draw_indexed(
+                                    pipeline,
+                                    &dynamic_state,
+                                    vec![vertex_buffer.clone()],
+                                    indices,
+                                    set,
+                                    pc,
+                                    vec![]).unwrap();
+                            } else {
+                                builder.draw_indexed(
+                                    pipeline,
+                                    &dynamic_state,
+                                    vec![vertex_buffer.clone()],
+                                    indices,
+                                    set,
+                                    pc,
+                                    vec![]).unwrap();
+                            }
                         }
                     }
                     DrawCmd::ResetRenderState => (), // TODO
@@ -358,3 +480,17 @@ mod fs_multisampled {
         bytes: "resources/shaders/imgui/render_multisampled.frag.spv"
     }
 }
+
+mod fs_depth {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/imgui/render_depth_multisampled.frag.single_sample.spv"
+    }
+}
+
+mod fs_depth_multisampled {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/imgui/render_depth_multisampled.frag.spv"
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+
+use winit::event::{ModifiersState, VirtualKeyCode};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Action {
+    // Camera movement, see `Camera::handle_event`
+    Forward,
+    Back,
+    Left,
+    Right,
+    Up,
+    Down,
+
+    // Handled by `run_app`
+    Quit,
+
+    ToggleWireframe,
+    AddLight,
+    ClearLights,
+    SaveScene,
+    LoadScene,
+    FocusSelection,
+    ResetCamera,
+    Undo,
+    Redo,
+}
+
+// A key, pressed with or without Ctrl. Ctrl+Z and Z are different bindings.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyBinding {
+    pub key: VirtualKeyCode,
+    pub ctrl: bool,
+}
+
+impl KeyBinding {
+    pub fn key(key: VirtualKeyCode) -> KeyBinding {
+        KeyBinding { key, ctrl: false }
+    }
+
+    pub fn ctrl(key: VirtualKeyCode) -> KeyBinding {
+        KeyBinding { key, ctrl: true }
+    }
+}
+
+// Key of each action. Defaults to the built-in layout, remap with `set` for e.g. ESDF or AZERTY.
+// Every binding belongs to one action at most.
+#[derive(Clone)]
+pub struct KeyBindings {
+    keys: BTreeMap<Action, KeyBinding>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        let keys = [
+            (Action::Forward, KeyBinding::key(VirtualKeyCode::W)),
+            (Action::Back, KeyBinding::key(VirtualKeyCode::S)),
+            (Action::Left, KeyBinding::key(VirtualKeyCode::A)),
+            (Action::Right, KeyBinding::key(VirtualKeyCode::D)),
+            (Action::Up, KeyBinding::key(VirtualKeyCode::Space)),
+            (Action::Down, KeyBinding::key(VirtualKeyCode::LShift)),
+            (Action::Quit, KeyBinding::key(VirtualKeyCode::Escape)),
+            (Action::ToggleWireframe, KeyBinding::key(VirtualKeyCode::F2)),
+            (Action::AddLight, KeyBinding::key(VirtualKeyCode::L)),
+            (Action::ClearLights, KeyBinding::key(VirtualKeyCode::Delete)),
+            (Action::SaveScene, KeyBinding::key(VirtualKeyCode::F5)),
+            (Action::LoadScene, KeyBinding::key(VirtualKeyCode::F9)),
+            (Action::FocusSelection, KeyBinding::key(VirtualKeyCode::F)),
+            (Action::ResetCamera, KeyBinding::key(VirtualKeyCode::Home)),
+            (Action::Undo, KeyBinding::ctrl(VirtualKeyCode::Z)),
+            (Action::Redo, KeyBinding::ctrl(VirtualKeyCode::Y)),
+        ];
+
+        KeyBindings { keys: keys.iter().cloned().collect() }
+    }
+}
+
+impl KeyBindings {
+    // The default bindings with the movement on ZQSD
+    pub fn azerty() -> KeyBindings {
+        let mut bindings = KeyBindings::default();
+        let movement = [
+            (Action::Forward, VirtualKeyCode::Z),
+            (Action::Left, VirtualKeyCode::Q),
+            (Action::Back, VirtualKeyCode::S),
+            (Action::Right, VirtualKeyCode::D),
+        ];
+        for &(action, key) in movement.iter() {
+            bindings.set(action, Some(KeyBinding::key(key))).unwrap();
+        }
+
+        bindings
+    }
+
+    // Binds `action` to `binding`, `None` leaves it unbound. Fails with the action already bound
+    // to `binding`, the bindings are left as they were.
+    pub fn set(&mut self, action: Action, binding: Option<KeyBinding>) -> Result<(), Action> {
+        match binding {
+            Some(binding) => {
+                let taken = self.keys.iter()
+                    .find(|&(&other, &bound)| other != action && bound == binding)
+                    .map(|(&other, _)| other);
+                if let Some(other) = taken {
+                    return Err(other);
+                }
+                self.keys.insert(action, binding);
+            }
+            None => {
+                self.keys.remove(&action);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Action bound to `key` with the Ctrl state of `modifiers`, if any. Takes the
+    // `virtual_keycode` of a keyboard event.
+    pub fn action(&self, key: Option<VirtualKeyCode>, modifiers: ModifiersState) -> Option<Action> {
+        let binding = KeyBinding { key: key?, ctrl: modifiers.ctrl() };
+        self.keys.iter().find(|(_, &bound)| bound == binding).map(|(&action, _)| action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use winit::event::{ModifiersState, VirtualKeyCode};
+
+    use super::{Action, KeyBinding, KeyBindings};
+
+    #[test]
+    fn remapped_action_moves_to_the_new_key() {
+        let mut bindings = KeyBindings::default();
+        bindings.set(Action::Forward, Some(KeyBinding::key(VirtualKeyCode::E))).unwrap();
+
+        assert_eq!(bindings.action(Some(VirtualKeyCode::E), ModifiersState::empty()), Some(Action::Forward));
+        assert_eq!(bindings.action(Some(VirtualKeyCode::W), ModifiersState::empty()), None);
+    }
+
+    #[test]
+    fn ctrl_scopes_the_binding() {
+        let bindings = KeyBindings::azerty();
+
+        assert_eq!(bindings.action(Some(VirtualKeyCode::Z), ModifiersState::empty()), Some(Action::Forward));
+        assert_eq!(bindings.action(Some(VirtualKeyCode::Z), ModifiersState::CTRL), Some(Action::Undo));
+        assert_eq!(bindings.action(Some(VirtualKeyCode::Q), ModifiersState::empty()), Some(Action::Left));
+        assert_eq!(bindings.action(Some(VirtualKeyCode::A), ModifiersState::empty()), None);
+    }
+
+    #[test]
+    fn taken_binding_is_rejected() {
+        let mut bindings = KeyBindings::default();
+
+        assert_eq!(bindings.set(Action::AddLight, Some(KeyBinding::ctrl(VirtualKeyCode::Z))), Err(Action::Undo));
+        assert_eq!(bindings.action(Some(VirtualKeyCode::L), ModifiersState::empty()), Some(Action::AddLight));
+        assert_eq!(bindings.action(Some(VirtualKeyCode::Z), ModifiersState::CTRL), Some(Action::Undo));
+
+        // Rebinding an action to its own key is fine
+        assert_eq!(bindings.set(Action::Undo, Some(KeyBinding::ctrl(VirtualKeyCode::Z))), Ok(()));
+    }
+}
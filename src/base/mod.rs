@@ -1,2 +1,8 @@
+pub mod attachment_pool;
 pub mod app;
 pub mod imgui_pass;
+pub mod key_bindings;
+pub mod parallel;
+pub mod pipeline;
+pub mod render_stats;
+pub mod upload;
@@ -0,0 +1,15 @@
+use rayon::prelude::*;
+
+// Records one secondary command buffer per job on rayon's global pool, whose threads live as long
+// as the app. The results are in `jobs` order, ready for `execute_commands`.
+//
+// Vulkano keeps a command pool per thread, so recording doesn't contend on anything but the
+// jobs' own data, and the pools are reused from frame to frame.
+pub fn record_parallel<'a, T: Send>(jobs: Vec<Box<dyn FnOnce() -> T + Send + 'a>>) -> Vec<T> {
+    jobs.into_par_iter().map(|job| job()).collect()
+}
+
+// Same as `record_parallel`, on the calling thread only
+pub fn record_serial<'a, T>(jobs: Vec<Box<dyn FnOnce() -> T + Send + 'a>>) -> Vec<T> {
+    jobs.into_iter().map(|job| job()).collect()
+}
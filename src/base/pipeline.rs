@@ -0,0 +1,20 @@
+use vulkano::pipeline::GraphicsPipelineBuilder;
+use vulkano::pipeline::raster::{CullMode, FrontFace};
+
+// Sets the face culling and winding of `builder`, which only has one method per value
+pub fn with_raster<'vs, 'tcs, 'tes, 'gs, 'fs, Vdef, Vss, Tcss, Tess, Gss, Fss>(
+    builder: GraphicsPipelineBuilder<'vs, 'tcs, 'tes, 'gs, 'fs, Vdef, Vss, Tcss, Tess, Gss, Fss>,
+    cull_mode: CullMode,
+    front_face: FrontFace,
+) -> GraphicsPipelineBuilder<'vs, 'tcs, 'tes, 'gs, 'fs, Vdef, Vss, Tcss, Tess, Gss, Fss> {
+    let builder = match cull_mode {
+        CullMode::None => builder.cull_mode_disabled(),
+        CullMode::Front => builder.cull_mode_front(),
+        CullMode::Back => builder.cull_mode_back(),
+        CullMode::FrontAndBack => builder.cull_mode_front_and_back(),
+    };
+    match front_face {
+        FrontFace::CounterClockwise => builder.front_face_counter_clockwise(),
+        FrontFace::Clockwise => builder.front_face_clockwise(),
+    }
+}
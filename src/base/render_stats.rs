@@ -0,0 +1,45 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Copy, Default)]
+pub struct RenderStats {
+    pub draw_calls: u64,
+    pub instances: u64,
+    pub triangles: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    draw_calls: AtomicU64,
+    instances: AtomicU64,
+    triangles: AtomicU64,
+}
+
+// Counts the draws recorded by every system holding a handle. Cloning gives another handle to
+// the same counters, recording from several threads is fine.
+#[derive(Clone, Default)]
+pub struct RenderCounters {
+    counters: Arc<Counters>,
+}
+
+impl RenderCounters {
+    pub fn new() -> RenderCounters {
+        RenderCounters::default()
+    }
+
+    // One draw call of `instances` instances, `triangles` triangles each
+    pub fn draw(&self, instances: u64, triangles: u64) {
+        self.counters.draw_calls.fetch_add(1, Ordering::Relaxed);
+        self.counters.instances.fetch_add(instances, Ordering::Relaxed);
+        self.counters.triangles.fetch_add(instances * triangles, Ordering::Relaxed);
+    }
+
+    // Everything counted since the previous call
+    pub fn take(&self) -> RenderStats {
+        RenderStats {
+            draw_calls: self.counters.draw_calls.swap(0, Ordering::Relaxed),
+            instances: self.counters.instances.swap(0, Ordering::Relaxed),
+            triangles: self.counters.triangles.swap(0, Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, ImmutableBuffer};
+use vulkano::device::Queue;
+use vulkano::format::{Format, Pixel};
+use vulkano::image::{ImageDimensions, ImmutableImage, MipmapsCount};
+use vulkano::sync;
+use vulkano::sync::GpuFuture;
+
+// Collects asset uploads into one joined future, so the caller waits for all of them at once
+// instead of stalling after every buffer/image.
+pub struct UploadBatch {
+    queue: Arc<Queue>,
+    future: Option<Box<dyn GpuFuture>>,
+}
+
+impl UploadBatch {
+    pub fn new(queue: Arc<Queue>) -> UploadBatch {
+        UploadBatch {
+            future: Some(Box::new(sync::now(queue.device().clone()))),
+            queue,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn queue(&self) -> Arc<Queue> {
+        self.queue.clone()
+    }
+
+    pub fn buffer<T, D>(&mut self, data: D, usage: BufferUsage) -> Arc<ImmutableBuffer<[T]>>
+        where D: ExactSizeIterator<Item=T>,
+              T: Send + Sync + 'static
+    {
+        let (buffer, future) = ImmutableBuffer::from_iter(data, usage, self.queue.clone()).unwrap();
+        self.join(future);
+
+        buffer
+    }
+
+    pub fn image<P, D>(&mut self, data: D, dimensions: ImageDimensions, mipmaps: MipmapsCount,
+                       format: Format) -> Arc<ImmutableImage>
+        where D: ExactSizeIterator<Item=P>,
+              P: Pixel + Send + Sync + Clone + 'static
+    {
+        let (image, future) = ImmutableImage::from_iter(data, dimensions, mipmaps, format, self.queue.clone()).unwrap();
+        self.join(future);
+
+        image
+    }
+
+    // Blocks until every upload recorded so far is finished.
+    pub fn wait(mut self) {
+        self.future.take().unwrap()
+            .then_signal_fence_and_flush().unwrap()
+            .wait(None).unwrap();
+    }
+
+    fn join<F>(&mut self, future: F)
+        where F: GpuFuture + 'static
+    {
+        let prev = self.future.take().unwrap();
+        self.future = Some(Box::new(prev.join(future)));
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::Instant;
+
+use cgmath::{Deg, Matrix4, Point3, Vector3};
+
+use crate::base::parallel;
+use crate::frustum::Frustum;
+use crate::terrain_game::Map;
+use crate::terrain_render_system::{BlockGrid, HighlightPulse, rebuild_instance_data};
+
+// Odd, so the maze is closed on every side
+const MAP_SIZE: u32 = 501;
+const ITERATIONS: u32 = 20;
+// Jobs of the recording comparison, like a frame with this many render systems
+const RECORDING_JOBS: u32 = 8;
+
+// Times the CPU side of the block rendering on a large maze, without a window or a device.
+// Run with `cargo run --release -- --bench`.
+pub fn run() {
+    let map = Map::from_maze(MAP_SIZE, MAP_SIZE, 42);
+    let grid = BlockGrid::default();
+    let pulse = HighlightPulse::default();
+    println!("{}x{} map, {} active blocks", map.w, map.h, map.active_blocks().count());
+
+    time("rebuild_instance_data", || rebuild_instance_data(map.active_blocks(), &grid, &pulse, 0.0).len());
+
+    // Looking over a corner of the map like the default camera, so most blocks are culled
+    let view = Matrix4::look_at_rh(Point3::new(20.0, -20.0, 10.0), Point3::new(20.0, 0.0, -20.0), Vector3::unit_y());
+    let proj = cgmath::perspective(Deg(45.0), 16.0 / 9.0, 0.01, 100.0);
+    time("frustum culling + rebuild_instance_data", || {
+        let frustum = Frustum::from_matrix(proj * view);
+        let visible = map.active_blocks().filter(|block| grid.in_frustum(block, &frustum));
+        rebuild_instance_data(visible, &grid, &pulse, 0.0).len()
+    });
+
+    // The CPU part of recording a busy frame: one job per band of rows, as if every band had its
+    // own render system
+    let band = MAP_SIZE.div_ceil(RECORDING_JOBS);
+    let jobs = || (0..RECORDING_JOBS)
+        .map(|idx| {
+            let (map, grid, pulse) = (&map, &grid, &pulse);
+            let rows = idx * band..(idx + 1) * band;
+            Box::new(move || {
+                rebuild_instance_data(map.active_blocks().filter(|block| rows.contains(&block.y)), grid, pulse, 0.0).len()
+            }) as Box<dyn FnOnce() -> usize + Send + '_>
+        })
+        .collect::<Vec<_>>();
+    time("serial recording", || parallel::record_serial(jobs()).iter().sum());
+    time("parallel recording", || parallel::record_parallel(jobs()).iter().sum());
+}
+
+// `f` returns the number of instances it emitted
+fn time<F>(name: &str, mut f: F)
+    where F: FnMut() -> usize
+{
+    // Warm-up
+    f();
+
+    let start = Instant::now();
+    let mut instances = 0;
+    for _ in 0..ITERATIONS {
+        instances = f();
+    }
+    let per_iteration = start.elapsed() / ITERATIONS;
+
+    println!("{}: {:.3} ms, {} instances", name, per_iteration.as_secs_f64() * 1000.0, instances);
+}
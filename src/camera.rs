@@ -1,9 +1,33 @@
 use std::f32;
 
-use cgmath::{Deg, Matrix4, Point3, SquareMatrix, vec3, Vector3};
+use cgmath::{Deg, Matrix4, Point3, Quaternion, SquareMatrix, vec3, Vector3, Vector4};
 use cgmath::{Angle, Rad};
 use cgmath::InnerSpace;
-use winit::event::{ElementState, MouseButton, VirtualKeyCode, WindowEvent};
+use cgmath::{EuclideanSpace, VectorSpace};
+use serde::{Deserialize, Serialize};
+use winit::event::{ElementState, ModifiersState, MouseButton, WindowEvent};
+
+use crate::base::key_bindings::{Action, KeyBindings};
+
+// How long `Camera::focus_on` takes to reach the target, in seconds
+const FOCUS_DURATION: f32 = 0.4;
+
+// Camera move started by `Camera::focus_on`
+struct Focus {
+    from_position: Point3<f32>,
+    to_position: Point3<f32>,
+    from_angles: [f32; 2],
+    to_angles: [f32; 2],
+    elapsed: f32,
+}
+
+// Saved part of the camera, see `scene`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CameraState {
+    pub position: Point3<f32>,
+    pub yaw: f32,
+    pub pitch: f32,
+}
 
 pub struct Camera {
     position: Point3<f32>,
@@ -12,77 +36,359 @@ pub struct Camera {
     pitch: f32,
 
     mouse_pressed: bool,
+    // Middle mouse drag moves the camera in its view plane
+    pan_pressed: bool,
+    // World units per pixel of a pan drag
+    pan_speed: f32,
     last_mouse_position: [i32; 2],
 
     view_dir: Vector3<f32>,
+    // Pitch is measured from the plane perpendicular to it, see `set_up_axis`
     up_dir: Vector3<f32>,
+    right_handed: bool,
 
     viewport: [u32; 2],
+    // Vertical field of view
+    fov_deg: f32,
+    near: f32,
+    far: f32,
+
+    focus: Option<Focus>,
+    keys: KeyBindings,
+    // Bindings are looked up with the Ctrl state, see `KeyBindings::action`
+    modifiers: ModifiersState,
 }
 
 impl Camera {
-    pub fn new() -> Camera {
+    // Moves with the `Forward`..`Down` actions of `keys`
+    pub fn new(keys: KeyBindings) -> Camera {
         Camera {
             position: Point3::new(0.0, 0.0, 0.0),
             proj: Matrix4::identity(),
             mouse_pressed: false,
+            pan_pressed: false,
+            pan_speed: 0.01,
             last_mouse_position: [0, 0],
             viewport: [0, 0],
             view_dir: vec3(0.0, 0.0, -1.0),
             up_dir: vec3(0.0, 1.0, 0.0),
+            right_handed: true,
             yaw: -90.0,
             pitch: 0.0,
+            fov_deg: 45.0,
+            near: 0.01,
+            far: 100.0,
+            focus: None,
+            keys,
+            modifiers: ModifiersState::empty(),
         }
     }
 
     pub fn set_viewport(&mut self, w: u32, h: u32) {
         self.viewport = [w, h];
         self.proj = cgmath::perspective(
-            Rad::from(Deg(45.0)),
+            Rad::from(Deg(self.fov_deg)),
             w as f32 / h as f32,
-            0.01,
-            100.0);
+            self.near,
+            self.far);
+    }
+
+    pub fn fov(&self) -> f32 {
+        self.fov_deg
+    }
+
+    // Vertical field of view in degrees, clamped to (1, 179). Rebuilds the projection for the
+    // current viewport.
+    pub fn set_fov(&mut self, fov_deg: f32) {
+        let fov_deg = fov_deg.max(1.0).min(179.0);
+        if fov_deg == self.fov_deg {
+            return;
+        }
+
+        self.fov_deg = fov_deg;
+        let [w, h] = self.viewport;
+        if w > 0 && h > 0 {
+            self.set_viewport(w, h);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn pan_speed(&self) -> f32 {
+        self.pan_speed
+    }
+
+    #[allow(dead_code)]
+    pub fn set_pan_speed(&mut self, pan_speed: f32) {
+        self.pan_speed = pan_speed;
+    }
+
+    // `[near, far]` of the projection, as expected by `linearize_depth` in the shaders
+    pub fn depth_range(&self) -> [f32; 2] {
+        [self.near, self.far]
+    }
+
+    pub fn position(&self) -> Point3<f32> {
+        self.position
+    }
+
+    pub fn state(&self) -> CameraState {
+        CameraState { position: self.position, yaw: self.yaw, pitch: self.pitch }
+    }
+
+    pub fn set_state(&mut self, state: &CameraState) {
+        self.focus = None;
+        self.position = state.position;
+        self.yaw = state.yaw;
+        self.pitch = state.pitch.max(-89.0).min(89.0);
+        self.update_view_dir();
+    }
+
+    // Back to the pose of `new`, stops a running `focus_on` move. Keeps the up axis and the
+    // handedness.
+    pub fn reset(&mut self) {
+        self.set_state(&CameraState { position: Point3::new(0.0, 0.0, 0.0), yaw: -90.0, pitch: 0.0 });
+    }
+
+    // Places the camera at `eye` facing `target`, mouse look continues from the derived angles.
+    // The scene draws visually up as -y, so an `eye` above the ground has a negative y.
+    pub fn look_at(&mut self, eye: Point3<f32>, target: Point3<f32>) {
+        let to_target = target - eye;
+        let [yaw, pitch] = if to_target.magnitude2() > 0.0 {
+            self.angles(to_target.normalize())
+        } else {
+            [self.yaw, self.pitch]
+        };
+        self.set_state(&CameraState { position: eye, yaw, pitch });
+    }
+
+    pub fn up_axis(&self) -> Vector3<f32> {
+        self.up_dir
+    }
+
+    // World axis the camera treats as up, for assets authored e.g. +Z up. Defaults to +Y; the
+    // scene itself still draws visually up as -y (terrain heights, block stacking), so this only
+    // changes how the camera moves and looks. The view direction is kept.
+    pub fn set_up_axis(&mut self, up: Vector3<f32>) {
+        self.focus = None;
+        self.up_dir = up.normalize();
+        let [yaw, pitch] = self.angles(self.view_dir);
+        self.yaw = yaw;
+        self.pitch = pitch;
+        self.update_view_dir();
+    }
+
+    // Left-handed worlds are mirrored compared to the default, so the winding of their triangles
+    // flips: pipelines drawing them need the opposite `FrontFace`. Picking follows on its own,
+    // `ray_from_screen` inverts the same matrices.
+    pub fn set_right_handed(&mut self, right_handed: bool) {
+        self.right_handed = right_handed;
+    }
+
+    pub fn right_handed(&self) -> bool {
+        self.right_handed
     }
 
     pub fn view_matrix(&self) -> Matrix4<f32> {
-        return Matrix4::<f32>::look_at_rh(self.position, self.position + self.view_dir, self.up_dir);
+        if self.right_handed {
+            Matrix4::<f32>::look_at_rh(self.position, self.position + self.view_dir, self.up_dir)
+        } else {
+            Matrix4::<f32>::look_at_lh(self.position, self.position + self.view_dir, self.up_dir)
+        }
     }
 
     pub fn proj_matrix(&self) -> Matrix4<f32> {
-        self.proj
+        if self.right_handed {
+            self.proj
+        } else {
+            // The left-handed view looks along +z
+            self.proj * Matrix4::from_nonuniform_scale(1.0, 1.0, -1.0)
+        }
+    }
+
+    /// Inverse of `proj * view`: maps NDC coordinates back to world space.
+    /// Returns `None` if the matrix is degenerate (e.g. a zero-sized viewport).
+    pub fn screen_to_world(&self) -> Option<Matrix4<f32>> {
+        (self.proj_matrix() * self.view_matrix()).invert()
+    }
+
+    // World-space ray through the given window pixel: (origin, normalized direction).
+    pub fn ray_from_screen(&self, x: f32, y: f32) -> Option<(Point3<f32>, Vector3<f32>)> {
+        let far = self.unproject(x, y, 1.0)?;
+        Some((self.position, (far - self.position).normalize()))
+    }
+
+    // World point of the given window pixel at depth buffer value `depth`, e.g. from
+    // `Picker::pick_depth`. The projection is used as is, so the depth is the NDC z.
+    pub fn unproject(&self, x: f32, y: f32, depth: f32) -> Option<Point3<f32>> {
+        if self.viewport[0] == 0 || self.viewport[1] == 0 {
+            return None;
+        }
+
+        let inv = self.screen_to_world()?;
+        let ndc_x = 2.0 * x / self.viewport[0] as f32 - 1.0;
+        let ndc_y = 2.0 * y / self.viewport[1] as f32 - 1.0;
+
+        let point = inv * Vector4::new(ndc_x, ndc_y, depth, 1.0);
+        Some(Point3::new(point.x / point.w, point.y / point.w, point.z / point.w))
+    }
+
+    // Smoothly moves the camera to look at `target` from `distance` away, keeping the current
+    // direction towards it. The move is advanced by `update`.
+    pub fn focus_on(&mut self, target: Point3<f32>, distance: f32) {
+        let to_target = target - self.position;
+        let dir = if to_target.magnitude2() > 0.0 { to_target.normalize() } else { self.view_dir };
+
+        let [mut yaw, pitch] = self.angles(dir);
+        // Turn the short way round
+        while yaw - self.yaw > 180.0 {
+            yaw -= 360.0;
+        }
+        while yaw - self.yaw < -180.0 {
+            yaw += 360.0;
+        }
+
+        self.focus = Some(Focus {
+            from_position: self.position,
+            to_position: target - dir * distance,
+            from_angles: [self.yaw, self.pitch],
+            to_angles: [yaw, pitch],
+            elapsed: 0.0,
+        });
+    }
+
+    // A `focus_on` move is running
+    pub fn is_moving(&self) -> bool {
+        self.focus.is_some()
+    }
+
+    // Advances a running `focus_on` move by `dt` seconds
+    pub fn update(&mut self, dt: f32) {
+        let focus = match self.focus.as_mut() {
+            Some(focus) => focus,
+            None => return,
+        };
+
+        focus.elapsed += dt;
+        let t = (focus.elapsed / FOCUS_DURATION).min(1.0);
+        let t = t * t * (3.0 - 2.0 * t);
+
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+        self.position = Point3::from_vec(focus.from_position.to_vec().lerp(focus.to_position.to_vec(), t));
+        self.yaw = lerp(focus.from_angles[0], focus.to_angles[0]);
+        self.pitch = lerp(focus.from_angles[1], focus.to_angles[1]);
+
+        if focus.elapsed >= FOCUS_DURATION {
+            self.focus = None;
+        }
+        self.update_view_dir();
+    }
+
+    fn update_view_dir(&mut self) {
+        let (yaw_zero, yaw_quarter) = self.horizontal_axes();
+        self.view_dir = (yaw_zero * Rad::from(Deg(self.yaw)).cos() * Rad::from(Deg(self.pitch)).cos() +
+            self.up_dir * Rad::from(Deg(self.pitch)).sin() +
+            yaw_quarter * Rad::from(Deg(self.yaw)).sin() * Rad::from(Deg(self.pitch)).cos()
+        ).normalize();
+    }
+
+    // Directions of yaw 0 and 90 degrees: world x and z for the default +Y up, turned along
+    // with the up axis otherwise
+    fn horizontal_axes(&self) -> (Vector3<f32>, Vector3<f32>) {
+        let rotation = Quaternion::from_arc(Vector3::unit_y(), self.up_dir, Some(Vector3::unit_x()));
+        (rotation * Vector3::unit_x(), rotation * Vector3::unit_z())
+    }
+
+    // Yaw and pitch in degrees of the normalized view direction `dir`, the inverse of
+    // `update_view_dir`
+    fn angles(&self, dir: Vector3<f32>) -> [f32; 2] {
+        let (yaw_zero, yaw_quarter) = self.horizontal_axes();
+        // Pitch stays inside the range allowed by mouse look
+        let pitch = Deg::from(Rad(dir.dot(self.up_dir).max(-1.0).min(1.0).asin())).0.max(-89.0).min(89.0);
+        let yaw = Deg::from(Rad(dir.dot(yaw_quarter).atan2(dir.dot(yaw_zero)))).0;
+        [yaw, pitch]
+    }
+
+    // Strafe direction, mirrored for left-handed worlds like the view
+    fn right_dir(&self) -> Vector3<f32> {
+        if self.right_handed {
+            self.view_dir.cross(self.up_dir)
+        } else {
+            self.up_dir.cross(self.view_dir)
+        }
+    }
+
+    // Up on the screen in world space: the up axis made perpendicular to the view
+    fn screen_up_dir(&self) -> Vector3<f32> {
+        let up = self.up_dir - self.view_dir * self.view_dir.dot(self.up_dir);
+        if up.magnitude2() > 0.0 {
+            // Vulkan's y points down, so the up axis of the view ends up at the bottom
+            -up.normalize()
+        } else {
+            -self.up_dir
+        }
     }
 
     pub fn handle_event(&mut self, event: &WindowEvent) {
         match event {
-            &WindowEvent::KeyboardInput { input, .. } => {
-                if input.state == ElementState::Pressed {
-                    match input.virtual_keycode {
-                        Some(VirtualKeyCode::W) => self.position += self.view_dir * 0.3,
-                        Some(VirtualKeyCode::S) => self.position -= self.view_dir * 0.3,
-
-                        Some(VirtualKeyCode::A) => self.position -= self.view_dir.cross(self.up_dir) * 0.3,
-                        Some(VirtualKeyCode::D) => self.position += self.view_dir.cross(self.up_dir) * 0.3,
-                        Some(VirtualKeyCode::Space) => self.position.y += 0.1,
-                        Some(VirtualKeyCode::LShift) => self.position.y -= 0.1,
-                        _ => (),
+            &WindowEvent::KeyboardInput { input, .. } if input.state == ElementState::Pressed => {
+                let action = self.keys.action(input.virtual_keycode, self.modifiers);
+
+                // Manual movement cancels a focus move
+                match action {
+                    Some(Action::Forward) | Some(Action::Back) | Some(Action::Left) |
+                    Some(Action::Right) | Some(Action::Up) | Some(Action::Down) => {
+                        self.focus = None;
                     }
+                    _ => (),
+                }
+
+                match action {
+                    Some(Action::Forward) => self.position += self.view_dir * 0.3,
+                    Some(Action::Back) => self.position -= self.view_dir * 0.3,
+
+                    Some(Action::Left) => self.position -= self.right_dir() * 0.3,
+                    Some(Action::Right) => self.position += self.right_dir() * 0.3,
+                    Some(Action::Up) => self.position += self.up_dir * 0.1,
+                    Some(Action::Down) => self.position -= self.up_dir * 0.1,
+                    _ => (),
                 }
             }
 
-            &WindowEvent::MouseInput { state, button, .. } => {
-                self.mouse_pressed = (state == ElementState::Pressed) && (button == MouseButton::Right);
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+            }
+
+            &WindowEvent::MouseInput { state, button: MouseButton::Right, .. } => {
+                self.mouse_pressed = state == ElementState::Pressed;
+            }
+            &WindowEvent::MouseInput { state, button: MouseButton::Middle, .. } => {
+                self.pan_pressed = state == ElementState::Pressed;
             }
             &WindowEvent::CursorMoved { position, .. } => {
+                let pos: [i32; 2] = position.into();
+
+                if self.pan_pressed && !self.mouse_pressed {
+                    // The scene follows the cursor, the orientation stays
+                    let dx = (pos[0] - self.last_mouse_position[0]) as f32 * self.pan_speed;
+                    let dy = (pos[1] - self.last_mouse_position[1]) as f32 * self.pan_speed;
+                    self.last_mouse_position = pos;
+                    self.focus = None;
+
+                    self.position -= self.right_dir().normalize() * dx;
+                    self.position += self.screen_up_dir() * dy;
+                    return;
+                }
+
                 if !self.mouse_pressed {
-                    self.last_mouse_position = position.into();
+                    self.last_mouse_position = pos;
                     return;
                 }
 
-                let pos: [i32; 2] = position.into();
                 let sensitivity = 0.5;
                 let dx = (pos[0] - self.last_mouse_position[0]) as f32 * sensitivity;
                 let dy = (pos[1] - self.last_mouse_position[1]) as f32 * sensitivity;
                 self.last_mouse_position = position.into();
+                self.focus = None;
 
                 self.yaw += dx;
                 self.pitch += dy;
@@ -95,13 +401,83 @@ impl Camera {
                     self.pitch = -89.0;
                 }
 
-                self.view_dir = Vector3::new(
-                    Rad::from(Deg(self.yaw)).cos() * Rad::from(Deg(self.pitch)).cos(),
-                    Rad::from(Deg(self.pitch)).sin(),
-                    Rad::from(Deg(self.yaw)).sin() * Rad::from(Deg(self.pitch)).cos(),
-                ).normalize();
+                self.update_view_dir();
             }
             _ => (),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{InnerSpace, Point3, Vector3, Vector4};
+
+    use crate::base::key_bindings::KeyBindings;
+
+    use super::{Camera, CameraState};
+
+    fn camera() -> Camera {
+        let mut camera = Camera::new(KeyBindings::default());
+        camera.set_viewport(800, 600);
+        camera.look_at(Point3::new(2.0, -3.0, 5.0), Point3::new(0.0, 0.0, 0.0));
+        camera
+    }
+
+    #[test]
+    fn screen_to_world_unprojects_projected_points() {
+        let camera = camera();
+        let world = Vector4::new(0.5, -0.25, -1.5, 1.0);
+
+        let clip = camera.proj_matrix() * camera.view_matrix() * world;
+        let ndc = clip / clip.w;
+        let back = camera.screen_to_world().unwrap() * ndc;
+        let back = back / back.w;
+
+        assert!((back - world).magnitude() < 1e-3, "{:?} != {:?}", back, world);
+    }
+
+    #[test]
+    fn unproject_finds_the_projected_pixel() {
+        let camera = camera();
+        let world = Point3::new(0.5, -0.25, -1.5);
+
+        let clip = camera.proj_matrix() * camera.view_matrix() * world.to_homogeneous();
+        let ndc = clip / clip.w;
+        let (x, y) = ((ndc.x + 1.0) * 400.0, (ndc.y + 1.0) * 300.0);
+        let back = camera.unproject(x, y, ndc.z).unwrap();
+
+        assert!((back - world).magnitude() < 1e-3, "{:?} != {:?}", back, world);
+    }
+
+    #[test]
+    fn depth_range_matches_projection() {
+        let camera = camera();
+        let [near, far] = camera.depth_range();
+
+        // The projection maps the near and far planes to the ends of the NDC depth range
+        let ndc_z = |distance: f32| {
+            let clip = camera.proj_matrix() * Vector4::new(0.0, 0.0, -distance, 1.0);
+            clip.z / clip.w
+        };
+        assert!((ndc_z(near) + 1.0).abs() < 1e-4);
+        assert!((ndc_z(far) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn angles_invert_the_view_dir_with_z_up() {
+        let mut camera = Camera::new(KeyBindings::default());
+        camera.set_up_axis(Vector3::unit_z());
+
+        for &(yaw, pitch) in [(0.0, 0.0), (30.0, 45.0), (-120.0, -60.0), (170.0, 89.0)].iter() {
+            camera.set_state(&CameraState { position: Point3::new(0.0, 0.0, 0.0), yaw, pitch });
+
+            // Pitch lifts the view towards +Z
+            let up = camera.view_dir.dot(Vector3::unit_z());
+            assert!((up - pitch.to_radians().sin()).abs() < 1e-4, "{} at pitch {}", up, pitch);
+
+            let [back_yaw, back_pitch] = camera.angles(camera.view_dir);
+            assert!((back_yaw - yaw).abs() < 1e-2, "{} != {}", back_yaw, yaw);
+            assert!((back_pitch - pitch).abs() < 1e-2, "{} != {}", back_pitch, pitch);
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use vulkano::buffer::{ImmutableBuffer, BufferUsage};
 use std::sync::Arc;
-use vulkano::sync::GpuFuture;
-use vulkano::device::Queue;
+
+use crate::base::upload::UploadBatch;
 
 #[derive(Default, Debug, Clone)]
 pub struct Vertex {
@@ -17,7 +17,7 @@ pub struct Cube {
 }
 
 impl Cube {
-    pub fn new(gfx_queue: Arc<Queue>, h: f32) -> Cube {
+    pub fn new(uploads: &mut UploadBatch, h: f32) -> Cube {
         let vertices = [
             // up
             Vertex { position: [0.0, -h, 0.0], normal: [0.0, 1.0, 0.0], color: [0.0, 1.0, 0.0] },
@@ -79,15 +79,8 @@ impl Cube {
 //        let x: Vec<_> = Cube::new().vertex(|v| Vertex { position: v.pos.into(), normal: v.normal.into() }).triangulate().vertices().collect();
 
 
-        let (bb, fut) = {
-            ImmutableBuffer::from_iter(vertices.iter().cloned(), BufferUsage::vertex_buffer(), gfx_queue.clone()).unwrap()
-        };
-
-        let (ib, fut2) = {
-            ImmutableBuffer::from_iter(indices.iter().cloned(), BufferUsage::index_buffer(), gfx_queue.clone()).unwrap()
-        };
-
-        fut.join(fut2).then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+        let bb = uploads.buffer(vertices.iter().cloned(), BufferUsage::vertex_buffer());
+        let ib = uploads.buffer(indices.iter().cloned(), BufferUsage::index_buffer());
 
         Cube {
             vertices: bb,
@@ -0,0 +1,192 @@
+use std::sync::Arc;
+
+use cgmath::{Matrix4, Point3, SquareMatrix, Vector3, Vector4};
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, SecondaryAutoCommandBuffer};
+use vulkano::device::Queue;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::depth_stencil::{Compare, DepthStencil};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::render_pass::Subpass;
+
+use crate::deferred::transparent_pass;
+
+// Lines per circle of `DebugDraw::sphere`
+const SPHERE_SEGMENTS: u32 = 24;
+
+#[derive(Default, Debug, Clone)]
+struct Vertex {
+    position: [f32; 3],
+    color: [f32; 4],
+}
+vulkano::impl_vertex!(Vertex, position, color);
+
+// Lines and boxes for visualizing things in the scene. Shapes are collected during the frame and
+// drawn (then forgotten) by `render`, depth tested against the scene but not writing depth.
+pub struct DebugDraw {
+    gfx_queue: Arc<Queue>,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    vertex_buffer: CpuBufferPool<Vertex>,
+
+    // Two per line
+    vertices: Vec<Vertex>,
+}
+
+#[allow(dead_code)]
+impl DebugDraw {
+    // `subpass` is usually `TransparentPass::subpass`
+    pub fn new(gfx_queue: Arc<Queue>, subpass: Subpass) -> DebugDraw {
+        let pipeline = {
+            let vs = vs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+            let fs = fs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .line_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(subpass)
+                .depth_stencil(DepthStencil {
+                    depth_compare: Compare::LessOrEqual,
+                    depth_write: false,
+                    ..DepthStencil::simple_depth_test()
+                })
+                .blend_collective(transparent_pass::alpha_blend())
+                .build(gfx_queue.device().clone())
+                .unwrap())
+        };
+
+        let vertex_buffer = CpuBufferPool::vertex_buffer(gfx_queue.device().clone());
+
+        DebugDraw {
+            gfx_queue,
+            pipeline,
+            vertex_buffer,
+            vertices: vec![],
+        }
+    }
+
+    // `color` is straight alpha
+    pub fn line(&mut self, a: Point3<f32>, b: Point3<f32>, color: [f32; 4]) {
+        self.vertices.push(Vertex { position: a.into(), color });
+        self.vertices.push(Vertex { position: b.into(), color });
+    }
+
+    // Edges of the axis-aligned box between `min` and `max`
+    pub fn aabb(&mut self, min: Point3<f32>, max: Point3<f32>, color: [f32; 4]) {
+        self.box_edges(|i| Point3::new(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z },
+        ), color);
+    }
+
+    // Edges of the volume `view_proj` (`proj * view`) maps to the [-1, 1] clip cube, the one
+    // `Frustum::from_matrix` culls against. Nothing if the matrix can't be inverted.
+    pub fn frustum(&mut self, view_proj: Matrix4<f32>, color: [f32; 4]) {
+        let inverse = match view_proj.invert() {
+            Some(inverse) => inverse,
+            None => return,
+        };
+
+        self.box_edges(|i| {
+            let corner = inverse * Vector4::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { -1.0 } else { 1.0 },
+                1.0,
+            );
+            Point3::from_homogeneous(corner)
+        }, color);
+    }
+
+    // `corner` gives the 8 corners, bit 0, 1 and 2 of the index select the side on x, y and z
+    fn box_edges<F>(&mut self, corner: F, color: [f32; 4])
+        where F: Fn(usize) -> Point3<f32>
+    {
+        // Corners differing in exactly one bit share an edge
+        for i in 0..8 {
+            for bit in [1, 2, 4].iter() {
+                if i & bit == 0 {
+                    self.line(corner(i), corner(i | bit), color);
+                }
+            }
+        }
+    }
+
+    // Circles around the three axes
+    pub fn sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 4]) {
+        let step = std::f32::consts::PI * 2.0 / SPHERE_SEGMENTS as f32;
+        let axes = [
+            (Vector3::unit_x(), Vector3::unit_y()),
+            (Vector3::unit_y(), Vector3::unit_z()),
+            (Vector3::unit_z(), Vector3::unit_x()),
+        ];
+
+        for &(u, v) in axes.iter() {
+            let point = |i: u32| {
+                let angle = step * i as f32;
+                center + (u * angle.cos() + v * angle.sin()) * radius
+            };
+            for i in 0..SPHERE_SEGMENTS {
+                self.line(point(i), point(i + 1), color);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+    }
+
+    // Draws everything added since the last call and clears it
+    pub fn render(&mut self, viewport_dimensions: [u32; 2], view: Matrix4<f32>, proj: Matrix4<f32>)
+                  -> SecondaryAutoCommandBuffer {
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+            self.pipeline.subpass().clone())
+            .unwrap();
+
+        if !self.vertices.is_empty() {
+            let dynamic_state = DynamicState {
+                viewports: Some(vec![Viewport {
+                    origin: [0.0, 0.0],
+                    dimensions: [viewport_dimensions[0] as f32,
+                        viewport_dimensions[1] as f32],
+                    depth_range: 0.0..1.0,
+                }]),
+                ..DynamicState::none()
+            };
+
+            let vertices = self.vertex_buffer.chunk(self.vertices.drain(..)).unwrap();
+            builder.draw(self.pipeline.clone(),
+                         &dynamic_state,
+                         vec![Arc::new(vertices)],
+                         (),
+                         vs::ty::PushConstants { view_proj: (proj * view).into() },
+                         vec![],
+            )
+                .unwrap();
+        }
+
+        builder.build().unwrap()
+    }
+}
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        bytes: "resources/shaders/debug/line.vert.spv"
+    }
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/debug/line.frag.spv"
+    }
+}
@@ -0,0 +1,383 @@
+use std::sync::Arc;
+
+use vulkano::{render_pass, sampler};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, PrimaryAutoCommandBuffer, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::{ClearValue, Format};
+use vulkano::image::{AttachmentImage, ImageCreationError, ImageUsage, ImageViewAbstract, SampleCount};
+use vulkano::image::view::ImageView;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::render_pass::{FramebufferAbstract, RenderPass, Subpass};
+use vulkano::sync::GpuFuture;
+
+use crate::base::attachment_pool::AttachmentPool;
+use super::downsampler::{DOWNSAMPLE_FORMAT, DownsampleKernel, Downsampler};
+use super::point_lighting::LightBlend;
+
+#[derive(Clone, Copy)]
+pub struct Bloom {
+    // Brightness a pixel needs to glow
+    pub threshold: f32,
+    // Scale of the blurred light added back, 0 disables the pass
+    pub intensity: f32,
+    // Spacing of the blur taps in texels of each level
+    pub radius: f32,
+}
+
+// Extracts the pixels of the HDR image above a threshold into a half size level, downsamples it
+// into a chain of smaller levels, blurs every level and adds them back on top of the image.
+pub struct BloomPass {
+    gfx_queue: Arc<Queue>,
+    pool: AttachmentPool,
+    format: Format,
+    levels: usize,
+
+    downsampler: Downsampler,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    downsample_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    blur_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    composite_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    sampler: Arc<sampler::Sampler>,
+
+    // Overwrites a level
+    level_render_pass: Arc<RenderPass>,
+    // Blends on top of the HDR image
+    composite_render_pass: Arc<RenderPass>,
+}
+
+impl BloomPass {
+    // `format` is the format of the HDR image, `levels` the length of the downsample chain
+    pub fn new(gfx_queue: Arc<Queue>, pool: AttachmentPool, format: Format, levels: usize) -> BloomPass {
+        assert!(levels > 0, "bloom needs at least one level");
+        assert_eq!(format, DOWNSAMPLE_FORMAT, "the downsampled levels are blurred in place");
+
+        let downsampler = Downsampler::new(gfx_queue.clone(), pool.clone());
+
+        let level_render_pass = Arc::new(
+            vulkano::single_pass_renderpass!(
+                gfx_queue.device().clone(),
+                attachments: {
+                    level: {
+                        load: DontCare,
+                        store: Store,
+                        format: format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                        color: [level],
+                        depth_stencil: {}
+                    }
+            ).unwrap(),
+        );
+
+        let composite_render_pass = Arc::new(
+            vulkano::single_pass_renderpass!(
+                gfx_queue.device().clone(),
+                attachments: {
+                    hdr: {
+                        load: Load,
+                        store: Store,
+                        format: format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                        color: [hdr],
+                        depth_stencil: {}
+                    }
+            ).unwrap(),
+        );
+
+        let vertex_buffer = {
+            CpuAccessibleBuffer::from_iter(gfx_queue.device().clone(), BufferUsage::all(), false, [
+                Vertex { position: [-1.0, -1.0] },
+                Vertex { position: [-1.0, 3.0] },
+                Vertex { position: [3.0, -1.0] }
+            ].iter().cloned()).expect("failed to create buffer")
+        };
+
+        let vs = vs::Shader::load(gfx_queue.device().clone())
+            .expect("failed to create shader module");
+
+        let downsample_pipeline = {
+            let fs = fs_downsample::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(level_render_pass.clone(), 0).unwrap())
+                .build(gfx_queue.device().clone())
+                .unwrap()) as Arc<_>
+        };
+
+        let blur_pipeline = {
+            let fs = fs_blur::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(level_render_pass.clone(), 0).unwrap())
+                .build(gfx_queue.device().clone())
+                .unwrap()) as Arc<_>
+        };
+
+        let composite_pipeline = {
+            let fs = fs_composite::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .blend_collective(LightBlend::Additive.attachment_blend())
+                .render_pass(Subpass::from(composite_render_pass.clone(), 0).unwrap())
+                .build(gfx_queue.device().clone())
+                .unwrap()) as Arc<_>
+        };
+
+        // Linear filtering does part of the downsampling and the upscaling
+        let sampler = sampler::Sampler::new(
+            gfx_queue.device().clone(),
+            sampler::Filter::Linear,
+            sampler::Filter::Linear,
+            sampler::MipmapMode::Nearest,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        ).unwrap();
+
+        BloomPass {
+            gfx_queue,
+            pool,
+            format,
+            levels,
+            downsampler,
+            vertex_buffer,
+            downsample_pipeline,
+            blur_pipeline,
+            composite_pipeline,
+            sampler,
+            level_render_pass,
+            composite_render_pass,
+        }
+    }
+
+    // Adds the bloom of `hdr_image` to itself
+    pub fn draw<F>(&self, before_future: F, hdr_image: Arc<ImageView<Arc<AttachmentImage>>>, bloom: Bloom)
+                   -> Result<Box<dyn GpuFuture>, ImageCreationError>
+        where F: GpuFuture + 'static
+    {
+        if bloom.intensity <= 0.0 {
+            return Ok(Box::new(before_future));
+        }
+
+        let [width, height] = hdr_image.image().dimensions().width_height();
+
+        // Half size of the previous level, levels that would be empty are skipped
+        let level_dimensions: Vec<[u32; 2]> = (1..=self.levels as u32)
+            .map(|level| [width >> level, height >> level])
+            .take_while(|&[w, h]| w > 0 && h > 0)
+            .collect();
+        if level_dimensions.is_empty() {
+            return Ok(Box::new(before_future));
+        }
+
+        let scratch = level_dimensions.iter().map(|&dims| self.level_image(dims)).collect::<Result<Vec<_>, _>>()?;
+
+        // The first level extracts the bright pixels, the others are downsampled from it
+        let bright = self.level_image(level_dimensions[0])?;
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+        self.fullscreen_draw(
+            &mut command_buffer_builder,
+            self.level_framebuffer(bright.clone()),
+            self.downsample_pipeline.clone(),
+            hdr_image.clone(),
+            level_dimensions[0],
+            fs_downsample::ty::PushConstants {
+                target_texel: [1.0 / level_dimensions[0][0] as f32, 1.0 / level_dimensions[0][1] as f32],
+                threshold: bloom.threshold,
+            },
+        );
+        let future: Box<dyn GpuFuture> = Box::new(
+            before_future.then_execute(self.gfx_queue.clone(), command_buffer_builder.build().unwrap()).unwrap());
+
+        let (future, mut levels) = if level_dimensions.len() > 1 {
+            self.downsampler.downsample(future, bright.clone(), level_dimensions.len() - 1, DownsampleKernel::Tent)?
+        } else {
+            (future, vec![])
+        };
+        levels.insert(0, bright);
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        for (idx, &dims) in level_dimensions.iter().enumerate() {
+            // Separable blur, through the scratch image and back
+            self.fullscreen_draw(
+                &mut command_buffer_builder,
+                self.level_framebuffer(scratch[idx].clone()),
+                self.blur_pipeline.clone(),
+                levels[idx].clone(),
+                dims,
+                fs_blur::ty::PushConstants { direction: [1.0, 0.0], radius: bloom.radius },
+            );
+            self.fullscreen_draw(
+                &mut command_buffer_builder,
+                self.level_framebuffer(levels[idx].clone()),
+                self.blur_pipeline.clone(),
+                scratch[idx].clone(),
+                dims,
+                fs_blur::ty::PushConstants { direction: [0.0, 1.0], radius: bloom.radius },
+            );
+        }
+
+        let composite_framebuffer = Arc::new(
+            render_pass::Framebuffer::start(self.composite_render_pass.clone())
+                .add(hdr_image.clone())
+                .unwrap()
+                .build()
+                .unwrap()
+        );
+
+        // Every level gets the same weight, so wider glows are as strong as the tight ones
+        let intensity = bloom.intensity / levels.len().max(1) as f32;
+        for level in levels.iter() {
+            self.fullscreen_draw(
+                &mut command_buffer_builder,
+                composite_framebuffer.clone(),
+                self.composite_pipeline.clone(),
+                level.clone(),
+                [width, height],
+                fs_composite::ty::PushConstants {
+                    target_texel: [1.0 / width as f32, 1.0 / height as f32],
+                    intensity,
+                },
+            );
+        }
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+
+        Ok(Box::new(future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap()))
+    }
+
+    fn level_image(&self, dimensions: [u32; 2]) -> Result<Arc<ImageView<Arc<AttachmentImage>>>, ImageCreationError> {
+        Ok(ImageView::new(self.pool.try_image(
+            dimensions,
+            SampleCount::Sample1,
+            self.format,
+            ImageUsage { sampled: true, ..ImageUsage::none() },
+        )?).unwrap())
+    }
+
+    fn level_framebuffer(&self, level: Arc<ImageView<Arc<AttachmentImage>>>)
+                         -> Arc<dyn FramebufferAbstract + Send + Sync> {
+        Arc::new(
+            render_pass::Framebuffer::start(self.level_render_pass.clone())
+                .add(level)
+                .unwrap()
+                .build()
+                .unwrap()
+        )
+    }
+
+    // One render pass drawing a fullscreen triangle that samples `source`
+    fn fullscreen_draw<S, Pc>(&self,
+                              builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+                              framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+                              pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+                              source: S,
+                              dimensions: [u32; 2],
+                              push_constants: Pc)
+        where S: ImageViewAbstract + Send + Sync + 'static
+    {
+        let layout = pipeline.layout().descriptor_set_layout(0).unwrap();
+        let descriptor_set = PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(source, self.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        builder
+            .begin_render_pass(framebuffer, SubpassContents::Inline, vec![ClearValue::None])
+            .unwrap()
+            .draw(
+                pipeline,
+                &dynamic_state,
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                push_constants,
+                vec![],
+            )
+            .unwrap()
+            .end_render_pass()
+            .unwrap();
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+struct Vertex {
+    position: [f32; 2],
+}
+vulkano::impl_vertex!(Vertex, position);
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        bytes: "resources/shaders/post/fullscreen.vert.spv"
+    }
+}
+
+mod fs_downsample {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/post/bloom_downsample.frag.spv"
+    }
+}
+
+mod fs_blur {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/post/bloom_blur.frag.spv"
+    }
+}
+
+mod fs_composite {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/post/bloom_composite.frag.spv"
+    }
+}
@@ -0,0 +1,197 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use vulkano::format::Format;
+use vulkano::image::{ImageDimensions, ImmutableImage, MipmapsCount};
+use vulkano::image::view::ImageView;
+
+use crate::base::upload::UploadBatch;
+
+// 3D color lookup tables for `ToneMapPass::set_lut`. Red grows along x, green along y and
+// blue along z.
+pub type ColorLut = Arc<ImageView<Arc<ImmutableImage>>>;
+
+// `size`^3 table with `f` applied to the color of every texel
+pub fn from_fn<F>(uploads: &mut UploadBatch, size: u32, f: F) -> ColorLut
+    where F: Fn([f32; 3]) -> [f32; 3]
+{
+    assert!(size >= 2, "a color LUT needs at least 2 texels per axis");
+
+    let max = (size - 1) as f32;
+    let mut colors = Vec::with_capacity((size * size * size) as usize);
+    for b in 0..size {
+        for g in 0..size {
+            for r in 0..size {
+                colors.push(f([r as f32 / max, g as f32 / max, b as f32 / max]));
+            }
+        }
+    }
+
+    from_colors(uploads, size, colors)
+}
+
+// Largest table `parse_cube` and `from_strip_png` accept, 128^3 texels are 8 MB on the GPU
+pub const MAX_SIZE: u32 = 128;
+
+pub fn identity(uploads: &mut UploadBatch, size: u32) -> ColorLut {
+    from_fn(uploads, size, |color| color)
+}
+
+// .CUBE file or PNG strip (see `from_strip_png`), told apart by the extension
+pub fn from_file(uploads: &mut UploadBatch, path: &str) -> Result<ColorLut, String> {
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    if path.to_lowercase().ends_with(".png") {
+        from_strip_png(uploads, &bytes)
+    } else {
+        let text = String::from_utf8(bytes).map_err(|e| e.to_string())?;
+        from_cube(uploads, &text)
+    }
+}
+
+// Adobe/Resolve .CUBE text. Only 3D tables with the default [0, 1] domain are supported.
+pub fn from_cube(uploads: &mut UploadBatch, text: &str) -> Result<ColorLut, String> {
+    let (size, colors) = parse_cube(text)?;
+    Ok(from_colors(uploads, size, colors))
+}
+
+// Size and colors of a .CUBE table, see `from_cube`. The colors have red changing fastest, like
+// the image.
+pub fn parse_cube(text: &str) -> Result<(u32, Vec<[f32; 3]>), String> {
+    let mut size = None;
+    let mut colors = vec![];
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        let keyword = words.next().unwrap();
+        let values: Vec<&str> = words.collect();
+        let parse = |value: &str| value.parse::<f32>()
+            .map_err(|_| format!("line {}: bad number {:?}", line_idx + 1, value));
+
+        match keyword {
+            "TITLE" => {}
+            "LUT_3D_SIZE" => {
+                let value = values.first().ok_or(format!("line {}: missing size", line_idx + 1))?;
+                size = Some(value.parse::<u32>().map_err(|_| format!("line {}: bad size {:?}", line_idx + 1, value))?);
+            }
+            "LUT_1D_SIZE" => return Err("1D tables are not supported".to_string()),
+            "DOMAIN_MIN" | "DOMAIN_MAX" => {
+                let expected = if keyword == "DOMAIN_MIN" { 0.0 } else { 1.0 };
+                for value in values {
+                    if parse(value)? != expected {
+                        return Err(format!("line {}: only the [0, 1] domain is supported", line_idx + 1));
+                    }
+                }
+            }
+            _ => {
+                if values.len() != 2 {
+                    return Err(format!("line {}: expected an RGB triplet", line_idx + 1));
+                }
+                colors.push([parse(keyword)?, parse(values[0])?, parse(values[1])?]);
+            }
+        }
+    }
+
+    let size = size.ok_or("missing LUT_3D_SIZE".to_string())?;
+    let entries = entry_count(size)?;
+    if colors.len() != entries {
+        return Err(format!("expected {} entries, found {}", entries, colors.len()));
+    }
+
+    Ok((size, colors))
+}
+
+// Horizontal strip of `size` squares of `size` x `size` texels, one per blue slice. Red grows
+// to the right inside a square, green downwards.
+pub fn from_strip_png(uploads: &mut UploadBatch, png_bytes: &[u8]) -> Result<ColorLut, String> {
+    let decoder = png::Decoder::new(Cursor::new(png_bytes));
+    let (info, mut reader) = decoder.read_info().map_err(|e| e.to_string())?;
+    if info.color_type != png::ColorType::RGBA || info.bit_depth != png::BitDepth::Eight {
+        return Err("the strip must be an 8-bit RGBA PNG".to_string());
+    }
+
+    let size = info.height;
+    let entries = entry_count(size)?;
+    if info.width as usize * info.height as usize != entries {
+        return Err(format!("a {}x{} image is not a LUT strip", info.width, info.height));
+    }
+
+    let mut pixels = vec![0; entries * 4];
+    reader.next_frame(&mut pixels).map_err(|e| e.to_string())?;
+
+    let mut colors = Vec::with_capacity(entries);
+    for b in 0..size {
+        for g in 0..size {
+            for r in 0..size {
+                let offset = ((g * info.width + b * size + r) * 4) as usize;
+                let texel = &pixels[offset..offset + 3];
+                colors.push([texel[0] as f32 / 255.0, texel[1] as f32 / 255.0, texel[2] as f32 / 255.0]);
+            }
+        }
+    }
+
+    Ok(from_colors(uploads, size, colors))
+}
+
+// `size`^3, for sizes in [2, `MAX_SIZE`]
+fn entry_count(size: u32) -> Result<usize, String> {
+    if !(2..=MAX_SIZE).contains(&size) {
+        return Err(format!("LUT size {} is not in [2, {}]", size, MAX_SIZE));
+    }
+    (size as usize).checked_mul(size as usize)
+        .and_then(|area| area.checked_mul(size as usize))
+        .ok_or(format!("LUT size {} is too large", size))
+}
+
+fn from_colors(uploads: &mut UploadBatch, size: u32, colors: Vec<[f32; 3]>) -> ColorLut {
+    let to_u8 = |v: f32| (v.max(0.0).min(1.0) * 255.0).round() as u8;
+    let image_data: Vec<u8> = colors.iter()
+        .flat_map(|c| vec![to_u8(c[0]), to_u8(c[1]), to_u8(c[2]), 255])
+        .collect();
+
+    let image = uploads.image(
+        image_data.into_iter(),
+        ImageDimensions::Dim3d { width: size, height: size, depth: size },
+        MipmapsCount::One,
+        Format::R8G8B8A8Unorm,
+    );
+
+    ImageView::new(image).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_SIZE, parse_cube};
+
+    #[test]
+    fn parses_a_2x2x2_table() {
+        let text = "TITLE \"test\"\n# comment\nLUT_3D_SIZE 2\nDOMAIN_MIN 0 0 0\n\n\
+                    0 0 0\n1 0 0\n0 1 0\n1 1 0\n0 0 1\n1 0 1\n0 1 1\n1 1 1\n";
+        let (size, colors) = parse_cube(text).unwrap();
+
+        assert_eq!(size, 2);
+        assert_eq!(colors.len(), 8);
+        // Red changes fastest
+        assert_eq!(colors[1], [1.0, 0.0, 0.0]);
+        assert_eq!(colors[4], [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn rejects_bad_tables() {
+        assert!(parse_cube("LUT_3D_SIZE 2\n0 0 0\n").is_err());
+        assert!(parse_cube("0 0 0\n").is_err());
+        assert!(parse_cube("LUT_1D_SIZE 16\n").is_err());
+        assert!(parse_cube("LUT_3D_SIZE 2\nDOMAIN_MAX 2 2 2\n").is_err());
+        assert!(parse_cube("LUT_3D_SIZE 2\n0 0\n").is_err());
+    }
+
+    #[test]
+    fn huge_sizes_are_rejected_without_overflowing() {
+        assert!(parse_cube(&format!("LUT_3D_SIZE {}\n", MAX_SIZE + 1)).is_err());
+        assert!(parse_cube(&format!("LUT_3D_SIZE {}\n", u32::MAX)).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use std::sync::Arc;
+
+use cgmath::{Matrix4, SquareMatrix};
+use vulkano::{render_pass, sampler};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::{ClearValue, Format};
+use vulkano::image::{ImageLayout, ImageViewAbstract, SampleCount};
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendFactor, BlendOp};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::render_pass::{AttachmentDesc, LoadOp, RenderPass, StoreOp, Subpass};
+use vulkano::sync::GpuFuture;
+
+// Projects textures onto the G-buffer surfaces, e.g. selection rings on the terrain. Runs between
+// the G-buffer and the lighting pass: every pixel whose world position (rebuilt from the depth)
+// falls into the decal box gets the decal blended into its albedo, so it's lit like the surface.
+//
+// Each decal is a full-screen draw, which is fine for a handful of markers.
+pub struct DecalPass {
+    gfx_queue: Arc<Queue>,
+
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    depth_sampler: Arc<sampler::Sampler>,
+    texture_sampler: Arc<sampler::Sampler>,
+    decal_buffer: CpuBufferPool<fs::ty::DecalData>,
+
+    render_pass: Arc<RenderPass>,
+}
+
+impl DecalPass {
+    // `albedo_format` and `samples` must match the G-buffer targets passed to `draw`
+    pub fn new(gfx_queue: Arc<Queue>, albedo_format: Format, samples: SampleCount) -> DecalPass {
+        let render_pass = create_render_pass(gfx_queue.clone(), albedo_format, samples);
+
+        let vertex_buffer = {
+            CpuAccessibleBuffer::from_iter(gfx_queue.device().clone(), BufferUsage::all(), false, [
+                Vertex { position: [-1.0, -1.0] },
+                Vertex { position: [-1.0, 3.0] },
+                Vertex { position: [3.0, -1.0] }
+            ].iter().cloned()).expect("failed to create buffer")
+        };
+
+        let pipeline = {
+            let vs = vs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+            let builder = GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                // Straight alpha over the albedo, the alpha of the G-buffer is kept
+                .blend_collective(AttachmentBlend {
+                    enabled: true,
+                    color_op: BlendOp::Add,
+                    color_source: BlendFactor::SrcAlpha,
+                    color_destination: BlendFactor::OneMinusSrcAlpha,
+                    alpha_op: BlendOp::Add,
+                    alpha_source: BlendFactor::Zero,
+                    alpha_destination: BlendFactor::One,
+                    mask_red: true,
+                    mask_green: true,
+                    mask_blue: true,
+                    mask_alpha: true,
+                })
+                .render_pass(Subpass::from(render_pass.clone(), 0).unwrap());
+
+            // Single-sampled G-buffers need the sampler2D build of the shader, and per sample
+            // reads need `sample_rate_shading`
+            if samples == SampleCount::Sample1 {
+                let fs = fs_single_sample::Shader::load(gfx_queue.device().clone())
+                    .expect("failed to create shader module");
+
+                Arc::new(builder
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .build(gfx_queue.device().clone())
+                    .unwrap()) as Arc<_>
+            } else if !gfx_queue.device().enabled_features().sample_rate_shading {
+                let fs = fs_first_sample::Shader::load(gfx_queue.device().clone())
+                    .expect("failed to create shader module");
+
+                Arc::new(builder
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .build(gfx_queue.device().clone())
+                    .unwrap()) as Arc<_>
+            } else {
+                let fs = fs::Shader::load(gfx_queue.device().clone())
+                    .expect("failed to create shader module");
+
+                Arc::new(builder
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .build(gfx_queue.device().clone())
+                    .unwrap()) as Arc<_>
+            }
+        };
+
+        // The depth is read with texelFetch, the sampler only has to exist
+        let depth_sampler = sampler::Sampler::new(
+            gfx_queue.device().clone(),
+            sampler::Filter::Nearest,
+            sampler::Filter::Nearest,
+            sampler::MipmapMode::Nearest,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        ).unwrap();
+        // Clamped, so the texture doesn't bleed over the edges of the box
+        let texture_sampler = sampler::Sampler::new(
+            gfx_queue.device().clone(),
+            sampler::Filter::Linear,
+            sampler::Filter::Linear,
+            sampler::MipmapMode::Nearest,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        ).unwrap();
+
+        let decal_buffer = CpuBufferPool::<fs::ty::DecalData>::new(gfx_queue.device().clone(), BufferUsage::all());
+
+        DecalPass {
+            gfx_queue,
+            vertex_buffer,
+            pipeline,
+            depth_sampler,
+            texture_sampler,
+            decal_buffer,
+            render_pass,
+        }
+    }
+
+    // Blends `texture` into `albedo` wherever the G-buffer surface lies inside the box `transform`
+    // maps the unit cube around the origin to. The texture covers the xz face of the box and is
+    // projected along its y axis. `depth_input` is the G-buffer depth, `view` and `proj` the
+    // camera matrices it was rendered with.
+    pub fn draw<F, A, D, T>(&self,
+                            before_future: F,
+                            albedo: Arc<A>,
+                            depth_input: D,
+                            view: Matrix4<f32>,
+                            proj: Matrix4<f32>,
+                            transform: Matrix4<f32>,
+                            texture: T,
+    ) -> Box<dyn GpuFuture>
+        where
+            F: GpuFuture + 'static,
+            A: ImageViewAbstract + Send + Sync + 'static,
+            D: ImageViewAbstract + Send + Sync + 'static,
+            T: ImageViewAbstract + Send + Sync + 'static
+    {
+        let (inv_view_proj, inv_transform) = match ((proj * view).invert(), transform.invert()) {
+            (Some(inv_view_proj), Some(inv_transform)) => (inv_view_proj, inv_transform),
+            // A flat box covers nothing
+            _ => return Box::new(before_future),
+        };
+
+        let framebuffer = Arc::new(
+            render_pass::Framebuffer::start(self.render_pass.clone())
+                .add(albedo.clone())
+                .unwrap()
+                .build()
+                .unwrap()
+        );
+
+        let decal_subbuffer = self.decal_buffer.next(fs::ty::DecalData {
+            inv_view_proj: inv_view_proj.into(),
+            inv_transform: inv_transform.into(),
+        }).unwrap();
+
+        let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
+        let descriptor_set = PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(depth_input, self.depth_sampler.clone())
+            .unwrap()
+            .add_sampled_image(texture, self.texture_sampler.clone())
+            .unwrap()
+            .add_buffer(decal_subbuffer)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let viewport_dimensions = albedo.image().dimensions().width_height();
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [viewport_dimensions[0] as f32,
+                    viewport_dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        command_buffer_builder
+            .begin_render_pass(
+                framebuffer,
+                SubpassContents::Inline,
+                vec![ClearValue::None],
+            )
+            .unwrap()
+            .draw(
+                self.pipeline.clone(),
+                &dynamic_state,
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                (),
+                vec![],
+            )
+            .unwrap()
+            .end_render_pass()
+            .unwrap();
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+
+        Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap())
+    }
+}
+
+// The albedo keeps what the G-buffer pass wrote
+fn create_render_pass(gfx_queue: Arc<Queue>, albedo_format: Format, samples: SampleCount) -> Arc<RenderPass> {
+    let attachments = vec![
+        AttachmentDesc {
+            format: albedo_format,
+            samples,
+            load: LoadOp::Load,
+            store: StoreOp::Store,
+            stencil_load: LoadOp::DontCare,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::ColorAttachmentOptimal,
+            final_layout: ImageLayout::ColorAttachmentOptimal,
+        },
+    ];
+
+    let subpasses = vec![
+        render_pass::SubpassDesc {
+            color_attachments: vec![(0, ImageLayout::ColorAttachmentOptimal)],
+            depth_stencil: None,
+            input_attachments: vec![],
+            resolve_attachments: vec![],
+            preserve_attachments: vec![],
+        },
+    ];
+
+    Arc::new(
+        RenderPass::new(
+            gfx_queue.device().clone(),
+            render_pass::RenderPassDesc::new(attachments, subpasses, vec![]),
+        ).unwrap()
+    )
+}
+
+#[derive(Default, Debug, Clone)]
+struct Vertex {
+    position: [f32; 2],
+}
+vulkano::impl_vertex!(Vertex, position);
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        bytes: "resources/shaders/post/fullscreen.vert.spv"
+    }
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/decal/decal.frag.spv"
+    }
+}
+
+mod fs_single_sample {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/decal/decal.frag.single_sample.spv"
+    }
+}
+
+mod fs_first_sample {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/decal/decal.frag.first_sample.spv"
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use vulkano::sampler;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, PrimaryAutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::image::{AttachmentImage, ImageCreationError, ImageUsage, ImageViewAbstract, SampleCount};
+use vulkano::image::view::ImageView;
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract};
+use vulkano::sync::GpuFuture;
+
+use crate::base::attachment_pool::AttachmentPool;
+
+// Format of every level, must match the image format of `u_output` in downsample.comp
+pub const DOWNSAMPLE_FORMAT: Format = Format::R16G16B16A16Sfloat;
+
+// Must match `local_size_x/y` in downsample.comp
+const GROUP_SIZE: u32 = 8;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum DownsampleKernel {
+    // Even average of the 4x4 input block around every output pixel
+    Box,
+    // 1-3-3-1 weights over the same block, fewer aliasing artifacts on thin bright features
+    Tent,
+}
+
+impl DownsampleKernel {
+    // Weights of the 4 input texels along each axis, the output pixel sits between the middle two
+    pub fn weights(&self) -> [f32; 4] {
+        match self {
+            DownsampleKernel::Box => [0.25, 0.25, 0.25, 0.25],
+            DownsampleKernel::Tent => [0.125, 0.375, 0.375, 0.125],
+        }
+    }
+
+    // Distance of the bilinear taps from the pixel center giving `weights`, in input texels. A
+    // tap at `offset` puts `offset - 0.5` of its weight on the outer texel, two taps per axis.
+    fn tap_offset(&self) -> f32 {
+        0.5 + 2.0 * self.weights()[0]
+    }
+}
+
+// Halves an image with a compute shader, as many times as requested. Unlike a blit the kernel
+// is ours, so bloom, blurs and thumbnails can share one filtered mip chain.
+pub struct Downsampler {
+    gfx_queue: Arc<Queue>,
+    pool: AttachmentPool,
+    pipeline: Arc<ComputePipeline>,
+    sampler: Arc<sampler::Sampler>,
+}
+
+impl Downsampler {
+    pub fn new(gfx_queue: Arc<Queue>, pool: AttachmentPool) -> Downsampler {
+        let pipeline = {
+            let cs = cs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(ComputePipeline::new(gfx_queue.device().clone(), &cs.main_entry_point(), &(), None)
+                .unwrap())
+        };
+
+        // The taps fall between texels, the edges repeat the border texels
+        let sampler = sampler::Sampler::new(
+            gfx_queue.device().clone(),
+            sampler::Filter::Linear,
+            sampler::Filter::Linear,
+            sampler::MipmapMode::Nearest,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        ).unwrap();
+
+        Downsampler {
+            gfx_queue,
+            pool,
+            pipeline,
+            sampler,
+        }
+    }
+
+    // Returns `levels` images, each half the size of the previous one (at least 1 pixel) and the
+    // first half the size of `input`. The levels come from the attachment pool, drop them once
+    // they're no longer needed so the next call reuses them. They can be rendered to as well.
+    pub fn downsample<F, I>(&self, before_future: F, input: I, levels: usize, kernel: DownsampleKernel)
+                            -> Result<(Box<dyn GpuFuture>, Vec<Arc<ImageView<Arc<AttachmentImage>>>>), ImageCreationError>
+        where
+            F: GpuFuture + 'static,
+            I: ImageViewAbstract + Send + Sync + 'static
+    {
+        assert!(levels > 0, "downsampling needs at least one level");
+
+        let mut dimensions = input.image().dimensions().width_height();
+        let mut targets = Vec::with_capacity(levels);
+        for _ in 0..levels {
+            dimensions = [(dimensions[0] / 2).max(1), (dimensions[1] / 2).max(1)];
+            targets.push(ImageView::new(self.pool.try_image(
+                dimensions,
+                SampleCount::Sample1,
+                DOWNSAMPLE_FORMAT,
+                ImageUsage { storage: true, sampled: true, ..ImageUsage::none() },
+            )?).unwrap());
+        }
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        self.dispatch(&mut command_buffer_builder, input, targets[0].clone(), kernel);
+        for level in 1..levels {
+            self.dispatch(&mut command_buffer_builder, targets[level - 1].clone(), targets[level].clone(), kernel);
+        }
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+
+        Ok((Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap()), targets))
+    }
+
+    fn dispatch<I>(&self,
+                   builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+                   input: I,
+                   output: Arc<ImageView<Arc<AttachmentImage>>>,
+                   kernel: DownsampleKernel)
+        where I: ImageViewAbstract + Send + Sync + 'static
+    {
+        let dimensions = output.image().dimensions().width_height();
+
+        let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
+        let descriptor_set = PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(input, self.sampler.clone())
+            .unwrap()
+            .add_image(output)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let push_constants = cs::ty::PushConstants {
+            tap_offset: kernel.tap_offset(),
+        };
+
+        let groups = [dimensions[0].div_ceil(GROUP_SIZE), dimensions[1].div_ceil(GROUP_SIZE), 1];
+        builder
+            .dispatch(groups, self.pipeline.clone(), descriptor_set, push_constants, vec![])
+            .unwrap();
+    }
+}
+
+mod cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        bytes: "resources/shaders/post/downsample.comp.spv"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DownsampleKernel;
+
+    // Weights of the two bilinear taps at -offset and +offset on the texels at -1.5, -0.5, 0.5
+    // and 1.5 around the output pixel
+    fn tap_weights(offset: f32) -> [f32; 4] {
+        let outer = (offset - 0.5) / 2.0;
+        let inner = (1.5 - offset) / 2.0;
+        [outer, inner, inner, outer]
+    }
+
+    #[test]
+    fn taps_reproduce_the_kernel_weights() {
+        for &kernel in [DownsampleKernel::Box, DownsampleKernel::Tent].iter() {
+            let weights = kernel.weights();
+            assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+
+            let taps = tap_weights(kernel.tap_offset());
+            for (tap, weight) in taps.iter().zip(weights.iter()) {
+                assert!((tap - weight).abs() < 1e-6, "{:?} != {:?}", taps, weights);
+            }
+        }
+
+        assert_eq!(DownsampleKernel::Box.tap_offset(), 1.0);
+        assert_eq!(DownsampleKernel::Tent.tap_offset(), 0.75);
+    }
+}
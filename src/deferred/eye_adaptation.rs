@@ -0,0 +1,164 @@
+use std::sync::Arc;
+
+use vulkano::sampler;
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::image::ImageViewAbstract;
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract};
+use vulkano::sync::GpuFuture;
+
+// Average luminance the exposure maps the image to. Well above middle grey (0.18), so turning
+// auto exposure on doesn't make the default look darker.
+const KEY_VALUE: f32 = 0.5;
+
+#[derive(Clone, Copy)]
+pub struct AutoExposure {
+    pub min_exposure: f32,
+    pub max_exposure: f32,
+    // How fast the exposure follows the image, roughly the inverse of the settle time in seconds
+    pub speed: f32,
+}
+
+// Automatic exposure for the tone mapping. A compute pass averages the log luminance of the HDR
+// image into a small buffer, the next frames read it back and move the exposure towards it.
+pub struct EyeAdaptation {
+    gfx_queue: Arc<Queue>,
+    pipeline: Arc<ComputePipeline>,
+    sampler: Arc<sampler::Sampler>,
+
+    result: Arc<CpuAccessibleBuffer<f32>>,
+    // A measurement was submitted and `result` wasn't read since
+    pending: bool,
+    avg_log_luminance: Option<f32>,
+}
+
+impl EyeAdaptation {
+    pub fn new(gfx_queue: Arc<Queue>) -> EyeAdaptation {
+        let pipeline = {
+            let cs = cs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(ComputePipeline::new(gfx_queue.device().clone(), &cs.main_entry_point(), &(), None)
+                .unwrap())
+        };
+
+        let sampler = sampler::Sampler::simple_repeat_linear_no_mipmap(gfx_queue.device().clone());
+
+        let result = CpuAccessibleBuffer::from_data(
+            gfx_queue.device().clone(),
+            BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+            true,
+            0.0,
+        ).unwrap();
+
+        EyeAdaptation {
+            gfx_queue,
+            pipeline,
+            sampler,
+            result,
+            pending: false,
+            avg_log_luminance: None,
+        }
+    }
+
+    // Measures `hdr_input`. Skipped while the GPU still works on the previous measurement, so
+    // the result lags a frame or two behind.
+    pub fn measure<F, H>(&mut self, before_future: F, hdr_input: H) -> Box<dyn GpuFuture>
+        where
+            F: GpuFuture + 'static,
+            H: ImageViewAbstract + Send + Sync + 'static
+    {
+        match self.result.read() {
+            Ok(result) => {
+                if self.pending {
+                    self.avg_log_luminance = Some(*result);
+                    self.pending = false;
+                }
+            }
+            Err(_) => return Box::new(before_future),
+        }
+
+        let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
+        let descriptor_set = PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(hdr_input, self.sampler.clone())
+            .unwrap()
+            .add_buffer(self.result.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        command_buffer_builder
+            .dispatch([1, 1, 1], self.pipeline.clone(), descriptor_set, (), vec![])
+            .unwrap();
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+        self.pending = true;
+
+        Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap())
+    }
+
+    // `exposure` moved towards the value matching the last measurement over `dt` seconds.
+    // Unchanged until the first measurement is read.
+    pub fn adapt(&self, exposure: f32, dt: f32, settings: AutoExposure) -> f32 {
+        match self.avg_log_luminance {
+            Some(avg_log_luminance) => adapted_exposure(exposure, avg_log_luminance, dt, settings),
+            None => exposure,
+        }
+    }
+}
+
+// See `EyeAdaptation::adapt`
+fn adapted_exposure(exposure: f32, avg_log_luminance: f32, dt: f32, settings: AutoExposure) -> f32 {
+    let target = (KEY_VALUE / avg_log_luminance.exp())
+        .max(settings.min_exposure)
+        .min(settings.max_exposure);
+
+    // Frame rate independent exponential smoothing
+    let t = 1.0 - (-dt * settings.speed).exp();
+    exposure + (target - exposure) * t
+}
+
+mod cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        bytes: "resources/shaders/post/luminance.comp.spv"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adapted_exposure, AutoExposure, KEY_VALUE};
+
+    const SETTINGS: AutoExposure = AutoExposure { min_exposure: 0.25, max_exposure: 4.0, speed: 2.0 };
+
+    #[test]
+    fn settles_on_the_key_value() {
+        let avg_log_luminance = 0.25f32.ln();
+        let mut exposure = 1.0;
+        for _ in 0..600 {
+            exposure = adapted_exposure(exposure, avg_log_luminance, 1.0 / 60.0, SETTINGS);
+        }
+        assert!((exposure - KEY_VALUE / 0.25).abs() < 1e-3, "{}", exposure);
+
+        // Far too dark or bright images stop at the limits
+        assert_eq!(adapted_exposure(4.0, 0.001f32.ln(), 100.0, SETTINGS), 4.0);
+        assert_eq!(adapted_exposure(0.25, 1000.0f32.ln(), 100.0, SETTINGS), 0.25);
+    }
+
+    #[test]
+    fn independent_of_the_frame_rate() {
+        let avg_log_luminance = 2.0f32.ln();
+        let one_step = adapted_exposure(1.0, avg_log_luminance, 0.1, SETTINGS);
+        let two_steps = adapted_exposure(adapted_exposure(1.0, avg_log_luminance, 0.05, SETTINGS),
+                                         avg_log_luminance, 0.05, SETTINGS);
+        assert!((one_step - two_steps).abs() < 1e-5, "{} != {}", one_step, two_steps);
+    }
+}
@@ -0,0 +1,216 @@
+use std::sync::Arc;
+
+use vulkano::{render_pass, sampler};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::image::{AttachmentImage, ImageCreationError, ImageUsage, ImageViewAbstract, SampleCount};
+use vulkano::image::view::ImageView;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::render_pass::{RenderPass, Subpass};
+use vulkano::sync::GpuFuture;
+
+use crate::base::attachment_pool::AttachmentPool;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AaMode {
+    Off,
+    // Multisampled gbuffer, the lighting averages the samples
+    Msaa(SampleCount),
+    // Single-sampled gbuffer, the edges of the final image are smoothed by `FxaaPass`
+    Fxaa,
+}
+
+impl AaMode {
+    // Sample count of the gbuffer targets
+    pub fn samples(&self) -> SampleCount {
+        match self {
+            AaMode::Msaa(samples) => *samples,
+            AaMode::Off | AaMode::Fxaa => SampleCount::Sample1,
+        }
+    }
+}
+
+// Post-process anti-aliasing of the final (tone-mapped) image. Much cheaper than MSAA of the
+// gbuffer, but blurs some texture detail and can't recover sub-pixel geometry.
+pub struct FxaaPass {
+    gfx_queue: Arc<Queue>,
+    pool: AttachmentPool,
+    format: Format,
+
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    sampler: Arc<sampler::Sampler>,
+
+    render_pass: Arc<RenderPass>,
+}
+
+impl FxaaPass {
+    // `format` is the format of both the input and the output image
+    pub fn new(gfx_queue: Arc<Queue>, pool: AttachmentPool, format: Format) -> FxaaPass {
+        let render_pass = Arc::new(
+            vulkano::single_pass_renderpass!(
+                gfx_queue.device().clone(),
+                attachments: {
+                    final_color: {
+                        load: DontCare,
+                        store: Store,
+                        format: format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                        color: [final_color],
+                        depth_stencil: {}
+                    }
+            ).unwrap(),
+        );
+
+        let vertex_buffer = {
+            CpuAccessibleBuffer::from_iter(gfx_queue.device().clone(), BufferUsage::all(), false, [
+                Vertex { position: [-1.0, -1.0] },
+                Vertex { position: [-1.0, 3.0] },
+                Vertex { position: [3.0, -1.0] }
+            ].iter().cloned()).expect("failed to create buffer")
+        };
+
+        let pipeline = {
+            let vs = vs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+            let fs = fs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+                .build(gfx_queue.device().clone())
+                .unwrap()) as Arc<_>
+        };
+
+        // The shader samples between texels along the edges
+        let sampler = sampler::Sampler::new(
+            gfx_queue.device().clone(),
+            sampler::Filter::Linear,
+            sampler::Filter::Linear,
+            sampler::MipmapMode::Nearest,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        ).unwrap();
+
+        FxaaPass {
+            gfx_queue,
+            pool,
+            format,
+            vertex_buffer,
+            pipeline,
+            sampler,
+            render_pass,
+        }
+    }
+
+    // Image to render the final frame into before `draw`. Comes from the attachment pool, so
+    // it's cheap to request every frame.
+    pub fn input_target(&self, dimensions: [u32; 2]) -> Result<Arc<ImageView<Arc<AttachmentImage>>>, ImageCreationError> {
+        Ok(ImageView::new(self.pool.try_image(
+            dimensions,
+            SampleCount::Sample1,
+            self.format,
+            ImageUsage { sampled: true, ..ImageUsage::none() },
+        )?).unwrap())
+    }
+
+    // Writes the anti-aliased `input` into `target_image`, both of the same size
+    pub fn draw<F, I, N>(&self, before_future: F, target_image: Arc<I>, input: N) -> Box<dyn GpuFuture>
+        where
+            F: GpuFuture + 'static,
+            I: ImageViewAbstract + Send + Sync + 'static,
+            N: ImageViewAbstract + Send + Sync + 'static
+    {
+        let framebuffer = Arc::new(
+            render_pass::Framebuffer::start(self.render_pass.clone())
+                .add(target_image.clone())
+                .unwrap()
+                .build()
+                .unwrap()
+        );
+
+        let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
+        let descriptor_set = PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(input, self.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let viewport_dimensions = target_image.image().dimensions().width_height();
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [viewport_dimensions[0] as f32,
+                    viewport_dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        command_buffer_builder
+            .begin_render_pass(
+                framebuffer,
+                SubpassContents::Inline,
+                vec![vulkano::format::ClearValue::None],
+            )
+            .unwrap()
+            .draw(
+                self.pipeline.clone(),
+                &dynamic_state,
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                (),
+                vec![],
+            )
+            .unwrap()
+            .end_render_pass()
+            .unwrap();
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+
+        Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap())
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+struct Vertex {
+    position: [f32; 2],
+}
+vulkano::impl_vertex!(Vertex, position);
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        bytes: "resources/shaders/post/fullscreen.vert.spv"
+    }
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/post/fxaa.frag.spv"
+    }
+}
@@ -0,0 +1,76 @@
+// Light colors from color temperatures, for natural looking lights without picking RGB values
+
+// Named color temperature, see `PRESETS`
+#[derive(Clone, Copy)]
+pub struct Preset {
+    pub name: &'static str,
+    pub kelvin: f32,
+}
+
+impl Preset {
+    pub fn color(&self) -> [f32; 3] {
+        from_kelvin(self.kelvin)
+    }
+}
+
+// From warm to cold
+pub const PRESETS: [Preset; 4] = [
+    Preset { name: "candle", kelvin: 1900.0 },
+    Preset { name: "tungsten", kelvin: 2700.0 },
+    Preset { name: "daylight", kelvin: 5500.0 },
+    Preset { name: "shade", kelvin: 7500.0 },
+];
+
+// Linear RGB of a black body at `temp` kelvin, brightest channel at 1. Tanner Helland's fit of
+// the blackbody colors, clamped to the [1000, 40000] range it covers.
+#[allow(clippy::excessive_precision)]
+pub fn from_kelvin(temp: f32) -> [f32; 3] {
+    let t = temp.max(1000.0).min(40000.0) / 100.0;
+
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.698727446 * (t - 60.0).powf(-0.1332047592)
+    };
+    let g = if t <= 66.0 {
+        99.4708025861 * t.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (t - 60.0).powf(-0.0755148492)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * (t - 10.0).ln() - 305.0447927307
+    };
+
+    // The fit gives sRGB encoded values, the lighting works in linear
+    let linear = |c: f32| (c.max(0.0).min(255.0) / 255.0).powf(2.2);
+    [linear(r), linear(g), linear(b)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_kelvin, PRESETS};
+
+    #[test]
+    fn white_at_6600_kelvin() {
+        for &c in from_kelvin(6600.0).iter() {
+            assert!((c - 1.0).abs() < 0.01, "{:?}", from_kelvin(6600.0));
+        }
+    }
+
+    #[test]
+    fn warm_is_red_and_cold_is_blue() {
+        let [r, g, b] = from_kelvin(1900.0);
+        assert!(r > g && g > b);
+        let [r, _, b] = from_kelvin(10000.0);
+        assert!(b > r);
+
+        for preset in PRESETS.iter() {
+            let color = preset.color();
+            assert!((color.iter().cloned().fold(0.0, f32::max) - 1.0).abs() < 1e-6, "{}", preset.name);
+        }
+    }
+}
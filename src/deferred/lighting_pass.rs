@@ -1,31 +1,81 @@
 use std::sync::Arc;
 
+use cgmath::{Matrix4, SquareMatrix};
 use vulkano::{image, render_pass, sampler};
 use vulkano::buffer::BufferUsage;
-use vulkano::buffer::CpuAccessibleBuffer;
-use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, SubpassContents};
+use vulkano::buffer::{CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, SecondaryAutoCommandBuffer, SubpassContents};
 use vulkano::command_buffer::DynamicState;
 use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
 use vulkano::device::Queue;
 use vulkano::image::ImageViewAbstract;
 use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::blend::{AttachmentBlend, BlendFactor};
 use vulkano::pipeline::GraphicsPipelineAbstract;
 use vulkano::pipeline::viewport::Viewport;
 use vulkano::render_pass::{RenderPass, Subpass};
 use vulkano::sync::GpuFuture;
 
+use crate::base::render_stats::RenderCounters;
+use super::point_lighting::LightBlend;
+use super::shadow_map::{Cascade, MAX_CASCADES};
+
+
+#[derive(Clone, Copy)]
+pub struct Fog {
+    pub density: f32,
+    pub color: [f32; 3],
+}
+
+// Hemispheric ambient: `sky` lights surfaces facing up (-y), `ground` those facing down, in
+// between they're blended by the normal. The same color for both gives a flat ambient.
+#[derive(Clone, Copy)]
+pub struct Ambient {
+    pub sky: [f32; 3],
+    pub ground: [f32; 3],
+}
+
+#[derive(Clone)]
+pub struct Shadows {
+    // One per layer of the shadow map, at most `shadow_map::MAX_CASCADES`
+    pub cascades: Vec<Cascade>,
+    // Half size of the PCF kernel in texels, 0 samples a single texel
+    pub pcf_radius: u32,
+    pub depth_bias: f32,
+    pub normal_offset: f32,
+}
+
+// Must match `MAX_AMBIENT_ZONES` in deferred_lighting.frag
+pub const MAX_AMBIENT_ZONES: usize = 8;
+
+// World-space box with its own ambient color, replacing the global one inside it
+#[derive(Clone, Copy)]
+pub struct AmbientZone {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+    pub color: [f32; 3],
+    // Distance outside of the box over which it blends into the surrounding ambient
+    pub falloff: f32,
+}
 
 pub struct LightingPass {
     gfx_queue: Arc<Queue>,
     vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
     pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
     sampler: Arc<sampler::Sampler>,
+    shadow_sampler: Arc<sampler::Sampler>,
+    shadow_buffer: CpuBufferPool<fs::ty::ShadowData>,
+    zone_buffer: CpuBufferPool<fs::ty::ZoneData>,
+    // Empty for a single global ambient
+    ambient_zones: Vec<AmbientZone>,
+    counters: RenderCounters,
 
     render_pass: Arc<RenderPass>,
 }
 
 impl LightingPass {
-    pub fn new(gfx_queue: Arc<Queue>, output_format: vulkano::format::Format, input_samples: image::SampleCount) -> LightingPass
+    pub fn new(gfx_queue: Arc<Queue>, output_format: vulkano::format::Format, input_samples: image::SampleCount,
+               counters: RenderCounters) -> LightingPass
     {
         let render_pass = Arc::new(
             vulkano::single_pass_renderpass!(
@@ -58,23 +108,41 @@ impl LightingPass {
         let pipeline = {
             let vs = vs::Shader::load(gfx_queue.device().clone())
                 .expect("failed to create shader module");
-            let fs = fs::Shader::load(gfx_queue.device().clone())
-                .expect("failed to create shader module");
-
-            let spec_consts = fs::SpecializationConstants {
-                NUM_SAMPLES: input_samples as i32,
-            };
-
-            Arc::new(GraphicsPipeline::start()
+            let builder = GraphicsPipeline::start()
                 .vertex_input_single_buffer::<Vertex>()
                 .vertex_shader(vs.main_entry_point(), ())
                 .triangle_list()
                 .viewports_dynamic_scissors_irrelevant(1)
-                .fragment_shader(fs.main_entry_point(), spec_consts)
-                .blend_alpha_blending()
-                .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
-                .build(gfx_queue.device().clone())
-                .unwrap()) as Arc<_>
+                // Drawn after the lights: fogs what they added (dst * visibility in the alpha) and adds
+                // the ambient term on top
+                .blend_collective(AttachmentBlend {
+                    color_destination: BlendFactor::SrcAlpha,
+                    ..LightBlend::Additive.attachment_blend()
+                })
+                .render_pass(Subpass::from(render_pass.clone(), 0).unwrap());
+
+            // Single-sampled G-buffers need the sampler2D build of the shader
+            if input_samples == image::SampleCount::Sample1 {
+                let fs = fs_single_sample::Shader::load(gfx_queue.device().clone())
+                    .expect("failed to create shader module");
+
+                Arc::new(builder
+                    .fragment_shader(fs.main_entry_point(), fs_single_sample::SpecializationConstants {
+                        NUM_SAMPLES: 1,
+                    })
+                    .build(gfx_queue.device().clone())
+                    .unwrap()) as Arc<_>
+            } else {
+                let fs = fs::Shader::load(gfx_queue.device().clone())
+                    .expect("failed to create shader module");
+
+                Arc::new(builder
+                    .fragment_shader(fs.main_entry_point(), fs::SpecializationConstants {
+                        NUM_SAMPLES: input_samples as i32,
+                    })
+                    .build(gfx_queue.device().clone())
+                    .unwrap()) as Arc<_>
+            }
         };
 
         let sampler = sampler::Sampler::new(
@@ -91,25 +159,80 @@ impl LightingPass {
             100.0,
         ).unwrap();
 
+        let shadow_sampler = sampler::Sampler::new(
+            gfx_queue.device().clone(),
+            sampler::Filter::Nearest,
+            sampler::Filter::Nearest,
+            sampler::MipmapMode::Nearest,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        ).unwrap();
+
+        let shadow_buffer = CpuBufferPool::<fs::ty::ShadowData>::new(gfx_queue.device().clone(), BufferUsage::all());
+        let zone_buffer = CpuBufferPool::<fs::ty::ZoneData>::new(gfx_queue.device().clone(), BufferUsage::all());
+
         LightingPass {
             gfx_queue,
             vertex_buffer,
             pipeline,
             sampler,
+            shadow_sampler,
+            shadow_buffer,
+            zone_buffer,
+            ambient_zones: vec![],
+            counters,
             render_pass,
         }
     }
 
-    pub fn draw<F, I, C>(&self,
-                         before_future: F,
-                         gfx_queue: Arc<Queue>,
-                         target_image: Arc<I>,
-                         color_input: C,
-                         ambient_color: [f32; 3],
+    #[allow(dead_code)]
+    pub fn ambient_zones(&self) -> &[AmbientZone] {
+        &self.ambient_zones
+    }
+
+    // Zones override the `ambient` of `draw` inside their box, later ones take precedence
+    // where they overlap. At most `MAX_AMBIENT_ZONES`.
+    pub fn set_ambient_zones(&mut self, zones: Vec<AmbientZone>) {
+        assert!(zones.len() <= MAX_AMBIENT_ZONES, "too many ambient zones");
+        self.ambient_zones = zones;
+    }
+
+    // Subpass that writes the final image, for systems adding to the lighting
+    pub fn subpass(&self) -> Subpass {
+        Subpass::from(self.render_pass.clone(), 0).unwrap()
+    }
+
+    // `lights` are secondary buffers recorded for `subpass()`; they are executed first and the
+    // ambient term is added on top of them.
+    // `depth_range` is the camera `[near, far]`, used to linearize `depth_input` for the fog.
+    // `shadow_map` is only sampled when `shadows` is set.
+    pub fn draw<F, I, C, N, P, D, S>(&self,
+                                     before_future: F,
+                                     gfx_queue: Arc<Queue>,
+                                     target_image: Arc<I>,
+                                     color_input: C,
+                                     normals_input: N,
+                                     positions_input: P,
+                                     depth_input: D,
+                                     shadow_map: S,
+                                     ambient: Ambient,
+                                     fog: Option<Fog>,
+                                     shadows: Option<Shadows>,
+                                     depth_range: [f32; 2],
+                                     lights: Vec<SecondaryAutoCommandBuffer>,
     ) -> Box<dyn GpuFuture>
         where
             F: GpuFuture + 'static,
             C: ImageViewAbstract + Send + Sync + 'static,
+            N: ImageViewAbstract + Send + Sync + 'static,
+            P: ImageViewAbstract + Send + Sync + 'static,
+            D: ImageViewAbstract + Send + Sync + 'static,
+            S: ImageViewAbstract + Send + Sync + 'static,
             I: ImageViewAbstract + Send + Sync + 'static
     {
         let framebuffer = Arc::new(
@@ -120,14 +243,59 @@ impl LightingPass {
                 .unwrap()
         );
 
-        let push_constants = fs::ty::PushConstants {
-            color: [ambient_color[0], ambient_color[1], ambient_color[2], 1.0],
+        let push_constants = push_constants(ambient, fog, depth_range);
+
+        let mut shadow_data = fs::ty::ShadowData {
+            light_view_proj: [Matrix4::identity().into(); MAX_CASCADES],
+            splits: [[0.0; 4]; MAX_CASCADES],
+            cascades: 0,
+            pcf_radius: 0,
+            depth_bias: 0.0,
+            normal_offset: 0.0,
         };
+        if let Some(shadows) = shadows {
+            assert!(shadows.cascades.len() <= MAX_CASCADES, "too many shadow cascades");
+
+            for (idx, cascade) in shadows.cascades.iter().enumerate() {
+                shadow_data.light_view_proj[idx] = cascade.light_view_proj.into();
+                shadow_data.splits[idx][0] = cascade.split_far;
+            }
+            shadow_data.cascades = shadows.cascades.len() as i32;
+            shadow_data.pcf_radius = shadows.pcf_radius as i32;
+            shadow_data.depth_bias = shadows.depth_bias;
+            shadow_data.normal_offset = shadows.normal_offset;
+        }
+        let shadow_subbuffer = self.shadow_buffer.next(shadow_data).unwrap();
+
+        let mut zone_data = fs::ty::ZoneData {
+            box_min: [[0.0; 4]; MAX_AMBIENT_ZONES],
+            box_max: [[0.0; 4]; MAX_AMBIENT_ZONES],
+            colors: [[0.0; 4]; MAX_AMBIENT_ZONES],
+            count: self.ambient_zones.len() as i32,
+        };
+        for (idx, zone) in self.ambient_zones.iter().enumerate() {
+            zone_data.box_min[idx] = [zone.min[0], zone.min[1], zone.min[2], zone.falloff];
+            zone_data.box_max[idx] = [zone.max[0], zone.max[1], zone.max[2], 0.0];
+            zone_data.colors[idx] = [zone.color[0], zone.color[1], zone.color[2], 1.0];
+        }
+        let zone_subbuffer = self.zone_buffer.next(zone_data).unwrap();
 
         let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
         let descriptor_set = PersistentDescriptorSet::start(layout.clone())
             .add_sampled_image(color_input, self.sampler.clone())
             .unwrap()
+            .add_sampled_image(depth_input, self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(normals_input, self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(positions_input, self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(shadow_map, self.shadow_sampler.clone())
+            .unwrap()
+            .add_buffer(shadow_subbuffer)
+            .unwrap()
+            .add_buffer(zone_subbuffer)
+            .unwrap()
             .build()
             .unwrap();
 
@@ -142,22 +310,16 @@ impl LightingPass {
             ..DynamicState::none()
         };
 
-        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+        let mut ambient_builder = AutoCommandBufferBuilder::secondary_graphics(
             self.gfx_queue.device().clone(),
             self.gfx_queue.family(),
             CommandBufferUsage::OneTimeSubmit,
+            self.subpass(),
         ).unwrap();
 
-        command_buffer_builder
-            .begin_render_pass(
-                framebuffer,
-                SubpassContents::Inline,
-                vec![
-                    [0.0, 0.0, 0.0, 0.0].into(),
-                ],
-            ).unwrap();
-
-        command_buffer_builder
+        // A single fullscreen triangle
+        self.counters.draw(1, 1);
+        ambient_builder
             .draw(
                 self.pipeline.clone(),
                 &dynamic_state,
@@ -168,6 +330,28 @@ impl LightingPass {
             )
             .unwrap();
 
+        let ambient_cb = ambient_builder.build().unwrap();
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        command_buffer_builder
+            .begin_render_pass(
+                framebuffer,
+                SubpassContents::SecondaryCommandBuffers,
+                vec![
+                    [0.0, 0.0, 0.0, 0.0].into(),
+                ],
+            ).unwrap();
+
+        for light_cb in lights {
+            command_buffer_builder.execute_commands(light_cb).unwrap();
+        }
+        command_buffer_builder.execute_commands(ambient_cb).unwrap();
+
         command_buffer_builder.end_render_pass().unwrap();
 
         let cmd_buf = command_buffer_builder.build().unwrap();
@@ -176,6 +360,20 @@ impl LightingPass {
     }
 }
 
+// No `fog` disables it with a zero density
+fn push_constants(ambient: Ambient, fog: Option<Fog>, depth_range: [f32; 2]) -> fs::ty::PushConstants {
+    let fog = fog.unwrap_or(Fog { density: 0.0, color: [0.0, 0.0, 0.0] });
+
+    fs::ty::PushConstants {
+        color: [ambient.sky[0], ambient.sky[1], ambient.sky[2], 1.0],
+        ground_color: [ambient.ground[0], ambient.ground[1], ambient.ground[2], 1.0],
+        fog_color: [fog.color[0], fog.color[1], fog.color[2], 1.0],
+        fog_density: fog.density,
+        near: depth_range[0],
+        far: depth_range[1],
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 struct Vertex {
     position: [f32; 2],
@@ -195,3 +393,33 @@ mod fs {
         bytes: "resources/shaders/deferred_lighting.frag.spv"
     }
 }
+
+mod fs_single_sample {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/deferred_lighting.frag.single_sample.spv"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::key_bindings::KeyBindings;
+    use crate::camera::Camera;
+
+    use super::{push_constants, Ambient, Fog};
+
+    #[test]
+    fn push_constants_take_the_camera_depth_range() {
+        let mut camera = Camera::new(KeyBindings::default());
+        camera.set_viewport(800, 600);
+        let ambient = Ambient { sky: [0.2, 0.3, 0.4], ground: [0.1, 0.1, 0.1] };
+        let fog = Fog { density: 0.05, color: [0.5, 0.6, 0.7] };
+
+        let constants = push_constants(ambient, Some(fog), camera.depth_range());
+
+        assert_eq!([constants.near, constants.far], camera.depth_range());
+        assert_eq!(constants.fog_density, 0.05);
+        assert_eq!(constants.color, [0.2, 0.3, 0.4, 1.0]);
+        assert_eq!(push_constants(ambient, None, camera.depth_range()).fog_density, 0.0);
+    }
+}
@@ -0,0 +1,128 @@
+use cgmath::{Matrix4, Vector3};
+use serde::{Deserialize, Serialize};
+use vulkano::command_buffer::SecondaryAutoCommandBuffer;
+
+use super::Framebuffer;
+use super::point_lighting::PointLightingSystem;
+
+// Brightest channel of the attenuated color where the light is considered out of reach, see
+// `radius`
+const LIGHT_CUTOFF: f32 = 0.05;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PointLight {
+    pub position: Vector3<f32>,
+    pub color: [f32; 3],
+    pub enabled: bool,
+}
+
+impl PointLight {
+    pub fn new(position: Vector3<f32>, color: [f32; 3]) -> PointLight {
+        PointLight { position, color, enabled: true }
+    }
+
+    // Distance where the brightest channel of `color`, attenuated by the falloff of
+    // point_lighting.frag (1 / exp(distance)), drops below `LIGHT_CUTOFF`. Brighter lights reach
+    // further, the lighting itself has no hard limit.
+    pub fn radius(&self) -> f32 {
+        let intensity = self.color[0].max(self.color[1]).max(self.color[2]);
+        (intensity / LIGHT_CUTOFF).ln().max(0.0)
+    }
+}
+
+// Owns the scene lights and records their contribution to the lighting subpass.
+pub struct LightManager {
+    point_system: PointLightingSystem,
+    point_lights: Vec<PointLight>,
+}
+
+#[allow(dead_code)]
+impl LightManager {
+    pub fn new(point_system: PointLightingSystem) -> LightManager {
+        LightManager {
+            point_system,
+            point_lights: vec![],
+        }
+    }
+
+    // Replaces the system the lights are drawn with, e.g. for a new gbuffer sample count
+    pub fn set_point_system(&mut self, point_system: PointLightingSystem) {
+        self.point_system = point_system;
+    }
+
+    pub fn add(&mut self, light: PointLight) -> usize {
+        self.point_lights.push(light);
+        self.point_lights.len() - 1
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Option<PointLight> {
+        if idx < self.point_lights.len() {
+            Some(self.point_lights.remove(idx))
+        } else {
+            None
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.point_lights.clear();
+    }
+
+    pub fn toggle(&mut self, idx: usize) {
+        if let Some(light) = self.point_lights.get_mut(idx) {
+            light.enabled = !light.enabled;
+        }
+    }
+
+    pub fn set_enabled(&mut self, idx: usize, enabled: bool) {
+        if let Some(light) = self.point_lights.get_mut(idx) {
+            light.enabled = enabled;
+        }
+    }
+
+    pub fn lights(&self) -> &[PointLight] {
+        &self.point_lights
+    }
+
+    pub fn lights_mut(&mut self) -> &mut [PointLight] {
+        &mut self.point_lights
+    }
+
+    // One secondary command buffer per enabled light, for the subpass the point system was
+    // created with. `gbuffer` views are expected as albedo, normals, positions, depth.
+    pub fn draw_all(&self, viewport_dimensions: [u32; 2], gbuffer: &Framebuffer,
+                    screen_to_world: Matrix4<f32>) -> Vec<SecondaryAutoCommandBuffer>
+    {
+        self.point_lights.iter()
+            .filter(|light| light.enabled)
+            .map(|light| {
+                self.point_system.draw(
+                    viewport_dimensions,
+                    gbuffer.view(0),
+                    gbuffer.view(1),
+                    gbuffer.view(3),
+                    screen_to_world,
+                    light.position,
+                    light.color,
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::vec3;
+
+    use super::{LIGHT_CUTOFF, PointLight};
+
+    #[test]
+    fn radius_scales_with_intensity() {
+        let light = |color| PointLight::new(vec3(0.0, 0.0, 0.0), color);
+        let white = light([1.0, 1.0, 1.0]).radius();
+
+        assert!(((-white).exp() - LIGHT_CUTOFF).abs() < 1e-6);
+        assert!((light([0.2, 1.0, 0.5]).radius() - white).abs() < 1e-6);
+        assert!((light([4.0, 4.0, 4.0]).radius() - white - 4.0f32.ln()).abs() < 1e-5);
+        assert_eq!(light([0.01, 0.0, 0.0]).radius(), 0.0);
+    }
+}
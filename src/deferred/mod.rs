@@ -3,13 +3,30 @@ use std::sync::Arc;
 use vulkano::{device, render_pass, sync};
 use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, PrimaryAutoCommandBuffer, SubpassContents};
 use vulkano::device::{Queue, Device};
-use vulkano::format::{Format, FormatTy};
-use vulkano::image::{AttachmentImage, ImageLayout, ImageViewAbstract, SampleCount};
+use vulkano::format::{ClearValue, Format, FormatTy};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::image::{AttachmentImage, ImageAccess, ImageCreationError, ImageLayout, ImageUsage, ImageViewAbstract, SampleCount};
 use vulkano::image::view::ImageView;
 use vulkano::render_pass::{AttachmentDesc, AttachmentsList, FramebufferAbstract, FramebufferSys, LoadOp, StoreOp};
+use vulkano::sampler::Filter;
 use vulkano::sync::GpuFuture;
 
+use crate::base::attachment_pool::AttachmentPool;
+
+pub mod bloom_pass;
+pub mod color_lut;
+pub mod decal_pass;
+pub mod downsampler;
+pub mod eye_adaptation;
+pub mod fxaa_pass;
+pub mod light_color;
 pub mod lighting_pass;
+pub mod lights;
+pub mod point_lighting;
+pub mod shadow_map;
+pub mod ssr_pass;
+pub mod tone_map_pass;
+pub mod transparent_pass;
 
 
 struct FbWrapper {
@@ -21,7 +38,7 @@ unsafe impl Send for FbWrapper {}
 unsafe impl Sync for FbWrapper {}
 
 unsafe impl FramebufferAbstract for FbWrapper {
-    fn inner(&self) -> FramebufferSys {
+    fn inner(&self) -> FramebufferSys<'_> {
         self.inner.inner()
     }
 
@@ -53,22 +70,46 @@ pub struct RenderTargetDesc {
 #[allow(dead_code)]
 pub struct Framebuffer {
     gfx_queue: Arc<Queue>,
+    // Images of the previous size are handed back to it on resize
+    pool: AttachmentPool,
     descriptions: Vec<RenderTargetDesc>,
 
     views: Vec<Arc<ImageView<Arc<AttachmentImage>>>>,
+    // Single-sampled copies of the color targets, filled at the end of the subpass.
+    // Indexed like `views`, `None` for the depth target.
+    resolve: bool,
+    resolved_views: Vec<Option<Arc<ImageView<Arc<AttachmentImage>>>>>,
     framebuffer: Option<Arc<dyn render_pass::FramebufferAbstract + Sync + Send>>,
     render_pass: Arc<render_pass::RenderPass>,
 }
 
 #[allow(dead_code)]
 impl Framebuffer {
-    pub fn new(gfx_queue: Arc<Queue>, targets: Vec<RenderTargetDesc>) -> Framebuffer {
+    pub fn new(gfx_queue: Arc<Queue>, pool: AttachmentPool, targets: Vec<RenderTargetDesc>) -> Framebuffer {
+        Self::_new(gfx_queue, pool, targets, false)
+    }
+
+    // Same as `new`, but every color target also gets a single-sampled resolve attachment
+    // (see `resolved_view`). Vulkano can't mark resolve attachments as unused, so it's all
+    // color targets or none. Single-sampled targets have nothing to resolve, they get no
+    // resolve attachments and `resolved_view` is always `None`. Integer targets (e.g. object
+    // ids) resolve to one of their samples instead of an average.
+    pub fn with_resolve(gfx_queue: Arc<Queue>, pool: AttachmentPool, targets: Vec<RenderTargetDesc>) -> Framebuffer {
+        let multisampled = targets.iter()
+            .any(|desc| !is_depth_format(desc.format) && desc.samples_count != SampleCount::Sample1);
+        Self::_new(gfx_queue, pool, targets, multisampled)
+    }
+
+    fn _new(gfx_queue: Arc<Queue>, pool: AttachmentPool, targets: Vec<RenderTargetDesc>, resolve: bool) -> Framebuffer {
         Framebuffer {
             gfx_queue: gfx_queue.clone(),
+            pool,
             descriptions: targets.clone(),
             views: vec![],
+            resolve,
+            resolved_views: vec![],
             framebuffer: None,
-            render_pass: Self::_create_render_pass(gfx_queue.device().clone(), targets),
+            render_pass: Self::_create_render_pass(gfx_queue.device().clone(), targets, resolve),
         }
     }
 
@@ -76,9 +117,16 @@ impl Framebuffer {
         self.views.get(idx).unwrap().clone()
     }
 
+    // Resolved single-sampled copy of the color target `idx`, if the framebuffer was created
+    // `with_resolve`.
+    pub fn resolved_view(&self, idx: usize) -> Option<Arc<ImageView<Arc<AttachmentImage>>>> {
+        self.resolved_views.get(idx).cloned().flatten()
+    }
+
     fn _create_render_pass(
         device: Arc<Device>,
         descriptions: Vec<RenderTargetDesc>,
+        resolve: bool,
     ) -> Arc<render_pass::RenderPass>
     {
         let mut attachments: Vec<AttachmentDesc> = vec![];
@@ -87,13 +135,7 @@ impl Framebuffer {
         let mut depth_attachment_ref: Option<(usize, ImageLayout)> = None;
 
         for (idx, view) in descriptions.iter().enumerate() {
-            let is_depth = match view.format.ty() {
-                FormatTy::Depth => true,
-                FormatTy::DepthStencil => true,
-                FormatTy::Stencil => true,
-                FormatTy::Compressed => panic!(),
-                _ => false,
-            };
+            let is_depth = is_depth_format(view.format);
 
             let final_layout = if is_depth {
                 ImageLayout::DepthStencilAttachmentOptimal
@@ -119,11 +161,29 @@ impl Framebuffer {
             }
         }
 
+        // Resolve attachments follow the targets, one per color attachment
+        let mut resolve_attachments_refs: Vec<(usize, ImageLayout)> = vec![];
+        if resolve {
+            for &(idx, _) in color_attachments_refs.iter() {
+                resolve_attachments_refs.push((attachments.len(), ImageLayout::ColorAttachmentOptimal));
+                attachments.push(AttachmentDesc {
+                    format: descriptions[idx].format,
+                    samples: SampleCount::Sample1,
+                    load: LoadOp::DontCare,
+                    store: StoreOp::Store,
+                    stencil_load: LoadOp::DontCare,
+                    stencil_store: StoreOp::DontCare,
+                    initial_layout: ImageLayout::Undefined,
+                    final_layout: ImageLayout::ColorAttachmentOptimal,
+                });
+            }
+        }
+
         let subpass = render_pass::SubpassDesc {
             color_attachments: color_attachments_refs,
             depth_stencil: depth_attachment_ref,
             input_attachments: vec!(),
-            resolve_attachments: vec!(),
+            resolve_attachments: resolve_attachments_refs,
             preserve_attachments: vec!(),
         };
 
@@ -188,17 +248,45 @@ impl Framebuffer {
         )
     }
 
-    pub fn resize_swapchain(&mut self, dimensions: [u32; 2]) {
-        self.views = self.descriptions.iter().map(|desc| {
-            ImageView::new(
-                AttachmentImage::sampled_multisampled_input_attachment(
-                    self.gfx_queue.device().clone(),
-                    dimensions,
-                    desc.samples_count,
-                    desc.format,
-                ).unwrap()
-            ).unwrap()
-        }).collect();
+    // Fails if the targets can't be allocated, e.g. when out of device memory. The framebuffer
+    // has no targets then until a later call succeeds.
+    pub fn resize_swapchain(&mut self, dimensions: [u32; 2]) -> Result<(), ImageCreationError> {
+        // Release the old images first, so the pool can reuse the ones the GPU is done with
+        self.framebuffer = None;
+        self.views.clear();
+        self.resolved_views.clear();
+
+        let views = self.descriptions.iter().map(|desc| {
+            let usage = ImageUsage {
+                sampled: true,
+                input_attachment: true,
+                transfer_source: true, // Needed for `render_and_read`
+                ..ImageUsage::none()
+            };
+
+            Ok(ImageView::new(
+                self.pool.try_image(dimensions, desc.samples_count, desc.format, usage)?
+            ).unwrap())
+        }).collect::<Result<Vec<_>, ImageCreationError>>()?;
+
+        let resolved_views = self.descriptions.iter().map(|desc| {
+            if !self.resolve || is_depth_format(desc.format) {
+                return Ok(None);
+            }
+
+            let usage = ImageUsage {
+                sampled: true,
+                transfer_source: true,
+                ..ImageUsage::none()
+            };
+
+            Ok(Some(ImageView::new(
+                self.pool.try_image(dimensions, SampleCount::Sample1, desc.format, usage)?
+            ).unwrap()))
+        }).collect::<Result<Vec<_>, ImageCreationError>>()?;
+
+        self.views = views;
+        self.resolved_views = resolved_views;
 
         let mut framebuffer_builder = render_pass::Framebuffer::start(
             self.render_pass.clone()
@@ -208,6 +296,10 @@ impl Framebuffer {
             framebuffer_builder = framebuffer_builder.add(view.clone()).unwrap().boxed();
         }
 
+        for view in self.resolved_views.iter().flatten() {
+            framebuffer_builder = framebuffer_builder.add(view.clone()).unwrap().boxed();
+        }
+
         self.framebuffer = Some(
             Arc::new(
                 FbWrapper {
@@ -215,6 +307,7 @@ impl Framebuffer {
                 }
             )
         );
+        Ok(())
     }
 
     pub fn framebuffer(&self) -> Arc<dyn render_pass::FramebufferAbstract + Sync + Send> {
@@ -235,6 +328,147 @@ pub fn render_to_framebuffer<F, Fn>(
     where
         F: GpuFuture + 'static,
         Fn: FnOnce(&mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>)
+{
+    let command_buffer_builder = record_render_pass(gfx_queue.clone(), framebuffer, f);
+    let cmd_buf = command_buffer_builder.build().unwrap();
+
+    Box::new(before_future.then_execute(gfx_queue.clone(), cmd_buf).unwrap())
+}
+
+// Same as `render_to_framebuffer`, but waits for the GPU and returns the raw texels of the view
+// with index `view_idx` (its resolved copy, if there is one). Only single-sampled views can be
+// copied to a buffer.
+#[allow(dead_code)]
+pub fn render_and_read<F, Fn>(
+    before_future: F,
+    gfx_queue: Arc<device::Queue>,
+    framebuffer: &Framebuffer,
+    view_idx: usize,
+    f: Fn,
+) -> Vec<u8>
+    where
+        F: GpuFuture + 'static,
+        Fn: FnOnce(&mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>)
+{
+    let view = match framebuffer.resolved_view(view_idx) {
+        Some(resolved) => resolved,
+        None => framebuffer.view(view_idx),
+    };
+    assert_eq!(view.image().samples(), SampleCount::Sample1, "multisampled views can't be read back");
+
+    let [w, h] = view.image().dimensions().width_height();
+    let texel_size = view.format().size().unwrap();
+
+    let cpu_buffer = CpuAccessibleBuffer::from_iter(
+        gfx_queue.device().clone(),
+        BufferUsage::transfer_destination(),
+        false,
+        (0..(w * h) as usize * texel_size).map(|_| 0u8),
+    ).expect("Failed to create buffer");
+
+    let mut command_buffer_builder = record_render_pass(gfx_queue.clone(), framebuffer, f);
+    command_buffer_builder
+        .copy_image_to_buffer(ImageView::image(&view).clone(), cpu_buffer.clone())
+        .unwrap();
+
+    let cmd_buf = command_buffer_builder.build().unwrap();
+
+    before_future
+        .then_execute(gfx_queue.clone(), cmd_buf).unwrap()
+        .then_signal_fence_and_flush().unwrap()
+        .wait(None).unwrap();
+
+    let content = cpu_buffer.read().unwrap();
+    content.to_vec()
+}
+
+// Downscales `source` on the GPU to fit into `max_size` with its aspect ratio kept (never
+// upscales), then waits and returns the size and the RGBA8 texels. `source` must be
+// single-sampled and have `transfer_source` usage.
+//
+// sRGB sources are blitted to an sRGB image, so the filtering happens on linear values and the
+// returned bytes are sRGB-encoded like the source. Anything else ends up as UNORM, float
+// values are clamped to [0, 1].
+#[allow(dead_code)]
+pub fn read_thumbnail<F, I>(
+    before_future: F,
+    gfx_queue: Arc<device::Queue>,
+    source: Arc<I>,
+    max_size: [u32; 2],
+) -> ([u32; 2], Vec<u8>)
+    where
+        F: GpuFuture + 'static,
+        I: ImageAccess + Send + Sync + 'static
+{
+    let [w, h] = source.dimensions().width_height();
+    let scale = (max_size[0] as f32 / w as f32).min(max_size[1] as f32 / h as f32).min(1.0);
+    let size = [((w as f32 * scale).round() as u32).max(1), ((h as f32 * scale).round() as u32).max(1)];
+
+    let format = match source.format() {
+        Format::B8G8R8A8Srgb | Format::R8G8B8A8Srgb | Format::A8B8G8R8SrgbPack32 => Format::R8G8B8A8Srgb,
+        _ => Format::R8G8B8A8Unorm,
+    };
+    let thumbnail = AttachmentImage::with_usage(
+        gfx_queue.device().clone(),
+        size,
+        format,
+        ImageUsage {
+            transfer_source: true,
+            transfer_destination: true,
+            ..ImageUsage::none()
+        },
+    ).unwrap();
+
+    let cpu_buffer = CpuAccessibleBuffer::from_iter(
+        gfx_queue.device().clone(),
+        BufferUsage::transfer_destination(),
+        false,
+        (0..(size[0] * size[1] * 4) as usize).map(|_| 0u8),
+    ).expect("Failed to create buffer");
+
+    let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+        gfx_queue.device().clone(),
+        gfx_queue.family(),
+        CommandBufferUsage::OneTimeSubmit,
+    ).unwrap();
+
+    command_buffer_builder
+        .blit_image(
+            source,
+            [0, 0, 0],
+            [w as i32, h as i32, 1],
+            0,
+            0,
+            thumbnail.clone(),
+            [0, 0, 0],
+            [size[0] as i32, size[1] as i32, 1],
+            0,
+            0,
+            1,
+            Filter::Linear,
+        )
+        .unwrap()
+        .copy_image_to_buffer(thumbnail, cpu_buffer.clone())
+        .unwrap();
+
+    let cmd_buf = command_buffer_builder.build().unwrap();
+
+    before_future
+        .then_execute(gfx_queue.clone(), cmd_buf).unwrap()
+        .then_signal_fence_and_flush().unwrap()
+        .wait(None).unwrap();
+
+    let content = cpu_buffer.read().unwrap();
+    (size, content.to_vec())
+}
+
+fn record_render_pass<Fn>(
+    gfx_queue: Arc<device::Queue>,
+    framebuffer: &Framebuffer,
+    f: Fn,
+) -> AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>
+    where
+        Fn: FnOnce(&mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>)
 {
     // Start the command buffer builder that will be filled throughout the frame handling.
     let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
@@ -246,21 +480,18 @@ pub fn render_to_framebuffer<F, Fn>(
 
     let mut clear = vec!();
     for view in framebuffer.views.iter() {
-        let is_depth = match view.format().ty() {
-            FormatTy::Depth => true,
-            FormatTy::DepthStencil => true,
-            FormatTy::Stencil => true,
-            FormatTy::Compressed => panic!(),
-            _ => false,
-        };
-
-        if is_depth {
-            clear.push(1.0f32.into());
-        } else {
-            clear.push([0.0, 0.0, 0.0, 0.0].into());
+        match view.format().ty() {
+            FormatTy::Uint => clear.push(ClearValue::Uint([0, 0, 0, 0])),
+            FormatTy::Sint => clear.push(ClearValue::Int([0, 0, 0, 0])),
+            _ if is_depth_format(view.format()) => clear.push(1.0f32.into()),
+            _ => clear.push([0.0, 0.0, 0.0, 0.0].into()),
         }
     }
 
+    for _ in framebuffer.resolved_views.iter().flatten() {
+        clear.push(ClearValue::None);
+    }
+
     command_buffer_builder
         .begin_render_pass(
             framebuffer.framebuffer().clone(),
@@ -272,7 +503,15 @@ pub fn render_to_framebuffer<F, Fn>(
     f(&mut command_buffer_builder);
     command_buffer_builder.end_render_pass().unwrap();
 
-    let cmd_buf = command_buffer_builder.build().unwrap();
+    command_buffer_builder
+}
 
-    Box::new(before_future.then_execute(gfx_queue.clone(), cmd_buf).unwrap())
+fn is_depth_format(format: Format) -> bool {
+    match format.ty() {
+        FormatTy::Depth => true,
+        FormatTy::DepthStencil => true,
+        FormatTy::Stencil => true,
+        FormatTy::Compressed => panic!(),
+        _ => false,
+    }
 }
@@ -0,0 +1,218 @@
+use std::sync::Arc;
+
+use cgmath::{Matrix4, Vector3};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, SecondaryAutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::image;
+use vulkano::image::ImageViewAbstract;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendFactor, BlendOp};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::render_pass::Subpass;
+use vulkano::sampler;
+
+use crate::base::render_stats::RenderCounters;
+
+// How the contribution of each light is combined with what is already in the target.
+#[allow(dead_code)]
+#[derive(Clone, Default)]
+pub enum LightBlend {
+    // dst + src, alpha keeps the max
+    #[default]
+    Additive,
+    // src + dst * (1 - src.a)
+    Premultiplied,
+    // src * src.a + dst * (1 - src.a)
+    Alpha,
+    Custom(AttachmentBlend),
+}
+
+impl LightBlend {
+    pub fn attachment_blend(&self) -> AttachmentBlend {
+        let base = AttachmentBlend {
+            enabled: true,
+            color_op: BlendOp::Add,
+            color_source: BlendFactor::One,
+            color_destination: BlendFactor::One,
+            alpha_op: BlendOp::Max,
+            alpha_source: BlendFactor::One,
+            alpha_destination: BlendFactor::One,
+            mask_red: true,
+            mask_green: true,
+            mask_blue: true,
+            mask_alpha: true,
+        };
+
+        match self {
+            LightBlend::Additive => base,
+            LightBlend::Premultiplied => AttachmentBlend {
+                color_destination: BlendFactor::OneMinusSrcAlpha,
+                alpha_op: BlendOp::Add,
+                alpha_destination: BlendFactor::OneMinusSrcAlpha,
+                ..base
+            },
+            LightBlend::Alpha => AttachmentBlend::alpha_blending(),
+            LightBlend::Custom(blend) => blend.clone(),
+        }
+    }
+}
+
+// Adds the light of a single point light to the lit image, reading the G-buffer.
+pub struct PointLightingSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    sampler: Arc<sampler::Sampler>,
+    counters: RenderCounters,
+}
+
+impl PointLightingSystem {
+    pub fn new(gfx_queue: Arc<Queue>, subpass: Subpass, input_samples: image::SampleCount,
+               blend: LightBlend, counters: RenderCounters) -> PointLightingSystem
+    {
+        let vertex_buffer = {
+            CpuAccessibleBuffer::from_iter(gfx_queue.device().clone(), BufferUsage::all(), false, [
+                Vertex { position: [-1.0, -1.0] },
+                Vertex { position: [-1.0, 3.0] },
+                Vertex { position: [3.0, -1.0] }
+            ].iter().cloned()).expect("failed to create buffer")
+        };
+
+        let pipeline = {
+            let vs = vs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+            let builder = GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .blend_collective(blend.attachment_blend())
+                .render_pass(subpass);
+
+            // Single-sampled G-buffers need the sampler2D build of the shader
+            if input_samples == image::SampleCount::Sample1 {
+                let fs = fs_single_sample::Shader::load(gfx_queue.device().clone())
+                    .expect("failed to create shader module");
+
+                Arc::new(builder
+                    .fragment_shader(fs.main_entry_point(), fs_single_sample::SpecializationConstants {
+                        NUM_SAMPLES: 1,
+                    })
+                    .build(gfx_queue.device().clone())
+                    .unwrap()) as Arc<_>
+            } else {
+                let fs = fs::Shader::load(gfx_queue.device().clone())
+                    .expect("failed to create shader module");
+
+                Arc::new(builder
+                    .fragment_shader(fs.main_entry_point(), fs::SpecializationConstants {
+                        NUM_SAMPLES: input_samples as i32,
+                    })
+                    .build(gfx_queue.device().clone())
+                    .unwrap()) as Arc<_>
+            }
+        };
+
+        let sampler = sampler::Sampler::simple_repeat_linear(gfx_queue.device().clone());
+
+        PointLightingSystem {
+            gfx_queue,
+            vertex_buffer,
+            pipeline,
+            sampler,
+            counters,
+        }
+    }
+
+    pub fn draw<C, N, D>(&self,
+                         viewport_dimensions: [u32; 2],
+                         color_input: C,
+                         normals_input: N,
+                         depth_input: D,
+                         screen_to_world: Matrix4<f32>,
+                         position: Vector3<f32>,
+                         color: [f32; 3],
+    ) -> SecondaryAutoCommandBuffer
+        where
+            C: ImageViewAbstract + Send + Sync + 'static,
+            N: ImageViewAbstract + Send + Sync + 'static,
+            D: ImageViewAbstract + Send + Sync + 'static,
+    {
+        let push_constants = fs::ty::PushConstants {
+            screen_to_world: screen_to_world.into(),
+            color: [color[0], color[1], color[2], 1.0],
+            position: position.extend(0.0).into(),
+        };
+
+        let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
+        let descriptor_set = PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(color_input, self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(normals_input, self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(depth_input, self.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [viewport_dimensions[0] as f32,
+                    viewport_dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+            self.pipeline.subpass().clone(),
+        ).unwrap();
+
+        self.counters.draw(1, 1);
+        builder
+            .draw(
+                self.pipeline.clone(),
+                &dynamic_state,
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                push_constants,
+                vec![],
+            )
+            .unwrap();
+
+        builder.build().unwrap()
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+struct Vertex {
+    position: [f32; 2],
+}
+vulkano::impl_vertex!(Vertex, position);
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        bytes: "resources/shaders/deferred_lighting.vert.spv"
+    }
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/point_lighting.frag.spv"
+    }
+}
+
+mod fs_single_sample {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/point_lighting.frag.single_sample.spv"
+    }
+}
@@ -0,0 +1,261 @@
+use std::sync::Arc;
+
+use cgmath::{InnerSpace, Matrix4, Point3, Vector3, Vector4};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, SecondaryAutoCommandBuffer, SubpassContents};
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::image::view::{ImageView, ImageViewType};
+use vulkano::render_pass::{FramebufferAbstract, RenderPass, Subpass};
+use vulkano::sync::GpuFuture;
+
+// Must match `MAX_CASCADES` in common/shadow.glsl
+pub const MAX_CASCADES: usize = 4;
+
+#[derive(Clone, Copy)]
+pub struct Cascade {
+    // See `light_view_proj`
+    pub light_view_proj: Matrix4<f32>,
+    // View-space distance where the next cascade takes over
+    pub split_far: f32,
+}
+
+// Depth of the scene as seen from a directional light. The camera frustum is split into
+// `cascades()` depth ranges, each one rendered into its own layer of an array image, so the
+// resolution is spent where the camera is.
+pub struct CascadedShadowMap {
+    gfx_queue: Arc<Queue>,
+    render_pass: Arc<RenderPass>,
+
+    view: Arc<ImageView<Arc<StorageImage>>>,
+    framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    resolution: u32,
+
+    // 0 splits the frustum uniformly, 1 logarithmically
+    split_lambda: f32,
+    cascades: Vec<Cascade>,
+}
+
+impl CascadedShadowMap {
+    pub fn new(gfx_queue: Arc<Queue>, resolution: u32, cascades: usize) -> CascadedShadowMap {
+        assert!(cascades > 0 && cascades <= MAX_CASCADES, "unsupported number of cascades");
+
+        let render_pass = Arc::new(
+            vulkano::single_pass_renderpass!(
+                gfx_queue.device().clone(),
+                attachments: {
+                    depth: {
+                        load: Clear,
+                        store: Store,
+                        format: Format::D32Sfloat,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                        color: [],
+                        depth_stencil: {depth}
+                    }
+            ).unwrap(),
+        );
+
+        let (view, framebuffers) = create_layers(gfx_queue.clone(), render_pass.clone(), resolution, cascades);
+
+        CascadedShadowMap {
+            gfx_queue,
+            render_pass,
+            view,
+            framebuffers,
+            resolution,
+            split_lambda: 0.75,
+            cascades: vec![],
+        }
+    }
+
+    pub fn subpass(&self) -> Subpass {
+        Subpass::from(self.render_pass.clone(), 0).unwrap()
+    }
+
+    pub fn dimensions(&self) -> [u32; 2] {
+        [self.resolution, self.resolution]
+    }
+
+    // Array view with one layer per cascade
+    pub fn view(&self) -> Arc<ImageView<Arc<StorageImage>>> {
+        self.view.clone()
+    }
+
+    pub fn cascade_count(&self) -> usize {
+        self.framebuffers.len()
+    }
+
+    // Recreates the layers, the render pass (and so pipelines built for `subpass()`) is kept
+    pub fn set_cascade_count(&mut self, cascades: usize) {
+        assert!(cascades > 0 && cascades <= MAX_CASCADES, "unsupported number of cascades");
+        if cascades == self.cascade_count() {
+            return;
+        }
+
+        let (view, framebuffers) = create_layers(self.gfx_queue.clone(), self.render_pass.clone(), self.resolution, cascades);
+        self.view = view;
+        self.framebuffers = framebuffers;
+        self.cascades.clear();
+    }
+
+    #[allow(dead_code)]
+    pub fn split_lambda(&self) -> f32 {
+        self.split_lambda
+    }
+
+    pub fn set_split_lambda(&mut self, split_lambda: f32) {
+        self.split_lambda = split_lambda.max(0.0).min(1.0);
+    }
+
+    // Cascades computed by the last `update`
+    pub fn cascades(&self) -> &[Cascade] {
+        &self.cascades
+    }
+
+    // Splits the camera frustum (`screen_to_world` and `[near, far]` of the camera) and fits a
+    // light matrix around every part.
+    pub fn update(&mut self, direction: Vector3<f32>, screen_to_world: Matrix4<f32>, depth_range: [f32; 2]) {
+        let [near, far] = depth_range;
+        let count = self.cascade_count();
+
+        // Frustum corners on the near and far planes, in world space
+        let unproject = |x: f32, y: f32, z: f32| {
+            let p = screen_to_world * Vector4::new(x, y, z, 1.0);
+            Point3::new(p.x / p.w, p.y / p.w, p.z / p.w)
+        };
+        let ndc_corners = [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)];
+        let near_corners: Vec<Point3<f32>> = ndc_corners.iter().map(|&(x, y)| unproject(x, y, -1.0)).collect();
+        let far_corners: Vec<Point3<f32>> = ndc_corners.iter().map(|&(x, y)| unproject(x, y, 1.0)).collect();
+
+        // View depth is linear along the corner edges
+        let corners_at = |distance: f32| -> Vec<Point3<f32>> {
+            let t = (distance - near) / (far - near);
+            near_corners.iter().zip(far_corners.iter())
+                .map(|(&n, &f)| n + (f - n) * t)
+                .collect()
+        };
+
+        let lambda = self.split_lambda;
+        let mut split_near = near;
+        self.cascades = (0..count).map(|idx| {
+            let p = (idx + 1) as f32 / count as f32;
+            let uniform = near + (far - near) * p;
+            let log = near * (far / near).powf(p);
+            let split_far = log * lambda + uniform * (1.0 - lambda);
+
+            let mut corners = corners_at(split_near);
+            corners.extend(corners_at(split_far));
+
+            let center = corners.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, c| acc + Vector3::new(c.x, c.y, c.z))
+                / corners.len() as f32;
+            let center = Point3::new(center.x, center.y, center.z);
+            let radius = corners.iter().map(|&c| (c - center).magnitude()).fold(0.0, f32::max);
+
+            split_near = split_far;
+
+            Cascade {
+                light_view_proj: light_view_proj(direction, center, radius),
+                split_far,
+            }
+        }).collect();
+    }
+
+    // `f(idx)` returns the casters' secondary buffers for cascade `idx`, recorded for
+    // `subpass()` with `cascades()[idx].light_view_proj`.
+    pub fn render<F, Fn>(&self, before_future: F, mut f: Fn) -> Box<dyn GpuFuture>
+        where
+            F: GpuFuture + 'static,
+            Fn: FnMut(usize) -> Vec<SecondaryAutoCommandBuffer>
+    {
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        for (idx, framebuffer) in self.framebuffers.iter().enumerate() {
+            command_buffer_builder
+                .begin_render_pass(
+                    framebuffer.clone(),
+                    SubpassContents::SecondaryCommandBuffers,
+                    vec![1.0f32.into()],
+                )
+                .unwrap();
+
+            command_buffer_builder.execute_commands_from_vec(f(idx)).unwrap();
+            command_buffer_builder.end_render_pass().unwrap();
+        }
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+        Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap())
+    }
+}
+
+fn create_layers(
+    gfx_queue: Arc<Queue>,
+    render_pass: Arc<RenderPass>,
+    resolution: u32,
+    cascades: usize,
+) -> (Arc<ImageView<Arc<StorageImage>>>, Vec<Arc<dyn FramebufferAbstract + Send + Sync>>)
+{
+    let image = StorageImage::with_usage(
+        gfx_queue.device().clone(),
+        ImageDimensions::Dim2d { width: resolution, height: resolution, array_layers: cascades as u32 },
+        Format::D32Sfloat,
+        ImageUsage {
+            depth_stencil_attachment: true,
+            sampled: true,
+            ..ImageUsage::none()
+        },
+        ImageCreateFlags::none(),
+        Some(gfx_queue.family()),
+    ).unwrap();
+
+    let view = ImageView::start(image.clone())
+        .with_type(ImageViewType::Dim2dArray)
+        .build()
+        .unwrap();
+
+    let framebuffers = (0..cascades as u32).map(|layer| {
+        let layer_view = ImageView::start(image.clone())
+            .with_type(ImageViewType::Dim2d)
+            .with_array_layers(layer..layer + 1)
+            .build()
+            .unwrap();
+
+        Arc::new(
+            vulkano::render_pass::Framebuffer::start(render_pass.clone())
+                .add(layer_view)
+                .unwrap()
+                .build()
+                .unwrap()
+        ) as Arc<dyn FramebufferAbstract + Send + Sync>
+    }).collect();
+
+    (view, framebuffers)
+}
+
+// Orthographic light matrix covering the sphere (`center`, `radius`), for a light travelling
+// along `direction`. Depth is mapped to [0, 1] so it can be compared with the shadow map directly.
+pub fn light_view_proj(direction: Vector3<f32>, center: Point3<f32>, radius: f32) -> Matrix4<f32> {
+    let direction = direction.normalize();
+    let up = if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+
+    let eye = center - direction * radius * 2.0;
+    let view = Matrix4::look_at_rh(eye, center, up);
+    // Casters up to `radius` in front of the sphere are kept
+    let proj = cgmath::ortho(-radius, radius, -radius, radius, 0.0, radius * 3.0);
+
+    // OpenGL clip space ([-1, 1] depth) to Vulkan ([0, 1])
+    let clip = Matrix4::new(
+        1.0, 0.0, 0.0, 0.0,
+        0.0, 1.0, 0.0, 0.0,
+        0.0, 0.0, 0.5, 0.0,
+        0.0, 0.0, 0.5, 1.0,
+    );
+
+    clip * proj * view
+}
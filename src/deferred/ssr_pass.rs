@@ -0,0 +1,261 @@
+use std::sync::Arc;
+
+use cgmath::Matrix4;
+use vulkano::{render_pass, sampler};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::image::{AttachmentImage, ImageCreationError, ImageUsage, ImageViewAbstract, SampleCount};
+use vulkano::image::view::ImageView;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::render_pass::{RenderPass, Subpass};
+use vulkano::sync::GpuFuture;
+
+use crate::base::attachment_pool::AttachmentPool;
+
+#[derive(Clone, Copy)]
+pub struct SsrSettings {
+    // Ray-march steps per pixel, 0 disables the reflections
+    pub steps: u32,
+    // How far behind the scene surface a ray still counts as a hit, in world units
+    pub thickness: f32,
+    // Length of the reflected ray, in world units
+    pub max_distance: f32,
+}
+
+// Screen-space reflections. The lit image is rendered into `lit_target` instead of the final
+// image, then every pixel marches along its reflected view ray through the gbuffer positions and
+// blends the lit color at the hit by its reflectivity (alpha of the normals target).
+pub struct SsrPass {
+    gfx_queue: Arc<Queue>,
+    pool: AttachmentPool,
+    output_format: Format,
+
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    sampler: Arc<sampler::Sampler>,
+    ssr_buffer: CpuBufferPool<fs::ty::SsrData>,
+
+    render_pass: Arc<RenderPass>,
+}
+
+impl SsrPass {
+    // `input_samples` is the sample count of the gbuffer targets passed to `draw`
+    pub fn new(gfx_queue: Arc<Queue>, pool: AttachmentPool, output_format: Format,
+               input_samples: SampleCount) -> SsrPass {
+        let render_pass = Arc::new(
+            vulkano::single_pass_renderpass!(
+                gfx_queue.device().clone(),
+                attachments: {
+                    final_color: {
+                        load: DontCare,
+                        store: Store,
+                        format: output_format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                        color: [final_color],
+                        depth_stencil: {}
+                    }
+            ).unwrap(),
+        );
+
+        let vertex_buffer = {
+            CpuAccessibleBuffer::from_iter(gfx_queue.device().clone(), BufferUsage::all(), false, [
+                Vertex { position: [-1.0, -1.0] },
+                Vertex { position: [-1.0, 3.0] },
+                Vertex { position: [3.0, -1.0] }
+            ].iter().cloned()).expect("failed to create buffer")
+        };
+
+        let pipeline = {
+            let vs = vs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+            let builder = GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .render_pass(Subpass::from(render_pass.clone(), 0).unwrap());
+
+            // Single-sampled G-buffers need the sampler2D build of the shader
+            if input_samples == SampleCount::Sample1 {
+                let fs = fs_single_sample::Shader::load(gfx_queue.device().clone())
+                    .expect("failed to create shader module");
+
+                Arc::new(builder
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .build(gfx_queue.device().clone())
+                    .unwrap()) as Arc<_>
+            } else {
+                let fs = fs::Shader::load(gfx_queue.device().clone())
+                    .expect("failed to create shader module");
+
+                Arc::new(builder
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .build(gfx_queue.device().clone())
+                    .unwrap()) as Arc<_>
+            }
+        };
+
+        // Every input is read with texelFetch, the sampler only has to exist
+        let sampler = sampler::Sampler::new(
+            gfx_queue.device().clone(),
+            sampler::Filter::Nearest,
+            sampler::Filter::Nearest,
+            sampler::MipmapMode::Nearest,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        ).unwrap();
+
+        let ssr_buffer = CpuBufferPool::<fs::ty::SsrData>::new(gfx_queue.device().clone(), BufferUsage::all());
+
+        SsrPass {
+            gfx_queue,
+            pool,
+            output_format,
+            vertex_buffer,
+            pipeline,
+            sampler,
+            ssr_buffer,
+            render_pass,
+        }
+    }
+
+    // Image the lighting pass should render into when the reflections are on. Comes from the
+    // attachment pool, so it's cheap to request every frame.
+    pub fn lit_target(&self, dimensions: [u32; 2]) -> Result<Arc<ImageView<Arc<AttachmentImage>>>, ImageCreationError> {
+        Ok(ImageView::new(self.pool.try_image(
+            dimensions,
+            SampleCount::Sample1,
+            self.output_format,
+            ImageUsage { sampled: true, ..ImageUsage::none() },
+        )?).unwrap())
+    }
+
+    // Writes `lit` with the reflections added into `target_image`. `normals_input` and
+    // `positions_input` are the gbuffer targets, `view` and `proj` the camera matrices they were
+    // rendered with.
+    pub fn draw<F, I, L, N, P>(&self,
+                               before_future: F,
+                               target_image: Arc<I>,
+                               lit: L,
+                               normals_input: N,
+                               positions_input: P,
+                               view: Matrix4<f32>,
+                               proj: Matrix4<f32>,
+                               settings: SsrSettings,
+    ) -> Box<dyn GpuFuture>
+        where
+            F: GpuFuture + 'static,
+            I: ImageViewAbstract + Send + Sync + 'static,
+            L: ImageViewAbstract + Send + Sync + 'static,
+            N: ImageViewAbstract + Send + Sync + 'static,
+            P: ImageViewAbstract + Send + Sync + 'static
+    {
+        let framebuffer = Arc::new(
+            render_pass::Framebuffer::start(self.render_pass.clone())
+                .add(target_image.clone())
+                .unwrap()
+                .build()
+                .unwrap()
+        );
+
+        let ssr_subbuffer = self.ssr_buffer.next(fs::ty::SsrData {
+            view: view.into(),
+            proj: proj.into(),
+            steps: settings.steps as i32,
+            thickness: settings.thickness,
+            max_distance: settings.max_distance,
+        }).unwrap();
+
+        let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
+        let descriptor_set = PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(lit, self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(normals_input, self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(positions_input, self.sampler.clone())
+            .unwrap()
+            .add_buffer(ssr_subbuffer)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let viewport_dimensions = target_image.image().dimensions().width_height();
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [viewport_dimensions[0] as f32,
+                    viewport_dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        command_buffer_builder
+            .begin_render_pass(
+                framebuffer,
+                SubpassContents::Inline,
+                vec![vulkano::format::ClearValue::None],
+            )
+            .unwrap()
+            .draw(
+                self.pipeline.clone(),
+                &dynamic_state,
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                (),
+                vec![],
+            )
+            .unwrap()
+            .end_render_pass()
+            .unwrap();
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+
+        Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap())
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+struct Vertex {
+    position: [f32; 2],
+}
+vulkano::impl_vertex!(Vertex, position);
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        bytes: "resources/shaders/ssr/ssr.vert.spv"
+    }
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/ssr/ssr.frag.spv"
+    }
+}
+
+mod fs_single_sample {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/ssr/ssr.frag.single_sample.spv"
+    }
+}
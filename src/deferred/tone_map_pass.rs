@@ -0,0 +1,255 @@
+use std::sync::Arc;
+
+use vulkano::{render_pass, sampler};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::image::{ImageViewAbstract};
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::render_pass::{RenderPass, Subpass};
+use vulkano::sync::GpuFuture;
+
+use crate::base::app::HDR_SWAPCHAIN_FORMAT;
+use crate::base::upload::UploadBatch;
+use super::color_lut;
+use super::color_lut::ColorLut;
+
+// Format of the lit image before tone mapping
+pub const HDR_FORMAT: Format = Format::R16G16B16A16Sfloat;
+
+// Output range on an HDR swapchain, in scRGB units (1.0 is 80 nits). White of the SDR image
+// is shown at 200 nits and highlights go up to 4 times that.
+const HDR_PAPER_WHITE: f32 = 2.5;
+const HDR_PEAK: f32 = 4.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ToneMapOperator {
+    // Values above 1 are clipped, the look of the LDR path
+    Clamp,
+    Reinhard,
+    // Filmic curve fitted to ACES
+    Aces,
+}
+
+#[derive(Clone, Copy)]
+pub struct ToneMapping {
+    pub operator: ToneMapOperator,
+    // Multiplies the HDR color before the curve
+    pub exposure: f32,
+}
+
+// Maps the HDR lit image to the final (LDR) image, then optionally grades it with a 3D LUT
+pub struct ToneMapPass {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    sampler: Arc<sampler::Sampler>,
+    lut_sampler: Arc<sampler::Sampler>,
+
+    lut: Option<ColorLut>,
+    // Bound when there is no `lut`, the shader skips the lookup then
+    placeholder_lut: ColorLut,
+
+    // Top of the curve relative to SDR white and the value white is written as. Both 1 unless
+    // the output is `HDR_SWAPCHAIN_FORMAT`.
+    peak: f32,
+    paper_white: f32,
+
+    render_pass: Arc<RenderPass>,
+}
+
+impl ToneMapPass {
+    pub fn new(gfx_queue: Arc<Queue>, uploads: &mut UploadBatch, output_format: Format) -> ToneMapPass {
+        let render_pass = Arc::new(
+            vulkano::single_pass_renderpass!(
+                gfx_queue.device().clone(),
+                attachments: {
+                    final_color: {
+                        load: DontCare,
+                        store: Store,
+                        format: output_format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                        color: [final_color],
+                        depth_stencil: {}
+                    }
+            ).unwrap(),
+        );
+
+        let vertex_buffer = {
+            CpuAccessibleBuffer::from_iter(gfx_queue.device().clone(), BufferUsage::all(), false, [
+                Vertex { position: [-1.0, -1.0] },
+                Vertex { position: [-1.0, 3.0] },
+                Vertex { position: [3.0, -1.0] }
+            ].iter().cloned()).expect("failed to create buffer")
+        };
+
+        let pipeline = {
+            let vs = vs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+            let fs = fs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+                .build(gfx_queue.device().clone())
+                .unwrap()) as Arc<_>
+        };
+
+        let sampler = sampler::Sampler::simple_repeat_linear_no_mipmap(gfx_queue.device().clone());
+
+        // Trilinear between the LUT entries, the edges are the [0, 1] limits
+        let lut_sampler = sampler::Sampler::new(
+            gfx_queue.device().clone(),
+            sampler::Filter::Linear,
+            sampler::Filter::Linear,
+            sampler::MipmapMode::Nearest,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            sampler::SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        ).unwrap();
+
+        let placeholder_lut = color_lut::identity(uploads, 2);
+
+        let (peak, paper_white) = if output_format == HDR_SWAPCHAIN_FORMAT.0 {
+            (HDR_PEAK, HDR_PAPER_WHITE)
+        } else {
+            (1.0, 1.0)
+        };
+
+        ToneMapPass {
+            gfx_queue,
+            vertex_buffer,
+            pipeline,
+            sampler,
+            lut_sampler,
+            lut: None,
+            placeholder_lut,
+            peak,
+            paper_white,
+            render_pass,
+        }
+    }
+
+    // Color grading applied to the tone-mapped color, `None` leaves it unchanged.
+    // See `color_lut` for loaders.
+    pub fn set_lut(&mut self, lut: Option<ColorLut>) {
+        self.lut = lut;
+    }
+
+    // `hdr_input` must have the dimensions of `target_image`
+    pub fn draw<F, I, H>(&self,
+                         before_future: F,
+                         target_image: Arc<I>,
+                         hdr_input: H,
+                         tone_mapping: ToneMapping,
+    ) -> Box<dyn GpuFuture>
+        where
+            F: GpuFuture + 'static,
+            I: ImageViewAbstract + Send + Sync + 'static,
+            H: ImageViewAbstract + Send + Sync + 'static
+    {
+        let framebuffer = Arc::new(
+            render_pass::Framebuffer::start(self.render_pass.clone())
+                .add(target_image.clone())
+                .unwrap()
+                .build()
+                .unwrap()
+        );
+
+        let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
+        let descriptor_set = PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(hdr_input, self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(self.lut.clone().unwrap_or(self.placeholder_lut.clone()), self.lut_sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let push_constants = fs::ty::PushConstants {
+            exposure: tone_mapping.exposure,
+            tone_operator: match tone_mapping.operator {
+                ToneMapOperator::Clamp => 0,
+                ToneMapOperator::Reinhard => 1,
+                ToneMapOperator::Aces => 2,
+            },
+            use_lut: self.lut.is_some() as i32,
+            peak: self.peak,
+            paper_white: self.paper_white,
+        };
+
+        let viewport_dimensions = target_image.image().dimensions().width_height();
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [viewport_dimensions[0] as f32,
+                    viewport_dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        command_buffer_builder
+            .begin_render_pass(
+                framebuffer,
+                SubpassContents::Inline,
+                vec![vulkano::format::ClearValue::None],
+            )
+            .unwrap()
+            .draw(
+                self.pipeline.clone(),
+                &dynamic_state,
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                push_constants,
+                vec![],
+            )
+            .unwrap()
+            .end_render_pass()
+            .unwrap();
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+
+        Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap())
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+struct Vertex {
+    position: [f32; 2],
+}
+vulkano::impl_vertex!(Vertex, position);
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        bytes: "resources/shaders/post/fullscreen.vert.spv"
+    }
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/post/tone_map.frag.spv"
+    }
+}
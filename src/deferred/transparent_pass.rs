@@ -0,0 +1,741 @@
+use std::sync::Arc;
+
+use vulkano::{render_pass, sampler, sync};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, SecondaryAutoCommandBuffer, SubpassContents};
+use vulkano::descriptor::DescriptorSet;
+use vulkano::descriptor::descriptor_set::{PersistentDescriptorSet, UnsafeDescriptorSetLayout};
+use vulkano::device::Queue;
+use vulkano::format::{ClearValue, Format};
+use vulkano::image::{AttachmentImage, ImageCreationError, ImageLayout, ImageUsage, ImageViewAbstract, SampleCount};
+use vulkano::image::view::ImageView;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendFactor, BlendOp};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::render_pass::{AttachmentDesc, FramebufferAbstract, LoadOp, RenderPass, StoreOp, Subpass};
+use vulkano::sync::GpuFuture;
+
+use crate::base::attachment_pool::AttachmentPool;
+
+// Format of the intermediate transparent layer
+const LAYER_FORMAT: Format = Format::R16G16B16A16Sfloat;
+
+// Inputs of the depth peeling test in common/peel.glsl for one layer, see `TransparentPass::draw`
+pub struct PeelLayer {
+    opaque_depth: Arc<ImageView<Arc<AttachmentImage>>>,
+    previous_depth: Arc<ImageView<Arc<AttachmentImage>>>,
+    sampler: Arc<sampler::Sampler>,
+}
+
+impl PeelLayer {
+    // Set 1 of a pipeline including common/peel.glsl, `layout` is its layout
+    pub fn descriptor_set(&self, layout: &Arc<UnsafeDescriptorSetLayout>) -> Arc<dyn DescriptorSet + Send + Sync> {
+        Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(self.opaque_depth.clone(), self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(self.previous_depth.clone(), self.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap())
+    }
+}
+
+// Forward pass for translucent geometry, drawn on top of the lit image.
+//
+// The gbuffer depth is multisampled while the lit image is not, so they can't be attachments of
+// the same subpass. Transparent draws go into a multisampled layer that shares the gbuffer depth
+// (tested, never written), the layer is resolved and then composited over the lit image. With a
+// single-sampled gbuffer the layer is composited directly.
+//
+// Geometry that overlaps itself can be drawn with depth peeling instead, see `set_peel_layers`.
+pub struct TransparentPass {
+    gfx_queue: Arc<Queue>,
+    pool: AttachmentPool,
+    samples: SampleCount,
+
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    composite_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+
+    render_pass: Arc<RenderPass>,
+
+    peel_layers: u32,
+    // Draws one layer and blends it under the layers before, see `create_peel_render_pass`
+    peel_render_pass: Arc<RenderPass>,
+    under_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    // Composites the peeled layers over the target
+    peel_composite_render_pass: Arc<RenderPass>,
+    peel_composite_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    peel_sampler: Arc<sampler::Sampler>,
+}
+
+impl TransparentPass {
+    // `depth_format` and `samples` must match the gbuffer depth passed to `draw`
+    pub fn new(gfx_queue: Arc<Queue>, pool: AttachmentPool, output_format: Format,
+               depth_format: Format, samples: SampleCount) -> TransparentPass {
+        let render_pass = create_render_pass(gfx_queue.clone(), output_format, depth_format, samples);
+
+        let vertex_buffer = {
+            CpuAccessibleBuffer::from_iter(gfx_queue.device().clone(), BufferUsage::all(), false, [
+                Vertex { position: [-1.0, -1.0] },
+                Vertex { position: [-1.0, 3.0] },
+                Vertex { position: [3.0, -1.0] }
+            ].iter().cloned()).expect("failed to create buffer")
+        };
+
+        // The layer is premultiplied, the alpha of the lit image is kept
+        let over_blend = AttachmentBlend {
+            enabled: true,
+            color_op: BlendOp::Add,
+            color_source: BlendFactor::One,
+            color_destination: BlendFactor::OneMinusSrcAlpha,
+            alpha_op: BlendOp::Add,
+            alpha_source: BlendFactor::Zero,
+            alpha_destination: BlendFactor::One,
+            mask_red: true,
+            mask_green: true,
+            mask_blue: true,
+            mask_alpha: true,
+        };
+        let composite_pipeline = create_composite_pipeline(
+            gfx_queue.clone(), Subpass::from(render_pass.clone(), 1).unwrap(), over_blend.clone());
+
+        let peel_render_pass = create_peel_render_pass(gfx_queue.clone(), depth_format, samples);
+        // Each layer lies behind everything accumulated so far
+        let under_pipeline = create_composite_pipeline(
+            gfx_queue.clone(),
+            Subpass::from(peel_render_pass.clone(), 1).unwrap(),
+            AttachmentBlend {
+                enabled: true,
+                color_op: BlendOp::Add,
+                color_source: BlendFactor::OneMinusDstAlpha,
+                color_destination: BlendFactor::One,
+                alpha_op: BlendOp::Add,
+                alpha_source: BlendFactor::OneMinusDstAlpha,
+                alpha_destination: BlendFactor::One,
+                mask_red: true,
+                mask_green: true,
+                mask_blue: true,
+                mask_alpha: true,
+            },
+        );
+
+        let peel_composite_render_pass = Arc::new(
+            vulkano::ordered_passes_renderpass!(
+                gfx_queue.device().clone(),
+                attachments: {
+                    layers: {
+                        load: Load,
+                        store: DontCare,
+                        format: LAYER_FORMAT,
+                        samples: 1,
+                    },
+                    target: {
+                        load: Load,
+                        store: Store,
+                        format: output_format,
+                        samples: 1,
+                    }
+                },
+                passes: [
+                    {
+                        color: [target],
+                        depth_stencil: {},
+                        input: [layers]
+                    }
+                ]
+            ).unwrap(),
+        );
+        let peel_composite_pipeline = create_composite_pipeline(
+            gfx_queue.clone(), Subpass::from(peel_composite_render_pass.clone(), 0).unwrap(), over_blend);
+
+        // Only read with texelFetch
+        let peel_sampler = sampler::Sampler::simple_repeat_linear_no_mipmap(gfx_queue.device().clone());
+
+        TransparentPass {
+            gfx_queue,
+            pool,
+            samples,
+            vertex_buffer,
+            composite_pipeline,
+            render_pass,
+            peel_layers: 0,
+            peel_render_pass,
+            under_pipeline,
+            peel_composite_render_pass,
+            peel_composite_pipeline,
+            peel_sampler,
+        }
+    }
+
+    pub fn peel_layers(&self) -> u32 {
+        self.peel_layers
+    }
+
+    // Number of layers `draw` peels the `peeled` geometry into, front to back. Fragments behind
+    // the last layer are dropped. 0 disables peeling, the geometry then has to go through
+    // `subpass` like everything else.
+    //
+    // Every layer draws all the peeled geometry again and blends a full-screen layer, so N layers
+    // cost roughly N times the single-layer pass. Multisampled, the peeled pipelines also shade
+    // per sample.
+    pub fn set_peel_layers(&mut self, layers: u32) {
+        self.peel_layers = layers;
+    }
+
+    // Subpass for depth peeled draws. Pipelines should include common/peel.glsl, test depth with
+    // Less and write it, and write premultiplied color without blending.
+    pub fn peel_subpass(&self) -> Subpass {
+        Subpass::from(self.peel_render_pass.clone(), 0).unwrap()
+    }
+
+    // Subpass for the transparent draws. Pipelines should test depth without writing it and
+    // blend with `alpha_blend()`.
+    pub fn subpass(&self) -> Subpass {
+        Subpass::from(self.render_pass.clone(), 0).unwrap()
+    }
+
+    // `transparent` is recorded for `subpass()` and executed in order. `depth_input` is the
+    // gbuffer depth the lit `target_image` was computed from.
+    //
+    // With `peel_layers` above 0, `peeled` records the depth peeled geometry for `peel_subpass()`.
+    // It's called once per layer and composited before `transparent`.
+    pub fn draw<F, I>(&self,
+                      before_future: F,
+                      target_image: Arc<I>,
+                      depth_input: Arc<ImageView<Arc<AttachmentImage>>>,
+                      transparent: Vec<SecondaryAutoCommandBuffer>,
+                      peeled: Option<&dyn Fn(&PeelLayer) -> Vec<SecondaryAutoCommandBuffer>>,
+    ) -> Result<Box<dyn GpuFuture>, ImageCreationError>
+        where
+            F: GpuFuture + 'static,
+            I: ImageViewAbstract + Send + Sync + 'static
+    {
+        let before_future = match peeled {
+            Some(peeled) if self.peel_layers > 0 => {
+                self.draw_peeled(before_future, target_image.clone(), depth_input.clone(), peeled)?
+            }
+            _ => Box::new(before_future) as Box<dyn GpuFuture>,
+        };
+
+        let dimensions = target_image.image().dimensions().width_height();
+
+        let single_sampled = self.samples == SampleCount::Sample1;
+        let layer = ImageView::new(self.pool.try_image(
+            dimensions,
+            self.samples,
+            LAYER_FORMAT,
+            ImageUsage { input_attachment: single_sampled, transient_attachment: true, ..ImageUsage::none() },
+        )?).unwrap();
+
+        // The image the composite subpass reads, see `create_render_pass`
+        let (framebuffer, composite_input) = if single_sampled {
+            let framebuffer = Arc::new(
+                render_pass::Framebuffer::start(self.render_pass.clone())
+                    .add(layer.clone())
+                    .unwrap()
+                    .add(depth_input)
+                    .unwrap()
+                    .add(target_image)
+                    .unwrap()
+                    .build()
+                    .unwrap()
+            ) as Arc<dyn FramebufferAbstract + Send + Sync>;
+            (framebuffer, layer)
+        } else {
+            let resolved_layer = ImageView::new(self.pool.try_image(
+                dimensions,
+                SampleCount::Sample1,
+                LAYER_FORMAT,
+                ImageUsage { input_attachment: true, transient_attachment: true, ..ImageUsage::none() },
+            )?).unwrap();
+
+            let framebuffer = Arc::new(
+                render_pass::Framebuffer::start(self.render_pass.clone())
+                    .add(layer)
+                    .unwrap()
+                    .add(depth_input)
+                    .unwrap()
+                    .add(resolved_layer.clone())
+                    .unwrap()
+                    .add(target_image)
+                    .unwrap()
+                    .build()
+                    .unwrap()
+            ) as Arc<dyn FramebufferAbstract + Send + Sync>;
+            (framebuffer, resolved_layer)
+        };
+
+        let layout = self.composite_pipeline.layout().descriptor_set_layout(0).unwrap();
+        let descriptor_set = PersistentDescriptorSet::start(layout.clone())
+            .add_image(composite_input)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        // Only the layer is cleared
+        let mut clear_values = vec![[0.0, 0.0, 0.0, 0.0].into()];
+        clear_values.resize(if single_sampled { 3 } else { 4 }, ClearValue::None);
+
+        command_buffer_builder
+            .begin_render_pass(
+                framebuffer,
+                SubpassContents::SecondaryCommandBuffers,
+                clear_values,
+            ).unwrap();
+
+        for cb in transparent {
+            command_buffer_builder.execute_commands(cb).unwrap();
+        }
+
+        command_buffer_builder
+            .next_subpass(SubpassContents::Inline)
+            .unwrap()
+            .draw(
+                self.composite_pipeline.clone(),
+                &dynamic_state,
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                (),
+                vec![],
+            )
+            .unwrap();
+
+        command_buffer_builder.end_render_pass().unwrap();
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+
+        Ok(Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap()))
+    }
+
+    fn draw_peeled<F, I>(&self,
+                         before_future: F,
+                         target_image: Arc<I>,
+                         depth_input: Arc<ImageView<Arc<AttachmentImage>>>,
+                         peeled: &dyn Fn(&PeelLayer) -> Vec<SecondaryAutoCommandBuffer>,
+    ) -> Result<Box<dyn GpuFuture>, ImageCreationError>
+        where
+            F: GpuFuture + 'static,
+            I: ImageViewAbstract + Send + Sync + 'static
+    {
+        let dimensions = target_image.image().dimensions().width_height();
+        let single_sampled = self.samples == SampleCount::Sample1;
+
+        let layer = ImageView::new(self.pool.try_image(
+            dimensions,
+            self.samples,
+            LAYER_FORMAT,
+            ImageUsage { input_attachment: single_sampled, transient_attachment: true, ..ImageUsage::none() },
+        )?).unwrap();
+        let resolved_layer = if single_sampled {
+            None
+        } else {
+            Some(ImageView::new(self.pool.try_image(
+                dimensions,
+                SampleCount::Sample1,
+                LAYER_FORMAT,
+                ImageUsage { input_attachment: true, transient_attachment: true, ..ImageUsage::none() },
+            )?).unwrap())
+        };
+        // Every layer so far, premultiplied
+        let accumulated_image = self.pool.try_image(
+            dimensions,
+            SampleCount::Sample1,
+            LAYER_FORMAT,
+            ImageUsage { input_attachment: true, transfer_destination: true, ..ImageUsage::none() },
+        )?;
+        let accumulated = ImageView::new(accumulated_image.clone()).unwrap();
+        // Ping-pong: a layer is drawn into one while the other holds the layer before
+        let depths = (0..2).map(|_| Ok(ImageView::new(self.pool.try_image(
+            dimensions,
+            self.samples,
+            depth_input.image().format(),
+            ImageUsage { sampled: true, ..ImageUsage::none() },
+        )?).unwrap())).collect::<Result<Vec<_>, ImageCreationError>>()?;
+
+        let framebuffer = |depth: &Arc<ImageView<Arc<AttachmentImage>>>| {
+            let builder = render_pass::Framebuffer::start(self.peel_render_pass.clone())
+                .add(layer.clone())
+                .unwrap()
+                .add(depth.clone())
+                .unwrap();
+            match &resolved_layer {
+                None => Arc::new(builder.add(accumulated.clone()).unwrap().build().unwrap())
+                    as Arc<dyn FramebufferAbstract + Send + Sync>,
+                Some(resolved_layer) => Arc::new(builder
+                    .add(resolved_layer.clone())
+                    .unwrap()
+                    .add(accumulated.clone())
+                    .unwrap()
+                    .build()
+                    .unwrap()) as Arc<dyn FramebufferAbstract + Send + Sync>,
+            }
+        };
+        // Layer, depth, the rest is loaded or not used
+        let clear_values = |depth: f32| {
+            let mut clear_values = vec![[0.0, 0.0, 0.0, 0.0].into(), ClearValue::Depth(depth)];
+            clear_values.resize(if single_sampled { 3 } else { 4 }, ClearValue::None);
+            clear_values
+        };
+
+        let layout = self.under_pipeline.layout().descriptor_set_layout(0).unwrap();
+        let under_set = PersistentDescriptorSet::start(layout.clone())
+            .add_image(resolved_layer.clone().unwrap_or(layer.clone()))
+            .unwrap()
+            .build()
+            .unwrap();
+        let under_set = Arc::new(under_set);
+
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        command_buffer_builder
+            .clear_color_image(accumulated_image, ClearValue::Float([0.0, 0.0, 0.0, 0.0]))
+            .unwrap();
+
+        // Nothing is drawn, this only clears the depth the first layer compares against to 0
+        command_buffer_builder
+            .begin_render_pass(framebuffer(&depths[1]), SubpassContents::Inline, clear_values(0.0))
+            .unwrap()
+            .next_subpass(SubpassContents::Inline)
+            .unwrap()
+            .end_render_pass()
+            .unwrap();
+
+        for index in 0..self.peel_layers as usize {
+            let (depth, previous_depth) = (&depths[index % 2], &depths[(index + 1) % 2]);
+            let cbs = peeled(&PeelLayer {
+                opaque_depth: depth_input.clone(),
+                previous_depth: previous_depth.clone(),
+                sampler: self.peel_sampler.clone(),
+            });
+
+            command_buffer_builder
+                .begin_render_pass(framebuffer(depth), SubpassContents::SecondaryCommandBuffers, clear_values(1.0))
+                .unwrap();
+            for cb in cbs {
+                command_buffer_builder.execute_commands(cb).unwrap();
+            }
+            command_buffer_builder
+                .next_subpass(SubpassContents::Inline)
+                .unwrap()
+                .draw(
+                    self.under_pipeline.clone(),
+                    &dynamic_state,
+                    vec![self.vertex_buffer.clone()],
+                    under_set.clone(),
+                    (),
+                    vec![],
+                )
+                .unwrap()
+                .end_render_pass()
+                .unwrap();
+        }
+
+        let composite_framebuffer = Arc::new(
+            render_pass::Framebuffer::start(self.peel_composite_render_pass.clone())
+                .add(accumulated.clone())
+                .unwrap()
+                .add(target_image)
+                .unwrap()
+                .build()
+                .unwrap()
+        );
+        let layout = self.peel_composite_pipeline.layout().descriptor_set_layout(0).unwrap();
+        let composite_set = PersistentDescriptorSet::start(layout.clone())
+            .add_image(accumulated)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        command_buffer_builder
+            .begin_render_pass(composite_framebuffer, SubpassContents::Inline,
+                               vec![ClearValue::None, ClearValue::None])
+            .unwrap()
+            .draw(
+                self.peel_composite_pipeline.clone(),
+                &dynamic_state,
+                vec![self.vertex_buffer.clone()],
+                composite_set,
+                (),
+                vec![],
+            )
+            .unwrap()
+            .end_render_pass()
+            .unwrap();
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+
+        Ok(Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap()))
+    }
+}
+
+// Blending for the transparent draws: straight alpha for color, the layer ends up premultiplied
+pub fn alpha_blend() -> AttachmentBlend {
+    AttachmentBlend {
+        alpha_source: BlendFactor::One,
+        ..AttachmentBlend::alpha_blending()
+    }
+}
+
+// Attachments: 0 layer, 1 depth, 2 resolved layer, 3 target.
+// Subpass 0 draws into the layer, subpass 1 composites the resolved layer over the target.
+// Single-sampled there's nothing to resolve: attachments are 0 layer, 1 depth, 2 target and
+// subpass 1 reads the layer itself.
+fn create_render_pass(gfx_queue: Arc<Queue>, output_format: Format, depth_format: Format,
+                      samples: SampleCount) -> Arc<RenderPass> {
+    let single_sampled = samples == SampleCount::Sample1;
+
+    let mut attachments = vec![
+        AttachmentDesc {
+            format: LAYER_FORMAT,
+            samples,
+            load: LoadOp::Clear,
+            store: StoreOp::DontCare,
+            stencil_load: LoadOp::DontCare,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::Undefined,
+            final_layout: ImageLayout::ColorAttachmentOptimal,
+        },
+        AttachmentDesc {
+            format: depth_format,
+            samples,
+            load: LoadOp::Load,
+            store: StoreOp::Store,
+            stencil_load: LoadOp::DontCare,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::DepthStencilReadOnlyOptimal,
+            final_layout: ImageLayout::DepthStencilReadOnlyOptimal,
+        },
+    ];
+    if !single_sampled {
+        attachments.push(AttachmentDesc {
+            format: LAYER_FORMAT,
+            samples: SampleCount::Sample1,
+            load: LoadOp::DontCare,
+            store: StoreOp::DontCare,
+            stencil_load: LoadOp::DontCare,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::Undefined,
+            final_layout: ImageLayout::ShaderReadOnlyOptimal,
+        });
+    }
+    attachments.push(AttachmentDesc {
+        format: output_format,
+        samples: SampleCount::Sample1,
+        load: LoadOp::Load,
+        store: StoreOp::Store,
+        stencil_load: LoadOp::DontCare,
+        stencil_store: StoreOp::DontCare,
+        initial_layout: ImageLayout::ColorAttachmentOptimal,
+        final_layout: ImageLayout::ColorAttachmentOptimal,
+    });
+
+    let target = attachments.len() - 1;
+    let (resolve_attachments, composite_input) = if single_sampled {
+        (vec![], 0)
+    } else {
+        (vec![(2, ImageLayout::ColorAttachmentOptimal)], 2)
+    };
+
+    let subpasses = vec![
+        render_pass::SubpassDesc {
+            color_attachments: vec![(0, ImageLayout::ColorAttachmentOptimal)],
+            // Read only, the transparent geometry is tested against the opaque scene
+            depth_stencil: Some((1, ImageLayout::DepthStencilReadOnlyOptimal)),
+            input_attachments: vec![],
+            resolve_attachments,
+            preserve_attachments: vec![target],
+        },
+        render_pass::SubpassDesc {
+            color_attachments: vec![(target, ImageLayout::ColorAttachmentOptimal)],
+            depth_stencil: None,
+            input_attachments: vec![(composite_input, ImageLayout::ShaderReadOnlyOptimal)],
+            resolve_attachments: vec![],
+            preserve_attachments: vec![],
+        },
+    ];
+
+    Arc::new(
+        RenderPass::new(
+            gfx_queue.device().clone(),
+            render_pass::RenderPassDesc::new(attachments, subpasses, vec![layer_dependency()]),
+        ).unwrap()
+    )
+}
+
+// Attachments: 0 layer, 1 layer depth, 2 resolved layer, 3 accumulated layers.
+// Subpass 0 draws the nearest fragments not peeled yet into the layer, subpass 1 blends the
+// resolved layer under the accumulated ones. Single-sampled the layer is read directly and the
+// accumulated layers are attachment 2. The layer depth is kept for the next layer to compare
+// against.
+fn create_peel_render_pass(gfx_queue: Arc<Queue>, depth_format: Format, samples: SampleCount) -> Arc<RenderPass> {
+    let single_sampled = samples == SampleCount::Sample1;
+
+    let mut attachments = vec![
+        AttachmentDesc {
+            format: LAYER_FORMAT,
+            samples,
+            load: LoadOp::Clear,
+            store: StoreOp::DontCare,
+            stencil_load: LoadOp::DontCare,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::Undefined,
+            final_layout: ImageLayout::ColorAttachmentOptimal,
+        },
+        AttachmentDesc {
+            format: depth_format,
+            samples,
+            load: LoadOp::Clear,
+            store: StoreOp::Store,
+            stencil_load: LoadOp::DontCare,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::Undefined,
+            final_layout: ImageLayout::DepthStencilAttachmentOptimal,
+        },
+    ];
+    if !single_sampled {
+        attachments.push(AttachmentDesc {
+            format: LAYER_FORMAT,
+            samples: SampleCount::Sample1,
+            load: LoadOp::DontCare,
+            store: StoreOp::DontCare,
+            stencil_load: LoadOp::DontCare,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::Undefined,
+            final_layout: ImageLayout::ShaderReadOnlyOptimal,
+        });
+    }
+    attachments.push(AttachmentDesc {
+        format: LAYER_FORMAT,
+        samples: SampleCount::Sample1,
+        load: LoadOp::Load,
+        store: StoreOp::Store,
+        stencil_load: LoadOp::DontCare,
+        stencil_store: StoreOp::DontCare,
+        initial_layout: ImageLayout::ColorAttachmentOptimal,
+        final_layout: ImageLayout::ColorAttachmentOptimal,
+    });
+
+    let accumulated = attachments.len() - 1;
+    let (resolve_attachments, under_input) = if single_sampled {
+        (vec![], 0)
+    } else {
+        (vec![(2, ImageLayout::ColorAttachmentOptimal)], 2)
+    };
+
+    let subpasses = vec![
+        render_pass::SubpassDesc {
+            color_attachments: vec![(0, ImageLayout::ColorAttachmentOptimal)],
+            depth_stencil: Some((1, ImageLayout::DepthStencilAttachmentOptimal)),
+            input_attachments: vec![],
+            resolve_attachments,
+            preserve_attachments: vec![accumulated],
+        },
+        render_pass::SubpassDesc {
+            color_attachments: vec![(accumulated, ImageLayout::ColorAttachmentOptimal)],
+            depth_stencil: None,
+            input_attachments: vec![(under_input, ImageLayout::ShaderReadOnlyOptimal)],
+            resolve_attachments: vec![],
+            preserve_attachments: vec![],
+        },
+    ];
+
+    Arc::new(
+        RenderPass::new(
+            gfx_queue.device().clone(),
+            render_pass::RenderPassDesc::new(attachments, subpasses, vec![layer_dependency()]),
+        ).unwrap()
+    )
+}
+
+// Subpass 1 reads what subpass 0 drew
+fn layer_dependency() -> render_pass::SubpassDependencyDesc {
+    render_pass::SubpassDependencyDesc {
+        source_subpass: 0,
+        destination_subpass: 1,
+        source_stages: sync::PipelineStages {
+            color_attachment_output: true,
+            ..sync::PipelineStages::none()
+        },
+        destination_stages: sync::PipelineStages {
+            fragment_shader: true,
+            ..sync::PipelineStages::none()
+        },
+        source_access: sync::AccessFlags {
+            color_attachment_write: true,
+            ..sync::AccessFlags::none()
+        },
+        destination_access: sync::AccessFlags {
+            input_attachment_read: true,
+            ..sync::AccessFlags::none()
+        },
+        by_region: true,
+    }
+}
+
+// Full-screen copy of the input attachment with `blend`
+fn create_composite_pipeline(gfx_queue: Arc<Queue>, subpass: Subpass, blend: AttachmentBlend)
+                             -> Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
+    let vs = vs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+    let fs = fs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+
+    Arc::new(GraphicsPipeline::start()
+        .vertex_input_single_buffer::<Vertex>()
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs.main_entry_point(), ())
+        .blend_collective(blend)
+        .render_pass(subpass)
+        .build(gfx_queue.device().clone())
+        .unwrap())
+}
+
+#[derive(Default, Debug, Clone)]
+struct Vertex {
+    position: [f32; 2],
+}
+vulkano::impl_vertex!(Vertex, position);
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        bytes: "resources/shaders/transparent/composite.vert.spv"
+    }
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/transparent/composite.frag.spv"
+    }
+}
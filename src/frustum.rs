@@ -0,0 +1,31 @@
+use cgmath::{Matrix, Matrix4, Vector4};
+
+// View volume of a camera as six planes, for culling on the CPU
+pub struct Frustum {
+    // xyz is the normal pointing inwards, w the distance: inside is `dot(n, p) + w >= 0`
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    // Planes of `proj * view` (Gribb & Hartmann). Takes the cgmath projection as is, so the
+    // near plane is the one of its [-1, 1] depth range.
+    pub fn from_matrix(view_proj: Matrix4<f32>) -> Frustum {
+        let (r0, r1, r2, r3) = (view_proj.row(0), view_proj.row(1), view_proj.row(2), view_proj.row(3));
+
+        Frustum {
+            planes: [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r3 + r2, r3 - r2],
+        }
+    }
+
+    // False only if the box is completely outside. Boxes near the corners of the frustum can
+    // pass although they're not visible.
+    pub fn intersects_aabb(&self, min: [f32; 3], max: [f32; 3]) -> bool {
+        self.planes.iter().all(|plane| {
+            // Corner furthest along the plane normal
+            let x = if plane.x >= 0.0 { max[0] } else { min[0] };
+            let y = if plane.y >= 0.0 { max[1] } else { min[1] };
+            let z = if plane.z >= 0.0 { max[2] } else { min[2] };
+            plane.x * x + plane.y * y + plane.z * z + plane.w >= 0.0
+        })
+    }
+}
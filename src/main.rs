@@ -1,118 +1,746 @@
+// `.max(a).min(b)` is used over `clamp`, which panics on a slider range set inverted in the GUI.
+// The render systems take their pipeline settings as plain arguments and keep vulkano's types
+// spelled out.
+#![allow(clippy::manual_clamp, clippy::too_many_arguments, clippy::type_complexity)]
+
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Instant;
 
-use cgmath::{Matrix4, SquareMatrix};
-use imgui;
+use cgmath::{Matrix4, Point3, SquareMatrix, Vector3};
 use imgui::{Condition, im_str, Window as ImguiWindow};
 use vulkano::{format, sampler};
+use vulkano::command_buffer::SecondaryAutoCommandBuffer;
 use vulkano::device::Queue;
 use vulkano::format::Format;
-use vulkano::image::{ImageViewAbstract, SampleCount};
+use vulkano::image::{AttachmentImage, ImageCreationError, ImageDimensions, ImageUsage, ImageViewAbstract,
+                     ImmutableImage, MipmapsCount, SampleCount};
+use vulkano::image::view::ImageView;
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::pipeline::raster::{CullMode, FrontFace};
 use vulkano::sync::GpuFuture;
-use winit::event::{ElementState, MouseButton, WindowEvent};
+use winit::event::{ElementState, ModifiersState, MouseButton, WindowEvent};
 
-use crate::base::{app, imgui_pass};
+use crate::base::{app, imgui_pass, parallel};
+use crate::base::attachment_pool::AttachmentPool;
+use crate::base::key_bindings::{Action, KeyBindings};
+use crate::base::render_stats::{RenderCounters, RenderStats};
+use crate::base::upload::UploadBatch;
 use crate::camera::Camera;
+use crate::frustum::Frustum;
+use crate::debug_draw::DebugDraw;
 use crate::deferred::{Framebuffer, lighting_pass, render_to_framebuffer, RenderTargetDesc};
-use crate::terrain_game::Map;
-use crate::terrain_render_system::{RenderPipeline, TerrainRenderSystem};
+use crate::deferred::bloom_pass::{Bloom, BloomPass};
+use crate::deferred::color_lut;
+use crate::deferred::color_lut::ColorLut;
+use crate::deferred::decal_pass::DecalPass;
+use crate::deferred::eye_adaptation::{AutoExposure, EyeAdaptation};
+use crate::deferred::fxaa_pass::{AaMode, FxaaPass};
+use crate::deferred::light_color;
+use crate::deferred::lights::{LightManager, PointLight};
+use crate::deferred::point_lighting::{LightBlend, PointLightingSystem};
+use crate::deferred::shadow_map;
+use crate::deferred::shadow_map::{CascadedShadowMap, MAX_CASCADES};
+use crate::deferred::ssr_pass::{SsrPass, SsrSettings};
+use crate::deferred::tone_map_pass::{HDR_FORMAT, ToneMapOperator, ToneMapPass, ToneMapping};
+use crate::deferred::transparent_pass::{PeelLayer, TransparentPass};
+use crate::normal_map::NormalMap;
+use crate::scene::Scene;
+use crate::terrain::{HeightMap, Terrain};
+use crate::terrain_game::{Connectivity, Map};
+use crate::terrain_render_system::{Easing, RenderPipeline, TerrainRenderSystem};
 
 mod terrain;
+mod mesh;
 mod camera;
 mod deferred;
 
 mod terrain_game;
 mod terrain_render_system;
 mod cube;
+mod quad;
+mod primitives;
+mod material;
+mod normal_map;
 mod mouse_picker;
+mod object_id;
+mod occlusion;
+mod debug_draw;
+mod scene;
 mod base;
+mod bench;
+mod frustum;
 
+const SHADOW_MAP_SIZE: u32 = 2048;
+const MINIMAP_SIZE: u32 = 256;
+// Index of the R32Uint object id target in `gbuffer_targets`
+const OBJECT_ID_TARGET: usize = 4;
+const BLOOM_LEVELS: usize = 5;
+// Colors cycled through by lights placed from the keyboard
+const LIGHT_PRESETS: [[f32; 3]; 4] = [[1.0, 0.8, 0.6], [0.6, 0.8, 1.0], [0.8, 1.0, 0.8], [1.0, 0.5, 0.9]];
+// Written with F5, read back with F9
+const SCENE_PATH: &str = "scene.json";
+const GUI_INI_PATH: &str = "imgui.ini";
+// Distance the camera keeps from a block focused with F
+const FOCUS_DISTANCE: f32 = 6.0;
+// Each ring is a full-screen draw, see `DecalPass`
+const MAX_SELECTION_RINGS: usize = 16;
+const TONE_MAP_OPERATORS: [ToneMapOperator; 3] = [ToneMapOperator::Clamp, ToneMapOperator::Reinhard, ToneMapOperator::Aces];
+const HIGHLIGHT_EASINGS: [Easing; 3] = [Easing::Sine, Easing::Triangle, Easing::Pulse];
+// Up axes offered in the settings, see `Camera::set_up_axis`
+const CAMERA_UP_AXES: [[f32; 3]; 3] = [[0.0, 1.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0]];
 
 struct MyApp {
     queue: Arc<Queue>,
 
     camera: Camera,
+    // Shared by the gbuffer, the minimap and the picker
+    attachment_pool: AttachmentPool,
     gbuffer: Framebuffer,
 
     mouse_picker: mouse_picker::Picker,
     terrain_map: Map,
     terrain: TerrainRenderSystem,
+    landscape: Terrain,
+    // Detail bumps of the ground, on `landscape` while `terrain_normal_mapping` is set
+    ground_normal_map: NormalMap,
+    terrain_normal_mapping: bool,
 
     lighting_pass: Option<lighting_pass::LightingPass>,
+    decal_pass: DecalPass,
+    // Stamped on the ground around the selected blocks
+    selection_ring: Arc<ImageView<Arc<ImmutableImage>>>,
+    selection_rings: bool,
+    ssr_pass: SsrPass,
+    bloom_pass: BloomPass,
+    tone_map_pass: ToneMapPass,
+    eye_adaptation: EyeAdaptation,
+    fxaa_pass: FxaaPass,
+    grading_lut: ColorLut,
+    transparent_pass: TransparentPass,
+    debug_draw: DebugDraw,
+    // Boxes around the block under the cursor and the lights
+    debug_draw_enabled: bool,
+    // Spheres of the light radii and boxes around the selected blocks
+    gizmos_enabled: bool,
+    // Boxes of every block, green if the frozen frustum keeps it, see `TerrainRenderSystem::freeze_frustum`
+    culling_boxes: bool,
+    lights: LightManager,
+    // Index into `LIGHT_PRESETS` of the next light placed with L
+    light_preset: usize,
+    // Color of lights placed with L: 0 cycles through `LIGHT_PRESETS`, otherwise one past the
+    // index into `light_color::PRESETS`
+    light_temperature: usize,
+    shadow_map: CascadedShadowMap,
 
-    last_cursor_pos: [u32; 2],
+    // Physical window coordinates of the cursor
+    cursor_window_pos: [f32; 2],
+    // Cursor in frame pixels, `None` when it's outside of the frame (the viewport image, when the
+    // frame is shown in a GUI window)
+    last_cursor_pos: Option<[u32; 2]>,
     cursor_pos_changed: bool,
     last_selected_object_id: Option<u32>,
+    // Pick with `Map::pick_ray` instead of the object id map. No GPU round trip, but scaled and
+    // rotated blocks are picked as unit cubes.
+    cpu_picking: bool,
+    // Read the pick from the object id target of the gbuffer instead of rendering the id map
+    // separately
+    gbuffer_picking: bool,
+
+    gbuffer_textures: Vec<imgui::TextureId>,
+    gbuffer_texture_idx: usize,
+
+    // Top-down view of the map, re-rendered when it changes
+    minimap: Framebuffer,
+    minimap_texture: Option<imgui::TextureId>,
+    minimap_dirty: bool,
+
+    // Show the frame in a GUI window instead of the whole swapchain image, editor style. The
+    // image is (re)allocated on the next `resize_swapchain`.
+    viewport_window: bool,
+    viewport_image: Option<Arc<ImageView<Arc<AttachmentImage>>>>,
+    viewport_texture: Option<imgui::TextureId>,
+    // Min corner and size of the frame in the viewport window, physical pixels. `None` while the
+    // window is collapsed.
+    viewport_rect: Option<[[f32; 2]; 2]>,
+
+    // See `lighting_pass::Ambient`
+    ambient_sky_color: [f32; 3],
+    ambient_ground_color: [f32; 3],
+    // Cooler ambient over one corner of the maze, see `lighting_pass::AmbientZone`
+    ambient_zone_enabled: bool,
+    ambient_zone_color: [f32; 3],
+    occlusion_culling: bool,
+    wireframe: bool,
+    // See `TerrainRenderSystem::set_sample_shading`
+    sample_shading: f32,
+
+    fog_enabled: bool,
+    fog_density: f32,
+    fog_color: [f32; 3],
+
+    shadows_enabled: bool,
+    shadow_cascades: u32,
+    shadow_split_lambda: f32,
+    sun_direction: Vector3<f32>,
+    pcf_radius: u32,
+    shadow_depth_bias: f32,
+    shadow_normal_offset: f32,
+
+    ssr_enabled: bool,
+    ssr_steps: u32,
+    ssr_thickness: f32,
+    ssr_max_distance: f32,
+
+    bloom_enabled: bool,
+    bloom_threshold: f32,
+    bloom_intensity: f32,
+    bloom_radius: f32,
+    // Index into `TONE_MAP_OPERATORS`
+    tone_map_operator: usize,
+    // Off, MSAA with the sample count given to `new` and FXAA
+    aa_modes: [AaMode; 3],
+    // Index into `aa_modes`
+    aa_mode: usize,
+    // What the gbuffer and everything drawing into or reading it is built for. Follows `aa_mode`
+    // on the next `resize_swapchain`.
+    gbuffer_samples: SampleCount,
+    swapchain_format: format::Format,
+    exposure: f32,
+    // Drives `exposure` from the brightness of the image
+    auto_exposure: bool,
+    min_exposure: f32,
+    max_exposure: f32,
+    adaptation_speed: f32,
+    color_grading: bool,
 
-    normal_texture: Option<imgui::TextureId>,
+    // Records the gbuffer command buffers on several threads, see `base::parallel`
+    parallel_recording: bool,
+    // Seconds spent recording them, smoothed over frames
+    record_time: f32,
+    // Read the whole id map back on the next frame, see `Picker::submit_full`
+    coverage_requested: bool,
+    // Distinct blocks in the last full readback and the fraction of the pixels they cover
+    block_coverage: Option<(usize, f32)>,
+    // Shared with every system that records draws, `render_stats` is last frame's total
+    render_counters: RenderCounters,
+    render_stats: RenderStats,
+
+    brush_enabled: bool,
+    brush_dragging: bool,
+    brush_radius: f32,
+    brush_strength: f32,
+    last_frame: Instant,
+    // Length of the fixed simulation steps, as last passed to `update`
+    update_step: f32,
+
+    modifiers: ModifiersState,
+    key_bindings: KeyBindings,
 
     dims: [u32; 2],
 }
 
 impl MyApp {
-    fn new(queue: Arc<Queue>, swapchain_format: format::Format) -> Self {
-        let mouse_picker = mouse_picker::Picker::new(queue.clone());
-
-        let gbuffer = deferred::Framebuffer::new(queue.clone(), vec!(
-            RenderTargetDesc { format: Format::R8G8B8A8Unorm, samples_count: SampleCount::Sample4 },
-            RenderTargetDesc { format: Format::R16G16B16A16Sfloat, samples_count: SampleCount::Sample4 },
-            RenderTargetDesc { format: Format::R16G16B16A16Sfloat, samples_count: SampleCount::Sample4 },
-            RenderTargetDesc { format: Format::D32Sfloat, samples_count: SampleCount::Sample4 },
-        ));
+    // `msaa_samples` is the count of the MSAA anti-aliasing mode, already validated by `run_app`.
+    // `lut_path` replaces the built-in color grading, see `color_lut::from_file`.
+    fn new(queue: Arc<Queue>, transfer_queue: Arc<Queue>, swapchain_format: format::Format,
+           msaa_samples: SampleCount, key_bindings: KeyBindings, lut_path: Option<&str>) -> Self {
+        let attachment_pool = AttachmentPool::new(queue.device().clone());
+        let render_counters = RenderCounters::new();
+        // The depth places the terrain brush
+        let mouse_picker = mouse_picker::Picker::new(queue.clone(), attachment_pool.clone(), true);
+
+        let aa_modes = [AaMode::Off, AaMode::Msaa(msaa_samples), AaMode::Fxaa];
+        let aa_mode = 1;
+        let gbuffer_samples = aa_modes[aa_mode].samples();
+        let gbuffer = deferred::Framebuffer::with_resolve(queue.clone(), attachment_pool.clone(),
+                                                          gbuffer_targets(gbuffer_samples));
+
+        // Same targets as the gbuffer, so the terrain pipelines can draw into it
+        let mut minimap = deferred::Framebuffer::with_resolve(queue.clone(), attachment_pool.clone(),
+                                                              gbuffer_targets(gbuffer_samples));
+        minimap.resize_swapchain([MINIMAP_SIZE, MINIMAP_SIZE]).unwrap();
+
+        let decal_pass = DecalPass::new(queue.clone(), gbuffer_targets(gbuffer_samples)[0].format, gbuffer_samples);
+
+        // Lighting, reflections and bloom work on HDR colors, tone mapping writes the swapchain image
+        let ssr_pass = SsrPass::new(queue.clone(), attachment_pool.clone(), HDR_FORMAT, gbuffer_samples);
+        let bloom_pass = BloomPass::new(queue.clone(), attachment_pool.clone(), HDR_FORMAT, BLOOM_LEVELS);
+        let transparent_pass = TransparentPass::new(
+            queue.clone(),
+            attachment_pool.clone(),
+            swapchain_format,
+            Format::D32Sfloat,
+            gbuffer_samples,
+        );
+        let debug_draw = DebugDraw::new(queue.clone(), transparent_pass.subpass());
+
+        let mut uploads = UploadBatch::new(transfer_queue);
+
+        let tone_map_pass = ToneMapPass::new(queue.clone(), &mut uploads, swapchain_format);
+        let eye_adaptation = EyeAdaptation::new(queue.clone());
+        let fxaa_pass = FxaaPass::new(queue.clone(), attachment_pool.clone(), swapchain_format);
+        let lut = lut_path.and_then(|path| match color_lut::from_file(&mut uploads, path) {
+            Ok(lut) => Some(lut),
+            Err(e) => {
+                println!("Failed to load the color LUT {}: {}", path, e);
+                None
+            }
+        });
+        // Warmer highlights and cooler shadows
+        let grading_lut = lut.unwrap_or_else(|| color_lut::from_fn(&mut uploads, 16, |[r, g, b]| {
+            let luma = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+            [r + 0.08 * luma, g + 0.02 * luma, b - 0.06 * luma + 0.04 * (1.0 - luma)]
+        }));
 
         let terrain = TerrainRenderSystem::new(
             queue.clone(),
+            &mut uploads,
+            gbuffer.subpass(),
+            mouse_picker.subpass(),
+            transparent_pass.subpass(),
+            transparent_pass.peel_subpass(),
+            CullMode::Back,
+            FrontFace::CounterClockwise,
+            Compare::Less,
+            true,
+            render_counters.clone(),
+        );
+
+        let shadow_map = CascadedShadowMap::new(queue.clone(), SHADOW_MAP_SIZE, 3);
+
+        let landscape = Terrain::new(
+            queue.clone(),
+            &mut uploads,
+            HeightMap::from_png(),
             gbuffer.subpass(),
+            shadow_map.subpass(),
             mouse_picker.subpass(),
+            CullMode::Back,
+            FrontFace::CounterClockwise,
+            Compare::Less,
+            true,
+            sampler::SamplerAddressMode::Repeat,
+            sampler::Filter::Linear,
+            sampler::MipmapMode::Nearest,
+            [1.0, 1.0],
+            render_counters.clone(),
         );
 
-        let terrain_map = Map::new(40, 40);
+        // Small round pebbles, a few per ground texture tile
+        let ground_normal_map = normal_map::from_heights(&mut uploads, 128, |u, v| {
+            let (fu, fv) = ((u * 6.0).fract() - 0.5, (v * 6.0).fract() - 0.5);
+            0.02 * (1.0 - (fu * fu + fv * fv) * 4.0).max(0.0).sqrt()
+        });
+
+        let selection_ring = selection_ring(&mut uploads, 64);
+
+        uploads.wait();
+
+        let terrain_map = Map::from_maze(41, 41, 42);
 
         let lighting_pass = Some(deferred::lighting_pass::LightingPass::new(
             queue.clone(),
-            swapchain_format,
-            vulkano::image::SampleCount::Sample4,
+            HDR_FORMAT,
+            gbuffer_samples,
+            render_counters.clone(),
         ));
 
+        let mut lights = LightManager::new(PointLightingSystem::new(
+            queue.clone(),
+            lighting_pass.as_ref().unwrap().subpass(),
+            gbuffer_samples,
+            LightBlend::Additive,
+            render_counters.clone(),
+        ));
+        lights.add(PointLight::new(Vector3::new(10.0, 2.0, 10.0), [1.0, 0.8, 0.6]));
+        lights.add(PointLight::new(Vector3::new(30.0, 2.0, 10.0), [0.6, 0.8, 1.0]));
+        lights.add(PointLight::new(Vector3::new(20.0, 2.0, 30.0), [0.8, 1.0, 0.8]));
+
+        // Overlooking the maze from its near edge, visually up is -y
+        let mut camera = Camera::new(key_bindings.clone());
+        let map_center = Point3::new(terrain_map.w as f32 * 0.5, 0.0, -(terrain_map.h as f32) * 0.5);
+        camera.look_at(Point3::new(map_center.x, -20.0, 10.0), map_center);
+
         MyApp {
-            camera: Camera::new(),
+            camera,
             queue: queue.clone(),
+            attachment_pool,
             gbuffer,
 
             mouse_picker,
 
             terrain,
             terrain_map,
+            landscape,
+            ground_normal_map,
+            terrain_normal_mapping: true,
 
             lighting_pass,
+            decal_pass,
+            selection_ring,
+            selection_rings: true,
+            ssr_pass,
+            bloom_pass,
+            tone_map_pass,
+            eye_adaptation,
+            fxaa_pass,
+            grading_lut,
+            transparent_pass,
+            debug_draw,
+            debug_draw_enabled: false,
+            gizmos_enabled: false,
+            culling_boxes: false,
+            lights,
+            light_preset: 0,
+            light_temperature: 0,
+            shadow_map,
 
-            last_cursor_pos: [0, 0],
+            cursor_window_pos: [0.0, 0.0],
+            last_cursor_pos: None,
             cursor_pos_changed: false,
             last_selected_object_id: None,
+            cpu_picking: false,
+            gbuffer_picking: true,
+
+            gbuffer_textures: vec![],
+            gbuffer_texture_idx: 1,
+
+            minimap,
+            minimap_texture: None,
+            minimap_dirty: true,
+
+            viewport_window: false,
+            viewport_image: None,
+            viewport_texture: None,
+            viewport_rect: None,
+
+            ambient_sky_color: [1.0, 1.0, 1.0],
+            ambient_ground_color: [1.0, 1.0, 1.0],
+            ambient_zone_enabled: false,
+            ambient_zone_color: [0.45, 0.55, 0.9],
+            occlusion_culling: false,
+            wireframe: false,
+            sample_shading: 0.0,
+
+            fog_enabled: false,
+            fog_density: 0.05,
+            fog_color: [0.6, 0.6, 0.7],
 
-            normal_texture: None,
+            shadows_enabled: true,
+            shadow_cascades: 3,
+            shadow_split_lambda: 0.75,
+            sun_direction: Vector3::new(0.4, 1.0, -0.3),
+            pcf_radius: 1,
+            shadow_depth_bias: 0.002,
+            shadow_normal_offset: 0.05,
+            ssr_enabled: false,
+            ssr_steps: 32,
+            ssr_thickness: 0.5,
+            ssr_max_distance: 10.0,
+            bloom_enabled: true,
+            bloom_threshold: 1.0,
+            bloom_intensity: 0.5,
+            bloom_radius: 1.0,
+            tone_map_operator: 0,
+            aa_modes,
+            aa_mode,
+            gbuffer_samples,
+            swapchain_format,
+            exposure: 1.0,
+            auto_exposure: false,
+            min_exposure: 0.25,
+            max_exposure: 4.0,
+            adaptation_speed: 1.5,
+            color_grading: false,
+
+            parallel_recording: true,
+            record_time: 0.0,
+            coverage_requested: false,
+            block_coverage: None,
+            render_counters,
+            render_stats: RenderStats::default(),
+            brush_enabled: false,
+            brush_dragging: false,
+            brush_radius: 1.0,
+            brush_strength: 2.0,
+            last_frame: Instant::now(),
+            update_step: 0.0,
+
+            modifiers: ModifiersState::empty(),
+            key_bindings,
             dims: [0, 0],
         }
     }
-}
 
+    // Rebuilds everything drawing into or reading the gbuffer for `samples` per pixel. The new
+    // gbuffer has no targets until the next `resize_swapchain`.
+    fn set_gbuffer_samples(&mut self, samples: SampleCount) {
+        self.gbuffer = deferred::Framebuffer::with_resolve(self.queue.clone(), self.attachment_pool.clone(),
+                                                           gbuffer_targets(samples));
+        self.minimap = deferred::Framebuffer::with_resolve(self.queue.clone(), self.attachment_pool.clone(),
+                                                           gbuffer_targets(samples));
+        self.minimap.resize_swapchain([MINIMAP_SIZE, MINIMAP_SIZE]).unwrap();
+        self.minimap_dirty = true;
+
+        self.decal_pass = DecalPass::new(self.queue.clone(), gbuffer_targets(samples)[0].format, samples);
+        self.ssr_pass = SsrPass::new(self.queue.clone(), self.attachment_pool.clone(), HDR_FORMAT, samples);
+        let peel_layers = self.transparent_pass.peel_layers();
+        self.transparent_pass = TransparentPass::new(
+            self.queue.clone(),
+            self.attachment_pool.clone(),
+            self.swapchain_format,
+            Format::D32Sfloat,
+            samples,
+        );
+        self.transparent_pass.set_peel_layers(peel_layers);
+        self.debug_draw = DebugDraw::new(self.queue.clone(), self.transparent_pass.subpass());
+        self.terrain.set_subpasses(self.gbuffer.subpass(), self.transparent_pass.subpass(),
+                                   self.transparent_pass.peel_subpass());
+        self.landscape.set_subpass(self.gbuffer.subpass());
+
+        let lighting_pass = lighting_pass::LightingPass::new(
+            self.queue.clone(),
+            HDR_FORMAT,
+            samples,
+            self.render_counters.clone(),
+        );
+        self.lights.set_point_system(PointLightingSystem::new(
+            self.queue.clone(),
+            lighting_pass.subpass(),
+            samples,
+            LightBlend::Additive,
+            self.render_counters.clone(),
+        ));
+        self.lighting_pass = Some(lighting_pass);
+
+        self.gbuffer_samples = samples;
+    }
+
+    // Tone maps `hdr` into `target`, then adds the transparent geometry and the anti-aliasing
+    fn finish_frame<I>(&self, after_future: Box<dyn GpuFuture>, target: Arc<I>,
+                       hdr: Arc<ImageView<Arc<AttachmentImage>>>, tone_mapping: ToneMapping,
+                       transparent_cbs: Vec<SecondaryAutoCommandBuffer>,
+                       peeled: Option<&dyn Fn(&PeelLayer) -> Vec<SecondaryAutoCommandBuffer>>)
+                       -> Result<Box<dyn GpuFuture>, ImageCreationError>
+        where I: ImageViewAbstract + Send + Sync + 'static
+    {
+        let dimensions = target.image().dimensions().width_height();
+
+        if self.aa_modes[self.aa_mode] == AaMode::Fxaa {
+            // The frame is finished in an intermediate image, FXAA writes the target
+            let ldr = self.fxaa_pass.input_target(dimensions)?;
+            let after_future = self.tone_map_pass.draw(after_future, ldr.clone(), hdr, tone_mapping);
+            let after_future = self.transparent_pass.draw(after_future, ldr.clone(), self.gbuffer.view(3),
+                                                          transparent_cbs, peeled)?;
+            Ok(self.fxaa_pass.draw(after_future, target, ldr))
+        } else {
+            let after_future = self.tone_map_pass.draw(after_future, target.clone(), hdr, tone_mapping);
+            self.transparent_pass.draw(after_future, target, self.gbuffer.view(3), transparent_cbs, peeled)
+        }
+    }
+
+    // Rings on the ground around the selected blocks, projected into the gbuffer albedo
+    fn draw_selection_rings(&self, before_future: Box<dyn GpuFuture>) -> Box<dyn GpuFuture> {
+        let selected = self.terrain_map.active_blocks().filter(|block| block.selected).take(MAX_SELECTION_RINGS);
+
+        selected.fold(before_future, |after_future, block| {
+            let offset = self.terrain.block_offset(block);
+            let center = block_center(offset);
+            // Twice the block footprint, a block high around its base
+            let transform = Matrix4::from_translation(Vector3::new(center.x, offset[1], center.z))
+                * Matrix4::from_nonuniform_scale(2.0, 1.0, 2.0);
+
+            self.decal_pass.draw(
+                after_future,
+                self.gbuffer.view(0),
+                self.gbuffer.view(3),
+                self.camera.view_matrix(),
+                self.camera.proj_matrix(),
+                transform,
+                self.selection_ring.clone(),
+            )
+        })
+    }
+
+    fn draw_lighting<F, I>(&self, before_future: F, target: Arc<I>, fog: Option<lighting_pass::Fog>,
+                           shadows: Option<lighting_pass::Shadows>,
+                           light_cbs: Vec<SecondaryAutoCommandBuffer>) -> Box<dyn GpuFuture>
+        where F: GpuFuture + 'static,
+              I: ImageViewAbstract + Send + Sync + 'static
+    {
+        self.lighting_pass.as_ref().unwrap().draw(
+            before_future,
+            self.queue.clone(),
+            target,
+            self.gbuffer.view(0).clone(),
+            self.gbuffer.view(1).clone(),
+            self.gbuffer.view(2).clone(),
+            self.gbuffer.view(3).clone(),
+            self.shadow_map.view(),
+            lighting_pass::Ambient { sky: self.ambient_sky_color, ground: self.ambient_ground_color },
+            fog,
+            shadows,
+            self.camera.depth_range(),
+            light_cbs,
+        )
+    }
+
+    fn apply_scene(&mut self, scene: Scene, map: Map) {
+        self.camera.set_state(&scene.camera);
+
+        self.terrain_map = map;
+        self.terrain_map.changed = true;
+        self.last_selected_object_id = None;
+
+        self.lights.clear();
+        for light in scene.lights {
+            self.lights.add(light);
+        }
+    }
+
+    // The mirrored view of a left-handed camera flips the winding of every triangle on screen
+    fn set_right_handed(&mut self, right_handed: bool) {
+        self.camera.set_right_handed(right_handed);
+        let front_face = if right_handed { FrontFace::CounterClockwise } else { FrontFace::Clockwise };
+        self.terrain.set_front_face(front_face);
+        self.landscape.set_front_face(front_face);
+    }
+
+    // Maps the window cursor into the frame, through the viewport image when it's shown
+    fn update_cursor_pos(&mut self) {
+        let [x, y] = self.cursor_window_pos;
+        let pos = match (self.viewport_image.is_some(), self.viewport_rect) {
+            (false, _) => Some([x, y]),
+            (true, Some([min, size])) if size[0] > 0.0 && size[1] > 0.0 => {
+                let u = (x - min[0]) / size[0];
+                let v = (y - min[1]) / size[1];
+                if (0.0..1.0).contains(&u) && (0.0..1.0).contains(&v) {
+                    Some([u * self.dims[0] as f32, v * self.dims[1] as f32])
+                } else {
+                    None
+                }
+            }
+            (true, _) => None,
+        };
+        let pos = pos.filter(|&[x, y]| x >= 0.0 && y >= 0.0).map(|[x, y]| [x as u32, y as u32]);
+
+        if self.last_cursor_pos != pos {
+            self.last_cursor_pos = pos;
+            self.cursor_pos_changed = true;
+        }
+    }
+
+    fn render_minimap<F>(&mut self, before_future: F) -> Box<dyn GpuFuture>
+        where F: GpuFuture + 'static
+    {
+        // Straight down on the whole map, x to the right
+        let w = self.terrain_map.w as f32;
+        let h = self.terrain_map.h as f32;
+        let center = Point3::new(w / 2.0, 0.0, -h / 2.0);
+        let view_proj = shadow_map::light_view_proj(Vector3::unit_y(), center, w.max(h) / 2.0);
+
+        let cb = self.terrain.render(
+            RenderPipeline::DiffuseNoCulling,
+            &self.terrain_map,
+            [MINIMAP_SIZE, MINIMAP_SIZE],
+            Matrix4::identity(),
+            Matrix4::identity(),
+            view_proj,
+        );
+
+        render_to_framebuffer(
+            before_future,
+            self.queue.clone(),
+            &self.minimap,
+            |cmd_buf| {
+                cmd_buf.execute_commands(cb).unwrap();
+            })
+    }
+}
 
 impl app::App for MyApp {
-    fn resize_swapchain(&mut self, dimensions: [u32; 2], textures: &mut imgui::Textures<imgui_pass::Texture>) {
+    fn resize_swapchain(&mut self, dimensions: [u32; 2], textures: &mut imgui::Textures<imgui_pass::Texture>)
+                        -> Result<(), ImageCreationError> {
+        // The GUI textures hold the old gbuffer images too, drop them before allocating
+        for id in self.gbuffer_textures.drain(..) {
+            textures.remove(id);
+        }
+
+        let samples = self.aa_modes[self.aa_mode].samples();
+        if samples != self.gbuffer_samples {
+            if let Some(id) = self.minimap_texture.take() {
+                textures.remove(id);
+            }
+            self.set_gbuffer_samples(samples);
+        }
+
+        self.gbuffer.resize_swapchain(dimensions)?;
         self.camera.set_viewport(dimensions[0], dimensions[1]);
-        self.gbuffer.resize_swapchain(dimensions);
 
         let sampler = sampler::Sampler::simple_repeat_linear(self.queue.device().clone());
 
-        self.normal_texture = Some(textures.insert((self.gbuffer.view(1).clone(), sampler)));
+        // Resolved copies for color targets, depth is shown through its own MSAA-aware shader
+        for idx in 0..4 {
+            let view = match self.gbuffer.resolved_view(idx) {
+                Some(resolved) => resolved,
+                None => self.gbuffer.view(idx),
+            };
+            self.gbuffer_textures.push(textures.insert((view, sampler.clone())));
+        }
+
+        if self.minimap_texture.is_none() {
+            let view = self.minimap.resolved_view(0).unwrap_or(self.minimap.view(0));
+            self.minimap_texture = Some(textures.insert((view, sampler.clone())));
+        }
+
+        if let Some(id) = self.viewport_texture.take() {
+            textures.remove(id);
+        }
+        self.viewport_image = None;
+        if self.viewport_window {
+            let view = ImageView::new(self.attachment_pool.try_image(
+                dimensions,
+                SampleCount::Sample1,
+                self.swapchain_format,
+                ImageUsage { sampled: true, ..ImageUsage::none() },
+            )?).unwrap();
+            self.viewport_texture = Some(textures.insert((view.clone(), sampler.clone())));
+            self.viewport_image = Some(view);
+        }
+        if dimensions != self.dims {
+            // Nothing asks for the old size again, don't wait for them to age out of the pool
+            self.attachment_pool.purge();
+        }
         self.dims = dimensions;
+        Ok(())
     }
 
-    fn render<F, I>(&mut self, before_future: F, dimensions: [u32; 2], image: Arc<I>) -> Box<dyn GpuFuture>
+    fn needs_resize(&self) -> bool {
+        self.aa_modes[self.aa_mode].samples() != self.gbuffer_samples
+            || self.viewport_window != self.viewport_texture.is_some()
+    }
+
+    fn render<F, I>(&mut self, before_future: F, dimensions: [u32; 2], image: Arc<I>)
+                    -> Result<Box<dyn GpuFuture>, ImageCreationError>
         where F: GpuFuture + 'static,
               I: ImageViewAbstract + Send + Sync + 'static
     {
-        self.terrain_map.update();
-        if self.cursor_pos_changed {
+        // Everything drawn since the last call, the GUI of last frame included
+        self.render_stats = self.render_counters.take();
+
+        let dt = self.last_frame.elapsed().as_secs_f32();
+        self.last_frame = Instant::now();
+        self.camera.update(dt);
+
+        if self.cursor_pos_changed && self.cpu_picking && !self.brush_dragging {
+            let ray = self.last_cursor_pos.and_then(|[x, y]| self.camera.ray_from_screen(x as f32, y as f32));
+            let entity_id = ray.and_then(|(origin, dir)| self.terrain_map.pick_ray(origin, dir, &self.terrain.grid()));
+            self.terrain_map.highlight(entity_id);
+            self.last_selected_object_id = entity_id;
+            self.cursor_pos_changed = false;
+        } else if (self.cursor_pos_changed && !self.gbuffer_picking) || self.brush_dragging {
+            // The brush needs the depth of the id map, the terrain changes under a still cursor
             let cb = self.terrain.render(
                 RenderPipeline::ObjectIdMap,
                 &self.terrain_map,
@@ -121,38 +749,323 @@ impl app::App for MyApp {
                 self.camera.view_matrix(),
                 self.camera.proj_matrix(),
             );
+            let landscape_cb = self.landscape.draw_object_id(dimensions, self.camera.view_matrix(),
+                                                             self.camera.proj_matrix(), self.mouse_picker.clear_color());
 
-            let entity_id = self.mouse_picker.draw(dimensions, vec![cb], self.last_cursor_pos);
-            self.terrain_map.highlight(entity_id);
+            match self.last_cursor_pos {
+                Some(pos) => {
+                    self.mouse_picker.submit(dimensions, vec![cb, landscape_cb], pos)?;
+                }
+                None => {
+                    self.terrain_map.highlight(None);
+                    self.last_selected_object_id = None;
+                }
+            }
             self.cursor_pos_changed = false;
+        }
 
+        // Picks finish a frame or so after their submission
+        if let Some(entity_id) = self.mouse_picker.poll() {
+            self.terrain_map.highlight(entity_id);
             self.last_selected_object_id = entity_id;
         }
 
-        let cb = self.terrain.render(
-            RenderPipeline::Diffuse,
-            &self.terrain_map,
-            dimensions,
-            Matrix4::identity(),
-            self.camera.view_matrix(),
-            self.camera.proj_matrix(),
-        );
+        if self.coverage_requested {
+            self.coverage_requested = false;
+            let cb = self.terrain.render(
+                RenderPipeline::ObjectIdMap,
+                &self.terrain_map,
+                dimensions,
+                Matrix4::identity(),
+                self.camera.view_matrix(),
+                self.camera.proj_matrix(),
+            );
+            let landscape_cb = self.landscape.draw_object_id(dimensions, self.camera.view_matrix(),
+                                                             self.camera.proj_matrix(), self.mouse_picker.clear_color());
+            self.mouse_picker.submit_full(dimensions, vec![cb, landscape_cb])?;
+        }
+        if let Some(ids) = self.mouse_picker.poll_full() {
+            let blocks: HashSet<u32> = ids.iter().filter_map(|&id| id).collect();
+            let covered = ids.iter().filter(|id| id.is_some()).count();
+            self.block_coverage = Some((blocks.len(), covered as f32 / ids.len().max(1) as f32));
+        }
+
+        if self.brush_dragging {
+            let hit = self.last_cursor_pos.and_then(|[x, y]| {
+                self.mouse_picker.pick_depth().and_then(|depth| self.camera.unproject(x as f32, y as f32, depth))
+            });
+            if let Some(hit) = hit {
+                let delta = if self.modifiers.ctrl() { -self.brush_strength } else { self.brush_strength };
+                self.landscape.apply_brush(hit, self.brush_radius, delta * dt);
+            }
+        }
+
+        let before_future = self.terrain.begin_frame(before_future, &self.terrain_map, self.occlusion_culling);
+        // Brush edits, see `Terrain::apply_brush`
+        let before_future = self.landscape.flush(before_future);
+
+        self.minimap_dirty |= self.terrain_map.changed;
+        self.terrain_map.changed = false;
+        let before_future = if self.minimap_dirty {
+            self.minimap_dirty = false;
+            self.render_minimap(before_future)
+        } else {
+            before_future
+        };
+
+        self.terrain.set_sample_shading(self.sample_shading);
+        let main_pipeline = if self.wireframe { RenderPipeline::Wireframe } else { RenderPipeline::Diffuse };
+        let gbuffer_cbs = {
+            let (view, proj, wireframe) = (self.camera.view_matrix(), self.camera.proj_matrix(), self.wireframe);
+            let (terrain, terrain_map, landscape) = (&mut self.terrain, &self.terrain_map, &self.landscape);
+            let jobs: Vec<Box<dyn FnOnce() -> SecondaryAutoCommandBuffer + Send + '_>> = vec![
+                Box::new(move || terrain.render(main_pipeline, terrain_map, dimensions, Matrix4::identity(), view, proj)),
+                Box::new(move || landscape.draw(dimensions, Matrix4::identity(), view, proj, wireframe)),
+            ];
+
+            let record_start = Instant::now();
+            let cbs = if self.parallel_recording { parallel::record_parallel(jobs) } else { parallel::record_serial(jobs) };
+            // Smoothed, a single frame is too noisy to compare the two
+            self.record_time = self.record_time * 0.95 + record_start.elapsed().as_secs_f32() * 0.05;
+            cbs
+        };
+
+        let screen_to_world = self.camera.screen_to_world();
+
+        let shadows = match screen_to_world {
+            Some(screen_to_world) if self.shadows_enabled => {
+                self.shadow_map.set_cascade_count(self.shadow_cascades as usize);
+                self.shadow_map.set_split_lambda(self.shadow_split_lambda);
+                self.shadow_map.update(self.sun_direction, screen_to_world, self.camera.depth_range());
+
+                Some(lighting_pass::Shadows {
+                    cascades: self.shadow_map.cascades().to_vec(),
+                    pcf_radius: self.pcf_radius,
+                    depth_bias: self.shadow_depth_bias,
+                    normal_offset: self.shadow_normal_offset,
+                })
+            }
+            _ => None,
+        };
+
+        let before_future = match &shadows {
+            Some(shadows) => {
+                let landscape = &self.landscape;
+                let dimensions = self.shadow_map.dimensions();
+                self.shadow_map.render(before_future, |idx| {
+                    vec![landscape.draw_shadow(dimensions, shadows.cascades[idx].light_view_proj)]
+                })
+            }
+            None => before_future,
+        };
+
+        let fog = if self.fog_enabled {
+            Some(lighting_pass::Fog { density: self.fog_density, color: self.fog_color })
+        } else {
+            None
+        };
 
         let after_future = render_to_framebuffer(
             before_future,
             self.queue.clone(),
             &self.gbuffer,
             |cmd_buf| {
-                cmd_buf.execute_commands(cb).unwrap();
+                for cb in gbuffer_cbs {
+                    cmd_buf.execute_commands(cb).unwrap();
+                }
             });
 
-        self.lighting_pass.as_ref().unwrap().draw(
-            after_future,
-            self.queue.clone(),
-            image,
-            self.gbuffer.view(0).clone(),
-            [1.0, 1.0, 1.0],
-        )
+        let after_future = if self.selection_rings {
+            self.draw_selection_rings(after_future)
+        } else {
+            after_future
+        };
+
+        let after_future = match self.last_cursor_pos {
+            Some(pos) if self.cursor_pos_changed && self.gbuffer_picking => {
+                self.cursor_pos_changed = false;
+                let object_ids = self.gbuffer.resolved_view(OBJECT_ID_TARGET)
+                    .unwrap_or(self.gbuffer.view(OBJECT_ID_TARGET));
+                self.mouse_picker.submit_from_image(after_future, ImageView::image(&object_ids).clone(), pos)
+            }
+            None if self.cursor_pos_changed && self.gbuffer_picking => {
+                self.cursor_pos_changed = false;
+                self.terrain_map.highlight(None);
+                self.last_selected_object_id = None;
+                after_future
+            }
+            _ => after_future,
+        };
+
+        let light_cbs = match screen_to_world {
+            Some(screen_to_world) => self.lights.draw_all(dimensions, &self.gbuffer, screen_to_world),
+            None => vec![],
+        };
+
+        let hdr = ImageView::new(self.attachment_pool.image(
+            dimensions,
+            SampleCount::Sample1,
+            HDR_FORMAT,
+            ImageUsage { sampled: true, ..ImageUsage::none() },
+        )).unwrap();
+
+        let zones = if self.ambient_zone_enabled {
+            vec![lighting_pass::AmbientZone {
+                min: [0.0, -10.0, -20.0],
+                max: [20.0, 10.0, 0.0],
+                color: self.ambient_zone_color,
+                falloff: 3.0,
+            }]
+        } else {
+            vec![]
+        };
+        self.lighting_pass.as_mut().unwrap().set_ambient_zones(zones);
+
+        let after_future = if self.ssr_enabled {
+            let lit = self.ssr_pass.lit_target(dimensions)?;
+            let after_future = self.draw_lighting(after_future, lit.clone(), fog, shadows, light_cbs);
+
+            self.ssr_pass.draw(
+                after_future,
+                hdr.clone(),
+                lit,
+                self.gbuffer.view(1).clone(),
+                self.gbuffer.view(2).clone(),
+                self.camera.view_matrix(),
+                self.camera.proj_matrix(),
+                SsrSettings {
+                    steps: self.ssr_steps,
+                    thickness: self.ssr_thickness,
+                    max_distance: self.ssr_max_distance,
+                },
+            )
+        } else {
+            self.draw_lighting(after_future, hdr.clone(), fog, shadows, light_cbs)
+        };
+
+        let after_future = if self.bloom_enabled {
+            self.bloom_pass.draw(after_future, hdr.clone(), Bloom {
+                threshold: self.bloom_threshold,
+                intensity: self.bloom_intensity,
+                radius: self.bloom_radius,
+            })?
+        } else {
+            after_future
+        };
+
+        let after_future = if self.auto_exposure {
+            self.exposure = self.eye_adaptation.adapt(self.exposure, dt, AutoExposure {
+                min_exposure: self.min_exposure,
+                max_exposure: self.max_exposure,
+                speed: self.adaptation_speed,
+            });
+            self.eye_adaptation.measure(after_future, hdr.clone())
+        } else {
+            after_future
+        };
+
+        let selection_color = [1.0, 0.85, 0.3, 0.45];
+
+        if self.debug_draw_enabled {
+            if let Some(block) = self.last_selected_object_id.and_then(|id| self.terrain_map.block(id)) {
+                let center = block_center(self.terrain.block_offset(block));
+                self.debug_draw.aabb(center - Vector3::new(0.5, 0.5, 0.5), center + Vector3::new(0.5, 0.5, 0.5),
+                                     [1.0, 1.0, 1.0, 1.0]);
+            }
+            for light in self.lights.lights() {
+                let position = Point3::new(light.position.x, light.position.y, light.position.z);
+                let color = [light.color[0], light.color[1], light.color[2], 1.0];
+                self.debug_draw.aabb(position - Vector3::new(0.2, 0.2, 0.2), position + Vector3::new(0.2, 0.2, 0.2), color);
+            }
+            // Maze solution between the corners the carving starts and ends in
+            let (w, h) = (self.terrain_map.w, self.terrain_map.h);
+            let path = self.terrain_map.find_path([1, 1], [w.saturating_sub(2), h.saturating_sub(2)], Connectivity::Eight);
+            let centers: Vec<Point3<f32>> = path.unwrap_or_default().iter()
+                .filter_map(|&[x, y]| self.terrain_map.block(self.terrain_map.xy_to_id(x, y)))
+                .map(|block| block_center(self.terrain.block_offset(block)))
+                .collect();
+            for segment in centers.windows(2) {
+                self.debug_draw.line(segment[0], segment[1], [0.3, 0.6, 1.0, 1.0]);
+            }
+        }
+        if self.gizmos_enabled {
+            for light in self.lights.lights() {
+                let position = Point3::new(light.position.x, light.position.y, light.position.z);
+                // Disabled lights stay visible, faded
+                let alpha = if light.enabled { 0.8 } else { 0.25 };
+                let color = [light.color[0], light.color[1], light.color[2], alpha];
+                self.debug_draw.sphere(position, light.radius(), color);
+            }
+            let outline_color = [selection_color[0], selection_color[1], selection_color[2], 1.0];
+            for block in self.terrain_map.active_blocks().filter(|block| block.selected) {
+                // A bit larger than the block, so the edges aren't hidden in its faces
+                let center = block_center(self.terrain.block_offset(block));
+                self.debug_draw.aabb(center - Vector3::new(0.52, 0.52, 0.52), center + Vector3::new(0.52, 0.52, 0.52),
+                                     outline_color);
+            }
+        }
+        if let Some(view_proj) = self.terrain.frozen_frustum() {
+            let frustum = Frustum::from_matrix(view_proj);
+            for block in self.terrain_map.active_blocks() {
+                let (min, max) = self.terrain.block_bounds(block);
+                let color = if frustum.intersects_aabb(min, max) { [0.2, 1.0, 0.2, 1.0] } else { [1.0, 0.2, 0.2, 0.6] };
+                self.debug_draw.aabb(Point3::from(min), Point3::from(max), color);
+            }
+            self.debug_draw.frustum(view_proj, [1.0, 1.0, 1.0, 1.0]);
+        }
+        let debug_cb = self.debug_draw.render(dimensions, self.camera.view_matrix(), self.camera.proj_matrix());
+
+        self.tone_map_pass.set_lut(if self.color_grading { Some(self.grading_lut.clone()) } else { None });
+        let tone_mapping = ToneMapping {
+            operator: TONE_MAP_OPERATORS[self.tone_map_operator],
+            exposure: self.exposure,
+        };
+
+        // Peeled, the selection is drawn once per layer and composited before the other transparent draws
+        let peeled_selection = |peel: &PeelLayer| vec![self.terrain.render_selection(
+            &self.terrain_map,
+            dimensions,
+            self.camera.view_matrix(),
+            self.camera.proj_matrix(),
+            selection_color,
+            Some(peel),
+        )];
+        let (transparent_cbs, peeled) = if self.transparent_pass.peel_layers() > 0 {
+            (vec![debug_cb], Some(&peeled_selection as &dyn Fn(&PeelLayer) -> Vec<SecondaryAutoCommandBuffer>))
+        } else {
+            let selection_cb = self.terrain.render_selection(
+                &self.terrain_map,
+                dimensions,
+                self.camera.view_matrix(),
+                self.camera.proj_matrix(),
+                selection_color,
+                None,
+            );
+            (vec![selection_cb, debug_cb], None)
+        };
+        match self.viewport_image.clone() {
+            // The swapchain image only gets the GUI, see `gui_clear_color`
+            Some(viewport_image) => self.finish_frame(after_future, viewport_image, hdr, tone_mapping,
+                                                      transparent_cbs, peeled),
+            None => self.finish_frame(after_future, image, hdr, tone_mapping, transparent_cbs, peeled),
+        }
+    }
+
+    fn gui_clear_color(&self) -> Option<[f32; 4]> {
+        self.viewport_image.as_ref().map(|_| [0.1, 0.1, 0.1, 1.0])
+    }
+
+    fn gui_depth_range(&self) -> [f32; 2] {
+        self.camera.depth_range()
+    }
+
+    fn update(&mut self, dt: f32) {
+        self.terrain_map.update(dt);
+        self.update_step = dt;
+    }
+
+    fn interpolate(&mut self, alpha: f32) {
+        self.terrain.set_update_lag(alpha * self.update_step);
     }
 
     fn handle_event(&mut self, event: &WindowEvent) {
@@ -160,42 +1073,435 @@ impl app::App for MyApp {
 
         match event {
             WindowEvent::CursorMoved { position, .. } => {
-                if self.last_cursor_pos != [position.x as u32, position.y as u32] {
-                    self.last_cursor_pos = [position.x as u32, position.y as u32];
-                    self.cursor_pos_changed = true;
-                }
+                self.cursor_window_pos = [position.x as f32, position.y as f32];
+                self.update_cursor_pos();
             }
             &WindowEvent::MouseInput { state, button, .. } => {
-                if (state == ElementState::Pressed) && (button == MouseButton::Left) {
+                if button == MouseButton::Left && self.brush_enabled {
+                    self.brush_dragging = state == ElementState::Pressed;
+                } else if (state == ElementState::Pressed) && (button == MouseButton::Left) {
                     self.terrain_map.select(self.last_selected_object_id);
                 }
             }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+            }
+            &WindowEvent::KeyboardInput { input, .. } => {
+                let action = self.key_bindings.action(input.virtual_keycode, self.modifiers);
+                if input.state == ElementState::Pressed {
+                    match action {
+                        Some(Action::Undo) => { self.terrain_map.undo(); }
+                        Some(Action::Redo) => { self.terrain_map.redo(); }
+                        Some(Action::ToggleWireframe) => { self.wireframe = !self.wireframe; }
+                        Some(Action::AddLight) => {
+                            let position = self.camera.position();
+                            let color = match self.light_temperature.checked_sub(1) {
+                                Some(preset) => light_color::PRESETS[preset].color(),
+                                None => {
+                                    let color = LIGHT_PRESETS[self.light_preset];
+                                    self.light_preset = (self.light_preset + 1) % LIGHT_PRESETS.len();
+                                    color
+                                }
+                            };
+                            self.lights.add(PointLight::new(Vector3::new(position.x, position.y, position.z), color));
+                        }
+                        Some(Action::ClearLights) => { self.lights.clear(); }
+                        Some(Action::SaveScene) => {
+                            let scene = Scene::new(self.camera.state(), &self.terrain_map, self.lights.lights());
+                            match scene.save(SCENE_PATH) {
+                                Ok(()) => println!("Scene saved to {}", SCENE_PATH),
+                                Err(e) => println!("Failed to save the scene: {}", e),
+                            }
+                        }
+                        Some(Action::LoadScene) => {
+                            match Scene::load(SCENE_PATH) {
+                                Ok((scene, map)) => self.apply_scene(scene, map),
+                                Err(e) => println!("Failed to load the scene: {}", e),
+                            }
+                        }
+                        Some(Action::FocusSelection) => {
+                            if let Some(block) = self.last_selected_object_id.and_then(|id| self.terrain_map.block(id)) {
+                                let center = block_center(self.terrain.block_offset(block));
+                                self.camera.focus_on(center, FOCUS_DISTANCE);
+                            }
+                        }
+                        Some(Action::ResetCamera) => { self.camera.reset(); }
+                        _ => {}
+                    }
+                }
+            }
             _ => {}
         }
     }
 
+    fn needs_redraw(&self) -> bool {
+        self.camera.is_moving() || self.terrain_map.is_animating() || self.mouse_picker.is_pending() ||
+            self.mouse_picker.is_full_pending() || self.coverage_requested || self.brush_dragging ||
+            self.minimap_dirty
+    }
+
+    fn render_counters(&self) -> Option<RenderCounters> {
+        Some(self.render_counters.clone())
+    }
+
     fn render_gui(&mut self, ui: &mut imgui::Ui) {
+        // Nothing below needs it mutably, the widgets take a shared one
+        let ui: &imgui::Ui = ui;
         ImguiWindow::new(im_str!("stats"))
             .title_bar(false)
-            .size([100.0, 40.0], Condition::FirstUseEver)
+            .size([220.0, 160.0], Condition::FirstUseEver)
             .position([0.0, 0.0], Condition::FirstUseEver)
-            .build(&ui, || {
+            .build(ui, || {
                 ui.text(format!("FPS: ({:.1})", ui.io().framerate));
+
+                let pool_stats = self.attachment_pool.stats();
+                let mb = |bytes: u64| bytes as f32 / (1024.0 * 1024.0);
+                ui.text(format!("attachments: {:.1} MB (peak {:.1} MB)",
+                                mb(pool_stats.allocated_bytes), mb(pool_stats.peak_bytes)));
+                ui.text(format!("lights: {} (L to add, Del to clear)", self.lights.lights().len()));
+                ui.text("F: focus the block under the cursor");
+                ui.text("F5/F9: save/load the scene");
+                ui.text(format!("gbuffer recording: {:.2} ms", self.record_time * 1000.0));
+                ui.text(format!("draw calls: {}", self.render_stats.draw_calls));
+                ui.text(format!("instances: {}", self.render_stats.instances));
+                ui.text(format!("triangles: {}", self.render_stats.triangles));
+                if ui.small_button(im_str!("count visible blocks")) {
+                    self.coverage_requested = true;
+                }
+                if let Some((blocks, coverage)) = self.block_coverage {
+                    ui.text(format!("visible blocks: {} ({:.1}% of the screen)", blocks, coverage * 100.0));
+                }
+            });
+
+        let msaa_label = imgui::ImString::new(format!("MSAA {}x", self.aa_modes[1].samples() as u32));
+        ImguiWindow::new(im_str!("settings"))
+            .size([250.0, 770.0], Condition::FirstUseEver)
+            .position([0.0, 160.0], Condition::FirstUseEver)
+            .collapsed(true, Condition::FirstUseEver)
+            .build(ui, || {
+                let light_colors: Vec<imgui::ImString> = std::iter::once("cycle tints")
+                    .chain(light_color::PRESETS.iter().map(|preset| preset.name))
+                    .map(imgui::ImString::new)
+                    .collect();
+                imgui::ComboBox::new(im_str!("new light color")).build_simple_string(
+                    ui,
+                    &mut self.light_temperature,
+                    &light_colors.iter().collect::<Vec<_>>(),
+                );
+                imgui::ColorEdit::new(im_str!("ambient sky"), &mut self.ambient_sky_color).build(ui);
+                imgui::ColorEdit::new(im_str!("ambient ground"), &mut self.ambient_ground_color).build(ui);
+                ui.checkbox(im_str!("ambient zone"), &mut self.ambient_zone_enabled);
+                imgui::ColorEdit::new(im_str!("zone ambient"), &mut self.ambient_zone_color).build(ui);
+                ui.checkbox(im_str!("occlusion culling"), &mut self.occlusion_culling);
+                let mut frustum_culling = self.terrain.frustum_culling();
+                ui.checkbox(im_str!("frustum culling"), &mut frustum_culling);
+                self.terrain.set_frustum_culling(frustum_culling);
+                if ui.checkbox(im_str!("culling boxes (freezes the frustum)"), &mut self.culling_boxes) {
+                    let view_proj = self.camera.proj_matrix() * self.camera.view_matrix();
+                    self.terrain.freeze_frustum(if self.culling_boxes { Some(view_proj) } else { None });
+                }
+                ui.checkbox(im_str!("wireframe"), &mut self.wireframe);
+                ui.checkbox(im_str!("parallel recording"), &mut self.parallel_recording);
+                ui.checkbox(im_str!("cpu picking"), &mut self.cpu_picking);
+                ui.checkbox(im_str!("gbuffer picking"), &mut self.gbuffer_picking);
+                let mut pick_tolerance = self.mouse_picker.tolerance();
+                imgui::Slider::new(im_str!("pick tolerance (px)"))
+                    .range(1..=mouse_picker::MAX_TOLERANCE)
+                    .build(ui, &mut pick_tolerance);
+                if let Err(e) = self.mouse_picker.set_tolerance(pick_tolerance) {
+                    println!("Failed to change the pick tolerance: {:?}", e);
+                }
+                ui.checkbox(im_str!("debug draw"), &mut self.debug_draw_enabled);
+                ui.checkbox(im_str!("light and selection gizmos"), &mut self.gizmos_enabled);
+                let mut fov = self.camera.fov();
+                imgui::Slider::new(im_str!("fov"))
+                    .range(20.0..=120.0)
+                    .build(ui, &mut fov);
+                self.camera.set_fov(fov);
+                let mut up_axis = CAMERA_UP_AXES.iter()
+                    .position(|&axis| Vector3::from(axis) == self.camera.up_axis())
+                    .unwrap_or(0);
+                if imgui::ComboBox::new(im_str!("camera up axis")).build_simple_string(
+                    ui,
+                    &mut up_axis,
+                    &[im_str!("+Y"), im_str!("-Y"), im_str!("+Z")],
+                ) {
+                    self.camera.set_up_axis(CAMERA_UP_AXES[up_axis].into());
+                }
+                let mut right_handed = self.camera.right_handed();
+                if ui.checkbox(im_str!("right-handed camera"), &mut right_handed) {
+                    self.set_right_handed(right_handed);
+                }
+                ui.checkbox(im_str!("selection rings"), &mut self.selection_rings);
+                let mut pulse = self.terrain.highlight_pulse();
+                let mut easing = HIGHLIGHT_EASINGS.iter().position(|&easing| easing == pulse.easing).unwrap_or(0);
+                imgui::ComboBox::new(im_str!("highlight easing")).build_simple_string(
+                    ui,
+                    &mut easing,
+                    &[im_str!("Sine"), im_str!("Triangle"), im_str!("Pulse")],
+                );
+                pulse.easing = HIGHLIGHT_EASINGS[easing];
+                imgui::Slider::new(im_str!("highlight amplitude"))
+                    .range(0.0..=0.5)
+                    .build(ui, &mut pulse.amplitude);
+                imgui::Slider::new(im_str!("highlight period (s)"))
+                    .range(0.1..=4.0)
+                    .build(ui, &mut pulse.period);
+                self.terrain.set_highlight_pulse(pulse);
+                let mut grid = self.terrain.grid();
+                imgui::Slider::new(im_str!("block spacing"))
+                    .range(0.5..=3.0)
+                    .build(ui, &mut grid.spacing);
+                imgui::Slider::new(im_str!("block grid origin"))
+                    .range(-20.0..=20.0)
+                    .build_array(ui, &mut grid.origin);
+                if grid != self.terrain.grid() {
+                    self.terrain.set_grid(grid.spacing, grid.origin);
+                    self.minimap_dirty = true;
+                }
+                ui.checkbox(im_str!("viewport in window"), &mut self.viewport_window);
+                // Each layer draws the selection again, see `TransparentPass::set_peel_layers`
+                let mut peel_layers = self.transparent_pass.peel_layers();
+                imgui::Slider::new(im_str!("selection peel layers (0 = off)"))
+                    .range(0..=8)
+                    .build(ui, &mut peel_layers);
+                self.transparent_pass.set_peel_layers(peel_layers);
+                if self.queue.device().enabled_features().sample_rate_shading {
+                    imgui::Slider::new(im_str!("sample shading"))
+                        .range(0.0..=1.0)
+                        .build(ui, &mut self.sample_shading);
+                }
+                ui.checkbox(im_str!("fog"), &mut self.fog_enabled);
+                imgui::Slider::new(im_str!("fog density"))
+                    .range(0.0..=0.5)
+                    .build(ui, &mut self.fog_density);
+                imgui::ColorEdit::new(im_str!("fog color"), &mut self.fog_color).build(ui);
+
+                ui.separator();
+                ui.checkbox(im_str!("shadows"), &mut self.shadows_enabled);
+                imgui::Slider::new(im_str!("cascades"))
+                    .range(1..=MAX_CASCADES as u32)
+                    .build(ui, &mut self.shadow_cascades);
+                imgui::Slider::new(im_str!("split lambda"))
+                    .range(0.0..=1.0)
+                    .build(ui, &mut self.shadow_split_lambda);
+                imgui::Slider::new(im_str!("pcf radius (0 = off)"))
+                    .range(0..=4)
+                    .build(ui, &mut self.pcf_radius);
+                imgui::Slider::new(im_str!("depth bias"))
+                    .range(0.0..=0.02)
+                    .build(ui, &mut self.shadow_depth_bias);
+                imgui::Slider::new(im_str!("normal offset"))
+                    .range(0.0..=0.5)
+                    .build(ui, &mut self.shadow_normal_offset);
+
+                ui.separator();
+                ui.checkbox(im_str!("reflections"), &mut self.ssr_enabled);
+                imgui::Slider::new(im_str!("ssr steps"))
+                    .range(1..=128)
+                    .build(ui, &mut self.ssr_steps);
+                imgui::Slider::new(im_str!("ssr thickness"))
+                    .range(0.05..=2.0)
+                    .build(ui, &mut self.ssr_thickness);
+                imgui::Slider::new(im_str!("ssr max distance"))
+                    .range(1.0..=50.0)
+                    .build(ui, &mut self.ssr_max_distance);
+
+                ui.separator();
+                ui.checkbox(im_str!("bloom"), &mut self.bloom_enabled);
+                imgui::Slider::new(im_str!("bloom threshold"))
+                    .range(0.0..=4.0)
+                    .build(ui, &mut self.bloom_threshold);
+                imgui::Slider::new(im_str!("bloom intensity"))
+                    .range(0.0..=2.0)
+                    .build(ui, &mut self.bloom_intensity);
+                imgui::Slider::new(im_str!("bloom radius"))
+                    .range(0.5..=4.0)
+                    .build(ui, &mut self.bloom_radius);
+                imgui::ComboBox::new(im_str!("anti-aliasing")).build_simple_string(
+                    ui,
+                    &mut self.aa_mode,
+                    &[im_str!("Off"), &msaa_label, im_str!("FXAA")],
+                );
+
+                // Passed to `ToneMapPass` every frame, see `ToneMapping`
+                ui.separator();
+                imgui::ComboBox::new(im_str!("tone mapping")).build_simple_string(
+                    ui,
+                    &mut self.tone_map_operator,
+                    &[im_str!("Clamp"), im_str!("Reinhard"), im_str!("ACES")],
+                );
+                if self.auto_exposure {
+                    // Overwritten by the eye adaptation every frame
+                    ui.text(format!("exposure: {:.2}", self.exposure));
+                } else {
+                    imgui::Slider::new(im_str!("exposure"))
+                        .range(0.1..=4.0)
+                        .build(ui, &mut self.exposure);
+                }
+                ui.checkbox(im_str!("auto exposure"), &mut self.auto_exposure);
+                if self.auto_exposure {
+                    imgui::Slider::new(im_str!("min exposure"))
+                        .range(0.1..=4.0)
+                        .build(ui, &mut self.min_exposure);
+                    imgui::Slider::new(im_str!("max exposure"))
+                        .range(0.1..=4.0)
+                        .build(ui, &mut self.max_exposure);
+                    imgui::Slider::new(im_str!("adaptation speed"))
+                        .range(0.1..=10.0)
+                        .build(ui, &mut self.adaptation_speed);
+                }
+                ui.checkbox(im_str!("color grading"), &mut self.color_grading);
+
+                ui.separator();
+                let mut ao = self.landscape.ao();
+                imgui::Slider::new(im_str!("terrain ao"))
+                    .range(0.0..=4.0)
+                    .build(ui, &mut ao.strength);
+                imgui::Slider::new(im_str!("slope ao"))
+                    .range(0.0..=1.0)
+                    .build(ui, &mut ao.slope_strength);
+                imgui::Slider::new(im_str!("ao radius (cells)"))
+                    .range(1.0..=8.0)
+                    .build(ui, &mut ao.radius);
+                self.landscape.set_ao(ao);
+                ui.checkbox(im_str!("terrain normal map"), &mut self.terrain_normal_mapping);
+                let mut triplanar = self.landscape.triplanar();
+                ui.checkbox(im_str!("triplanar terrain"), &mut triplanar);
+                self.landscape.set_triplanar(triplanar);
+                let mut gpu_normals = self.landscape.gpu_normals();
+                ui.checkbox(im_str!("terrain normals on the gpu"), &mut gpu_normals);
+                self.landscape.set_gpu_normals(gpu_normals);
+                self.landscape.set_normal_map(if self.terrain_normal_mapping {
+                    Some(self.ground_normal_map.clone())
+                } else {
+                    None
+                });
+
+                ui.separator();
+                ui.checkbox(im_str!("terrain brush (ctrl lowers)"), &mut self.brush_enabled);
+                imgui::Slider::new(im_str!("brush radius"))
+                    .range(0.1..=5.0)
+                    .build(ui, &mut self.brush_radius);
+                imgui::Slider::new(im_str!("brush strength"))
+                    .range(0.1..=10.0)
+                    .build(ui, &mut self.brush_strength);
             });
 
         let w = 210.0;
         ImguiWindow::new(im_str!("gbuffer content"))
-            .size([w, 240.0], Condition::FirstUseEver)
+            .size([w, 265.0], Condition::FirstUseEver)
             .position([self.dims[0] as f32 - w, 0.0], Condition::Always)
             .collapsed(true, Condition::FirstUseEver)
-            .build(&ui, || {
-                imgui::Image::new(self.normal_texture.unwrap(), [200.0, 200.0]).build(&ui);
+            .build(ui, || {
+                imgui::ComboBox::new(im_str!("channel")).build_simple_string(
+                    ui,
+                    &mut self.gbuffer_texture_idx,
+                    &[im_str!("Albedo"), im_str!("Normals"), im_str!("Positions"), im_str!("Depth")],
+                );
+                imgui::Image::new(self.gbuffer_textures[self.gbuffer_texture_idx], [200.0, 200.0]).build(ui);
             });
+
+        if let Some(viewport) = self.viewport_texture {
+            let dims = self.dims;
+            let framebuffer_scale = ui.io().display_framebuffer_scale;
+            let mut rect = None;
+            ImguiWindow::new(im_str!("viewport"))
+                .size([640.0, 400.0], Condition::FirstUseEver)
+                .position([240.0, 40.0], Condition::FirstUseEver)
+                .build(ui, || {
+                    // Fit into the window with the aspect ratio of the frame
+                    let available = ui.content_region_avail();
+                    let scale = (available[0] / dims[0] as f32).min(available[1] / dims[1] as f32).max(0.0);
+                    imgui::Image::new(viewport, [dims[0] as f32 * scale, dims[1] as f32 * scale]).build(ui);
+
+                    // imgui works in logical pixels, the cursor is in physical ones
+                    let (min, size) = (ui.item_rect_min(), ui.item_rect_size());
+                    rect = Some([
+                        [min[0] * framebuffer_scale[0], min[1] * framebuffer_scale[1]],
+                        [size[0] * framebuffer_scale[0], size[1] * framebuffer_scale[1]],
+                    ]);
+                });
+            self.viewport_rect = rect;
+        } else {
+            self.viewport_rect = None;
+        }
+        self.update_cursor_pos();
+
+        if let Some(minimap) = self.minimap_texture {
+            ImguiWindow::new(im_str!("minimap"))
+                .size([w, 235.0], Condition::FirstUseEver)
+                .position([self.dims[0] as f32 - w, 270.0], Condition::Always)
+                .collapsed(true, Condition::FirstUseEver)
+                .build(ui, || {
+                    imgui::Image::new(minimap, [200.0, 200.0]).build(ui);
+                });
+        }
+    }
+}
+
+// Color, normals (alpha is the reflectivity), world positions and depth
+fn gbuffer_targets(samples: SampleCount) -> Vec<RenderTargetDesc> {
+    vec![
+        RenderTargetDesc { format: Format::R8G8B8A8Unorm, samples_count: samples },
+        RenderTargetDesc { format: Format::R16G16B16A16Sfloat, samples_count: samples },
+        RenderTargetDesc { format: Format::R16G16B16A16Sfloat, samples_count: samples },
+        RenderTargetDesc { format: Format::D32Sfloat, samples_count: samples },
+        // Object ids, see `OBJECT_ID_TARGET`
+        RenderTargetDesc { format: Format::R32Uint, samples_count: samples },
+    ]
+}
+
+// `size`x`size` decal of a soft-edged ring, see `DecalPass::draw`
+fn selection_ring(uploads: &mut UploadBatch, size: u32) -> Arc<ImageView<Arc<ImmutableImage>>> {
+    let mut data = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let u = (x as f32 + 0.5) / size as f32 - 0.5;
+            let v = (y as f32 + 0.5) / size as f32 - 0.5;
+            // Radius 0.4 of the texture, 0.05 wide
+            let distance = ((u * u + v * v).sqrt() - 0.4).abs();
+            let alpha = (1.0 - distance / 0.05).max(0.0).min(1.0);
+            data.extend_from_slice(&[255, 215, 80, (alpha * 230.0) as u8]);
+        }
     }
+
+    let image = uploads.image(
+        data.into_iter(),
+        ImageDimensions::Dim2d { width: size, height: size, array_layers: 1 },
+        MipmapsCount::One,
+        Format::R8G8B8A8Srgb,
+    );
+    ImageView::new(image).unwrap()
+}
+
+// Middle of the unit cube drawn at `offset`, see `TerrainRenderSystem::block_offset`
+fn block_center(offset: [f32; 3]) -> Point3<f32> {
+    Point3::new(offset[0] + 0.5, offset[1] - 0.5, -offset[2] - 0.5)
 }
 
 fn main() {
-    app::run_app(|queue, swapchain_format| -> MyApp {
-        MyApp::new(queue, swapchain_format)
+    if std::env::args().any(|arg| arg == "--bench") {
+        bench::run();
+        return;
+    }
+
+    let key_bindings = if std::env::args().any(|arg| arg == "--azerty") {
+        KeyBindings::azerty()
+    } else {
+        KeyBindings::default()
+    };
+    // `--lut <path>`, a .cube file or a PNG strip
+    let lut_path = std::env::args().skip_while(|arg| arg != "--lut").nth(1);
+    let config = app::AppConfig {
+        key_bindings: key_bindings.clone(),
+        reactive: std::env::args().any(|arg| arg == "--reactive"),
+        verbose: std::env::args().any(|arg| arg == "--verbose"),
+        gui_ini_path: Some(GUI_INI_PATH.into()),
+        ..app::AppConfig::default()
+    };
+
+    app::run_app(config, |queue, transfer_queue, swapchain_format, samples| -> MyApp {
+        MyApp::new(queue, transfer_queue, swapchain_format, samples, key_bindings.clone(), lut_path.as_deref())
     });
 }
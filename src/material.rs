@@ -0,0 +1,57 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use vulkano::format::Format;
+use vulkano::image::{ImageDimensions, ImmutableImage, MipmapsCount};
+use vulkano::image::view::ImageView;
+
+use crate::base::upload::UploadBatch;
+
+// Surface description bound per draw batch. Only the albedo texture for now, multiplied
+// with the vertex color.
+pub struct Material {
+    pub albedo: Arc<ImageView<Arc<ImmutableImage>>>,
+}
+
+impl Material {
+    pub fn from_png(uploads: &mut UploadBatch, png_bytes: &[u8]) -> Material {
+        let decoder = png::Decoder::new(Cursor::new(png_bytes));
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut image_data = vec![0; (info.width * info.height * 4) as usize];
+        reader.next_frame(&mut image_data).unwrap();
+
+        Self::from_rgba(uploads, info.width, info.height, image_data)
+    }
+
+    // Single texel, leaves the vertex color as is for white
+    pub fn solid(uploads: &mut UploadBatch, color: [u8; 4]) -> Material {
+        Self::from_rgba(uploads, 1, 1, color.to_vec())
+    }
+
+    // `cells` x `cells` squares alternating between `a` and `b`, 8 texels each
+    pub fn checker(uploads: &mut UploadBatch, cells: u32, a: [u8; 4], b: [u8; 4]) -> Material {
+        let size = cells * 8;
+        let mut image_data = Vec::with_capacity((size * size * 4) as usize);
+        for y in 0..size {
+            for x in 0..size {
+                let color = if (x / 8 + y / 8) % 2 == 0 { a } else { b };
+                image_data.extend_from_slice(&color);
+            }
+        }
+
+        Self::from_rgba(uploads, size, size, image_data)
+    }
+
+    fn from_rgba(uploads: &mut UploadBatch, width: u32, height: u32, image_data: Vec<u8>) -> Material {
+        let image = uploads.image(
+            image_data.into_iter(),
+            ImageDimensions::Dim2d { width, height, array_layers: 1 },
+            MipmapsCount::One,
+            Format::R8G8B8A8Srgb,
+        );
+
+        Material {
+            albedo: ImageView::new(image).unwrap(),
+        }
+    }
+}
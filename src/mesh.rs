@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use crate::terrain::Vertex;
+
+// Attributes closer than this are taken as equal by `dedup`
+const QUANTUM: f32 = 1.0 / 4096.0;
+
+// Merges vertices whose attributes match after quantizing to `QUANTUM` and remaps `indices` to
+// the merged buffer, e.g. for meshes built face by face. The first vertex of every group is kept,
+// the order of first appearance is preserved, so an already minimal mesh comes back unchanged.
+#[allow(dead_code)]
+pub fn dedup(vertices: &[Vertex], indices: &[u32]) -> (Vec<Vertex>, Vec<u32>) {
+    let mut unique = Vec::with_capacity(vertices.len());
+    let mut remap = Vec::with_capacity(vertices.len());
+    let mut seen: HashMap<[i64; 12], u32> = HashMap::with_capacity(vertices.len());
+
+    for vertex in vertices.iter() {
+        let next = unique.len() as u32;
+        let index = *seen.entry(key(vertex)).or_insert(next);
+        if index == next {
+            unique.push(vertex.clone());
+        }
+        remap.push(index);
+    }
+
+    let indices = indices.iter().map(|&index| remap[index as usize]).collect();
+    (unique, indices)
+}
+
+// Exact for attributes up to 2^51, far beyond any mesh coordinate
+fn key(vertex: &Vertex) -> [i64; 12] {
+    let mut key = [0; 12];
+    let attributes = vertex.position.iter()
+        .chain(vertex.normal.iter())
+        .chain(vertex.texcoord.iter())
+        .chain(vertex.tangent.iter());
+    for (k, &value) in key.iter_mut().zip(attributes) {
+        // Also folds -0.0 into 0.0
+        *k = (value / QUANTUM).round() as i64;
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::terrain::Vertex;
+
+    use super::dedup;
+
+    fn vertex(position: [f32; 3], texcoord: [f32; 2]) -> Vertex {
+        Vertex { position, normal: [0.0, -1.0, 0.0], texcoord, tangent: [1.0, 0.0, 0.0, 1.0] }
+    }
+
+    #[test]
+    fn merges_duplicates_and_remaps_indices() {
+        // Two triangles of a quad built face by face, sharing an edge
+        let vertices = vec![
+            vertex([0.0, 0.0, 0.0], [0.0, 0.0]),
+            vertex([1.0, 0.0, 0.0], [1.0, 0.0]),
+            vertex([0.0, 0.0, 1.0], [0.0, 1.0]),
+            vertex([0.0, 0.0, 1.0], [0.0, 1.0]),
+            vertex([1.0, 0.0, 0.0], [1.0, 0.0]),
+            vertex([1.0, 0.0, 1.0], [1.0, 1.0]),
+        ];
+        let indices = vec![0, 1, 2, 3, 4, 5];
+
+        let (unique, remapped) = dedup(&vertices, &indices);
+
+        assert_eq!(unique.len(), 4);
+        assert_eq!(remapped.len(), indices.len());
+        for (&before, &after) in indices.iter().zip(remapped.iter()) {
+            let (a, b) = (&vertices[before as usize], &unique[after as usize]);
+            assert_eq!(a.position, b.position);
+            assert_eq!(a.normal, b.normal);
+            assert_eq!(a.texcoord, b.texcoord);
+            assert_eq!(a.tangent, b.tangent);
+        }
+    }
+
+    #[test]
+    fn keeps_far_apart_vertices() {
+        // Beyond the i32 range once quantized
+        let vertices = vec![
+            vertex([1.0e7, 0.0, 0.0], [0.0, 0.0]),
+            vertex([2.0e7, 0.0, 0.0], [0.0, 0.0]),
+        ];
+
+        let (unique, remapped) = dedup(&vertices, &[0, 1]);
+
+        assert_eq!(unique.len(), 2);
+        assert_eq!(remapped, vec![0, 1]);
+    }
+}
@@ -1,109 +1,91 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
+use std::time::Duration;
 
 use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
 use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, PrimaryCommandBuffer, SecondaryCommandBuffer, SubpassContents};
 use vulkano::device::Queue;
 use vulkano::format::Format;
-use vulkano::image::{AttachmentImage, ImageAccess, ImageUsage};
+use vulkano::image::{AttachmentImage, ImageAccess, ImageCreationError, ImageLayout, ImageUsage, SampleCount};
 use vulkano::image::view::ImageView;
-use vulkano::render_pass::{Framebuffer, FramebufferAbstract, RenderPass, Subpass};
-use vulkano::sync::GpuFuture;
+use vulkano::render_pass::{AttachmentDesc, Framebuffer, FramebufferAbstract, LoadOp, RenderPass, RenderPassDesc, StoreOp,
+                           Subpass, SubpassDesc};
+use vulkano::sync::{FenceSignalFuture, GpuFuture};
 
+use crate::base::attachment_pool::AttachmentPool;
+use crate::object_id;
 
-pub struct Picker {
-    // Queue to use to render everything.
-    gfx_queue: Arc<Queue>,
+// Picks in flight at once. A pick is usually read back one frame after its submission.
+const RING_SIZE: usize = 3;
 
-    framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
-
-    // Render pass used for the drawing. See the `new` method for the actual render pass content.
-    // We need to keep it in `FrameSystem` because we may want to recreate the intermediate buffers
-    // in of a change in the dimensions.
-    render_pass: Arc<RenderPass>,
+// Largest side of the neighbourhood read around the cursor, see `Picker::set_tolerance`
+pub const MAX_TOLERANCE: u32 = 9;
 
+// Targets and readback buffer of one pick. Every slot has its own images, so a new pick
+// doesn't have to wait for the GPU to be done with the previous one.
+struct PickSlot {
+    framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
     object_id_buffer: Arc<ImageView<Arc<AttachmentImage>>>,
+    // Host visible and mapped for its whole lifetime. Room for the ids of `tolerance` x
+    // `tolerance` texels, 4 bytes each.
     object_id_cpu: Arc<CpuAccessibleBuffer<[u8]>>,
+    // Texels the last pick copied into `object_id_cpu`
+    region_texels: usize,
+    // Index of the texel under the cursor in the last copied region
+    center_texel: usize,
+    // Stored depth of the id map and the texel under the cursor, only with `keep_depth` of
+    // `Picker::new`
+    depth: Option<(Arc<ImageView<Arc<AttachmentImage>>>, Arc<CpuAccessibleBuffer<[f32]>>)>,
+    // The last pick read an R32Uint id target (see `submit_from_image`) instead of the RGBA8
+    // id map
+    packed_id: bool,
 
-    depth_buffer: Arc<ImageView<Arc<AttachmentImage>>>,
+    // `None` for picks recorded into someone else's frame, `object_id_cpu` stays locked until
+    // the GPU is done with them
+    fence: Option<FenceSignalFuture<Box<dyn GpuFuture>>>,
 }
 
-
-fn get_entity_id(r: u8, g: u8, b: u8, a: u8) -> Option<u32> {
-    if a == 0 {
-        None
-    } else {
-        Some(((r as usize) | (g as usize) << 8 | (b as usize) << 16) as u32)
-    }
-}
-
-impl Picker {
-    pub fn new(gfx_queue: Arc<Queue>) -> Picker {
-        let render_pass = Arc::new(
-            vulkano::single_pass_renderpass!(gfx_queue.device().clone(),
-            attachments: {
-                id_map: {
-                    load: Clear,
-                    store: Store,
-                    format: Format::R8G8B8A8Unorm,
-                    samples: 1,
-                    final_layout: ImageLayout::ColorAttachmentOptimal,
-                },
-                // Will be bound to `self.depth_buffer`.
-                depth: {
-                    load: Clear,
-                    store: DontCare,
-                    format: Format::D32Sfloat,
-                    samples: 1,
-                        final_layout: ImageLayout::DepthStencilAttachmentOptimal,
-                }
-            },
-            pass: {
-                color: [id_map],
-                depth_stencil: {depth}
-            }
-        ).unwrap());
-
+impl PickSlot {
+    fn new(gfx_queue: Arc<Queue>, pool: &AttachmentPool, render_pass: Arc<RenderPass>, dims: [u32; 2],
+           keep_depth: bool, tolerance: u32) -> Result<PickSlot, ImageCreationError> {
         let obj_id_usage = ImageUsage {
             transfer_source: true, // This is necessary to copy to external buffer
             color_attachment: true,
             ..ImageUsage::none()
         };
         let object_id_buffer = ImageView::new(
-            AttachmentImage::with_usage(
-                gfx_queue.device().clone(),
-                [1, 1],
-                Format::R8G8B8A8Unorm,
-                obj_id_usage,
-            )
-                .unwrap()
+            pool.try_image(dims, SampleCount::Sample1, Format::R8G8B8A8Unorm, obj_id_usage)?
         )
             .unwrap();
 
         let object_id_cpu = CpuAccessibleBuffer::from_iter(
             gfx_queue.device().clone(),
             BufferUsage::all(),
-            false, (0..0).map(|_| 0u8),
+            false, (0..4 * tolerance * tolerance).map(|_| 0u8),
         ).expect("Failed to create buffer");
 
-        let atch_usage = ImageUsage {
-            transient_attachment: true,
-            depth_stencil_attachment: true,
-            ..ImageUsage::none()
+        let atch_usage = if keep_depth {
+            ImageUsage {
+                transfer_source: true,
+                depth_stencil_attachment: true,
+                ..ImageUsage::none()
+            }
+        } else {
+            ImageUsage {
+                transient_attachment: true,
+                depth_stencil_attachment: true,
+                ..ImageUsage::none()
+            }
         };
 
         let depth_buffer = ImageView::new(
-            AttachmentImage::with_usage(
-                gfx_queue.device().clone(),
-                [1, 1],
-                Format::D32Sfloat,
-                atch_usage,
-            )
-                .unwrap()
+            pool.try_image(dims, SampleCount::Sample1, Format::D32Sfloat, atch_usage)?
         )
             .unwrap();
 
         let framebuffer = Arc::new(
-            Framebuffer::start(render_pass.clone())
+            Framebuffer::start(render_pass)
                 .add(object_id_buffer.clone())
                 .unwrap()
                 .add(depth_buffer.clone())
@@ -112,78 +94,287 @@ impl Picker {
                 .unwrap()
         );
 
-        Picker {
-            gfx_queue,
-            render_pass,
+        let depth = if keep_depth {
+            let depth_cpu = CpuAccessibleBuffer::from_iter(
+                gfx_queue.device().clone(),
+                BufferUsage::all(),
+                false, (0..1).map(|_| 0.0f32),
+            ).expect("Failed to create buffer");
+            Some((depth_buffer, depth_cpu))
+        } else {
+            None
+        };
+
+        Ok(PickSlot {
             framebuffer,
             object_id_buffer,
             object_id_cpu,
-            depth_buffer,
+            region_texels: 1,
+            center_texel: 0,
+            depth,
+            packed_id: false,
+            fence: None,
+        })
+    }
+
+    fn is_done(&self) -> bool {
+        match &self.fence {
+            Some(fence) => fence.wait(Some(Duration::from_secs(0))).is_ok(),
+            None => self.object_id_cpu.read().is_ok(),
+        }
+    }
+
+    fn wait(&mut self) {
+        if let Some(fence) = self.fence.take() {
+            fence.wait(None).unwrap();
+        }
+    }
+
+    // Depth under the cursor of the last pick, `None` where nothing was drawn. Picks read from
+    // an id target (see `submit_from_image`) don't copy a depth.
+    fn depth(&self) -> Option<f32> {
+        if self.packed_id {
+            return None;
+        }
+
+        let depth = self.depth.as_ref()?.1.read().unwrap()[0];
+        if depth >= 1.0 {
+            None
+        } else {
+            Some(depth)
+        }
+    }
+
+    // Id of the last copied region, see `region_entity_id`. Both `draw` and `poll` decode
+    // through here, so the blocking and the queued pick agree. `clear` is what the id map was
+    // cleared to, see `Picker::set_clear_color`.
+    fn entity_id(&self, clear: [u8; 4]) -> Option<u32> {
+        let buffer_content = self.object_id_cpu.read().unwrap();
+        region_entity_id(&buffer_content[..4 * self.region_texels], self.center_texel, self.packed_id, clear)
+    }
+}
+
+// Id of one texel copied back from the id map, `None` where nothing was drawn. `packed_id`
+// reads it as an R32Uint id plus one instead of the RGBA8 id map cleared to `clear`.
+fn texel_entity_id(bytes: [u8; 4], packed_id: bool, clear: [u8; 4]) -> Option<u32> {
+    if packed_id {
+        u32::from_ne_bytes(bytes).checked_sub(1)
+    } else if bytes == clear {
+        None
+    } else {
+        object_id::decode(bytes)
+    }
+}
+
+// Most frequent id of `region` (4 bytes per texel), empty texels don't vote. The one at
+// `center_texel` wins a tie. See `texel_entity_id` for `packed_id` and `clear`.
+fn region_entity_id(region: &[u8], center_texel: usize, packed_id: bool, clear: [u8; 4]) -> Option<u32> {
+    let texel_id = |texel: usize| {
+        let bytes = [region[4 * texel], region[4 * texel + 1], region[4 * texel + 2], region[4 * texel + 3]];
+        texel_entity_id(bytes, packed_id, clear)
+    };
+
+    let region_texels = region.len() / 4;
+    let center = texel_id(center_texel);
+    if region_texels == 1 {
+        return center;
+    }
+
+    let mut votes: HashMap<u32, usize> = HashMap::new();
+    for texel in 0..region_texels {
+        if let Some(id) = texel_id(texel) {
+            *votes.entry(id).or_insert(0) += 1;
+        }
+    }
+
+    votes.into_iter()
+        // Lowest id among the rest, so the pick doesn't flicker between equal votes
+        .max_by_key(|&(id, count)| (count, Some(id) == center, Reverse(id)))
+        .map(|(id, _)| id)
+}
+
+// Copy of a whole id map, see `Picker::submit_full`
+struct FullReadback {
+    // Ring slot the id map was rendered into
+    slot: usize,
+    dims: [u32; 2],
+    // 4 bytes per pixel, reused while the size stays the same
+    buffer: Arc<CpuAccessibleBuffer<[u8]>>,
+    // `Picker::clear_color` at the time of the copy
+    clear: [u8; 4],
+    // Not returned by `poll_full` yet
+    pending: bool,
+}
+
+pub struct Picker {
+    // Queue to use to render everything.
+    gfx_queue: Arc<Queue>,
+
+    // Render pass used for the drawing, see `create_render_pass`.
+    // We need to keep it in `FrameSystem` because we may want to recreate the intermediate buffers
+    // in of a change in the dimensions.
+    render_pass: Arc<RenderPass>,
+    pool: AttachmentPool,
+    keep_depth: bool,
+    // Side of the neighbourhood around the cursor voting for the picked id
+    tolerance: u32,
+    // The id map starts out as this, picks read it back as "no object"
+    clear_color: [f32; 4],
+
+    slots: Vec<PickSlot>,
+    next_slot: usize,
+    // Slots with a submitted pick, oldest first
+    pending: VecDeque<usize>,
+    // See `pick_depth`
+    last_depth: Option<f32>,
+    full_readback: Option<FullReadback>,
+}
+
+
+impl Picker {
+    // `keep_depth` stores the depth of the id map for `pick_depth`. Without it the depth is a
+    // transient attachment that's thrown away after the pass, which is all picking ids needs.
+    pub fn new(gfx_queue: Arc<Queue>, pool: AttachmentPool, keep_depth: bool) -> Picker {
+        let render_pass = create_render_pass(gfx_queue.clone(), keep_depth);
+
+        let slots = (0..RING_SIZE)
+            .map(|_| PickSlot::new(gfx_queue.clone(), &pool, render_pass.clone(), [1, 1], keep_depth, 1).unwrap())
+            .collect();
+
+        Picker {
+            gfx_queue,
+            render_pass,
+            pool,
+            keep_depth,
+            tolerance: 1,
+            clear_color: object_id::CLEAR,
+            slots,
+            next_slot: 0,
+            pending: VecDeque::new(),
+            last_depth: None,
+            full_readback: None,
+        }
+    }
+
+    pub fn tolerance(&self) -> u32 {
+        self.tolerance
+    }
+
+    // Picks read a `tolerance` x `tolerance` neighbourhood around the cursor (moved inside the
+    // image at its borders) and return its most frequent id, so clicks next to the edge of thin
+    // geometry still hit it. 1 reads only the texel under the cursor. Clamped to
+    // [1, `MAX_TOLERANCE`], picks in flight are dropped when it changes. On error the tolerance
+    // is left as it was.
+    pub fn set_tolerance(&mut self, tolerance: u32) -> Result<(), ImageCreationError> {
+        let tolerance = tolerance.max(1).min(MAX_TOLERANCE);
+        if tolerance == self.tolerance {
+            return Ok(());
         }
+
+        let previous = std::mem::replace(&mut self.tolerance, tolerance);
+        let img_dims = self.slots[0].object_id_buffer.image().dimensions().width_height();
+        self.recreate_slots(img_dims).inspect_err(|_| self.tolerance = previous)
+    }
+
+    pub fn clear_color(&self) -> [f32; 4] {
+        self.clear_color
+    }
+
+    // What the id map is cleared to before the draw, `object_id::CLEAR` by default. Texels still
+    // holding it are "no object", whatever `object_id::decode` would make of them, so an id
+    // encoding without a free alpha can reserve any color for it. Only for `submit` and `draw`,
+    // `submit_from_image` reads a target cleared by someone else. Drops the pending picks, they
+    // were drawn over the old color.
+    #[allow(dead_code)]
+    pub fn set_clear_color(&mut self, clear_color: [f32; 4]) -> Result<(), ImageCreationError> {
+        if clear_color == self.clear_color {
+            return Ok(());
+        }
+
+        let previous = std::mem::replace(&mut self.clear_color, clear_color);
+        let img_dims = self.slots[0].object_id_buffer.image().dimensions().width_height();
+        self.recreate_slots(img_dims).inspect_err(|_| self.clear_color = previous)
+    }
+
+    // The old slots are kept when the new ones can't be allocated, so the picker stays usable at
+    // its previous size
+    fn recreate_slots(&mut self, img_dims: [u32; 2]) -> Result<(), ImageCreationError> {
+        self.pending.clear();
+        for slot in self.slots.iter_mut() {
+            slot.wait();
+        }
+        let slots = (0..RING_SIZE)
+            .map(|_| PickSlot::new(self.gfx_queue.clone(), &self.pool, self.render_pass.clone(), img_dims,
+                                   self.keep_depth, self.tolerance))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Gives the old images back to the pool
+        self.slots = slots;
+        self.last_depth = None;
+        Ok(())
+    }
+
+    // Offset and extent of the texels read around `mouse_pos` inside an image of `img_dims`,
+    // plus the index of the cursor texel in the region
+    fn region(&self, img_dims: [u32; 2], mouse_pos: [u32; 2]) -> ([u32; 3], [u32; 3], usize) {
+        let side = [self.tolerance.min(img_dims[0]), self.tolerance.min(img_dims[1])];
+        let origin = [
+            mouse_pos[0].saturating_sub(self.tolerance / 2).min(img_dims[0] - side[0]),
+            mouse_pos[1].saturating_sub(self.tolerance / 2).min(img_dims[1] - side[1]),
+        ];
+        let center = (mouse_pos[1] - origin[1]) * side[0] + mouse_pos[0] - origin[0];
+
+        ([origin[0], origin[1], 0], [side[0], side[1], 1], center as usize)
     }
+
     pub fn subpass(&self) -> Subpass {
         Subpass::from(self.render_pass.clone(), 0).unwrap()
     }
-    pub fn draw<C>(&mut self, img_dims: [u32; 2], cmds: Vec<C>, mouse_pos: [u32; 2]) -> Option<u32>
+    // Renders the id map and reads the entity under `mouse_pos` back, blocking until the GPU is
+    // done. See `submit`/`poll` for the non-blocking version.
+    #[allow(dead_code)]
+    pub fn draw<C>(&mut self, img_dims: [u32; 2], cmds: Vec<C>, mouse_pos: [u32; 2])
+                   -> Result<Option<u32>, ImageCreationError>
         where C: SecondaryCommandBuffer + Send + Sync + 'static
     {
-        // Recreate framebuffer
-        if self.object_id_buffer.image().dimensions().width_height() != img_dims {
-            println!("recreated");
-            let obj_id_usage = ImageUsage {
-                transfer_source: true,
-                color_attachment: true,
-                ..ImageUsage::none()
-            };
-            self.object_id_buffer = ImageView::new(
-                AttachmentImage::with_usage(
-                    self.gfx_queue.device().clone(),
-                    img_dims,
-                    Format::R8G8B8A8Unorm,
-                    obj_id_usage,
-                )
-                    .unwrap()
-            )
-                .unwrap();
-
-            self.object_id_cpu = CpuAccessibleBuffer::from_iter(
-                self.gfx_queue.device().clone(),
-                BufferUsage::all(),
-                false, (0..4).map(|_| 0u8),
-            ).expect("Failed to create buffer");
+        let slot = match self.submit(img_dims, cmds, mouse_pos)? {
+            Some(slot) => slot,
+            None => return Ok(None),
+        };
+        self.pending.retain(|&pending| pending != slot);
 
-            let atch_usage = ImageUsage {
-                transient_attachment: true,
-                depth_stencil_attachment: true,
-                ..ImageUsage::none()
-            };
-
-            self.depth_buffer = ImageView::new(
-                AttachmentImage::with_usage(
-                    self.gfx_queue.device().clone(),
-                    img_dims,
-                    Format::D32Sfloat,
-                    atch_usage,
-                )
-                    .unwrap()
-            ).unwrap();
+        self.slots[slot].wait();
+        self.last_depth = self.slots[slot].depth();
+        Ok(self.slots[slot].entity_id(object_id::to_bytes(self.clear_color)))
+    }
 
-            self.framebuffer = Arc::new(
-                Framebuffer::start(self.render_pass.clone())
-                    .add(self.object_id_buffer.clone())
-                    .unwrap()
-                    .add(self.depth_buffer.clone())
-                    .unwrap()
-                    .build()
-                    .unwrap()
-            );
+    // Queues a pick without waiting for it, the result is returned by a later `poll`.
+    // Returns the ring slot used, `None` if `mouse_pos` is outside of the image. Fails when the
+    // id map can't be allocated at `img_dims`.
+    pub fn submit<C>(&mut self, img_dims: [u32; 2], cmds: Vec<C>, mouse_pos: [u32; 2])
+                     -> Result<Option<usize>, ImageCreationError>
+        where C: SecondaryCommandBuffer + Send + Sync + 'static
+    {
+        // Recreate framebuffers
+        if self.slots[0].object_id_buffer.image().dimensions().width_height() != img_dims {
+            self.recreate_slots(img_dims)?;
         }
 
-        let dims = self.object_id_buffer.image().dimensions().width_height();
-        if !(0..dims[0]).contains(&mouse_pos[0]) || !(0..dims[1]).contains(&mouse_pos[1]) {
-            return None;
+        if !(0..img_dims[0]).contains(&mouse_pos[0]) || !(0..img_dims[1]).contains(&mouse_pos[1]) {
+            return Ok(None);
         }
 
+        let (origin, extent, center) = self.region(img_dims, mouse_pos);
+        let idx = self.next_slot;
+        self.next_slot = (self.next_slot + 1) % RING_SIZE;
+
+        // Only happens when picks are submitted faster than the GPU finishes them
+        self.pending.retain(|&pending| pending != idx);
+        let slot = &mut self.slots[idx];
+        slot.wait();
+        slot.packed_id = false;
+        slot.region_texels = (extent[0] * extent[1]) as usize;
+        slot.center_texel = center;
+
         // Start the command buffer builder that will be filled throughout the frame handling.
         let mut command_buffer_builder =
             AutoCommandBufferBuilder::primary(self.gfx_queue.device().clone(),
@@ -191,9 +382,9 @@ impl Picker {
                                               CommandBufferUsage::OneTimeSubmit).unwrap();
 
         command_buffer_builder.begin_render_pass(
-            self.framebuffer.clone(),
+            slot.framebuffer.clone(),
             SubpassContents::SecondaryCommandBuffers,
-            vec![[0.0, 0.0, 0.0, 0.0].into(), 1.0f32.into()],
+            vec![self.clear_color.into(), 1.0f32.into()],
         )
             .unwrap();
 
@@ -202,21 +393,290 @@ impl Picker {
         command_buffer_builder
             .end_render_pass().unwrap()
             .copy_image_to_buffer_dimensions(
-                self.object_id_buffer.image().clone(),
-                self.object_id_cpu.clone(),
-                [mouse_pos[0], mouse_pos[1], 0],
-                [1, 1, 1],
+                slot.object_id_buffer.image().clone(),
+                slot.object_id_cpu.clone(),
+                origin,
+                extent,
                 0, 1, 0,
             ).unwrap();
+        if let Some((depth_buffer, depth_cpu)) = &slot.depth {
+            command_buffer_builder
+                .copy_image_to_buffer_dimensions(
+                    depth_buffer.image().clone(),
+                    depth_cpu.clone(),
+                    [mouse_pos[0], mouse_pos[1], 0],
+                    [1, 1, 1],
+                    0, 1, 0,
+                ).unwrap();
+        }
 
 
         let cmd_buf = command_buffer_builder.build().unwrap();
 
-        cmd_buf.execute(self.gfx_queue.clone()).unwrap()
-            .then_signal_fence_and_flush().unwrap()
-            .wait(None).unwrap();
+        let future: Box<dyn GpuFuture> = Box::new(cmd_buf.execute(self.gfx_queue.clone()).unwrap());
+        slot.fence = Some(future.then_signal_fence_and_flush().unwrap());
 
-        let buffer_content = self.object_id_cpu.read().unwrap();
-        get_entity_id(buffer_content[0], buffer_content[1], buffer_content[2], buffer_content[3])
+        self.pending.push_back(idx);
+        Ok(Some(idx))
+    }
+
+    // Depth buffer value (0 near, 1 far) under the cursor of the pick last returned by `poll` or
+    // `draw`, e.g. to rebuild the picked world point with the inverse camera matrices. Copied
+    // with the ids, so it never blocks. `None` without `keep_depth`, where nothing was drawn and
+    // for picks read by `submit_from_image`.
+    pub fn pick_depth(&self) -> Option<f32> {
+        self.last_depth
+    }
+
+    // Reads the id under `mouse_pos` from `id_image`, an R32Uint target the scene was already
+    // rendered to (the object id target of the gbuffer), instead of rendering the id map again.
+    // It holds the entity id plus one, 0 where there is nothing. The copy runs after
+    // `before_future`, a later `poll` returns the result once that frame is done.
+    pub fn submit_from_image<F>(&mut self, before_future: F, id_image: Arc<AttachmentImage>,
+                                mouse_pos: [u32; 2]) -> Box<dyn GpuFuture>
+        where F: GpuFuture + 'static
+    {
+        let img_dims = id_image.dimensions().width_height();
+        if !(0..img_dims[0]).contains(&mouse_pos[0]) || !(0..img_dims[1]).contains(&mouse_pos[1]) {
+            return Box::new(before_future);
+        }
+
+        let (origin, extent, center) = self.region(img_dims, mouse_pos);
+        let idx = self.next_slot;
+        self.next_slot = (self.next_slot + 1) % RING_SIZE;
+
+        self.pending.retain(|&pending| pending != idx);
+        let slot = &mut self.slots[idx];
+        slot.wait();
+        slot.packed_id = true;
+        slot.region_texels = (extent[0] * extent[1]) as usize;
+        slot.center_texel = center;
+
+        let mut command_buffer_builder =
+            AutoCommandBufferBuilder::primary(self.gfx_queue.device().clone(),
+                                              self.gfx_queue.family(),
+                                              CommandBufferUsage::OneTimeSubmit).unwrap();
+
+        command_buffer_builder
+            .copy_image_to_buffer_dimensions(
+                id_image,
+                slot.object_id_cpu.clone(),
+                origin,
+                extent,
+                0, 1, 0,
+            ).unwrap();
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+
+        self.pending.push_back(idx);
+        Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap())
+    }
+
+    // Renders the id map like `submit` and copies all of it back instead of the texels around the
+    // cursor, for whole-screen statistics: block coverage, framing what's visible, histograms.
+    // The result is returned by a later `poll_full`, one id per pixel in rows from the top.
+    //
+    // Costs a host-visible buffer of 4 bytes per pixel (about 8 MB at 1080p), kept for the next
+    // call at the same size, plus decoding every pixel on the CPU. Waits for a previous full
+    // readback still in flight.
+    pub fn submit_full<C>(&mut self, img_dims: [u32; 2], cmds: Vec<C>) -> Result<(), ImageCreationError>
+        where C: SecondaryCommandBuffer + Send + Sync + 'static
+    {
+        if self.slots[0].object_id_buffer.image().dimensions().width_height() != img_dims {
+            self.recreate_slots(img_dims)?;
+        }
+
+        let buffer = match self.full_readback.take() {
+            Some(previous) => {
+                // Its slot is about to be reused or the buffer is
+                self.slots[previous.slot].wait();
+                if previous.dims == img_dims {
+                    Some(previous.buffer)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+        let buffer = buffer.unwrap_or_else(|| CpuAccessibleBuffer::from_iter(
+            self.gfx_queue.device().clone(),
+            BufferUsage::all(),
+            false, (0..4 * img_dims[0] as usize * img_dims[1] as usize).map(|_| 0u8),
+        ).expect("Failed to create buffer"));
+
+        let idx = self.next_slot;
+        self.next_slot = (self.next_slot + 1) % RING_SIZE;
+
+        self.pending.retain(|&pending| pending != idx);
+        let slot = &mut self.slots[idx];
+        slot.wait();
+
+        let mut command_buffer_builder =
+            AutoCommandBufferBuilder::primary(self.gfx_queue.device().clone(),
+                                              self.gfx_queue.family(),
+                                              CommandBufferUsage::OneTimeSubmit).unwrap();
+
+        command_buffer_builder.begin_render_pass(
+            slot.framebuffer.clone(),
+            SubpassContents::SecondaryCommandBuffers,
+            vec![self.clear_color.into(), 1.0f32.into()],
+        )
+            .unwrap();
+
+        command_buffer_builder.execute_commands_from_vec(cmds).unwrap();
+
+        command_buffer_builder
+            .end_render_pass().unwrap()
+            .copy_image_to_buffer(slot.object_id_buffer.image().clone(), buffer.clone())
+            .unwrap();
+
+        let cmd_buf = command_buffer_builder.build().unwrap();
+
+        let future: Box<dyn GpuFuture> = Box::new(cmd_buf.execute(self.gfx_queue.clone()).unwrap());
+        slot.fence = Some(future.then_signal_fence_and_flush().unwrap());
+
+        self.full_readback = Some(FullReadback {
+            slot: idx,
+            dims: img_dims,
+            buffer,
+            clear: object_id::to_bytes(self.clear_color),
+            pending: true,
+        });
+        Ok(())
+    }
+
+    // Ids of the last `submit_full` once the GPU is done with it, `None` before that and after
+    // the result was returned once. Never blocks.
+    pub fn poll_full(&mut self) -> Option<Vec<Option<u32>>> {
+        let full = self.full_readback.as_mut().filter(|full| full.pending)?;
+        let slot = &mut self.slots[full.slot];
+        if !slot.is_done() {
+            return None;
+        }
+        slot.wait();
+
+        let content = full.buffer.read().ok()?;
+        let ids = content.chunks_exact(4)
+            .map(|texel| texel_entity_id([texel[0], texel[1], texel[2], texel[3]], false, full.clear))
+            .collect();
+        drop(content);
+
+        full.pending = false;
+        Some(ids)
+    }
+
+    // A `submit_full` didn't come back through `poll_full` yet
+    pub fn is_full_pending(&self) -> bool {
+        self.full_readback.as_ref().is_some_and(|full| full.pending)
+    }
+
+    // A submitted pick didn't come back through `poll` yet
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // Result of the most recent finished pick, `None` if no pick finished since the last call.
+    // Never blocks.
+    pub fn poll(&mut self) -> Option<Option<u32>> {
+        let mut result = None;
+
+        while let Some(&idx) = self.pending.front() {
+            if !self.slots[idx].is_done() {
+                break;
+            }
+
+            self.pending.pop_front();
+            self.slots[idx].wait();
+            self.last_depth = self.slots[idx].depth();
+            result = Some(self.slots[idx].entity_id(object_id::to_bytes(self.clear_color)));
+        }
+
+        result
+    }
+}
+
+// One RGBA8 id map and a depth attachment, only stored with `keep_depth`. Transient otherwise,
+// which is all picking ids needs.
+fn create_render_pass(gfx_queue: Arc<Queue>, keep_depth: bool) -> Arc<RenderPass> {
+    let attachments = vec![
+        AttachmentDesc {
+            format: Format::R8G8B8A8Unorm,
+            samples: SampleCount::Sample1,
+            load: LoadOp::Clear,
+            store: StoreOp::Store,
+            stencil_load: LoadOp::DontCare,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::Undefined,
+            final_layout: ImageLayout::ColorAttachmentOptimal,
+        },
+        AttachmentDesc {
+            format: Format::D32Sfloat,
+            samples: SampleCount::Sample1,
+            load: LoadOp::Clear,
+            store: if keep_depth { StoreOp::Store } else { StoreOp::DontCare },
+            stencil_load: LoadOp::DontCare,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::Undefined,
+            final_layout: ImageLayout::DepthStencilAttachmentOptimal,
+        },
+    ];
+
+    let subpasses = vec![
+        SubpassDesc {
+            color_attachments: vec![(0, ImageLayout::ColorAttachmentOptimal)],
+            depth_stencil: Some((1, ImageLayout::DepthStencilAttachmentOptimal)),
+            input_attachments: vec![],
+            resolve_attachments: vec![],
+            preserve_attachments: vec![],
+        },
+    ];
+
+    Arc::new(RenderPass::new(gfx_queue.device().clone(), RenderPassDesc::new(attachments, subpasses, vec![])).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object_id;
+
+    use super::{region_entity_id, texel_entity_id};
+
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+    fn region(ids: &[Option<u32>]) -> Vec<u8> {
+        ids.iter()
+            .flat_map(|id| id.map_or(CLEAR, |id| object_id::to_bytes(object_id::encode(id))).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn single_texel() {
+        assert_eq!(region_entity_id(&region(&[Some(300)]), 0, false, CLEAR), Some(300));
+        assert_eq!(region_entity_id(&region(&[None]), 0, false, CLEAR), None);
+    }
+
+    #[test]
+    fn custom_clear_color_is_empty() {
+        let clear = [10, 20, 30, 255];
+        assert_eq!(texel_entity_id(clear, false, clear), None);
+        assert_eq!(texel_entity_id(clear, false, CLEAR), object_id::decode(clear));
+        assert_eq!(texel_entity_id(CLEAR, false, clear), None);
+    }
+
+    #[test]
+    fn packed_ids_are_offset_by_one() {
+        assert_eq!(region_entity_id(&0u32.to_ne_bytes(), 0, true, CLEAR), None);
+        assert_eq!(region_entity_id(&5u32.to_ne_bytes(), 0, true, CLEAR), Some(4));
+    }
+
+    #[test]
+    fn majority_wins_and_center_breaks_ties() {
+        let (a, b) = (Some(7), Some(9));
+        let majority = region(&[a, a, a, b, b, a, None, b, a]);
+        assert_eq!(region_entity_id(&majority, 4, false, CLEAR), Some(7));
+
+        let tie = region(&[a, b, None, a, b, None, None, None, None]);
+        assert_eq!(region_entity_id(&tie, 4, false, CLEAR), Some(9));
+        // Empty center: the lowest id
+        assert_eq!(region_entity_id(&tie, 8, false, CLEAR), Some(7));
     }
 }
@@ -0,0 +1,79 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use cgmath::{InnerSpace, Vector3};
+use vulkano::format::Format;
+use vulkano::image::{ImageDimensions, ImmutableImage, MipmapsCount};
+use vulkano::image::view::ImageView;
+
+use crate::base::upload::UploadBatch;
+
+// Tangent-space normal maps for `Terrain::set_normal_map`. x follows the u texcoord, y the v
+// texcoord and z points out of the surface, stored as `n * 0.5 + 0.5`.
+pub type NormalMap = Arc<ImageView<Arc<ImmutableImage>>>;
+
+// 1x1 map that leaves the normals unchanged
+pub fn flat(uploads: &mut UploadBatch) -> NormalMap {
+    from_rgba(uploads, 1, 1, vec![128, 128, 255, 255])
+}
+
+// `size`x`size` map of the bumps of the height field `f` over [0, 1)^2, in texture widths.
+// `f` should tile, the map repeats over the terrain.
+pub fn from_heights<F>(uploads: &mut UploadBatch, size: u32, f: F) -> NormalMap
+    where F: Fn(f32, f32) -> f32
+{
+    assert!(size >= 2, "a normal map needs at least 2 texels per axis");
+
+    let step = 1.0 / size as f32;
+    let mut data = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let u = x as f32 * step;
+            let v = y as f32 * step;
+            let du = (f(u + step, v) - f(u - step, v)) / (2.0 * step);
+            let dv = (f(u, v + step) - f(u, v - step)) / (2.0 * step);
+            let normal = Vector3::new(-du, -dv, 1.0).normalize();
+
+            data.extend_from_slice(&[encode(normal.x), encode(normal.y), encode(normal.z), 255]);
+        }
+    }
+
+    from_rgba(uploads, size, size, data)
+}
+
+// 8 bit RGB or RGBA png, e.g. exported from a texture tool with y pointing along +v
+#[allow(dead_code)]
+pub fn from_png(uploads: &mut UploadBatch, png_bytes: &[u8]) -> Result<NormalMap, String> {
+    let decoder = png::Decoder::new(Cursor::new(png_bytes));
+    let (info, mut reader) = decoder.read_info().map_err(|e| e.to_string())?;
+    if info.bit_depth != png::BitDepth::Eight {
+        return Err(format!("unsupported bit depth {:?}", info.bit_depth));
+    }
+
+    let mut image_data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut image_data).map_err(|e| e.to_string())?;
+
+    let data = match info.color_type {
+        png::ColorType::RGBA => image_data,
+        png::ColorType::RGB => image_data.chunks(3).flat_map(|c| vec![c[0], c[1], c[2], 255]).collect(),
+        other => return Err(format!("unsupported color type {:?}", other)),
+    };
+
+    Ok(from_rgba(uploads, info.width, info.height, data))
+}
+
+fn encode(value: f32) -> u8 {
+    ((value * 0.5 + 0.5) * 255.0).round() as u8
+}
+
+// Unorm, the vectors are data and must not be gamma decoded
+fn from_rgba(uploads: &mut UploadBatch, width: u32, height: u32, data: Vec<u8>) -> NormalMap {
+    let image = uploads.image(
+        data.into_iter(),
+        ImageDimensions::Dim2d { width, height, array_layers: 1 },
+        MipmapsCount::One,
+        Format::R8G8B8A8Unorm,
+    );
+
+    ImageView::new(image).unwrap()
+}
@@ -0,0 +1,58 @@
+// Block ids in the R8G8B8A8Unorm object id map of `mouse_picker`. The low 24 bits of the id go
+// into RGB, alpha marks that something was drawn at all. Both sides live here so they can't
+// drift apart.
+
+// Color of `id` for the id map shaders. Every channel is an exact multiple of 1/255, so the
+// Unorm target stores the bytes back unchanged.
+pub fn encode(id: u32) -> [f32; 4] {
+    debug_assert!(id < 1 << 24, "block id {} doesn't fit into the 24 bits of the id map", id);
+    [(id & 0xFF) as f32 / 255.0,
+        ((id >> 8) & 0xFF) as f32 / 255.0,
+        ((id >> 16) & 0xFF) as f32 / 255.0,
+        1.0]
+}
+
+// Default clear color of the id map, `decode` gives `None` for it
+pub const CLEAR: [f32; 4] = [0.0, 0.0, 0.0, 0.0];
+
+// Bytes the id map stores for `color`
+pub fn to_bytes(color: [f32; 4]) -> [u8; 4] {
+    let byte = |c: f32| (c.max(0.0).min(1.0) * 255.0).round() as u8;
+    [byte(color[0]), byte(color[1]), byte(color[2]), byte(color[3])]
+}
+
+// Id of a texel read back from the id map, `None` where nothing was drawn
+pub fn decode(rgba: [u8; 4]) -> Option<u32> {
+    let [r, g, b, a] = rgba;
+    if a == 0 {
+        None
+    } else {
+        Some(r as u32 | (g as u32) << 8 | (b as u32) << 16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, to_bytes, CLEAR};
+
+    #[test]
+    fn round_trips_through_bytes() {
+        // Every id up to 0x10000, the neighbours of the multiples of 255 and the largest id
+        let steps = (1..=0x10000u32).flat_map(|k| vec![k * 255 - 1, k * 255 + 1]);
+        for id in (0..=0x1_0000).chain(steps).chain(std::iter::once(0xFFFFFF)) {
+            assert_eq!(decode(to_bytes(encode(id))), Some(id), "id {:#x}", id);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn encode_rejects_ids_past_24_bits() {
+        encode(1 << 24);
+    }
+
+    #[test]
+    fn clear_decodes_to_none() {
+        assert_eq!(decode(to_bytes(CLEAR)), None);
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+use vulkano::device::Queue;
+use vulkano::query::{QueryPool, QueryResultFlags, QueryType};
+use vulkano::sync::GpuFuture;
+
+// One occlusion query per object id. Results of the previous frame decide what is drawn in
+// the current one; objects without a result yet are treated as visible.
+pub struct OcclusionQueries {
+    gfx_queue: Arc<Queue>,
+    pool: Arc<QueryPool>,
+
+    visible: Vec<bool>,
+    // Ids whose queries were recorded since the last reset, their results are still to be read
+    pending: Option<Vec<u32>>,
+}
+
+impl OcclusionQueries {
+    pub fn new(gfx_queue: Arc<Queue>, count: u32) -> OcclusionQueries {
+        let pool = Arc::new(
+            QueryPool::new(gfx_queue.device().clone(), QueryType::Occlusion, count).unwrap()
+        );
+
+        OcclusionQueries {
+            gfx_queue,
+            pool,
+            visible: vec![true; count as usize],
+            pending: None,
+        }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.visible.len() as u32
+    }
+
+    pub fn pool(&self) -> Arc<QueryPool> {
+        self.pool.clone()
+    }
+
+    pub fn is_visible(&self, id: u32) -> bool {
+        self.visible.get(id as usize).cloned().unwrap_or(true)
+    }
+
+    pub fn mark_pending(&mut self, ids: Vec<u32>) {
+        self.pending = Some(ids);
+    }
+
+    // False while the previous queries are in flight, they can't be recorded again until reset
+    pub fn can_record(&self) -> bool {
+        self.pending.is_none()
+    }
+
+    // Reads the results of the last recorded frame (without waiting) and resets the pool for
+    // the next one. Must be called outside of a render pass, before the queries are recorded.
+    // Results that are not available yet keep the previous visibility and the pool untouched.
+    pub fn begin_frame<F>(&mut self, before_future: F) -> Box<dyn GpuFuture>
+        where F: GpuFuture + 'static
+    {
+        if let Some(ids) = &self.pending {
+            let flags = QueryResultFlags { wait: false, with_availability: false, partial: false };
+            let mut results = Vec::with_capacity(ids.len());
+            // Only the recorded queries, the others never become available after a reset
+            for &id in ids {
+                let mut samples = [0u32];
+                let available = self.pool.queries_range(id..id + 1).unwrap()
+                    .get_results(&mut samples, flags)
+                    .unwrap_or(false);
+                if !available {
+                    return Box::new(before_future);
+                }
+                results.push((id, samples[0]));
+            }
+
+            update_visibility(&mut self.visible, &results);
+            self.pending = None;
+        }
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+
+        unsafe {
+            builder.reset_query_pool(self.pool.clone(), 0..self.len()).unwrap();
+        }
+
+        let cmd_buf = builder.build().unwrap();
+        Box::new(before_future.then_execute(self.gfx_queue.clone(), cmd_buf).unwrap())
+    }
+}
+
+// (id, samples passed) pairs, ids without a result keep their previous visibility
+pub fn update_visibility(visible: &mut [bool], results: &[(u32, u32)]) {
+    for &(id, samples) in results {
+        if let Some(visible) = visible.get_mut(id as usize) {
+            *visible = samples > 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::update_visibility;
+
+    #[test]
+    fn update_visibility_keeps_ids_without_results() {
+        let mut visible = vec![true, true, false, true];
+        update_visibility(&mut visible, &[(0, 0), (2, 12), (9, 0)]);
+
+        assert_eq!(visible, vec![false, true, true, true]);
+    }
+}
@@ -0,0 +1,169 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, ImmutableBuffer};
+
+use crate::base::upload::UploadBatch;
+
+#[derive(Default, Debug, Clone)]
+pub struct Vertex {
+    position: [f32; 3],
+    normal: [f32; 3],
+}
+vulkano::impl_vertex!(Vertex, position, normal);
+
+// Both meshes stand on the XZ plane at the origin and go up to `height` along +Y.
+// `segments` is the number of sides around the axis (at least 3).
+
+// Smooth sides, a flat cap at both ends.
+// Vertices: 4 * segments + 2, indices: 12 * segments.
+#[allow(dead_code)]
+pub struct Cylinder {
+    pub vertices: Arc<ImmutableBuffer<[Vertex]>>,
+    pub indices: Arc<ImmutableBuffer<[u32]>>,
+}
+
+#[allow(dead_code)]
+impl Cylinder {
+    pub fn new(uploads: &mut UploadBatch, radius: f32, height: f32, segments: u32) -> Cylinder {
+        let (vertices, indices) = cylinder_mesh(radius, height, segments);
+
+        Cylinder {
+            vertices: uploads.buffer(vertices.into_iter(), BufferUsage::vertex_buffer()),
+            indices: uploads.buffer(indices.into_iter(), BufferUsage::index_buffer()),
+        }
+    }
+}
+
+// Apex at the top, smooth sides and a flat base cap.
+// Vertices: 3 * segments + 1, indices: 6 * segments.
+#[allow(dead_code)]
+pub struct Cone {
+    pub vertices: Arc<ImmutableBuffer<[Vertex]>>,
+    pub indices: Arc<ImmutableBuffer<[u32]>>,
+}
+
+#[allow(dead_code)]
+impl Cone {
+    pub fn new(uploads: &mut UploadBatch, radius: f32, height: f32, segments: u32) -> Cone {
+        let (vertices, indices) = cone_mesh(radius, height, segments);
+
+        Cone {
+            vertices: uploads.buffer(vertices.into_iter(), BufferUsage::vertex_buffer()),
+            indices: uploads.buffer(indices.into_iter(), BufferUsage::index_buffer()),
+        }
+    }
+}
+
+fn cylinder_mesh(radius: f32, height: f32, segments: u32) -> (Vec<Vertex>, Vec<u32>) {
+    let n = segments.max(3);
+    let mut vertices = Vec::with_capacity((4 * n + 2) as usize);
+    let mut indices = Vec::with_capacity((12 * n) as usize);
+
+    // Sides: bottom ring, then top ring
+    for &y in [0.0, height].iter() {
+        for i in 0..n {
+            let (sin, cos) = segment_angle(i, n).sin_cos();
+            vertices.push(Vertex { position: [radius * cos, y, radius * sin], normal: [cos, 0.0, sin] });
+        }
+    }
+    for i in 0..n {
+        let (b0, b1) = (i, (i + 1) % n);
+        let (t0, t1) = (n + i, n + (i + 1) % n);
+        indices.extend_from_slice(&[b0, t0, b1, b1, t0, t1]);
+    }
+
+    // Caps get their own vertices so their normals stay flat
+    push_cap(&mut vertices, &mut indices, radius, 0.0, n, false);
+    push_cap(&mut vertices, &mut indices, radius, height, n, true);
+
+    (vertices, indices)
+}
+
+fn cone_mesh(radius: f32, height: f32, segments: u32) -> (Vec<Vertex>, Vec<u32>) {
+    let n = segments.max(3);
+    let mut vertices = Vec::with_capacity((3 * n + 1) as usize);
+    let mut indices = Vec::with_capacity((6 * n) as usize);
+
+    let side_normal = |angle: f32| -> [f32; 3] {
+        let (sin, cos) = angle.sin_cos();
+        let len = (height * height + radius * radius).sqrt();
+        [height * cos / len, radius / len, height * sin / len]
+    };
+
+    // Base ring
+    for i in 0..n {
+        let angle = segment_angle(i, n);
+        let (sin, cos) = angle.sin_cos();
+        vertices.push(Vertex { position: [radius * cos, 0.0, radius * sin], normal: side_normal(angle) });
+    }
+    // One apex per side, facing the middle of it, otherwise the tip shading collapses
+    for i in 0..n {
+        let angle = segment_angle(i, n) + PI / n as f32;
+        vertices.push(Vertex { position: [0.0, height, 0.0], normal: side_normal(angle) });
+    }
+    for i in 0..n {
+        indices.extend_from_slice(&[i, n + i, (i + 1) % n]);
+    }
+
+    push_cap(&mut vertices, &mut indices, radius, 0.0, n, false);
+
+    (vertices, indices)
+}
+
+fn segment_angle(i: u32, segments: u32) -> f32 {
+    2.0 * PI * i as f32 / segments as f32
+}
+
+// Fan around a center vertex at `y`, facing +Y if `up` and -Y otherwise
+fn push_cap(vertices: &mut Vec<Vertex>, indices: &mut Vec<u32>, radius: f32, y: f32, segments: u32, up: bool) {
+    let normal = if up { [0.0, 1.0, 0.0] } else { [0.0, -1.0, 0.0] };
+
+    let center = vertices.len() as u32;
+    vertices.push(Vertex { position: [0.0, y, 0.0], normal });
+    for i in 0..segments {
+        let (sin, cos) = segment_angle(i, segments).sin_cos();
+        vertices.push(Vertex { position: [radius * cos, y, radius * sin], normal });
+    }
+
+    for i in 0..segments {
+        let a = center + 1 + i;
+        let b = center + 1 + (i + 1) % segments;
+        if up {
+            indices.extend_from_slice(&[center, b, a]);
+        } else {
+            indices.extend_from_slice(&[center, a, b]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cone_mesh, cylinder_mesh};
+
+    #[test]
+    fn cylinder_counts() {
+        for &n in &[3, 8, 32] {
+            let (vertices, indices) = cylinder_mesh(1.0, 2.0, n);
+            assert_eq!(vertices.len(), (4 * n + 2) as usize);
+            assert_eq!(indices.len(), (12 * n) as usize);
+            assert!(indices.iter().all(|&i| (i as usize) < vertices.len()));
+        }
+    }
+
+    #[test]
+    fn cone_counts() {
+        for &n in &[3, 8, 32] {
+            let (vertices, indices) = cone_mesh(1.0, 2.0, n);
+            assert_eq!(vertices.len(), (3 * n + 1) as usize);
+            assert_eq!(indices.len(), (6 * n) as usize);
+            assert!(indices.iter().all(|&i| (i as usize) < vertices.len()));
+        }
+    }
+
+    #[test]
+    fn segments_are_clamped_to_three() {
+        assert_eq!(cylinder_mesh(1.0, 1.0, 1).0.len(), 14);
+        assert_eq!(cone_mesh(1.0, 1.0, 0).0.len(), 10);
+    }
+}
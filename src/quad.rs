@@ -0,0 +1,40 @@
+use vulkano::buffer::{ImmutableBuffer, BufferUsage};
+use std::sync::Arc;
+
+use crate::base::upload::UploadBatch;
+use crate::terrain::Vertex;
+
+// Plane on XZ centered at the origin, texcoords span [0, 1]. Wound like the cube and the
+// heightmap, so it works with the same counter-clockwise front face pipelines.
+#[allow(dead_code)]
+pub struct Quad {
+    pub vertices: Arc<ImmutableBuffer<[Vertex]>>,
+    pub indices: Arc<ImmutableBuffer<[u32]>>,
+}
+
+#[allow(dead_code)]
+impl Quad {
+    pub fn new(uploads: &mut UploadBatch, size: [f32; 2]) -> Quad {
+        let hw = size[0] / 2.0;
+        let hd = size[1] / 2.0;
+
+        let vertices = [
+            Vertex { position: [-hw, 0.0, hd], normal: [0.0, 1.0, 0.0], texcoord: [0.0, 0.0], tangent: [1.0, 0.0, 0.0, 1.0] },
+            Vertex { position: [hw, 0.0, hd], normal: [0.0, 1.0, 0.0], texcoord: [1.0, 0.0], tangent: [1.0, 0.0, 0.0, 1.0] },
+            Vertex { position: [hw, 0.0, -hd], normal: [0.0, 1.0, 0.0], texcoord: [1.0, 1.0], tangent: [1.0, 0.0, 0.0, 1.0] },
+            Vertex { position: [-hw, 0.0, -hd], normal: [0.0, 1.0, 0.0], texcoord: [0.0, 1.0], tangent: [1.0, 0.0, 0.0, 1.0] },
+        ];
+
+        let indices = [
+            0, 1, 3, 3, 1, 2,
+        ];
+
+        let bb = uploads.buffer(vertices.iter().cloned(), BufferUsage::vertex_buffer());
+        let ib = uploads.buffer(indices.iter().cloned(), BufferUsage::index_buffer());
+
+        Quad {
+            vertices: bb,
+            indices: ib,
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::camera::CameraState;
+use crate::deferred::lights::PointLight;
+use crate::terrain_game::{Map, MapData};
+
+// Bumped on every incompatible change of the file layout
+const SCENE_VERSION: u32 = 1;
+
+// Everything the demo saves to a single JSON file
+#[derive(Serialize, Deserialize)]
+pub struct Scene {
+    pub version: u32,
+    pub camera: CameraState,
+    pub map: MapData,
+    pub lights: Vec<PointLight>,
+}
+
+impl Scene {
+    pub fn new(camera: CameraState, map: &Map, lights: &[PointLight]) -> Scene {
+        Scene {
+            version: SCENE_VERSION,
+            camera,
+            map: map.data(),
+            lights: lights.to_vec(),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(path, json).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // Also checks that the map can be built, so a scene that loads can be applied as a whole
+    pub fn load(path: &str) -> Result<(Scene, Map), String> {
+        let json = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let scene: Scene = serde_json::from_str(&json).map_err(|e| format!("{}: {}", path, e))?;
+        if scene.version != SCENE_VERSION {
+            return Err(format!("{}: scene version {} is not supported (expected {})", path, scene.version, SCENE_VERSION));
+        }
+
+        let map = Map::from_data(&scene.map).map_err(|e| format!("{}: {}", path, e))?;
+        Ok((scene, map))
+    }
+}
@@ -1,20 +1,28 @@
 use std::io::Cursor;
 use std::sync::Arc;
 
-use cgmath::{InnerSpace, Matrix4, Vector3};
-use vulkano::buffer::{BufferUsage, CpuBufferPool, ImmutableBuffer};
-use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, SecondaryAutoCommandBuffer};
+use cgmath::{InnerSpace, Matrix4, Point3, SquareMatrix, Vector3};
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuBufferPool, DeviceLocalBuffer, ImmutableBuffer, TypedBufferAccess};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, PrimaryAutoCommandBuffer, SecondaryAutoCommandBuffer};
 use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
 use vulkano::device::Queue;
 use vulkano::format::Format;
-use vulkano::image::{ImageDimensions, ImmutableImage, MipmapsCount};
-use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::image::{ImageDimensions, ImmutableImage, MipmapsCount, StorageImage};
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract, GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::depth_stencil::{Compare, DepthStencil};
+use vulkano::pipeline::raster::{CullMode, FrontFace};
 use vulkano::pipeline::viewport::Viewport;
 use vulkano::render_pass::Subpass;
-use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use vulkano::sampler::{BorderColor, Filter, MipmapMode, Sampler, SamplerAddressMode};
 use vulkano::sync::GpuFuture;
 use vulkano::image::view::ImageView;
 
+use crate::base::pipeline::with_raster;
+use crate::base::render_stats::RenderCounters;
+use crate::base::upload::UploadBatch;
+use crate::normal_map;
+use crate::normal_map::NormalMap;
+
 #[allow(dead_code)]
 pub struct HeightMap {
     pub w: u32,
@@ -30,8 +38,7 @@ impl HeightMap {
         let decoder = png::Decoder::new(cursor);
 
         let (info, mut reader) = decoder.read_info().unwrap();
-        let mut image_data = Vec::new();
-        image_data.resize((info.width * info.height * 4) as usize, 0);
+        let mut image_data = vec![0; (info.width * info.height * 4) as usize];
         reader.next_frame(&mut image_data).unwrap();
 
         let w = info.width;
@@ -45,10 +52,18 @@ impl HeightMap {
     }
 
     pub fn empty(w: u32, h: u32) -> HeightMap {
+        HeightMap::from_fn(w, h, |_, _| 0.0)
+    }
+
+    // `f` gives the height of texel (x, y), positive up. `get_height` and `sample` return it
+    // negated, in world y.
+    pub fn from_fn<F>(w: u32, h: u32, f: F) -> HeightMap
+        where F: Fn(u32, u32) -> f32 + 'static
+    {
         HeightMap {
             w,
             h,
-            height_fn: Box::new(|_, _| -> f32 { 0.0 }),
+            height_fn: Box::new(f),
         }
     }
 
@@ -61,7 +76,7 @@ impl HeightMap {
                 return max;
             }
 
-            return val;
+            val
         };
 
         let xx = clamp(x, 0, (self.w - 1) as i32);
@@ -70,65 +85,146 @@ impl HeightMap {
 
         -fn_(xx as u32, yy as u32)
     }
+
+    // Bilinear height at `u`, `v` in [0, 1], spanning the whole grid (0 is the first row or
+    // column, 1 the last). Same units as `get_height`: world y, up is negative.
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let x = u.max(0.0).min(1.0) * (self.w - 1) as f32;
+        let y = v.max(0.0).min(1.0) * (self.h - 1) as f32;
+        let (x0, y0) = (x.floor() as i32, y.floor() as i32);
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+        let top = self.get_height(x0, y0) * (1.0 - fx) + self.get_height(x0 + 1, y0) * fx;
+        let bottom = self.get_height(x0, y0 + 1) * (1.0 - fx) + self.get_height(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
 }
 
 #[derive(Default, Debug, Clone)]
 pub struct Vertex {
-    position: [f32; 3],
-    normal: [f32; 3],
-    texcoord: [f32; 2],
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub texcoord: [f32; 2],
+    // Direction of growing texcoord.x, w is the sign of the bitangent (growing texcoord.y)
+    pub tangent: [f32; 4],
+}
+vulkano::impl_vertex!(Vertex, position, normal, texcoord, tangent);
+
+// Analytic ambient occlusion of the terrain, see `analytic_ao` in terrain.frag
+#[derive(Clone, Copy)]
+pub struct TerrainAo {
+    // Darkening per unit of height below the neighbours (relative to their distance), 0 disables
+    pub strength: f32,
+    // Darkening of vertical slopes, 0 disables
+    pub slope_strength: f32,
+    // Distance of the neighbours, in grid cells
+    pub radius: f32,
 }
-vulkano::impl_vertex!(Vertex, position, normal, texcoord);
 
 #[allow(dead_code)]
 pub struct Terrain {
     gfx_queue: Arc<Queue>,
     pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    // Needs the `fill_mode_non_solid` feature
+    wireframe_pipeline: Option<Arc<dyn GraphicsPipelineAbstract + Send + Sync>>,
+    shadow_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    // Draws into the picker id map, see `draw_object_id`
+    object_id_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    // Kept to rebuild the pipelines in `set_subpass`
+    cull_mode: CullMode,
+    front_face: FrontFace,
+    depth_stencil: DepthStencil,
     uniform_buffer: CpuBufferPool<vs::ty::Data>,
+    ao_buffer: CpuBufferPool<fs::ty::AoData>,
+    ao: TerrainAo,
+    // Textures projected along the world axes, no stretching on steep slopes
+    triplanar: bool,
+    // Multiplies the grid texcoords, see `new`
+    texture_scale: [f32; 2],
+    counters: RenderCounters,
 
     texture: Arc<ImageView<Arc<ImmutableImage>>>,
     sampler: Arc<Sampler>,
-    pub vertices: Arc<ImmutableBuffer<[Vertex]>>,
+    // GPU copy of `heights` for the occlusion term
+    height_texture: Arc<ImageView<Arc<StorageImage>>>,
+    height_sampler: Arc<Sampler>,
+    // Shade with `normal_texture` instead of the vertex normals, see `set_gpu_normals`
+    gpu_normals: bool,
+    // Generated from `height_texture` by `normals_pipeline`, only up to date with `gpu_normals`
+    normal_texture: Arc<ImageView<Arc<StorageImage>>>,
+    // `normal_texture` is regenerated by the next `flush`
+    normals_dirty: bool,
+    normals_pipeline: Arc<ComputePipeline>,
+    // Tiled like `texture`
+    normal_map: Option<NormalMap>,
+    // Bound when there is no `normal_map`, the shader skips the lookup then
+    placeholder_normal_map: NormalMap,
+    pub vertices: Arc<DeviceLocalBuffer<[Vertex]>>,
     pub indices: Arc<ImmutableBuffer<[u32]>>,
-
+    // First and last grid row edited since the last `flush`
+    dirty_rows: Option<[u32; 2]>,
+    vertex_staging: CpuBufferPool<Vertex>,
+    height_staging: CpuBufferPool<f32>,
+
+    // CPU copy of the grid, kept for runtime editing
+    w: u32,
+    h: u32,
+    heights: Vec<f32>,
+    mesh: Vec<Vertex>,
 }
 
 #[allow(dead_code)]
 impl Terrain {
-    pub fn new(gfx_queue: Arc<Queue>, height_map: HeightMap, subpass: Subpass) -> Terrain {
+    // `cull_mode` and `front_face` are for the filled pipeline, the demo uses back faces and
+    // counter-clockwise. `depth_compare` and `depth_write` apply to both pipelines drawing into
+    // `subpass`, the shadow pipeline keeps its own depth test.
+    //
+    // `address_mode` applies to the ground texture and the normal map on both axes, the demo
+    // tiles them with `Repeat`. `ClampToBorder` carries the border color, which has to be a
+    // float one since both textures are sampled as floats.
+    //
+    // `filter` is the min and mag filter of the same two textures, `Filter::Nearest` gives them
+    // a blocky pixel-art look, and `mipmap_mode` picks between their mip levels. The demo uses
+    // `Linear` and `MipmapMode::Nearest`. The modes combine freely, e.g. nearest texels with
+    // linear blending between mips.
+    //
+    // `texture_scale` multiplies the texcoords (one unit per grid cell) on x and y, so the
+    // textures tile independently of the mesh resolution. [1.0, 1.0] repeats them every 25 cells,
+    // the triplanar projections follow the same scale. Must be positive.
+    pub fn new(gfx_queue: Arc<Queue>, uploads: &mut UploadBatch, height_map: HeightMap, subpass: Subpass,
+               shadow_subpass: Subpass, object_id_subpass: Subpass, cull_mode: CullMode, front_face: FrontFace,
+               depth_compare: Compare, depth_write: bool, address_mode: SamplerAddressMode,
+               filter: Filter, mipmap_mode: MipmapMode, texture_scale: [f32; 2],
+               counters: RenderCounters) -> Terrain {
+        if let SamplerAddressMode::ClampToBorder(border_color) = address_mode {
+            match border_color {
+                BorderColor::FloatTransparentBlack | BorderColor::FloatOpaqueBlack | BorderColor::FloatOpaqueWhite => {}
+                _ => panic!("integer border color {:?} on a float texture", border_color),
+            }
+        }
+
+        let depth_stencil = DepthStencil {
+            depth_compare,
+            depth_write,
+            ..DepthStencil::simple_depth_test()
+        };
+
         let w = height_map.w;
         let h = height_map.h;
 
-        let mut vertices = Vec::with_capacity((h * w) as usize);
+        let mut heights = Vec::with_capacity((h * w) as usize);
         let mut indices = Vec::with_capacity((h * (w - 1) * 6) as usize);
 
-        let get_pos = |x: i32, y: i32| -> Vector3<f32> {
-            let height = height_map.get_height(x, y);
-            Vector3::new((x as f32) * 0.1, height, -(y as f32) * 0.1)
-        };
+        for y in 0..(h as i32) {
+            for x in 0..(w as i32) {
+                heights.push(height_map.get_height(x, y));
+            }
+        }
 
+        let mut vertices = Vec::with_capacity((h * w) as usize);
         for y in 0..(h as i32) {
             for x in 0..(w as i32) {
-                let pos = get_pos(x, y);
-
-                // Bottom left, Bottom right, Upper left
-                let l = get_pos(x - 1, y) - pos;
-                let t = get_pos(x, y + 1) - pos;
-                let r = get_pos(x + 1, y) - pos;
-                let b = get_pos(x, y - 1) - pos;
-
-                let lb = l.cross(b).normalize();
-                let br = b.cross(r).normalize();
-                let rt = r.cross(t).normalize();
-                let tl = t.cross(l).normalize();
-
-                let normal = -(lb + br + rt + tl).normalize();
-
-                vertices.push(Vertex {
-                    position: pos.into(), //[(x as f32) * 0.1, height, -(y as f32) * 0.1],
-                    normal: normal.into(),
-                    texcoord: [x as f32, y as f32],
-                });
+                vertices.push(build_vertex(&heights, w, h, x, y, texture_scale));
             }
         }
 
@@ -144,20 +240,34 @@ impl Terrain {
             }
         }
 
-        let (bb, fut) = {
-            ImmutableBuffer::from_iter(vertices.iter().cloned(), BufferUsage::vertex_buffer(), gfx_queue.clone()).unwrap()
-        };
+        let ib = uploads.buffer(indices.iter().cloned(), BufferUsage::index_buffer());
 
-        let (ib, fut2) = {
-            ImmutableBuffer::from_iter(indices.iter().cloned(), BufferUsage::index_buffer(), gfx_queue.clone()).unwrap()
-        };
+        let pipeline = create_pipeline(gfx_queue.clone(), subpass.clone(), cull_mode, front_face,
+                                       depth_stencil.clone());
+        let wireframe_pipeline = create_wireframe_pipeline(gfx_queue.clone(), subpass, depth_stencil.clone());
 
-        fut2.join(fut).then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+        let shadow_pipeline = {
+            let vs = vs::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+            let fs = fs_shadow::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(shadow_subpass)
+                .depth_stencil_simple_depth()
+                .build(gfx_queue.device().clone())
+                .unwrap())
+        };
 
-        let pipeline = {
+        let object_id_pipeline = {
             let vs = vs::Shader::load(gfx_queue.device().clone())
                 .expect("failed to create shader module");
-            let fs = fs::Shader::load(gfx_queue.device().clone())
+            let fs = fs_object_id::Shader::load(gfx_queue.device().clone())
                 .expect("failed to create shader module");
 
             Arc::new(GraphicsPipeline::start()
@@ -166,55 +276,306 @@ impl Terrain {
                 .triangle_list()
                 .viewports_dynamic_scissors_irrelevant(1)
                 .fragment_shader(fs.main_entry_point(), ())
-                .render_pass(subpass)
-                .cull_mode_back()
-                .front_face_counter_clockwise()
-//        .polygon_mode_line()
+                .render_pass(object_id_subpass)
                 .depth_stencil_simple_depth()
                 .build(gfx_queue.device().clone())
                 .unwrap())
         };
 
         let uniform_buffer = CpuBufferPool::<vs::ty::Data>::new(gfx_queue.device().clone(), BufferUsage::all());
+        let ao_buffer = CpuBufferPool::<fs::ty::AoData>::new(gfx_queue.device().clone(), BufferUsage::all());
 
-        let (texture, tex_future) = {
+        let texture = {
             let png_bytes = include_bytes!("static/ground.png").to_vec();
             let cursor = Cursor::new(png_bytes);
             let decoder = png::Decoder::new(cursor);
             let (info, mut reader) = decoder.read_info().unwrap();
             let dimensions = ImageDimensions::Dim2d { width: info.width, height: info.height, array_layers: 0 }; // FIXME: check need array=0 or array=1?
-            let mut image_data = Vec::new();
-            image_data.resize((info.width * info.height * 4) as usize, 0);
+            let mut image_data = vec![0; (info.width * info.height * 4) as usize];
             reader.next_frame(&mut image_data).unwrap();
 
-            let (image, future) = ImmutableImage::from_iter(
+            let image = uploads.image(
                 image_data.iter().cloned(),
                 dimensions,
-                MipmapsCount::One,
+                // Blitted down to 1x1 on upload, for `mipmap_mode` to blend between
+                MipmapsCount::Log2,
                 Format::R8G8B8A8Srgb,
-                gfx_queue.clone(),
-            ).unwrap();
+            );
 
-            (ImageView::new(image), future)
+            ImageView::new(image)
         };
 
-        tex_future.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+        let sampler = Sampler::new(gfx_queue.device().clone(), filter, filter,
+                                   mipmap_mode, address_mode, address_mode,
+                                   address_mode, 0.0, 5.0, 0.0, 0.0).unwrap();
+
+        // Filled by the first `flush`
+        let bb = create_vertex_buffer(&gfx_queue, vertices.len());
+        let height_texture = create_height_texture(&gfx_queue, w, h);
+        let vertex_staging = CpuBufferPool::upload(gfx_queue.device().clone());
+        let height_staging = CpuBufferPool::upload(gfx_queue.device().clone());
+        let height_sampler = Sampler::new(gfx_queue.device().clone(), Filter::Linear, Filter::Linear,
+                                          MipmapMode::Nearest, SamplerAddressMode::ClampToEdge,
+                                          SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                          0.0, 1.0, 0.0, 0.0).unwrap();
+        let placeholder_normal_map = normal_map::flat(uploads);
+
+        let normal_texture = create_normal_texture(&gfx_queue, w, h);
+        let normals_pipeline = {
+            let cs = cs_normals::Shader::load(gfx_queue.device().clone())
+                .expect("failed to create shader module");
+
+            Arc::new(ComputePipeline::new(gfx_queue.device().clone(), &cs.main_entry_point(), &(), None)
+                .unwrap())
+        };
 
-        let sampler = Sampler::new(gfx_queue.device().clone(), Filter::Linear, Filter::Linear,
-                                   MipmapMode::Nearest, SamplerAddressMode::Repeat, SamplerAddressMode::Repeat,
-                                   SamplerAddressMode::Repeat, 0.0, 5.0, 0.0, 0.0).unwrap();
         Terrain {
             gfx_queue,
+            w,
+            h,
+            heights,
+            mesh: vertices,
             pipeline,
+            wireframe_pipeline,
+            shadow_pipeline,
+            object_id_pipeline,
+            cull_mode,
+            front_face,
+            depth_stencil,
             uniform_buffer,
+            ao_buffer,
+            ao: TerrainAo { strength: 1.0, slope_strength: 0.3, radius: 2.0 },
+            triplanar: false,
+            texture_scale,
+            counters,
             sampler,
+            height_texture,
+            height_sampler,
+            gpu_normals: false,
+            normal_texture,
+            normals_dirty: false,
+            normals_pipeline,
+            normal_map: None,
+            placeholder_normal_map,
             texture: texture.unwrap(),
             vertices: bb,
             indices: ib,
+            dirty_rows: Some([0, h - 1]),
+            vertex_staging,
+            height_staging,
+        }
+    }
+
+    // Winding of the front faces of the filled pipeline, e.g. flipped for a left-handed camera,
+    // see `Camera::set_right_handed`. Rebuilds it.
+    pub fn set_front_face(&mut self, front_face: FrontFace) {
+        self.front_face = front_face;
+        self.pipeline = create_pipeline(self.gfx_queue.clone(), self.pipeline.subpass().clone(), self.cull_mode,
+                                        self.front_face, self.depth_stencil.clone());
+    }
+
+    // Rebuilds the pipelines drawing into `subpass` of `new`, e.g. after its sample count changed
+    pub fn set_subpass(&mut self, subpass: Subpass) {
+        self.pipeline = create_pipeline(self.gfx_queue.clone(), subpass.clone(), self.cull_mode, self.front_face,
+                                        self.depth_stencil.clone());
+        self.wireframe_pipeline = create_wireframe_pipeline(self.gfx_queue.clone(), subpass, self.depth_stencil.clone());
+    }
+
+    // Raises (positive `delta`) or lowers the terrain around `center` with a linear falloff
+    // up to `radius` (both in world units). Cells outside the grid are ignored. The GPU copy is
+    // updated by the next `flush`.
+    pub fn apply_brush(&mut self, center: Point3<f32>, radius: f32, delta: f32) {
+        let cx = center.x / CELL_SIZE;
+        let cy = -center.z / CELL_SIZE;
+        let r = radius / CELL_SIZE;
+
+        if r <= 0.0 || cx + r < 0.0 || cy + r < 0.0
+            || cx - r > (self.w - 1) as f32 || cy - r > (self.h - 1) as f32 {
+            return;
+        }
+
+        let x0 = (cx - r).floor().max(0.0) as u32;
+        let y0 = (cy - r).floor().max(0.0) as u32;
+        let x1 = ((cx + r).ceil() as u32).min(self.w - 1);
+        let y1 = ((cy + r).ceil() as u32).min(self.h - 1);
+
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let dist = ((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)).sqrt();
+                if dist > r {
+                    continue;
+                }
+
+                self.heights[(y * self.w + x) as usize] += delta * (1.0 - dist / r);
+            }
+        }
+
+        if self.gpu_normals {
+            // Only the heights, `generate_normals` takes care of the normals
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    let i = (y * self.w + x) as usize;
+                    self.mesh[i].position[1] = self.heights[i];
+                }
+            }
+        } else {
+            // Normals of the direct neighbours depend on the edited heights too
+            for y in y0.saturating_sub(1)..=(y1 + 1).min(self.h - 1) {
+                for x in x0.saturating_sub(1)..=(x1 + 1).min(self.w - 1) {
+                    self.mesh[(y * self.w + x) as usize] = build_vertex(&self.heights, self.w, self.h, x as i32,
+                                                                         y as i32, self.texture_scale);
+                }
+            }
+        }
+
+        // The CPU normals of the neighbour rows changed too
+        self.mark_dirty(y0.saturating_sub(1), (y1 + 1).min(self.h - 1));
+        self.normals_dirty = self.gpu_normals;
+    }
+
+    fn mark_dirty(&mut self, y0: u32, y1: u32) {
+        self.dirty_rows = Some(match self.dirty_rows {
+            Some([first, last]) => [first.min(y0), last.max(y1)],
+            None => [y0, y1],
+        });
+    }
+
+    // Copies the rows edited since the last call to the GPU and regenerates the GPU normals if
+    // needed, after `before_future`. Has to run before the terrain is drawn. The edited rows go
+    // into new buffers, the rest is copied over on the GPU, so frames still in flight keep
+    // reading the previous ones and nothing waits.
+    pub fn flush<F>(&mut self, before_future: F) -> Box<dyn GpuFuture>
+        where F: GpuFuture + 'static
+    {
+        if self.dirty_rows.is_none() && !self.normals_dirty {
+            return Box::new(before_future);
+        }
+
+        let mut builder = AutoCommandBufferBuilder::primary(self.gfx_queue.device().clone(),
+                                                            self.gfx_queue.family(),
+                                                            CommandBufferUsage::OneTimeSubmit).unwrap();
+        if let Some([y0, y1]) = self.dirty_rows.take() {
+            self.upload_rows(&mut builder, y0, y1);
+        }
+        if self.normals_dirty {
+            self.normals_dirty = false;
+            self.generate_normals(&mut builder);
+        }
+
+        Box::new(before_future.then_execute(self.gfx_queue.clone(), builder.build().unwrap()).unwrap())
+    }
+
+    fn upload_rows(&mut self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>, y0: u32, y1: u32) {
+        let (first, end) = ((y0 * self.w) as usize, ((y1 + 1) * self.w) as usize);
+        let vertices = create_vertex_buffer(&self.gfx_queue, self.mesh.len());
+        let height_texture = create_height_texture(&self.gfx_queue, self.w, self.h);
+
+        // Rows above and below the edit are unchanged
+        for &(start, stop) in [(0, first), (end, self.mesh.len())].iter() {
+            if start < stop {
+                builder.copy_buffer(self.vertices.clone().into_buffer_slice().slice(start..stop).unwrap(),
+                                    vertices.clone().into_buffer_slice().slice(start..stop).unwrap())
+                    .unwrap();
+            }
+        }
+        for &(start, stop) in [(0, y0), (y1 + 1, self.h)].iter() {
+            if start < stop {
+                builder.copy_image(ImageView::image(&self.height_texture).clone(), [0, start as i32, 0], 0, 0,
+                                   ImageView::image(&height_texture).clone(), [0, start as i32, 0], 0, 0,
+                                   [self.w, stop - start, 1], 1)
+                    .unwrap();
+            }
+        }
+
+        let mesh = self.vertex_staging.chunk(self.mesh[first..end].iter().cloned()).unwrap();
+        builder.copy_buffer(mesh, vertices.clone().into_buffer_slice().slice(first..end).unwrap()).unwrap();
+        let heights = self.height_staging.chunk(self.heights[first..end].iter().cloned()).unwrap();
+        builder.copy_buffer_to_image_dimensions(heights, ImageView::image(&height_texture).clone(),
+                                                [0, y0, 0], [self.w, y1 - y0 + 1, 1], 0, 1, 0)
+            .unwrap();
+
+        self.vertices = vertices;
+        self.height_texture = height_texture;
+    }
+
+    pub fn gpu_normals(&self) -> bool {
+        self.gpu_normals
+    }
+
+    // Generates the normals from the height texture with a compute shader instead of the cross
+    // products of `build_vertex`, so `apply_brush` only has to update the heights on the CPU.
+    // The vertex normals are the fallback and go stale while this is on, turning it off
+    // rebuilds them.
+    pub fn set_gpu_normals(&mut self, gpu_normals: bool) {
+        if gpu_normals == self.gpu_normals {
+            return;
         }
+
+        self.gpu_normals = gpu_normals;
+        if gpu_normals {
+            self.normals_dirty = true;
+        } else {
+            for y in 0..self.h {
+                for x in 0..self.w {
+                    self.mesh[(y * self.w + x) as usize] = build_vertex(&self.heights, self.w, self.h, x as i32,
+                                                                         y as i32, self.texture_scale);
+                }
+            }
+            self.mark_dirty(0, self.h - 1);
+        }
+    }
+
+    // Records filling a new `normal_texture` from `height_texture`, for the same reason as
+    // `upload_rows`
+    fn generate_normals(&mut self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        self.normal_texture = create_normal_texture(&self.gfx_queue, self.w, self.h);
+
+        let layout = self.normals_pipeline.layout().descriptor_set_layout(0).unwrap();
+        let set = PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(self.height_texture.clone(), self.height_sampler.clone())
+            .unwrap()
+            .add_image(self.normal_texture.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let groups = [self.w.div_ceil(NORMALS_GROUP_SIZE), self.h.div_ceil(NORMALS_GROUP_SIZE), 1];
+        builder
+            .dispatch(groups, self.normals_pipeline.clone(), set,
+                      cs_normals::ty::PushConstants { cell_size: CELL_SIZE }, vec![])
+            .unwrap();
+    }
+
+    pub fn ao(&self) -> TerrainAo {
+        self.ao
+    }
+
+    pub fn set_ao(&mut self, ao: TerrainAo) {
+        self.ao = ao;
+    }
+
+    // Detail normals over the vertex normals, `None` shades with the vertex normals only.
+    // See `normal_map` for loaders.
+    pub fn set_normal_map(&mut self, normal_map: Option<NormalMap>) {
+        self.normal_map = normal_map;
     }
 
-    pub fn draw(&self, viewport_dimensions: [u32; 2], world: Matrix4<f32>, view: Matrix4<f32>, proj: Matrix4<f32>) -> SecondaryAutoCommandBuffer {
+    pub fn triplanar(&self) -> bool {
+        self.triplanar
+    }
+
+    pub fn set_triplanar(&mut self, triplanar: bool) {
+        self.triplanar = triplanar;
+    }
+
+    // `wireframe` draws lines instead of filled triangles, when the device supports it
+    pub fn draw(&self, viewport_dimensions: [u32; 2], world: Matrix4<f32>, view: Matrix4<f32>, proj: Matrix4<f32>,
+                wireframe: bool) -> SecondaryAutoCommandBuffer {
+        let pipeline = match &self.wireframe_pipeline {
+            Some(wireframe_pipeline) if wireframe => wireframe_pipeline.clone(),
+            _ => self.pipeline.clone(),
+        };
+
         let uniform_buffer_subbuffer = {
             let uniform_data = vs::ty::Data {
                 world: world.into(),
@@ -225,8 +586,16 @@ impl Terrain {
             self.uniform_buffer.next(uniform_data).unwrap()
         };
 
+        let ao_subbuffer = self.ao_buffer.next(fs::ty::AoData {
+            grid_size: [self.w as f32, self.h as f32],
+            cell_size: CELL_SIZE,
+            radius: self.ao.radius,
+            strength: self.ao.strength,
+            slope_strength: self.ao.slope_strength,
+            texture_scale: self.texture_scale,
+        }).unwrap();
 
-        let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
+        let layout = pipeline.layout().descriptor_set_layout(0).unwrap();
 
         let set = Arc::new(
             PersistentDescriptorSet::start(layout.clone())
@@ -234,6 +603,15 @@ impl Terrain {
                 .unwrap()
                 .add_sampled_image(self.texture.clone(), self.sampler.clone())
                 .unwrap()
+                .add_sampled_image(self.height_texture.clone(), self.height_sampler.clone())
+                .unwrap()
+                .add_buffer(ao_subbuffer)
+                .unwrap()
+                .add_sampled_image(self.normal_map.clone().unwrap_or(self.placeholder_normal_map.clone()),
+                                   self.sampler.clone())
+                .unwrap()
+                .add_sampled_image(self.normal_texture.clone(), self.height_sampler.clone())
+                .unwrap()
                 .build()
                 .unwrap()
         );
@@ -241,9 +619,10 @@ impl Terrain {
         let mut builder = AutoCommandBufferBuilder::secondary_graphics(self.gfx_queue.device().clone(),
                                                      self.gfx_queue.family(),
                                                      CommandBufferUsage::MultipleSubmit,
-                                                     self.pipeline.subpass().clone()).unwrap();
+                                                     pipeline.subpass().clone()).unwrap();
+        self.counters.draw(1, self.indices.len() as u64 / 3);
         builder.draw_indexed(
-                self.pipeline.clone(),
+                pipeline.clone(),
                 &DynamicState {
                     viewports: Some(vec![Viewport {
                         origin: [0.0, 0.0],
@@ -256,6 +635,109 @@ impl Terrain {
                 vec![self.vertices.clone()],
                 self.indices.clone(),
                 set.clone(),
+                fs::ty::PushConstants {
+                    use_normal_map: self.normal_map.is_some() as i32,
+                    triplanar: self.triplanar as i32,
+                    gpu_normals: self.gpu_normals as i32,
+                },
+                vec![],
+            )
+            .unwrap();
+
+        builder.build().unwrap()
+    }
+
+    // Draws into the picker id map (the `object_id_subpass` of `new`) in `clear_color`, the color
+    // of "no object". The terrain hides the blocks behind it and leaves its depth for
+    // `Picker::pick_depth`.
+    pub fn draw_object_id(&self, viewport_dimensions: [u32; 2], view: Matrix4<f32>, proj: Matrix4<f32>,
+                          clear_color: [f32; 4]) -> SecondaryAutoCommandBuffer {
+        let uniform_buffer_subbuffer = {
+            let uniform_data = vs::ty::Data {
+                world: Matrix4::identity().into(),
+                view: view.into(),
+                proj: proj.into(),
+            };
+
+            self.uniform_buffer.next(uniform_data).unwrap()
+        };
+
+        let layout = self.object_id_pipeline.layout().descriptor_set_layout(0).unwrap();
+        let set = Arc::new(
+            PersistentDescriptorSet::start(layout.clone())
+                .add_buffer(uniform_buffer_subbuffer)
+                .unwrap()
+                .build()
+                .unwrap()
+        );
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(self.gfx_queue.device().clone(),
+                                                     self.gfx_queue.family(),
+                                                     CommandBufferUsage::OneTimeSubmit,
+                                                     self.object_id_pipeline.subpass().clone()).unwrap();
+        self.counters.draw(1, self.indices.len() as u64 / 3);
+        builder.draw_indexed(
+                self.object_id_pipeline.clone(),
+                &DynamicState {
+                    viewports: Some(vec![Viewport {
+                        origin: [0.0, 0.0],
+                        dimensions: [viewport_dimensions[0] as f32,
+                            viewport_dimensions[1] as f32],
+                        depth_range: 0.0..1.0,
+                    }]),
+                    ..DynamicState::none()
+                },
+                vec![self.vertices.clone()],
+                self.indices.clone(),
+                set,
+                fs_object_id::ty::PushConstants { color: clear_color },
+                vec![],
+            )
+            .unwrap();
+
+        builder.build().unwrap()
+    }
+
+    // Depth-only draw into the shadow map subpass
+    pub fn draw_shadow(&self, viewport_dimensions: [u32; 2], light_view_proj: Matrix4<f32>) -> SecondaryAutoCommandBuffer {
+        let uniform_buffer_subbuffer = {
+            let uniform_data = vs::ty::Data {
+                world: Matrix4::identity().into(),
+                view: Matrix4::identity().into(),
+                proj: light_view_proj.into(),
+            };
+
+            self.uniform_buffer.next(uniform_data).unwrap()
+        };
+
+        let layout = self.shadow_pipeline.layout().descriptor_set_layout(0).unwrap();
+        let set = Arc::new(
+            PersistentDescriptorSet::start(layout.clone())
+                .add_buffer(uniform_buffer_subbuffer)
+                .unwrap()
+                .build()
+                .unwrap()
+        );
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(self.gfx_queue.device().clone(),
+                                                     self.gfx_queue.family(),
+                                                     CommandBufferUsage::OneTimeSubmit,
+                                                     self.shadow_pipeline.subpass().clone()).unwrap();
+        self.counters.draw(1, self.indices.len() as u64 / 3);
+        builder.draw_indexed(
+                self.shadow_pipeline.clone(),
+                &DynamicState {
+                    viewports: Some(vec![Viewport {
+                        origin: [0.0, 0.0],
+                        dimensions: [viewport_dimensions[0] as f32,
+                            viewport_dimensions[1] as f32],
+                        depth_range: 0.0..1.0,
+                    }]),
+                    ..DynamicState::none()
+                },
+                vec![self.vertices.clone()],
+                self.indices.clone(),
+                set,
                 (),
                 vec![],
             )
@@ -265,6 +747,123 @@ impl Terrain {
     }
 }
 
+const CELL_SIZE: f32 = 0.1;
+
+// Must match `local_size_x/y` in normals.comp
+const NORMALS_GROUP_SIZE: u32 = 8;
+
+fn create_pipeline(gfx_queue: Arc<Queue>, subpass: Subpass, cull_mode: CullMode, front_face: FrontFace,
+                   depth_stencil: DepthStencil) -> Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
+    let vs = vs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+    let fs = fs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+
+    let builder = GraphicsPipeline::start()
+        .vertex_input_single_buffer::<Vertex>()
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs.main_entry_point(), ())
+        .render_pass(subpass)
+        .depth_stencil(depth_stencil);
+    let builder = with_raster(builder, cull_mode, front_face);
+
+    Arc::new(builder.build(gfx_queue.device().clone()).unwrap())
+}
+
+// Needs the `fill_mode_non_solid` feature, `None` without it
+fn create_wireframe_pipeline(gfx_queue: Arc<Queue>, subpass: Subpass, depth_stencil: DepthStencil)
+                             -> Option<Arc<dyn GraphicsPipelineAbstract + Send + Sync>> {
+    if !gfx_queue.device().enabled_features().fill_mode_non_solid {
+        return None;
+    }
+
+    let vs = vs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+    let fs = fs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+
+    Some(Arc::new(GraphicsPipeline::start()
+        .vertex_input_single_buffer::<Vertex>()
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs.main_entry_point(), ())
+        .render_pass(subpass)
+        .polygon_mode_line()
+        .depth_stencil(depth_stencil)
+        .build(gfx_queue.device().clone())
+        .unwrap()))
+}
+
+// Written by `Terrain::upload_rows`
+fn create_vertex_buffer(gfx_queue: &Arc<Queue>, len: usize) -> Arc<DeviceLocalBuffer<[Vertex]>> {
+    let usage = BufferUsage {
+        transfer_source: true,
+        ..BufferUsage::vertex_buffer_transfer_destination()
+    };
+    DeviceLocalBuffer::array(gfx_queue.device().clone(), len, usage, Some(gfx_queue.family())).unwrap()
+}
+
+// Written by `Terrain::upload_rows`
+fn create_height_texture(gfx_queue: &Arc<Queue>, w: u32, h: u32) -> Arc<ImageView<Arc<StorageImage>>> {
+    ImageView::new(StorageImage::new(
+        gfx_queue.device().clone(),
+        ImageDimensions::Dim2d { width: w, height: h, array_layers: 1 },
+        Format::R32Sfloat,
+        Some(gfx_queue.family()),
+    ).unwrap()).unwrap()
+}
+
+// Written by `Terrain::generate_normals`
+fn create_normal_texture(gfx_queue: &Arc<Queue>, w: u32, h: u32) -> Arc<ImageView<Arc<StorageImage>>> {
+    ImageView::new(StorageImage::new(
+        gfx_queue.device().clone(),
+        ImageDimensions::Dim2d { width: w, height: h, array_layers: 1 },
+        Format::R16G16B16A16Sfloat,
+        Some(gfx_queue.family()),
+    ).unwrap()).unwrap()
+}
+
+fn grid_position(heights: &[f32], w: u32, h: u32, x: i32, y: i32) -> Vector3<f32> {
+    let xx = x.max(0).min(w as i32 - 1);
+    let yy = y.max(0).min(h as i32 - 1);
+    let height = heights[(yy * w as i32 + xx) as usize];
+
+    Vector3::new((x as f32) * CELL_SIZE, height, -(y as f32) * CELL_SIZE)
+}
+
+fn build_vertex(heights: &[f32], w: u32, h: u32, x: i32, y: i32, texture_scale: [f32; 2]) -> Vertex {
+    let get_pos = |x: i32, y: i32| grid_position(heights, w, h, x, y);
+    let pos = get_pos(x, y);
+
+    // Bottom left, Bottom right, Upper left
+    let l = get_pos(x - 1, y) - pos;
+    let t = get_pos(x, y + 1) - pos;
+    let r = get_pos(x + 1, y) - pos;
+    let b = get_pos(x, y - 1) - pos;
+
+    let lb = l.cross(b).normalize();
+    let br = b.cross(r).normalize();
+    let rt = r.cross(t).normalize();
+    let tl = t.cross(l).normalize();
+
+    let normal = -(lb + br + rt + tl).normalize();
+
+    // Central differences of the position along the texcoords
+    let tangent = (r - l).normalize();
+    let bitangent = t - b;
+    let handedness = if normal.cross(tangent).dot(bitangent) < 0.0 { -1.0 } else { 1.0 };
+
+    Vertex {
+        position: pos.into(),
+        normal: normal.into(),
+        texcoord: [x as f32 * texture_scale[0], y as f32 * texture_scale[1]],
+        tangent: [tangent.x, tangent.y, tangent.z, handedness],
+    }
+}
+
 mod vs {
     vulkano_shaders::shader! {
         ty: "vertex",
@@ -278,3 +877,42 @@ mod fs {
         bytes: "resources/shaders/heightmap/terrain.frag.spv"
     }
 }
+
+mod fs_object_id {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/heightmap/object_id.frag.spv"
+    }
+}
+
+mod fs_shadow {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/shadow/depth.frag.spv"
+    }
+}
+
+mod cs_normals {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        bytes: "resources/shaders/heightmap/normals.comp.spv"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeightMap;
+
+    #[test]
+    fn sample_interpolates_between_texels() {
+        // Height x + 10 * y
+        let height_map = HeightMap::from_fn(3, 2, |x, y| (x + 10 * y) as f32);
+
+        assert_eq!(height_map.sample(0.0, 0.0), 0.0);
+        assert_eq!(height_map.sample(1.0, 1.0), -12.0);
+        assert_eq!(height_map.sample(0.25, 0.0), -0.5);
+        assert_eq!(height_map.sample(0.5, 0.5), -6.0);
+        // Clamped to the edges
+        assert_eq!(height_map.sample(-1.0, 2.0), -10.0);
+    }
+}
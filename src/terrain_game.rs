@@ -1,4 +1,14 @@
-use std::time::Instant;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, BTreeSet, HashMap, VecDeque};
+
+use cgmath::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::terrain::HeightMap;
+use crate::terrain_render_system::BlockGrid;
+
+// Number of block materials the renderer provides
+pub const BLOCK_MATERIALS: u32 = 3;
 
 #[derive(Clone, PartialEq)]
 #[allow(dead_code)]
@@ -13,24 +23,135 @@ pub struct TerrainBlock {
     pub id: u32,
     pub x: u32,
     pub y: u32,
+    // Stack level in whole cubes, level 0 sits on the ground. Visually up is -y, so the block
+    // spans [-height - 1, -height] on world y.
+    pub height: u32,
 
     pub selected: bool,
-    pub selected_time: Instant,
+    // Simulated seconds since the block got selected, advanced by `Map::update`
+    pub selected_age: f32,
 
     pub highlighted: bool,
-    pub hightligh_start: Instant,
+    // Simulated seconds since the block got highlighted or lost the highlight, advanced by
+    // `Map::update` while highlighted
+    pub highlight_age: f32,
 
     pub state: BlockState,
+    // Index into the materials of `TerrainRenderSystem`, below `BLOCK_MATERIALS`
+    pub material: u32,
+    // Around the center of the block footprint, so scaled blocks stay on the ground. Every
+    // render pipeline applies it, but `Map::pick_ray` still assumes unit blocks.
+    pub scale: [f32; 3],
+    // Around the vertical axis, in radians
+    pub rotation: f32,
+}
+
+impl TerrainBlock {
+    fn new(id: u32, x: u32, y: u32, state: BlockState) -> TerrainBlock {
+        TerrainBlock {
+            id,
+            x,
+            y,
+            height: 0,
+            selected: false,
+            selected_age: 0.0,
+            highlighted: false,
+            highlight_age: 0.0,
+            state,
+            material: 0,
+            scale: [1.0, 1.0, 1.0],
+            rotation: 0.0,
+        }
+    }
+}
+
+// Saved part of a block, see `MapData`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BlockData {
+    pub x: u32,
+    pub y: u32,
+    pub cleared: bool,
+    pub material: u32,
+    // Missing in scenes saved before blocks could be stacked
+    #[serde(default)]
+    pub height: u32,
+    // Missing in scenes saved before blocks could be scaled and rotated
+    #[serde(default = "unit_scale")]
+    pub scale: [f32; 3],
+    #[serde(default)]
+    pub rotation: f32,
+}
+
+fn unit_scale() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+// Saved map, see `Map::data`. Selection, highlights and the undo history are not kept.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MapData {
+    pub w: u32,
+    pub h: u32,
+    pub blocks: Vec<BlockData>,
 }
 
+#[derive(Clone, PartialEq)]
+struct BlockSnapshot {
+    state: BlockState,
+    selected: bool,
+}
+
+impl BlockSnapshot {
+    fn of(block: &TerrainBlock) -> BlockSnapshot {
+        BlockSnapshot { state: block.state.clone(), selected: block.selected }
+    }
+}
+
+// Change of a single block (index into `Map::blocks`)
+struct BlockChange {
+    index: usize,
+    before: BlockSnapshot,
+    after: BlockSnapshot,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum Connectivity {
+    Four,
+    // Diagonal steps cost sqrt(2); squeezing between two walls touching by corner is not allowed
+    Eight,
+}
+
+const DEFAULT_HISTORY_DEPTH: usize = 100;
+
+// Seconds a selected block stays before `Map::update` clears it
+const SELECTED_CLEAR_DELAY: f32 = 0.5;
+
+// Largest map `Map::from_data` loads, the renderer keeps per-cell state
+const MAX_MAP_CELLS: u32 = 4096 * 4096;
+
 pub struct Map {
     pub changed: bool,
     pub w: u32,
     pub h: u32,
-    pub blocks: Vec<TerrainBlock>,
+    // Cleared blocks included, read through `blocks` and `block`. Only changed here so `index`,
+    // `active` and the undo history stay in sync.
+    blocks: Vec<TerrainBlock>,
+
+    // Block id -> slot in `blocks`
+    index: HashMap<u32, usize>,
+    // Slots of blocks that are not `Cleared`, in `blocks` order
+    active: BTreeSet<usize>,
+    // Slot of the only block with `highlighted` set
+    highlighted: Option<usize>,
+
+    // Each entry is one user-visible operation, possibly touching several blocks
+    undo_stack: VecDeque<Vec<BlockChange>>,
+    redo_stack: Vec<Vec<BlockChange>>,
+    history_depth: usize,
 }
 
 impl Map {
+    #[allow(dead_code)]
     pub fn new(w: u32, h: u32) -> Map {
         let mut blocks = Vec::new();
 
@@ -40,62 +161,775 @@ impl Map {
                     continue;
                 }
 
-                blocks.push(TerrainBlock {
-                    x,
-                    y,
-                    selected: false,
-                    selected_time: Instant::now(),
-                    highlighted: false,
-                    hightligh_start: Instant::now(),
-                    state: BlockState::Normal,
-                    id: y * (w) + x,
-                });
+                blocks.push(TerrainBlock::new(y * w + x, x, y, BlockState::Normal));
+            }
+        }
+
+        Map::from_blocks(w, h, blocks)
+    }
+
+    // Carves a maze with a recursive backtracker: corridors run through odd cells and are
+    // `Cleared`, everything else stays a `Normal` wall. Same `seed` gives the same maze.
+    pub fn from_maze(w: u32, h: u32, seed: u64) -> Map {
+        let mut cleared = vec![false; (w * h) as usize];
+        let mut rng = XorShift::new(seed);
+
+        if w > 1 && h > 1 {
+            let mut stack = vec![(1u32, 1u32)];
+            cleared[(w + 1) as usize] = true;
+
+            while let Some(&(x, y)) = stack.last() {
+                let mut neighbours = Vec::with_capacity(4);
+                if x >= 3 && !cleared[(y * w + x - 2) as usize] {
+                    neighbours.push((x - 2, y));
+                }
+                if x + 2 < w && !cleared[(y * w + x + 2) as usize] {
+                    neighbours.push((x + 2, y));
+                }
+                if y >= 3 && !cleared[((y - 2) * w + x) as usize] {
+                    neighbours.push((x, y - 2));
+                }
+                if y + 2 < h && !cleared[((y + 2) * w + x) as usize] {
+                    neighbours.push((x, y + 2));
+                }
+
+                if neighbours.is_empty() {
+                    stack.pop();
+                    continue;
+                }
+
+                let (nx, ny) = neighbours[(rng.next() % neighbours.len() as u64) as usize];
+
+                // Remove the wall between the current cell and the chosen one
+                cleared[(((y + ny) / 2) * w + (x + nx) / 2) as usize] = true;
+                cleared[(ny * w + nx) as usize] = true;
+                stack.push((nx, ny));
+            }
+        }
+
+        let mut blocks = Vec::with_capacity((w * h) as usize);
+        for y in 0..h {
+            for x in 0..w {
+                let id = y * w + x;
+                let state = if cleared[id as usize] { BlockState::Cleared } else { BlockState::Normal };
+                let mut block = TerrainBlock::new(id, x, y, state);
+                block.material = (rng.next() % BLOCK_MATERIALS as u64) as u32;
+                blocks.push(block);
+            }
+        }
+
+        Map::from_blocks(w, h, blocks)
+    }
+
+    // `w`x`h` cells over the whole height map: cell (x, y) samples it at the cell center,
+    // `HeightMap::sample((x + 0.5) / w, (y + 0.5) / h)`. Both grids grow along world +x and -z,
+    // so the blocks line up with the terrain once they're spread over its extent, i.e.
+    // `TerrainRenderSystem::set_grid` with a spacing of the terrain width / `w` and origin 0.
+    //
+    // `threshold` is a height in the units of `HeightMap::sample` (world y, up is negative).
+    // Cells where the terrain rises above it become `Normal` walls, the rest is `Cleared`.
+    #[allow(dead_code)]
+    pub fn from_heightmap(height_map: &HeightMap, w: u32, h: u32, threshold: f32) -> Map {
+        let mut blocks = Vec::with_capacity((w * h) as usize);
+        for y in 0..h {
+            for x in 0..w {
+                let height = height_map.sample((x as f32 + 0.5) / w as f32, (y as f32 + 0.5) / h as f32);
+                let state = if height < threshold { BlockState::Normal } else { BlockState::Cleared };
+                blocks.push(TerrainBlock::new(y * w + x, x, y, state));
+            }
+        }
+
+        Map::from_blocks(w, h, blocks)
+    }
+
+    // Fails if the map is larger than `MAX_MAP_CELLS`, a block is outside of it or two blocks
+    // share a cell
+    pub fn from_data(data: &MapData) -> Result<Map, String> {
+        let cells = data.w.checked_mul(data.h)
+            .filter(|&cells| cells <= MAX_MAP_CELLS)
+            .ok_or(format!("a {}x{} map is too large", data.w, data.h))?;
+        if data.blocks.len() > cells as usize {
+            return Err(format!("{} blocks don't fit in a {}x{} map", data.blocks.len(), data.w, data.h));
+        }
+        let mut occupied = vec![false; cells as usize];
+        let mut blocks = Vec::with_capacity(data.blocks.len());
+
+        for block in data.blocks.iter() {
+            if block.x >= data.w || block.y >= data.h {
+                return Err(format!("block ({}, {}) is outside of the {}x{} map", block.x, block.y, data.w, data.h));
+            }
+
+            let id = block.y * data.w + block.x;
+            if occupied[id as usize] {
+                return Err(format!("more than one block at ({}, {})", block.x, block.y));
             }
+            occupied[id as usize] = true;
+
+            let state = if block.cleared { BlockState::Cleared } else { BlockState::Normal };
+            let mut terrain_block = TerrainBlock::new(id, block.x, block.y, state);
+            terrain_block.material = block.material;
+            terrain_block.height = block.height;
+            terrain_block.scale = block.scale;
+            terrain_block.rotation = block.rotation;
+            blocks.push(terrain_block);
         }
 
+        Ok(Map::from_blocks(data.w, data.h, blocks))
+    }
+
+    pub fn data(&self) -> MapData {
+        MapData {
+            w: self.w,
+            h: self.h,
+            blocks: self.blocks.iter().map(|block| BlockData {
+                x: block.x,
+                y: block.y,
+                cleared: block.state == BlockState::Cleared,
+                material: block.material,
+                height: block.height,
+                scale: block.scale,
+                rotation: block.rotation,
+            }).collect(),
+        }
+    }
+
+    fn from_blocks(w: u32, h: u32, blocks: Vec<TerrainBlock>) -> Map {
+        let index = blocks.iter().enumerate().map(|(slot, block)| (block.id, slot)).collect();
+        let active = blocks.iter().enumerate()
+            .filter(|(_, block)| block.state != BlockState::Cleared)
+            .map(|(slot, _)| slot)
+            .collect();
+
         Map {
             w,
             h,
             blocks,
+            index,
+            active,
+            highlighted: None,
             changed: false,
+            undo_stack: VecDeque::new(),
+            redo_stack: vec![],
+            history_depth: DEFAULT_HISTORY_DEPTH,
         }
     }
 
-    pub fn highlight(&mut self, id: Option<u32>) {
-        let mut changed = false;
+    // Every block, `Cleared` ones included, see `active_blocks`
+    #[allow(dead_code)]
+    pub fn blocks(&self) -> &[TerrainBlock] {
+        &self.blocks
+    }
+
+    pub fn block(&self, id: u32) -> Option<&TerrainBlock> {
+        self.index.get(&id).map(|&slot| &self.blocks[slot])
+    }
 
-        for block in self.blocks.iter_mut() {
-            let new_highlighted = Some(block.id) == id;
+    pub fn xy_to_id(&self, x: u32, y: u32) -> u32 {
+        y * self.w + x
+    }
 
-            if block.highlighted != new_highlighted {
-                block.highlighted = new_highlighted;
-                block.hightligh_start = Instant::now();
-                changed = true;
+    // Not part of the undo history
+    #[allow(dead_code)]
+    pub fn set_height(&mut self, id: u32, height: u32) {
+        if let Some(&slot) = self.index.get(&id) {
+            self.blocks[slot].height = height;
+            self.changed = true;
+        }
+    }
+
+    // CPU alternative to the object id picker: first block that is not `Cleared` along the ray.
+    // Blocks are the unit cubes `grid` places them at (see `BlockGrid::offset`), [-height - 1,
+    // -height] on world y. With a spacing below 1 the cubes overlap, each one is then only hit
+    // inside its own cell.
+    pub fn pick_ray(&self, origin: Point3<f32>, dir: Vector3<f32>, grid: &BlockGrid) -> Option<u32> {
+        if self.w == 0 || self.h == 0 {
+            return None;
+        }
+
+        // Grid space: x and y in cells, t stays the world ray parameter
+        let p = [(origin.x - grid.origin[0]) / grid.spacing, (-origin.z - grid.origin[1]) / grid.spacing];
+        let d = [dir.x / grid.spacing, -dir.z / grid.spacing];
+        // Side of a cube in cells
+        let footprint = (1.0 / grid.spacing).min(1.0);
+
+        // Part of the ray inside the blocks' height range and the grid bounds
+        let mut t_range = (0.0f32, f32::INFINITY);
+        let mut clip = |start: f32, dir: f32, min: f32, max: f32| {
+            if dir == 0.0 {
+                if start < min || start > max {
+                    t_range = (1.0, 0.0);
+                }
+                return;
+            }
+            let (t0, t1) = ((min - start) / dir, (max - start) / dir);
+            t_range = (t_range.0.max(t0.min(t1)), t_range.1.min(t0.max(t1)));
+        };
+        let max_height = self.active_blocks().map(|block| block.height).max().unwrap_or(0);
+        clip(origin.y, dir.y, -1.0 - max_height as f32, 0.0);
+        clip(p[0], d[0], 0.0, self.w as f32);
+        clip(p[1], d[1], 0.0, self.h as f32);
+        let (t_enter, t_exit) = t_range;
+        if t_enter > t_exit {
+            return None;
+        }
+
+        // Walk the cells the ray crosses (Amanatides & Woo)
+        let start = [p[0] + d[0] * t_enter, p[1] + d[1] * t_enter];
+        let mut cell = [0usize; 2];
+        let mut step = [0i64; 2];
+        let mut t_next = [f32::INFINITY; 2];
+        let mut t_delta = [f32::INFINITY; 2];
+        let size = [self.w, self.h];
+        for axis in 0..2 {
+            cell[axis] = (start[axis].floor().max(0.0) as usize).min(size[axis] as usize - 1);
+            if d[axis] > 0.0 {
+                step[axis] = 1;
+                t_next[axis] = t_enter + ((cell[axis] + 1) as f32 - start[axis]) / d[axis];
+                t_delta[axis] = 1.0 / d[axis];
+            } else if d[axis] < 0.0 {
+                step[axis] = -1;
+                t_next[axis] = t_enter + (cell[axis] as f32 - start[axis]) / d[axis];
+                t_delta[axis] = -1.0 / d[axis];
             }
         }
 
-        self.changed = changed;
+        let mut t_cell = t_enter;
+        loop {
+            let axis = if t_next[0] < t_next[1] { 0 } else { 1 };
+
+            // The ray's height range over the cube footprint in the cell has to overlap the block
+            let id = self.xy_to_id(cell[0] as u32, cell[1] as u32);
+            let mut t_block = (t_cell, t_next[axis].min(t_exit));
+            for footprint_axis in 0..2 {
+                let min = cell[footprint_axis] as f32;
+                if d[footprint_axis] == 0.0 {
+                    if p[footprint_axis] > min + footprint {
+                        t_block = (1.0, 0.0);
+                    }
+                    continue;
+                }
+                let t0 = (min - p[footprint_axis]) / d[footprint_axis];
+                let t1 = (min + footprint - p[footprint_axis]) / d[footprint_axis];
+                t_block = (t_block.0.max(t0.min(t1)), t_block.1.min(t0.max(t1)));
+            }
+            let (y0, y1) = (origin.y + dir.y * t_block.0, origin.y + dir.y * t_block.1);
+            let hit = t_block.0 <= t_block.1 && self.block(id).is_some_and(|block| {
+                let bottom = -(block.height as f32);
+                block.state != BlockState::Cleared && y0.min(y1) <= bottom && y0.max(y1) >= bottom - 1.0
+            });
+            if hit {
+                return Some(id);
+            }
+
+            if t_next[axis] > t_exit {
+                return None;
+            }
+
+            let next = cell[axis] as i64 + step[axis];
+            if next < 0 || next >= size[axis] as i64 {
+                return None;
+            }
+            cell[axis] = next as usize;
+            t_cell = t_next[axis];
+            t_next[axis] += t_delta[axis];
+        }
+    }
+
+    // Block of the `grid` cell under the world position, the cell starts at the block offset
+    // like in `pick_ray`. `None` outside the grid or over a `Cleared` block.
+    #[allow(dead_code)]
+    pub fn nearest_block(&self, world_x: f32, world_z: f32, grid: &BlockGrid) -> Option<u32> {
+        let x = ((world_x - grid.origin[0]) / grid.spacing).floor();
+        let y = ((-world_z - grid.origin[1]) / grid.spacing).floor();
+        if !(x >= 0.0 && y >= 0.0 && x < self.w as f32 && y < self.h as f32) {
+            return None;
+        }
+
+        let id = self.xy_to_id(x as u32, y as u32);
+        self.block(id)
+            .filter(|block| block.state != BlockState::Cleared)
+            .map(|block| block.id)
+    }
+
+    pub fn active_blocks(&self) -> impl Iterator<Item=&TerrainBlock> {
+        self.active.iter().map(move |&slot| &self.blocks[slot])
+    }
+
+    // Only touches the previously and the newly highlighted block
+    pub fn highlight(&mut self, id: Option<u32>) {
+        // Cleared blocks are never highlighted, see `update`
+        let slot = id
+            .and_then(|id| self.index.get(&id).copied())
+            .filter(|slot| self.active.contains(slot));
+        if slot == self.highlighted {
+            return;
+        }
+
+        if let Some(old) = self.highlighted.take() {
+            let block = &mut self.blocks[old];
+            block.highlighted = false;
+            block.highlight_age = 0.0;
+        }
+
+        if let Some(new) = slot {
+            let block = &mut self.blocks[new];
+            block.highlighted = true;
+            block.highlight_age = 0.0;
+        }
+
+        self.highlighted = slot;
+        self.changed = true;
     }
 
     pub fn select(&mut self, id: Option<u32>) {
-        for block in self.blocks.iter_mut() {
-            if Some(block.id) == id {
-                block.selected = !block.selected;
-                block.selected_time = Instant::now();
-                break;
-            }
+        if let Some(&index) = id.and_then(|id| self.index.get(&id)) {
+            let block = &mut self.blocks[index];
+            let before = BlockSnapshot::of(block);
+            block.selected = !block.selected;
+            block.selected_age = 0.0;
+
+            let after = BlockSnapshot::of(block);
+            self.record(vec![BlockChange { index, before, after }]);
         }
 
         self.changed = true;
     }
 
-    pub fn update(&mut self) {
-        for block in self.blocks.iter_mut() {
-            if block.selected && block.selected_time.elapsed().as_millis() > 500 {
+    // A highlight pulses or a selected block waits to be cleared by `update`
+    pub fn is_animating(&self) -> bool {
+        self.active_blocks().any(|block| block.selected || block.highlighted)
+    }
+
+    // Advances the simulation by `dt` seconds. Only depends on the steps it's given, not on the
+    // wall clock, see `AppConfig::fixed_timestep`.
+    pub fn update(&mut self, dt: f32) {
+        let mut cleared = vec![];
+
+        for &index in self.active.iter() {
+            let block = &mut self.blocks[index];
+            if block.highlighted {
+                block.highlight_age += dt;
+            }
+            if !block.selected {
+                continue;
+            }
+
+            block.selected_age += dt;
+            if block.selected_age > SELECTED_CLEAR_DELAY {
                 block.selected = false;
                 block.highlighted = false;
                 block.state = BlockState::Cleared;
+                cleared.push(index);
             }
         }
+
+        for index in cleared {
+            if self.highlighted == Some(index) {
+                self.highlighted = None;
+            }
+
+            self.active.remove(&index);
+            self.record_clear(index);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.history_depth = depth;
+        while self.undo_stack.len() > depth {
+            self.undo_stack.pop_front();
+        }
+    }
+
+    pub fn undo(&mut self) -> bool {
+        match self.undo_stack.pop_back() {
+            Some(changes) => {
+                for change in changes.iter().rev() {
+                    self.restore(change.index, &change.before);
+                }
+
+                self.redo_stack.push(changes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        match self.redo_stack.pop() {
+            Some(changes) => {
+                for change in changes.iter() {
+                    self.restore(change.index, &change.after);
+                }
+
+                self.undo_stack.push_back(changes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // A* over the block grid. Cells without a block or with a `Cleared` block are walkable.
+    // Returns grid coordinates from `start` to `goal` inclusive.
+    pub fn find_path(&self, start: [u32; 2], goal: [u32; 2], connectivity: Connectivity) -> Option<Vec<[u32; 2]>> {
+        let (w, h) = (self.w as i64, self.h as i64);
+        let idx = |x: i64, y: i64| (y * w + x) as usize;
+
+        let mut walkable = vec![true; (w * h) as usize];
+        for block in self.blocks.iter() {
+            if block.state != BlockState::Cleared {
+                walkable[idx(block.x as i64, block.y as i64)] = false;
+            }
+        }
+
+        let is_walkable = |x: i64, y: i64| x >= 0 && y >= 0 && x < w && y < h && walkable[idx(x, y)];
+
+        let (sx, sy) = (start[0] as i64, start[1] as i64);
+        let (gx, gy) = (goal[0] as i64, goal[1] as i64);
+        if !is_walkable(sx, sy) || !is_walkable(gx, gy) {
+            return None;
+        }
+
+        let heuristic = |x: i64, y: i64| -> f32 {
+            let dx = (x - gx).abs() as f32;
+            let dy = (y - gy).abs() as f32;
+            match connectivity {
+                Connectivity::Four => dx + dy,
+                Connectivity::Eight => dx.max(dy) + (std::f32::consts::SQRT_2 - 1.0) * dx.min(dy),
+            }
+        };
+
+        let steps: &[(i64, i64)] = match connectivity {
+            Connectivity::Four => &[(1, 0), (-1, 0), (0, 1), (0, -1)],
+            Connectivity::Eight => &[(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)],
+        };
+
+        let mut cost = vec![f32::INFINITY; (w * h) as usize];
+        let mut came_from = vec![usize::MAX; (w * h) as usize];
+        let mut open = BinaryHeap::new();
+
+        cost[idx(sx, sy)] = 0.0;
+        open.push(OpenNode { priority: heuristic(sx, sy), x: sx, y: sy });
+
+        while let Some(OpenNode { x, y, .. }) = open.pop() {
+            if (x, y) == (gx, gy) {
+                let mut path = vec![[x as u32, y as u32]];
+                let mut current = idx(x, y);
+                while came_from[current] != usize::MAX {
+                    current = came_from[current];
+                    path.push([(current as i64 % w) as u32, (current as i64 / w) as u32]);
+                }
+                path.reverse();
+
+                return Some(path);
+            }
+
+            for &(dx, dy) in steps {
+                let (nx, ny) = (x + dx, y + dy);
+                if !is_walkable(nx, ny) {
+                    continue;
+                }
+
+                let diagonal = dx != 0 && dy != 0;
+                if diagonal && !is_walkable(x + dx, y) && !is_walkable(x, y + dy) {
+                    continue;
+                }
+
+                let step_cost = if diagonal { std::f32::consts::SQRT_2 } else { 1.0 };
+                let new_cost = cost[idx(x, y)] + step_cost;
+                if new_cost < cost[idx(nx, ny)] {
+                    cost[idx(nx, ny)] = new_cost;
+                    came_from[idx(nx, ny)] = idx(x, y);
+                    open.push(OpenNode { priority: new_cost + heuristic(nx, ny), x: nx, y: ny });
+                }
+            }
+        }
+
+        None
+    }
+
+    fn restore(&mut self, index: usize, snapshot: &BlockSnapshot) {
+        let block = &mut self.blocks[index];
+        block.state = snapshot.state.clone();
+        block.selected = snapshot.selected;
+        block.selected_age = 0.0;
+        block.highlighted = false;
+        if self.highlighted == Some(index) {
+            self.highlighted = None;
+        }
+
+        if block.state == BlockState::Cleared {
+            self.active.remove(&index);
+        } else {
+            self.active.insert(index);
+        }
+
+        self.changed = true;
+    }
+
+    fn record(&mut self, changes: Vec<BlockChange>) {
+        self.redo_stack.clear();
+
+        if self.history_depth == 0 {
+            return;
+        }
+
+        self.undo_stack.push_back(changes);
+        while self.undo_stack.len() > self.history_depth {
+            self.undo_stack.pop_front();
+        }
+    }
+
+    // A selected block gets cleared automatically; fold the clear into the selection entry,
+    // so that select + clear is undone in one step.
+    fn record_clear(&mut self, index: usize) {
+        let after = BlockSnapshot::of(&self.blocks[index]);
+
+        for changes in self.undo_stack.iter_mut().rev() {
+            if let Some(change) = changes.iter_mut().find(|c| c.index == index) {
+                if change.after.selected {
+                    change.after = after;
+                    return;
+                }
+                break;
+            }
+        }
+
+        let before = BlockSnapshot { state: BlockState::Normal, selected: false };
+        self.record(vec![BlockChange { index, before, after }]);
+    }
+}
+
+struct OpenNode {
+    priority: f32,
+    x: i64,
+    y: i64,
+}
+
+impl PartialEq for OpenNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority
+    }
+}
+
+impl Eq for OpenNode {}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OpenNode {
+    // Reversed, so that `BinaryHeap` pops the lowest priority first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.priority.partial_cmp(&self.priority).unwrap_or(Ordering::Equal)
+    }
+}
+
+// Small deterministic generator for map layouts, no need for a full rand dependency.
+struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        XorShift { state: seed.max(1) }
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Point3, Vector3};
+
+    use crate::terrain::HeightMap;
+    use crate::terrain_render_system::BlockGrid;
+
+    use super::{BlockData, BlockState, Connectivity, Map, MapData, TerrainBlock};
+
+    // '#' is a wall, anything else an empty cell
+    fn map(rows: &[&str]) -> Map {
+        let (w, h) = (rows[0].len() as u32, rows.len() as u32);
+        let mut blocks = Vec::new();
+        for (y, row) in rows.iter().enumerate() {
+            for (x, cell) in row.chars().enumerate() {
+                if cell == '#' {
+                    let (x, y) = (x as u32, y as u32);
+                    blocks.push(TerrainBlock::new(y * w + x, x, y, BlockState::Normal));
+                }
+            }
+        }
+
+        Map::from_blocks(w, h, blocks)
+    }
+
+    #[test]
+    fn find_path_takes_diagonals() {
+        let map = map(&[
+            ".....",
+            ".....",
+            ".....",
+            ".....",
+            ".....",
+        ]);
+
+        let four = map.find_path([0, 0], [4, 4], Connectivity::Four).unwrap();
+        let eight = map.find_path([0, 0], [4, 4], Connectivity::Eight).unwrap();
+
+        assert_eq!(four.len(), 9);
+        assert_eq!(eight, vec![[0, 0], [1, 1], [2, 2], [3, 3], [4, 4]]);
+    }
+
+    #[test]
+    fn find_path_does_not_cut_corners_between_walls() {
+        let closed = map(&[
+            ".#.",
+            "#..",
+            "...",
+        ]);
+        assert_eq!(closed.find_path([0, 0], [2, 2], Connectivity::Eight), None);
+
+        // A single wall beside the diagonal does not block it
+        let open = map(&[
+            ".#",
+            "..",
+        ]);
+        assert_eq!(open.find_path([0, 0], [1, 1], Connectivity::Eight), Some(vec![[0, 0], [1, 1]]));
+    }
+
+    #[test]
+    fn data_round_trips() {
+        let mut map = Map::new(4, 4);
+        let id = map.xy_to_id(1, 2);
+        let slot = map.index[&id];
+        map.blocks[slot].material = 2;
+        map.blocks[slot].height = 3;
+        map.blocks[slot].scale = [0.5, 2.0, 0.5];
+        map.blocks[slot].rotation = 1.25;
+        map.blocks[0].state = BlockState::Cleared;
+
+        let loaded = Map::from_data(&map.data()).unwrap();
+        assert_eq!((loaded.w, loaded.h), (4, 4));
+        assert_eq!(loaded.blocks.len(), map.blocks.len());
+        let block = loaded.block(id).unwrap();
+        assert_eq!((block.material, block.height, block.scale, block.rotation), (2, 3, [0.5, 2.0, 0.5], 1.25));
+        assert!(loaded.block(map.blocks[0].id).unwrap().state == BlockState::Cleared);
+        assert_eq!(loaded.active_blocks().count(), map.blocks.len() - 1);
+    }
+
+    #[test]
+    fn from_data_rejects_bad_maps() {
+        let block = |x, y| BlockData { x, y, cleared: false, material: 0, height: 0, scale: [1.0; 3], rotation: 0.0 };
+
+        assert!(Map::from_data(&MapData { w: 2, h: 2, blocks: vec![block(2, 0)] }).is_err());
+        assert!(Map::from_data(&MapData { w: 2, h: 2, blocks: vec![block(1, 1), block(1, 1)] }).is_err());
+        let crowded = MapData { w: 1, h: 1, blocks: vec![block(0, 0); 2] };
+        assert!(Map::from_data(&crowded).is_err());
+        assert!(Map::from_data(&MapData { w: 1 << 16, h: 1 << 16, blocks: vec![] }).is_err());
+    }
+
+    #[test]
+    fn scale_and_rotation_default_in_old_scenes() {
+        let block: BlockData = serde_json::from_str(r#"{"x": 1, "y": 2, "cleared": false, "material": 1}"#).unwrap();
+        assert_eq!((block.height, block.scale, block.rotation), (0, [1.0, 1.0, 1.0], 0.0));
+    }
+
+    #[test]
+    fn pick_ray_follows_the_grid() {
+        let map = map(&[
+            "##.",
+            "...",
+            "..#",
+        ]);
+        let down = Vector3::new(0.0, 1.0, 0.0);
+        // Above the middle of block (x, y) of `grid`, up is -y
+        let above = |grid: &BlockGrid, x: f32, y: f32| {
+            Point3::new(grid.origin[0] + x * grid.spacing + 0.5, -5.0, -(grid.origin[1] + y * grid.spacing + 0.5))
+        };
+
+        let grid = BlockGrid::default();
+        assert_eq!(map.pick_ray(above(&grid, 1.0, 0.0), down, &grid), Some(map.xy_to_id(1, 0)));
+        assert_eq!(map.pick_ray(above(&grid, 1.0, 1.0), down, &grid), None);
+
+        let grid = BlockGrid { spacing: 2.0, origin: [-3.0, 1.5] };
+        assert_eq!(map.pick_ray(above(&grid, 2.0, 2.0), down, &grid), Some(map.xy_to_id(2, 2)));
+        assert_eq!(map.pick_ray(above(&grid, 0.0, 0.0), down, &grid), Some(map.xy_to_id(0, 0)));
+        // In the gap between the cubes of (0, 0) and (1, 0)
+        let gap = above(&grid, 0.0, 0.0) + Vector3::new(1.0, 0.0, 0.0);
+        assert_eq!(map.pick_ray(gap, down, &grid), None);
+
+        // Grazing along row 0 from the left hits its first block
+        let side = Point3::new(grid.origin[0] - 1.0, -0.5, -(grid.origin[1] + 0.5));
+        assert_eq!(map.pick_ray(side, Vector3::new(1.0, 0.0, 0.0), &grid), Some(map.xy_to_id(0, 0)));
+    }
+
+    #[test]
+    fn nearest_block_follows_the_grid() {
+        let map = map(&[
+            "#.",
+            "##",
+        ]);
+        let grid = BlockGrid { spacing: 2.0, origin: [1.0, -1.0] };
+
+        // Cell (1, 1) spans [3, 5] on x and [-3, -1] on z
+        assert_eq!(map.nearest_block(4.5, -1.5, &grid), Some(map.xy_to_id(1, 1)));
+        assert_eq!(map.nearest_block(1.0, 1.0, &grid), Some(map.xy_to_id(0, 0)));
+        // Cleared and outside of the grid
+        assert_eq!(map.nearest_block(3.5, 0.5, &grid), None);
+        assert_eq!(map.nearest_block(0.5, 0.5, &grid), None);
+        assert_eq!(map.nearest_block(5.5, -1.5, &grid), None);
+    }
+
+    #[test]
+    fn from_heightmap_walls_off_high_ground() {
+        let flat = HeightMap::empty(8, 8);
+        assert_eq!(Map::from_heightmap(&flat, 4, 4, 0.5).active_blocks().count(), 16);
+        assert_eq!(Map::from_heightmap(&flat, 4, 4, -0.5).active_blocks().count(), 0);
+
+        // A ridge 2 high over the right half of the terrain
+        let ridge = HeightMap::from_fn(8, 8, |x, _| if x >= 4 { 2.0 } else { 0.0 });
+        let map = Map::from_heightmap(&ridge, 2, 3, -1.0);
+        let walls: Vec<[u32; 2]> = map.active_blocks().map(|block| [block.x, block.y]).collect();
+        assert_eq!(walls, vec![[1, 0], [1, 1], [1, 2]]);
+    }
+
+    #[test]
+    fn update_clears_selected_blocks_after_the_delay() {
+        let mut map = Map::new(3, 3);
+        let id = map.xy_to_id(1, 1);
+        map.select(Some(id));
+
+        map.update(0.3);
+        assert!(map.blocks[map.index[&id]].selected);
+        assert!(map.active_blocks().any(|block| block.id == id));
+
+        map.update(0.3);
+        let block = &map.blocks[map.index[&id]];
+        assert!(!block.selected);
+        assert!(block.state == BlockState::Cleared);
+        assert!(!map.active_blocks().any(|block| block.id == id));
+    }
+
+    #[test]
+    fn highlight_ages_with_the_simulation() {
+        let mut map = Map::new(3, 3);
+        let id = map.xy_to_id(1, 1);
+        map.highlight(Some(id));
+
+        map.update(0.25);
+        map.update(0.25);
+        assert_eq!(map.blocks[map.index[&id]].highlight_age, 0.5);
+
+        map.highlight(None);
+        assert_eq!(map.blocks[map.index[&id]].highlight_age, 0.0);
     }
 }
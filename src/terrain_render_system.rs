@@ -1,98 +1,419 @@
+use std::ffi::CStr;
 use std::sync::Arc;
 
-use cgmath::{Angle, Deg, Matrix4, Rad};
-use vulkano::buffer::{BufferUsage, CpuBufferPool};
-use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, SecondaryAutoCommandBuffer};
-use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use cgmath::{Matrix4, SquareMatrix};
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuBufferPool, TypedBufferAccess};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, DrawIndexedIndirectCommand, DynamicState,
+                              SecondaryAutoCommandBuffer};
+use vulkano::descriptor::DescriptorSet;
+use vulkano::descriptor::descriptor_set::{DescriptorSetsCollection, PersistentDescriptorSet, UnsafeDescriptorSetLayout};
 use vulkano::device::Queue;
+use vulkano::image::SampleCount;
 use vulkano::impl_vertex;
 use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::blend::AttachmentBlend;
+use vulkano::pipeline::depth_stencil::{Compare, DepthStencil};
+use vulkano::pipeline::raster::{CullMode, FrontFace};
+use vulkano::pipeline::shader::{EntryPointAbstract, ShaderModule};
 use vulkano::pipeline::vertex::OneVertexOneInstanceDefinition;
 use vulkano::pipeline::viewport::Viewport;
+use vulkano::query::QueryControlFlags;
 use vulkano::render_pass::Subpass;
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
 
+use crate::base::pipeline::with_raster;
+use crate::base::render_stats::RenderCounters;
+use crate::base::upload::UploadBatch;
 use crate::cube::{Cube, Vertex};
-use crate::terrain_game::{BlockState, Map, TerrainBlock};
+use crate::deferred::transparent_pass;
+use crate::deferred::transparent_pass::PeelLayer;
+use crate::frustum::Frustum;
+use crate::material::Material;
+use crate::object_id;
+use crate::occlusion::OcclusionQueries;
+use crate::terrain_game::{BLOCK_MATERIALS, Map, TerrainBlock};
 
 #[allow(dead_code)]
 pub enum RenderPipeline {
     ObjectIdMap,
     Diffuse,
+    // `Diffuse` without occlusion culling, for views other than the main camera
+    DiffuseNoCulling,
+    // Same output as `Diffuse` with polygons drawn as lines. Falls back to `Diffuse` if the
+    // device has no `fill_mode_non_solid`.
+    Wireframe,
     Shadows,
 }
 
 #[derive(Default, Debug, Clone)]
-struct InstanceData {
-    position_offset: [f32; 2],
+pub struct InstanceData {
+    position_offset: [f32; 3],
     object_id: [f32; 4],
     highlight: [f32; 4],
+    // Index into the bindless material array
+    material_index: u32,
+    // Scale in xyz and rotation around the vertical axis in w, see common/instance.glsl
+    transform: [f32; 4],
 }
-impl_vertex!(InstanceData, position_offset, object_id, highlight);
+impl_vertex!(InstanceData, position_offset, object_id, highlight, material_index, transform);
+
+// Shape of one period of the highlight pulse, from 0 to 1 and back
+#[derive(Clone, Copy, PartialEq)]
+pub enum Easing {
+    // Smooth, slows down at the bright end
+    Sine,
+    // Linear up and down
+    Triangle,
+    // Bright for the first half of the period, off for the second: a blink
+    Pulse,
+}
+
+impl Easing {
+    // `t` is the position in the period, in [0, 1)
+    fn value(&self, t: f32) -> f32 {
+        match self {
+            Easing::Sine => (t * std::f32::consts::PI).sin(),
+            Easing::Triangle => 1.0 - (2.0 * t - 1.0).abs(),
+            Easing::Pulse => if t < 0.5 { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+// Brightness of a highlighted block over time: `0.5 + amplitude * easing` on the highlight
+// channel, 0.5 being the tint of a selected block
+#[derive(Clone, Copy)]
+pub struct HighlightPulse {
+    pub easing: Easing,
+    pub amplitude: f32,
+    // Seconds, must be positive
+    pub period: f32,
+}
+
+impl Default for HighlightPulse {
+    fn default() -> HighlightPulse {
+        HighlightPulse { easing: Easing::Sine, amplitude: 0.25, period: 1.44 }
+    }
+}
+
+impl HighlightPulse {
+    fn value(&self, elapsed: f32) -> f32 {
+        0.5 + self.amplitude * self.easing.value((elapsed / self.period).fract())
+    }
+}
+
+// Block (x, y) is drawn at `origin + (x, y) * spacing`
+#[derive(Clone, Copy, PartialEq)]
+pub struct BlockGrid {
+    pub spacing: f32,
+    pub origin: [f32; 2],
+}
+
+impl Default for BlockGrid {
+    fn default() -> BlockGrid {
+        BlockGrid { spacing: 1.0, origin: [0.0, 0.0] }
+    }
+}
+
+impl BlockGrid {
+    // Instance offset of `block`: x goes along world x, y is `TerrainBlock::height` in whole cubes
+    // (not scaled by the spacing, up is -y) and z is the grid y along world -z
+    pub fn offset(&self, block: &TerrainBlock) -> [f32; 3] {
+        [self.origin[0] + block.x as f32 * self.spacing,
+            -(block.height as f32),
+            self.origin[1] + block.y as f32 * self.spacing]
+    }
+
+    // World space box around `block` with its scale, large enough for any rotation
+    pub fn bounds(&self, block: &TerrainBlock) -> ([f32; 3], [f32; 3]) {
+        let offset = self.offset(block);
+        let center = [offset[0] + 0.5, -offset[2] - 0.5];
+        let radius = 0.5 * block.scale[0].max(block.scale[2]) * std::f32::consts::SQRT_2;
+
+        ([center[0] - radius, offset[1] - block.scale[1], center[1] - radius],
+            [center[0] + radius, offset[1], center[1] + radius])
+    }
+
+    pub fn in_frustum(&self, block: &TerrainBlock, frustum: &Frustum) -> bool {
+        let (min, max) = self.bounds(block);
+        frustum.intersects_aabb(min, max)
+    }
+}
+
+// Must match `MAX_MATERIALS` in mrt_bindless.frag
+const MAX_BINDLESS_MATERIALS: usize = 16;
 
 pub struct TerrainRenderSystem {
     gfx_queue: Arc<Queue>,
     cube: Cube,
 
-    object_map_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    // Built on the first `RenderPipeline::ObjectIdMap` render, apps that never pick skip it
+    object_map_pipeline: Option<Arc<dyn GraphicsPipelineAbstract + Send + Sync>>,
+    object_map_subpass: Subpass,
     main_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    wireframe_pipeline: Option<Arc<dyn GraphicsPipelineAbstract + Send + Sync>>,
+    bbox_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    // Translucent overlay of the selected blocks, drawn in the transparent pass
+    selection_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    // Same overlay drawn into `TransparentPass::peel_subpass`
+    selection_peel_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+
+    occlusion: Option<OcclusionQueries>,
+
+    // Subpass of the shaded pipelines, kept to rebuild them when `sample_shading` changes
+    main_subpass: Subpass,
+    // Minimum fraction of the samples shaded individually, 0 shades once per pixel
+    sample_shading: f32,
+    cull_mode: CullMode,
+    front_face: FrontFace,
+    // Depth test of the shaded pipelines, see `new`
+    depth_compare: Compare,
+    depth_write: bool,
+    counters: RenderCounters,
+    // Where the blocks are drawn, see `set_grid`
+    grid: BlockGrid,
+    highlight_pulse: HighlightPulse,
+    // Real seconds since the last simulation step, added to the highlight ages, see `set_update_lag`
+    update_lag: f32,
+    // Skip blocks outside of the camera for the `Diffuse` and `Wireframe` pipelines
+    frustum_culling: bool,
+    // `proj * view` culled against instead of the camera, see `freeze_frustum`
+    frozen_frustum: Option<Matrix4<f32>>,
+
+    // Set 1 of the main pipelines, indexed by `TerrainBlock::material`
+    material_sets: Vec<Arc<dyn DescriptorSet + Send + Sync>>,
+    // Draws every material in a single batch, the texture is picked per instance. Needs
+    // non-uniform indexing of sampled image arrays, `material_sets` are used without it.
+    bindless: Option<(Arc<dyn GraphicsPipelineAbstract + Send + Sync>, Arc<dyn DescriptorSet + Send + Sync>)>,
 
     uniform_buffer: CpuBufferPool<vs::ty::Data>,
     instance_data: CpuBufferPool<InstanceData>,
+    // Draw parameters for the main instanced draw. Filled on the CPU for now, but keeps the
+    // render path ready for counts written by a culling compute shader.
+    indirect_commands: CpuBufferPool<DrawIndexedIndirectCommand>,
 }
 
 impl TerrainRenderSystem {
-    pub fn new(gfx_queue: Arc<Queue>, main_subpass: Subpass, object_map_subpass: Subpass) -> TerrainRenderSystem {
-        let main_pipeline = {
-            let vs = vs::Shader::load(gfx_queue.device().clone())
-                .expect("failed to create shader module");
-            let fs = fs::Shader::load(gfx_queue.device().clone())
-                .expect("failed to create shader module");
-
-            Arc::new(GraphicsPipeline::start()
-                .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
-                .vertex_shader(vs.main_entry_point(), ())
-                .triangle_list()
-                .viewports_dynamic_scissors_irrelevant(1)
-                .fragment_shader(fs.main_entry_point(), ())
-                .render_pass(main_subpass)
-                .cull_mode_back()
-                .front_face_counter_clockwise()
-                .depth_stencil_simple_depth()
-                .build(gfx_queue.device().clone())
-                .unwrap())
-        };
+    // `cull_mode` and `front_face` apply to every block pipeline, the demo uses back faces and
+    // counter-clockwise. `depth_compare` and `depth_write` are the depth test of the pipelines
+    // drawing into `main_subpass`, Less with writes unless the depth is already laid down.
+    pub fn new(gfx_queue: Arc<Queue>, uploads: &mut UploadBatch, main_subpass: Subpass,
+               object_map_subpass: Subpass, transparent_subpass: Subpass, peel_subpass: Subpass,
+               cull_mode: CullMode, front_face: FrontFace, depth_compare: Compare, depth_write: bool,
+               counters: RenderCounters) -> TerrainRenderSystem {
+        let main_pipeline = create_main_pipeline(gfx_queue.clone(), main_subpass.clone(), 0.0, cull_mode, front_face,
+                                                 depth_compare, depth_write);
 
-        let object_map_pipeline = {
-            let vs = vs_object_map::Shader::load(gfx_queue.device().clone())
-                .expect("failed to create shader module");
-            let fs = fs_object_map::Shader::load(gfx_queue.device().clone())
-                .expect("failed to create shader module");
-
-            Arc::new(GraphicsPipeline::start()
-                .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
-                .vertex_shader(vs.main_entry_point(), ())
-                .triangle_list()
-                .viewports_dynamic_scissors_irrelevant(1)
-                .fragment_shader(fs.main_entry_point(), ())
-                .render_pass(object_map_subpass)
-                .cull_mode_back()
-                .front_face_counter_clockwise()
-                .depth_stencil_simple_depth()
-                .build(gfx_queue.device().clone())
-                .unwrap())
+        let wireframe_pipeline = create_wireframe_pipeline(gfx_queue.clone(), main_subpass.clone(),
+                                                           depth_compare, depth_write);
+        let bbox_pipeline = create_bbox_pipeline(gfx_queue.clone(), main_subpass.clone(), cull_mode, front_face);
+        let selection_pipeline = create_selection_pipeline(gfx_queue.clone(), transparent_subpass,
+                                                           cull_mode, front_face);
+        let selection_peel_pipeline = create_selection_peel_pipeline(gfx_queue.clone(), peel_subpass,
+                                                                     cull_mode, front_face);
+
+        let materials = vec![
+            Material::solid(uploads, [255, 255, 255, 255]),
+            Material::from_png(uploads, include_bytes!("static/ground.png")),
+            Material::checker(uploads, 4, [255, 255, 255, 255], [90, 90, 90, 255]),
+        ];
+        assert_eq!(materials.len(), BLOCK_MATERIALS as usize);
+
+        let sampler = Sampler::new(gfx_queue.device().clone(), Filter::Linear, Filter::Linear,
+                                   MipmapMode::Nearest, SamplerAddressMode::Repeat, SamplerAddressMode::Repeat,
+                                   SamplerAddressMode::Repeat, 0.0, 1.0, 0.0, 0.0).unwrap();
+
+        let material_layout = main_pipeline.layout().descriptor_set_layout(1).unwrap();
+        let material_sets = materials.iter().map(|material| {
+            Arc::new(PersistentDescriptorSet::start(material_layout.clone())
+                .add_sampled_image(material.albedo.clone(), sampler.clone()).unwrap()
+                .build().unwrap()
+            ) as Arc<dyn DescriptorSet + Send + Sync>
+        }).collect();
+
+        let bindless = if gfx_queue.device().enabled_features().shader_sampled_image_array_non_uniform_indexing {
+            let pipeline = create_bindless_pipeline(gfx_queue.clone(), main_subpass.clone(), 0.0, cull_mode, front_face,
+                                                    depth_compare, depth_write);
+
+            assert!(materials.len() <= MAX_BINDLESS_MATERIALS, "too many materials for the bindless array");
+            let layout = pipeline.layout().descriptor_set_layout(1).unwrap();
+            let set = bindless_material_set(layout.clone(), &materials, sampler.clone());
+
+            Some((pipeline, set))
+        } else {
+            None
         };
 
         let uniform_buffer = CpuBufferPool::<vs::ty::Data>::new(gfx_queue.device().clone(), BufferUsage::all());
 
         let instance_data = CpuBufferPool::<InstanceData>::vertex_buffer(gfx_queue.device().clone());
+        let indirect_commands = CpuBufferPool::<DrawIndexedIndirectCommand>::new(
+            gfx_queue.device().clone(),
+            BufferUsage { indirect_buffer: true, ..BufferUsage::none() },
+        );
         TerrainRenderSystem {
             gfx_queue: gfx_queue.clone(),
-            cube: Cube::new(gfx_queue.clone(), 1.0),
+            cube: Cube::new(uploads, 1.0),
             uniform_buffer,
             main_pipeline,
-            object_map_pipeline,
+            wireframe_pipeline,
+            bbox_pipeline,
+            selection_pipeline,
+            selection_peel_pipeline,
+            occlusion: None,
+            main_subpass,
+            sample_shading: 0.0,
+            cull_mode,
+            front_face,
+            depth_compare,
+            depth_write,
+            counters,
+            grid: BlockGrid::default(),
+            highlight_pulse: HighlightPulse::default(),
+            update_lag: 0.0,
+            frustum_culling: false,
+            frozen_frustum: None,
+            material_sets,
+            bindless,
+            object_map_pipeline: None,
+            object_map_subpass,
             instance_data,
+            indirect_commands,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn sample_shading(&self) -> f32 {
+        self.sample_shading
+    }
+
+    // Shades at least `quality` (0..1) of the samples of every pixel separately instead of once
+    // per pixel, which removes the aliasing inside the block textures. The fragment shader cost
+    // grows with the number of samples shaded, up to 4 times with the gbuffer MSAA at 1.0.
+    // Stays at 0 if the device has no `sample_rate_shading`. Rebuilds the shaded pipelines.
+    pub fn set_sample_shading(&mut self, quality: f32) {
+        let quality = if self.gfx_queue.device().enabled_features().sample_rate_shading {
+            quality.max(0.0).min(1.0)
+        } else {
+            0.0
+        };
+        if quality == self.sample_shading {
+            return;
+        }
+
+        self.sample_shading = quality;
+        self.main_pipeline = create_main_pipeline(self.gfx_queue.clone(), self.main_subpass.clone(), quality,
+                                                  self.cull_mode, self.front_face, self.depth_compare, self.depth_write);
+        if let Some((pipeline, _)) = self.bindless.as_mut() {
+            *pipeline = create_bindless_pipeline(self.gfx_queue.clone(), self.main_subpass.clone(), quality,
+                                                 self.cull_mode, self.front_face, self.depth_compare, self.depth_write);
+        }
+    }
+
+    // Rebuilds the pipelines drawing into the gbuffer and the transparent pass for new subpasses,
+    // e.g. after their sample count changed. The object id map keeps its own subpass.
+    pub fn set_subpasses(&mut self, main_subpass: Subpass, transparent_subpass: Subpass, peel_subpass: Subpass) {
+        self.main_pipeline = create_main_pipeline(self.gfx_queue.clone(), main_subpass.clone(), self.sample_shading,
+                                                  self.cull_mode, self.front_face, self.depth_compare, self.depth_write);
+        if let Some((pipeline, _)) = self.bindless.as_mut() {
+            *pipeline = create_bindless_pipeline(self.gfx_queue.clone(), main_subpass.clone(), self.sample_shading,
+                                                 self.cull_mode, self.front_face, self.depth_compare, self.depth_write);
+        }
+        self.wireframe_pipeline = create_wireframe_pipeline(self.gfx_queue.clone(), main_subpass.clone(),
+                                                            self.depth_compare, self.depth_write);
+        self.bbox_pipeline = create_bbox_pipeline(self.gfx_queue.clone(), main_subpass.clone(),
+                                                  self.cull_mode, self.front_face);
+        self.selection_pipeline = create_selection_pipeline(self.gfx_queue.clone(), transparent_subpass,
+                                                            self.cull_mode, self.front_face);
+        self.selection_peel_pipeline = create_selection_peel_pipeline(self.gfx_queue.clone(), peel_subpass,
+                                                                      self.cull_mode, self.front_face);
+        self.main_subpass = main_subpass;
+    }
+
+    // Winding of the front faces of every block pipeline, e.g. flipped for a left-handed camera,
+    // see `Camera::set_right_handed`. Rebuilds them.
+    pub fn set_front_face(&mut self, front_face: FrontFace) {
+        self.front_face = front_face;
+        let transparent_subpass = self.selection_pipeline.subpass().clone();
+        let peel_subpass = self.selection_peel_pipeline.subpass().clone();
+        self.set_subpasses(self.main_subpass.clone(), transparent_subpass, peel_subpass);
+        // Created again on the next object id map draw
+        self.object_map_pipeline = None;
+    }
+
+    pub fn grid(&self) -> BlockGrid {
+        self.grid
+    }
+
+    // Places the blocks `spacing` apart starting at `origin`, e.g. to line the grid up with
+    // terrain cells. The blocks keep their own scale and rotation. Every pipeline, including the
+    // object id map used for picking, shares the same offsets.
+    pub fn set_grid(&mut self, spacing: f32, origin: [f32; 2]) {
+        self.grid = BlockGrid { spacing, origin };
+    }
+
+    pub fn highlight_pulse(&self) -> HighlightPulse {
+        self.highlight_pulse
+    }
+
+    // How a highlighted block pulses, see `rebuild_instance_data`
+    pub fn set_highlight_pulse(&mut self, highlight_pulse: HighlightPulse) {
+        self.highlight_pulse = highlight_pulse;
+    }
+
+    // How far real time is past the last `Map::update` step, in seconds. Keeps the highlight
+    // pulse smooth between the fixed steps, see `App::interpolate`.
+    pub fn set_update_lag(&mut self, lag: f32) {
+        self.update_lag = lag;
+    }
+
+    pub fn frustum_culling(&self) -> bool {
+        self.frustum_culling
+    }
+
+    pub fn set_frustum_culling(&mut self, enabled: bool) {
+        self.frustum_culling = enabled;
+    }
+
+    pub fn frozen_frustum(&self) -> Option<Matrix4<f32>> {
+        self.frozen_frustum
+    }
+
+    // Culls against `view_proj` (`proj * view`) instead of the matrices given to `render`, e.g.
+    // to fly outside of the frustum and see what it keeps. `None` follows the camera again.
+    pub fn freeze_frustum(&mut self, view_proj: Option<Matrix4<f32>>) {
+        self.frozen_frustum = view_proj;
+    }
+
+    // See `BlockGrid::bounds`
+    pub fn block_bounds(&self, block: &TerrainBlock) -> ([f32; 3], [f32; 3]) {
+        self.grid.bounds(block)
+    }
+
+    // See `BlockGrid::offset`
+    pub fn block_offset(&self, block: &TerrainBlock) -> [f32; 3] {
+        self.grid.offset(block)
+    }
+
+    // Occlusion culling skips blocks whose bounding box was not visible in the previous frame.
+    // It costs one extra draw per block, so it only pays off for densely occluded scenes.
+    //
+    // Must be called every frame before `render` when occlusion culling is on (outside of a
+    // render pass): collects the previous frame results and resets the queries.
+    pub fn begin_frame<F>(&mut self, before_future: F, map: &Map, occlusion_culling: bool) -> Box<dyn GpuFuture>
+        where F: GpuFuture + 'static
+    {
+        if !occlusion_culling {
+            self.occlusion = None;
+            return Box::new(before_future);
+        }
+
+        let count = map.w * map.h;
+        if self.occlusion.as_ref().map(|o| o.len()) != Some(count) {
+            self.occlusion = Some(OcclusionQueries::new(self.gfx_queue.clone(), count));
         }
+
+        self.occlusion.as_mut().unwrap().begin_frame(before_future)
     }
 
     pub fn render(&mut self, pipeline: RenderPipeline, map: &Map, viewport_dimensions: [u32; 2],
@@ -108,14 +429,43 @@ impl TerrainRenderSystem {
             self.uniform_buffer.next(uniform_data).unwrap()
         };
 
-        let instance_data_subbuffer = {
-            let inst_data = self.rebuild_instance_data(map.blocks.clone());
-            self.instance_data.chunk(inst_data).unwrap()
+        let shaded = matches!(pipeline, RenderPipeline::Diffuse | RenderPipeline::DiffuseNoCulling | RenderPipeline::Wireframe);
+        let cull = match pipeline {
+            RenderPipeline::Diffuse | RenderPipeline::Wireframe => self.occlusion.is_some(),
+            _ => false,
+        };
+
+        let mut blocks: Vec<&TerrainBlock> = match &self.occlusion {
+            Some(occlusion) if cull => visible_blocks(map, |id| occlusion.is_visible(id)),
+            _ => map.active_blocks().collect(),
+        };
+        match pipeline {
+            RenderPipeline::Diffuse | RenderPipeline::Wireframe if self.frustum_culling => {
+                let frustum = Frustum::from_matrix(self.frozen_frustum.unwrap_or(proj * view) * world);
+                blocks.retain(|block| self.grid.in_frustum(block, &frustum));
+            }
+            _ => (),
+        }
+
+        // Filled polygons go through the bindless pipeline when there is one
+        let bindless = match (&pipeline, &self.bindless) {
+            (RenderPipeline::Diffuse, Some(bindless)) | (RenderPipeline::DiffuseNoCulling, Some(bindless)) => {
+                Some(bindless.clone())
+            }
+            _ => None,
+        };
+
+        // Otherwise shaded pipelines draw one batch per material, the rest ignore materials
+        let batches = if shaded && bindless.is_none() {
+            self.rebuild_material_batches(blocks.into_iter())
+        } else {
+            vec![rebuild_instance_data(blocks.into_iter(), &self.grid, &self.highlight_pulse, self.update_lag)]
         };
 
         let pipeline = match pipeline {
-            RenderPipeline::Diffuse => self.main_pipeline.clone(),
-            RenderPipeline::ObjectIdMap => self.object_map_pipeline.clone(),
+            RenderPipeline::Diffuse | RenderPipeline::DiffuseNoCulling => self.main_pipeline.clone(),
+            RenderPipeline::Wireframe => self.wireframe_pipeline.clone().unwrap_or(self.main_pipeline.clone()),
+            RenderPipeline::ObjectIdMap => self.object_map_pipeline(),
             RenderPipeline::Shadows => unreachable!(),
         };
 
@@ -132,62 +482,496 @@ impl TerrainRenderSystem {
             pipeline.subpass().clone())
             .unwrap();
 
-        builder.draw_indexed(pipeline.clone(),
-                             &DynamicState {
-                                 viewports: Some(vec![Viewport {
-                                     origin: [0.0, 0.0],
-                                     dimensions: [viewport_dimensions[0] as f32,
-                                         viewport_dimensions[1] as f32],
-                                     depth_range: 0.0..1.0,
-                                 }]),
-                                 ..DynamicState::none()
-                             },
-                             vec!(self.cube.vertices.clone(),
-                                  Arc::new(instance_data_subbuffer)),
-                             self.cube.indices.clone(),
-                             set.clone(),
-                             (),
-                             vec![],
-        )
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [viewport_dimensions[0] as f32,
+                    viewport_dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        for (material, inst_data) in batches.into_iter().enumerate() {
+            if inst_data.is_empty() {
+                continue;
+            }
+
+            let indirect_buffer = self.indirect_commands.chunk(vec![DrawIndexedIndirectCommand {
+                index_count: self.cube.indices.len() as u32,
+                instance_count: inst_data.len() as u32,
+                first_index: 0,
+                vertex_offset: 0,
+                first_instance: 0,
+            }]).unwrap();
+
+            self.counters.draw(inst_data.len() as u64, self.cube.indices.len() as u64 / 3);
+            let instance_data_subbuffer = self.instance_data.chunk(inst_data).unwrap();
+            let vertex_buffers: Vec<Arc<dyn BufferAccess + Send + Sync>> =
+                vec!(self.cube.vertices.clone(), Arc::new(instance_data_subbuffer));
+
+            if let Some((bindless_pipeline, materials_set)) = &bindless {
+                builder.draw_indexed_indirect(bindless_pipeline.clone(),
+                                              &dynamic_state,
+                                              vertex_buffers,
+                                              self.cube.indices.clone(),
+                                              indirect_buffer,
+                                              (set.clone(), materials_set.clone()),
+                                              (),
+                                              vec![],
+                )
+                    .unwrap();
+            } else if shaded {
+                builder.draw_indexed_indirect(pipeline.clone(),
+                                              &dynamic_state,
+                                              vertex_buffers,
+                                              self.cube.indices.clone(),
+                                              indirect_buffer,
+                                              (set.clone(), self.material_sets[material].clone()),
+                                              (),
+                                              vec![],
+                )
+                    .unwrap();
+            } else {
+                builder.draw_indexed_indirect(pipeline.clone(),
+                                              &dynamic_state,
+                                              vertex_buffers,
+                                              self.cube.indices.clone(),
+                                              indirect_buffer,
+                                              set.clone(),
+                                              (),
+                                              vec![],
+                )
+                    .unwrap();
+            }
+        }
+
+        if cull {
+            self.record_occlusion_queries(&mut builder, map, &dynamic_state, set.clone());
+        }
+
+        builder.build().unwrap()
+    }
+
+    // Translucent `color` (straight alpha) over the selected blocks, recorded for the subpass
+    // of `TransparentPass`. With `peel` it's recorded for `TransparentPass::peel_subpass` instead,
+    // once per layer.
+    pub fn render_selection(&self, map: &Map, viewport_dimensions: [u32; 2], view: Matrix4<f32>,
+                            proj: Matrix4<f32>, color: [f32; 4], peel: Option<&PeelLayer>) -> SecondaryAutoCommandBuffer
+    {
+        let pipeline = match peel {
+            Some(_) => &self.selection_peel_pipeline,
+            None => &self.selection_pipeline,
+        };
+
+        let uniform_buffer_subbuffer = self.uniform_buffer.next(vs::ty::Data {
+            world: Matrix4::identity().into(),
+            view: view.into(),
+            proj: proj.into(),
+        }).unwrap();
+
+        let layout = pipeline.layout().descriptor_set_layout(0).unwrap();
+        let set = Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_buffer(uniform_buffer_subbuffer).unwrap()
+            .build().unwrap()
+        );
+        let mut sets = vec![set as Arc<dyn DescriptorSet + Send + Sync>];
+        if let Some(peel) = peel {
+            sets.push(peel.descriptor_set(pipeline.layout().descriptor_set_layout(1).unwrap()));
+        }
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+            pipeline.subpass().clone())
             .unwrap();
 
+        let inst_data = rebuild_instance_data(map.active_blocks().filter(|block| block.selected), &self.grid,
+                                              &self.highlight_pulse, self.update_lag);
+        if !inst_data.is_empty() {
+            let dynamic_state = DynamicState {
+                viewports: Some(vec![Viewport {
+                    origin: [0.0, 0.0],
+                    dimensions: [viewport_dimensions[0] as f32,
+                        viewport_dimensions[1] as f32],
+                    depth_range: 0.0..1.0,
+                }]),
+                ..DynamicState::none()
+            };
+
+            self.counters.draw(inst_data.len() as u64, self.cube.indices.len() as u64 / 3);
+            let instance_data_subbuffer = self.instance_data.chunk(inst_data).unwrap();
+            builder.draw_indexed(pipeline.clone(),
+                                 &dynamic_state,
+                                 vec!(self.cube.vertices.clone(), Arc::new(instance_data_subbuffer)),
+                                 self.cube.indices.clone(),
+                                 sets,
+                                 fs_selection::ty::PushConstants { color },
+                                 vec![],
+            )
+                .unwrap();
+        }
+
         builder.build().unwrap()
     }
 
-    fn rebuild_instance_data(&self, blocks: Vec<TerrainBlock>) -> Vec<InstanceData> {
-        let mut instance_data = Vec::<InstanceData>::new();
+    fn object_map_pipeline(&mut self) -> Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
+        if self.object_map_pipeline.is_none() {
+            self.object_map_pipeline = Some(create_object_map_pipeline(
+                self.gfx_queue.clone(), self.object_map_subpass.clone(), self.cull_mode, self.front_face));
+        }
 
-        for block in blocks {
-            if block.state == BlockState::Cleared {
-                continue;
-            }
-            let id = block.id;
-            let x = [((id & 0xFF) as f32) / 255.0,
-                ((id >> 8) & 0xFF) as f32 / 255.0,
-                ((id >> 16) & 0xFF) as f32 / 255.0,
-                1.0];
+        self.object_map_pipeline.clone().unwrap()
+    }
 
-            let mut hightlight = [1.0, 1.0, 1.0, 1.0];
+    fn record_occlusion_queries<S>(&mut self, builder: &mut AutoCommandBufferBuilder<SecondaryAutoCommandBuffer>,
+                                   map: &Map, dynamic_state: &DynamicState, set: S)
+        where S: DescriptorSetsCollection + Clone
+    {
+        // The previous frame queries are still in flight and can't be recorded over
+        if !self.occlusion.as_ref().unwrap().can_record() {
+            return;
+        }
 
-            if block.highlighted && !block.selected {
-                hightlight[0] = 0.5 + (Rad::from(Deg(block.hightligh_start.elapsed().as_millis() as f32 / 8.0)).sin() / 4.0).abs();
-            }
+        let ids: Vec<u32> = map.active_blocks().map(|block| block.id).collect();
+        if ids.is_empty() {
+            return;
+        }
+
+        let bboxes = Arc::new(
+            self.instance_data.chunk(rebuild_instance_data(map.active_blocks(), &self.grid, &self.highlight_pulse, self.update_lag)).unwrap()
+        );
+
+        let occlusion = self.occlusion.as_mut().unwrap();
+        for (idx, &id) in ids.iter().enumerate() {
+            let instance = bboxes.clone().into_buffer_slice().slice(idx..idx + 1).unwrap();
 
-            if block.selected {
-                hightlight[0] = 0.5;
+            unsafe {
+                builder.begin_query(occlusion.pool(), id, QueryControlFlags { precise: false }).unwrap();
             }
 
-            instance_data.push(InstanceData {
-                position_offset: [block.x as f32, block.y as f32],
-                object_id: x,
-                highlight: hightlight,
-            });
+            builder.draw_indexed(self.bbox_pipeline.clone(),
+                                 dynamic_state,
+                                 vec!(self.cube.vertices.clone(), Arc::new(instance)),
+                                 self.cube.indices.clone(),
+                                 set.clone(),
+                                 (),
+                                 vec![],
+            )
+                .unwrap();
+
+            builder.end_query(occlusion.pool(), id).unwrap();
+            self.counters.draw(1, self.cube.indices.len() as u64 / 3);
+        }
+
+        occlusion.mark_pending(ids);
+    }
+
+    // Instance data grouped by `TerrainBlock::material`, unknown materials fall back to the first one
+    fn rebuild_material_batches<'a, I>(&self, blocks: I) -> Vec<Vec<InstanceData>>
+        where I: Iterator<Item=&'a TerrainBlock>
+    {
+        let mut batches: Vec<Vec<&TerrainBlock>> = vec![vec![]; self.material_sets.len()];
+        for block in blocks {
+            let material = if (block.material as usize) < batches.len() { block.material as usize } else { 0 };
+            batches[material].push(block);
+        }
+
+        batches.into_iter()
+            .map(|blocks| rebuild_instance_data(blocks.into_iter(), &self.grid, &self.highlight_pulse, self.update_lag))
+            .collect()
+    }
+}
+
+fn create_main_pipeline(gfx_queue: Arc<Queue>, subpass: Subpass, sample_shading: f32,
+                        cull_mode: CullMode, front_face: FrontFace, depth_compare: Compare, depth_write: bool)
+                        -> Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
+    let vs = vs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+    let fs = fs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+
+    let builder = GraphicsPipeline::start()
+        .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs.main_entry_point(), ())
+        .render_pass(subpass)
+        .depth_stencil(DepthStencil {
+            depth_compare,
+            depth_write,
+            ..DepthStencil::simple_depth_test()
+        });
+
+    let builder = with_raster(builder, cull_mode, front_face);
+
+    let builder = if sample_shading > 0.0 {
+        builder.sample_shading_enabled(sample_shading)
+    } else {
+        builder.sample_shading_disabled()
+    };
+
+    Arc::new(builder.build(gfx_queue.device().clone()).unwrap())
+}
+
+// The array has a fixed size, unused slots repeat the first material. The builder changes its
+// type with every element, so the `MAX_BINDLESS_MATERIALS` slots are spelled out.
+fn bindless_material_set(layout: Arc<UnsafeDescriptorSetLayout>, materials: &[Material], sampler: Arc<Sampler>)
+                         -> Arc<dyn DescriptorSet + Send + Sync> {
+    let albedo = |idx: usize| materials.get(idx).unwrap_or(&materials[0]).albedo.clone();
+
+    Arc::new(PersistentDescriptorSet::start(layout)
+        .enter_array().unwrap()
+        .add_sampled_image(albedo(0), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(1), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(2), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(3), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(4), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(5), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(6), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(7), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(8), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(9), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(10), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(11), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(12), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(13), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(14), sampler.clone()).unwrap()
+        .add_sampled_image(albedo(15), sampler.clone()).unwrap()
+        .leave_array().unwrap()
+        .build().unwrap())
+}
+
+// Same as `create_main_pipeline` with the bindless fragment shader
+fn create_bindless_pipeline(gfx_queue: Arc<Queue>, subpass: Subpass, sample_shading: f32,
+                            cull_mode: CullMode, front_face: FrontFace, depth_compare: Compare,
+                            depth_write: bool)
+                            -> Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
+    let vs = vs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+    // The macro only reflects the UNIFORM_INDEX build, the pipeline runs the real module with
+    // the same interface
+    let fs_interface = fs_bindless::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+    let fs_module = unsafe { ShaderModule::new(gfx_queue.device().clone(), BINDLESS_FS_SPIRV) }
+        .expect("failed to create shader module");
+    let interface = fs_interface.main_entry_point();
+    let fs_entry = unsafe {
+        fs_module.graphics_entry_point(CStr::from_bytes_with_nul_unchecked(b"main\0"),
+                                       interface.layout_desc().clone(), &[],
+                                       interface.input().clone(), interface.output().clone(), interface.ty())
+    };
+
+    let builder = GraphicsPipeline::start()
+        .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs_entry, ())
+        .render_pass(subpass)
+        .depth_stencil(DepthStencil {
+            depth_compare,
+            depth_write,
+            ..DepthStencil::simple_depth_test()
+        });
+
+    let builder = with_raster(builder, cull_mode, front_face);
+
+    let builder = if sample_shading > 0.0 {
+        builder.sample_shading_enabled(sample_shading)
+    } else {
+        builder.sample_shading_disabled()
+    };
+
+    Arc::new(builder.build(gfx_queue.device().clone()).unwrap())
+}
+
+// Needs the `fill_mode_non_solid` feature, `None` without it
+fn create_wireframe_pipeline(gfx_queue: Arc<Queue>, subpass: Subpass, depth_compare: Compare, depth_write: bool)
+                             -> Option<Arc<dyn GraphicsPipelineAbstract + Send + Sync>> {
+    if !gfx_queue.device().enabled_features().fill_mode_non_solid {
+        return None;
+    }
+
+    let vs = vs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+    let fs = fs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+
+    Some(Arc::new(GraphicsPipeline::start()
+        .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs.main_entry_point(), ())
+        .render_pass(subpass)
+        .polygon_mode_line()
+        .depth_stencil(DepthStencil {
+            depth_compare,
+            depth_write,
+            ..DepthStencil::simple_depth_test()
+        })
+        .build(gfx_queue.device().clone())
+        .unwrap()))
+}
+
+// Bounding boxes for occlusion queries: depth tested (LessOrEqual, so a block does not
+// occlude its own box), but no color or depth writes.
+fn create_bbox_pipeline(gfx_queue: Arc<Queue>, subpass: Subpass, cull_mode: CullMode, front_face: FrontFace)
+                        -> Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
+    let vs = vs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+    let fs = fs_bbox::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+
+    let builder = GraphicsPipeline::start()
+        .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs.main_entry_point(), ())
+        .render_pass(subpass)
+        .depth_stencil(DepthStencil {
+            depth_compare: Compare::LessOrEqual,
+            depth_write: false,
+            ..DepthStencil::simple_depth_test()
+        })
+        .blend_collective(AttachmentBlend {
+            mask_red: false,
+            mask_green: false,
+            mask_blue: false,
+            mask_alpha: false,
+            ..AttachmentBlend::pass_through()
+        });
+    let builder = with_raster(builder, cull_mode, front_face);
+
+    Arc::new(builder.build(gfx_queue.device().clone()).unwrap())
+}
+
+fn create_object_map_pipeline(gfx_queue: Arc<Queue>, subpass: Subpass, cull_mode: CullMode, front_face: FrontFace)
+                              -> Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
+    let vs = vs_object_map::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+    let fs = fs_object_map::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+
+    let builder = GraphicsPipeline::start()
+        .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs.main_entry_point(), ())
+        .render_pass(subpass)
+        .depth_stencil_simple_depth();
+    let builder = with_raster(builder, cull_mode, front_face);
+
+    Arc::new(builder.build(gfx_queue.device().clone()).unwrap())
+}
+
+// Same geometry as the main pipeline, so LessOrEqual passes on the block faces
+fn create_selection_pipeline(gfx_queue: Arc<Queue>, subpass: Subpass, cull_mode: CullMode, front_face: FrontFace)
+                             -> Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
+    let vs = vs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+    let fs = fs_selection::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+
+    let builder = GraphicsPipeline::start()
+        .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs.main_entry_point(), ())
+        .render_pass(subpass)
+        .depth_stencil(DepthStencil {
+            depth_compare: Compare::LessOrEqual,
+            depth_write: false,
+            ..DepthStencil::simple_depth_test()
+        })
+        .blend_collective(transparent_pass::alpha_blend());
+    let builder = with_raster(builder, cull_mode, front_face);
+
+    Arc::new(builder.build(gfx_queue.device().clone()).unwrap())
+}
+
+// `create_selection_pipeline` for depth peeling: every layer keeps the nearest remaining
+// fragment, so the depth is tested and written and nothing is blended
+fn create_selection_peel_pipeline(gfx_queue: Arc<Queue>, subpass: Subpass, cull_mode: CullMode, front_face: FrontFace)
+                                  -> Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
+    let vs = vs::Shader::load(gfx_queue.device().clone())
+        .expect("failed to create shader module");
+
+    let builder = GraphicsPipeline::start()
+        .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .depth_stencil_simple_depth();
+    let builder = with_raster(builder, cull_mode, front_face);
+
+    let pipeline = if subpass.num_samples() == Some(SampleCount::Sample1) {
+        let fs = fs_selection_peel_single_sample::Shader::load(gfx_queue.device().clone())
+            .expect("failed to create shader module");
+        builder.fragment_shader(fs.main_entry_point(), ())
+            .render_pass(subpass)
+            .build(gfx_queue.device().clone())
+    } else if !gfx_queue.device().enabled_features().sample_rate_shading {
+        // gl_SampleID can't be used, see common/peel.glsl
+        let fs = fs_selection_peel_first_sample::Shader::load(gfx_queue.device().clone())
+            .expect("failed to create shader module");
+        builder.fragment_shader(fs.main_entry_point(), ())
+            .render_pass(subpass)
+            .build(gfx_queue.device().clone())
+    } else {
+        let fs = fs_selection_peel::Shader::load(gfx_queue.device().clone())
+            .expect("failed to create shader module");
+        builder.fragment_shader(fs.main_entry_point(), ())
+            .render_pass(subpass)
+            .build(gfx_queue.device().clone())
+    };
+
+    Arc::new(pipeline.unwrap())
+}
+
+// Per-instance vertex data of `blocks`. Doesn't touch the device, see `bench`.
+// `lag` is added to the simulated highlight ages, see `TerrainRenderSystem::set_update_lag`.
+pub fn rebuild_instance_data<'a, I>(blocks: I, grid: &BlockGrid, pulse: &HighlightPulse, lag: f32)
+                                    -> Vec<InstanceData>
+    where I: Iterator<Item=&'a TerrainBlock>
+{
+    let mut instance_data = Vec::<InstanceData>::new();
+
+    for block in blocks {
+        let mut hightlight = [1.0, 1.0, 1.0, 1.0];
+
+        if block.highlighted && !block.selected {
+            hightlight[0] = pulse.value(block.highlight_age + lag);
+        }
+
+        if block.selected {
+            hightlight[0] = 0.5;
         }
 
-        return instance_data;
+        instance_data.push(InstanceData {
+            position_offset: grid.offset(block),
+            object_id: object_id::encode(block.id),
+            highlight: hightlight,
+            material_index: if block.material < BLOCK_MATERIALS { block.material } else { 0 },
+            transform: [block.scale[0], block.scale[1], block.scale[2], block.rotation],
+        });
     }
+
+    instance_data
 }
 
+// Active blocks that passed the occlusion test of the previous frame.
+fn visible_blocks<F>(map: &Map, is_visible: F) -> Vec<&TerrainBlock>
+    where F: Fn(u32) -> bool
+{
+    map.active_blocks().filter(|block| is_visible(block.id)).collect()
+}
 
 mod vs {
     vulkano_shaders::shader! {
@@ -203,6 +987,15 @@ mod fs {
     }
 }
 
+mod fs_bindless {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/blocks_terrain/mrt_bindless.frag.uniform_index.spv"
+    }
+}
+
+static BINDLESS_FS_SPIRV: &[u8] = include_bytes!("../resources/shaders/blocks_terrain/mrt_bindless.frag.spv");
+
 mod vs_object_map {
     vulkano_shaders::shader! {
         ty: "vertex",
@@ -216,3 +1009,194 @@ mod fs_object_map {
         bytes: "resources/shaders/blocks_terrain/object_id.frag.spv"
     }
 }
+
+mod fs_bbox {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/blocks_terrain/bbox.frag.spv"
+    }
+}
+
+mod fs_selection {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/blocks_terrain/selection.frag.spv"
+    }
+}
+
+mod fs_selection_peel {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/blocks_terrain/selection_peel.frag.spv"
+    }
+}
+
+mod fs_selection_peel_single_sample {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/blocks_terrain/selection_peel.frag.single_sample.spv"
+    }
+}
+
+mod fs_selection_peel_first_sample {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        bytes: "resources/shaders/blocks_terrain/selection_peel.frag.first_sample.spv"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cgmath::{Matrix4, SquareMatrix, Vector3};
+    use vulkano::device::{Device, DeviceExtensions, Queue};
+    use vulkano::format::Format;
+    use vulkano::image::SampleCount;
+    use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice};
+    use vulkano::pipeline::depth_stencil::Compare;
+    use vulkano::pipeline::raster::{CullMode, FrontFace};
+    use vulkano::sync;
+    use vulkano::Version;
+
+    use crate::base::attachment_pool::AttachmentPool;
+    use crate::base::key_bindings::KeyBindings;
+    use crate::base::render_stats::RenderCounters;
+    use crate::base::upload::UploadBatch;
+    use crate::camera::Camera;
+    use crate::deferred;
+    use crate::deferred::transparent_pass::TransparentPass;
+    use crate::mouse_picker::Picker;
+    use crate::occlusion::update_visibility;
+    use crate::terrain_game::Map;
+
+    use super::{Easing, HighlightPulse, RenderPipeline, TerrainRenderSystem, visible_blocks};
+
+    // Graphics queue of the first device, `None` without a Vulkan loader or device
+    fn headless_queue() -> Option<Arc<Queue>> {
+        let instance = Instance::new(None, Version::V1_1, &InstanceExtensions::none(), None).ok()?;
+        let physical = PhysicalDevice::enumerate(&instance).next()?;
+        let family = physical.queue_families().find(|&q| q.supports_graphics())?;
+
+        // Same features and extensions as `run_app`, so the same pipelines are built
+        let supported_ext = DeviceExtensions::supported_by_device(physical);
+        let device_ext = DeviceExtensions {
+            ext_descriptor_indexing: supported_ext.ext_descriptor_indexing,
+            khr_maintenance3: supported_ext.khr_maintenance3,
+            ..DeviceExtensions::none()
+        };
+        let (_, mut queues) = Device::new(physical, physical.supported_features(), &device_ext,
+                                          [(family, 0.5)].iter().cloned()).ok()?;
+        queues.next()
+    }
+
+    #[test]
+    fn easings_over_the_period() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+
+        assert!(close(Easing::Sine.value(0.0), 0.0));
+        assert!(close(Easing::Sine.value(0.5), 1.0));
+        assert!(close(Easing::Triangle.value(0.25), 0.5));
+        assert!(close(Easing::Triangle.value(0.5), 1.0));
+        assert!(close(Easing::Triangle.value(0.75), 0.5));
+        assert!(close(Easing::Pulse.value(0.25), 1.0));
+        assert!(close(Easing::Pulse.value(0.75), 0.0));
+    }
+
+    #[test]
+    fn default_pulse_matches_the_old_sine() {
+        // Before `HighlightPulse`: 0.5 + |sin(milliseconds / 8 degrees)| / 4
+        let old = |seconds: f32| 0.5 + ((seconds * 1000.0 / 8.0).to_radians().sin() / 4.0).abs();
+        let pulse = HighlightPulse::default();
+
+        for &seconds in [0.0, 0.36, 0.72, 1.0, 2.5].iter() {
+            assert!((pulse.value(seconds) - old(seconds)).abs() < 1e-4, "at {} s", seconds);
+        }
+        assert!((pulse.value(0.36) - (0.5 + 0.25 * std::f32::consts::FRAC_1_SQRT_2)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn visible_blocks_skips_occluded() {
+        let map = Map::new(5, 5);
+        let occluded = [map.xy_to_id(0, 0), map.xy_to_id(4, 2)];
+
+        let mut visible = vec![true; (map.w * map.h) as usize];
+        let results: Vec<(u32, u32)> = map.active_blocks()
+            .map(|block| (block.id, if occluded.contains(&block.id) { 0 } else { 5 }))
+            .collect();
+        update_visibility(&mut visible, &results);
+
+        let ids: Vec<u32> = visible_blocks(&map, |id| visible[id as usize]).iter().map(|block| block.id).collect();
+        assert_eq!(ids.len(), map.active_blocks().count() - occluded.len());
+        assert!(occluded.iter().all(|id| !ids.contains(id)));
+    }
+
+    #[test]
+    fn renders_and_picks_a_block() {
+        let queue = match headless_queue() {
+            Some(queue) => queue,
+            None => {
+                println!("No Vulkan device, skipped");
+                return;
+            }
+        };
+        let dims = [64, 64];
+
+        let pool = AttachmentPool::new(queue.device().clone());
+        let mut picker = Picker::new(queue.clone(), pool.clone(), true);
+        let mut gbuffer = deferred::Framebuffer::new(queue.clone(), pool.clone(),
+                                                     crate::gbuffer_targets(SampleCount::Sample1));
+        gbuffer.resize_swapchain(dims).unwrap();
+        let transparent_pass = TransparentPass::new(queue.clone(), pool, Format::R8G8B8A8Unorm, Format::D32Sfloat,
+                                                    SampleCount::Sample1);
+
+        let mut uploads = UploadBatch::new(queue.clone());
+        let mut terrain = TerrainRenderSystem::new(
+            queue.clone(),
+            &mut uploads,
+            gbuffer.subpass(),
+            picker.subpass(),
+            transparent_pass.subpass(),
+            transparent_pass.peel_subpass(),
+            CullMode::Back,
+            FrontFace::CounterClockwise,
+            Compare::Less,
+            true,
+            RenderCounters::new(),
+        );
+        uploads.wait();
+
+        // The camera looks at the center of one block from above
+        let map = Map::new(3, 3);
+        let block = map.block(map.xy_to_id(1, 1)).unwrap();
+        let center = crate::block_center(terrain.block_offset(block));
+        let mut camera = Camera::new(KeyBindings::default());
+        camera.set_viewport(dims[0], dims[1]);
+        camera.look_at(center + Vector3::new(0.5, -3.0, 3.0), center);
+        let (view, proj) = (camera.view_matrix(), camera.proj_matrix());
+
+        let clip = proj * view * center.to_homogeneous();
+        let ndc = clip / clip.w;
+        let pixel = [((ndc.x + 1.0) * 0.5 * dims[0] as f32) as u32, ((ndc.y + 1.0) * 0.5 * dims[1] as f32) as u32];
+
+        // Albedo target, cleared to transparent black
+        let cb = terrain.render(RenderPipeline::Diffuse, &map, dims, Matrix4::identity(), view, proj);
+        let albedo = deferred::render_and_read(sync::now(queue.device().clone()), queue.clone(), &gbuffer, 0,
+                                               |builder| {
+                                                   builder.execute_commands(cb).unwrap();
+                                               });
+        let texel = 4 * (pixel[1] * dims[0] + pixel[0]) as usize;
+        assert_ne!(&albedo[texel..texel + 4], &[0, 0, 0, 0], "nothing drawn at {:?}", pixel);
+
+        let cb = terrain.render(RenderPipeline::ObjectIdMap, &map, dims, Matrix4::identity(), view, proj);
+        assert_eq!(picker.draw(dims, vec![cb], pixel).unwrap(), Some(block.id));
+
+        // The picked point is on the block
+        let depth = picker.pick_depth().unwrap();
+        let hit = camera.unproject(pixel[0] as f32 + 0.5, pixel[1] as f32 + 0.5, depth).unwrap();
+        let (min, max) = terrain.block_bounds(block);
+        for axis in 0..3 {
+            assert!(hit[axis] > min[axis] - 0.01 && hit[axis] < max[axis] + 0.01, "{:?} outside of {:?}", hit, (min, max));
+        }
+    }
+}